@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Feeds arbitrary descriptor dumps through the checks `bmputil dump-descriptors --check` does,
+//! which parse descriptors the same way discovery does for a live device.
+
+#![no_main]
+
+use bmputil::usb::dump::DescriptorDump;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|json: &str| {
+    if let Ok(dump) = DescriptorDump::from_json(json) {
+        let _ = dump.check();
+    }
+});
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Walks arbitrary bytes as the "extra" descriptors following a configuration or interface.
+
+#![no_main]
+
+use bmputil::usb::ExtraDescriptors;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for descriptor in ExtraDescriptors::new(data) {
+        let _ = descriptor;
+    }
+});
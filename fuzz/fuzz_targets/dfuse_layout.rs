@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Parses arbitrary DfuSe interface strings as memory layouts.
+
+#![no_main]
+
+use bmputil::dfu::DfuProtocol;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|interface_string: &str| {
+    if let Ok(DfuProtocol::Dfuse(segments)) = DfuProtocol::parse_dfuse_layout(interface_string) {
+        for segment in &segments {
+            assert!(segment.end() <= 1 << 32);
+            segment.page_ranges().for_each(drop);
+        }
+    }
+});
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Parses arbitrary data as S-record firmware files.
+
+#![no_main]
+
+use bmputil::srec;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(image) = srec::parse(data) {
+        assert!(image.address as u64 + image.data.len() as u64 <= 1 << 32);
+    }
+});
@@ -0,0 +1,16 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+//! Runs arbitrary string descriptors through what we do with a probe's product string and serial.
+
+#![no_main]
+
+use bmputil::usb::sanitize_descriptor_string;
+use bmputil::version::FirmwareVersion;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|string: &str| {
+    let sanitized = sanitize_descriptor_string(string);
+    assert!(!sanitized.chars().any(char::is_control));
+
+    let _ = FirmwareVersion::from_product_string(&sanitized);
+});
@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module with `async` variants of the operations that block for a long time: finding probes,
+//! reading their serial numbers, waiting for a probe to come back after rebooting, and flashing.
+//!
+//! libusb has no async interface we can use, so each operation runs on its own thread, and the
+//! returned [`BlockingTask`] completes when that thread is done. This works with any executor,
+//! without tying the library to a particular runtime. Enabled by the `async` feature.
+//!
+//! If an operation panics, its task completes with [`ErrorKind::TaskPanicked`], and anything moved
+//! onto its thread (such as the device being flashed) is lost.
+
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+use crate::bmp::{self, BmpDevice, BmpMatchResults, BmpMatcher, DownloadOptions, FirmwareType, ProbeIdentity};
+use crate::dfu::DownloadProgress;
+use crate::error::{Error, ErrorKind, ErrorSource};
+use crate::timeouts::Timeouts;
+
+
+/// Shared between a [`BlockingTask`] and the thread running it.
+struct TaskState<T>
+{
+    output: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// A future for an operation running on its own thread.
+pub struct BlockingTask<T>
+{
+    name: String,
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+impl<T: Send + 'static> BlockingTask<T>
+{
+    /// Starts running `operation` on a new thread named `name`.
+    fn spawn<F>(name: &str, operation: F) -> Result<Self, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(TaskState {
+            output: None,
+            waker: None,
+        }));
+
+        let thread_state = Arc::clone(&state);
+        thread::Builder::new()
+            .name(format!("bmputil {}", name))
+            .spawn(move || {
+                // Caught, so that a panic completes the task rather than leaving it pending forever.
+                let output = panic::catch_unwind(AssertUnwindSafe(operation));
+                let mut state = thread_state.lock().unwrap();
+                state.output = Some(output);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            })
+            .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error())?;
+
+        Ok(Self {
+            name: name.to_string(),
+            state,
+        })
+    }
+}
+
+/// The message a thread panicked with, if it panicked with a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str
+{
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("(no message)")
+}
+
+impl<T> Future for BlockingTask<T>
+{
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, Error>>
+    {
+        let mut state = self.state.lock().unwrap();
+        match state.output.take() {
+            Some(Ok(output)) => Poll::Ready(Ok(output)),
+            Some(Err(payload)) => {
+                let message = panic_message(payload.as_ref()).to_string();
+                Poll::Ready(Err(ErrorKind::TaskPanicked(self.name.clone(), message).error()))
+            },
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+
+/// Async variant of [`BmpMatcher::find_matching_probes`].
+pub async fn find_matching_probes(matcher: BmpMatcher) -> Result<BmpMatchResults, Error>
+{
+    BlockingTask::spawn("probe discovery", move || matcher.find_matching_probes())?.await
+}
+
+/// Async variant of [`BmpDevice::try_serial_number`].
+///
+/// As with [`download`], the device is moved onto another thread, and handed back along with the
+/// result. Combined with [`BmpDevice::partial_info`], this lets a UI list devices straight away and
+/// fill in their serial numbers as they arrive.
+pub async fn serial_number(device: BmpDevice, timeout: Duration) -> Result<(BmpDevice, Result<String, Error>), Error>
+{
+    let task = BlockingTask::spawn("serial number", move || {
+        let result = device.try_serial_number(timeout).map(|serial| serial.to_string());
+        (device, result)
+    })?;
+
+    task.await
+}
+
+/// Async variant of [`bmp::wait_for_probe_reboot`].
+pub async fn wait_for_probe_reboot(identity: ProbeIdentity, timeouts: Timeouts, operation: String)
+    -> Result<BmpDevice, Error>
+{
+    BlockingTask::spawn("reboot wait", move || bmp::wait_for_probe_reboot(&identity, timeouts, &operation))?.await?
+}
+
+/// Async variant of [`BmpDevice::download`].
+///
+/// The device is moved onto the flashing thread, and handed back along with the result once
+/// flashing is done. `progress` is called on that thread; to update a UI, forward the progress to
+/// it, e.g. through a channel.
+pub async fn download<P>(
+    mut device: BmpDevice,
+    firmware: Vec<u8>,
+    firmware_type: FirmwareType,
+    options: DownloadOptions,
+    progress: P,
+) -> Result<(BmpDevice, Result<(), Error>), Error>
+where
+    P: Fn(DownloadProgress) + Send + 'static,
+{
+    let task = BlockingTask::spawn("download", move || {
+        let result = u32::try_from(firmware.len())
+            .map_err(|_| {
+                ErrorKind::InvalidFirmware(Some(format!("{} bytes is more than a probe's address space", firmware.len())))
+                    .error()
+                    .with_ctx("checking firmware size")
+            })
+            .and_then(|length| device.download(firmware.as_slice(), length, firmware_type, &options, progress));
+        (device, result)
+    })?;
+
+    task.await
+}
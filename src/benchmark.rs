@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil benchmark`, which measures how fast a probe's bootloader erases and
+//! writes flash with different DFU transfer sizes and ways of polling it.
+//!
+//! The probe is flashed with the given image once for each combination (leaving it in DFU mode in
+//! between), and the time spent erasing and writing is measured, along with the time spent in USB
+//! transfers. A write much slower than the transfers it took points at the bootloader or the flash;
+//! a write about as slow as its transfers, but slow all the same, points at the bus (a slow hub, a
+//! VM) instead.
+
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+
+use bmputil::bmp::{BmpDevice, DownloadOptions, FirmwareType, RebootTarget};
+use bmputil::dfu::{DownloadPhase, DownloadProgress, PollStrategy};
+use bmputil::error::{Error, ErrorKind};
+use bmputil::usb::telemetry;
+use bmputil::usb::DfuOperatingMode;
+
+use crate::confirm::{AuthorizationLevel, ConfirmationPolicy};
+
+/// Transfer sizes tried by default; sizes the probe doesn't take are skipped.
+const DEFAULT_TRANSFER_SIZES: &[u16] = &[256, 512, 1024, 2048];
+
+/// Polling intervals tried by default, besides waiting as long as the probe asks, in milliseconds.
+const DEFAULT_POLL_INTERVALS: &[u64] = &[0, 1, 5];
+
+
+/// The combination of settings one run uses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Setting
+{
+    transfer_size: u16,
+    poll: PollStrategy,
+}
+
+/// How long one run took.
+#[derive(Debug, Clone)]
+struct Measurement
+{
+    setting: Setting,
+    /// The transfer size actually used, which the probe may have capped.
+    transfer_size: u16,
+    erase: Duration,
+    /// How many bytes were erased, or 0 for a mass erase.
+    erased: usize,
+    write: Duration,
+    written: usize,
+    /// Time spent in USB transfers over the whole run.
+    transfer_time: Duration,
+    transfers: u64,
+}
+
+impl Measurement
+{
+    fn write_throughput(&self) -> f64
+    {
+        self.written as f64 / self.write.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Keeps track of when each phase of a download started.
+#[derive(Default)]
+struct PhaseTimes
+{
+    erase_start: Cell<Option<Instant>>,
+    erased: Cell<usize>,
+    write_start: Cell<Option<Instant>>,
+}
+
+impl PhaseTimes
+{
+    fn record(&self, progress: DownloadProgress)
+    {
+        match progress.phase {
+            DownloadPhase::Erase | DownloadPhase::MassErase => {
+                if self.erase_start.get().is_none() {
+                    self.erase_start.set(Some(Instant::now()));
+                }
+                self.erased.set(progress.total);
+            },
+            DownloadPhase::Download if self.write_start.get().is_none() => {
+                self.write_start.set(Some(Instant::now()));
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Returns the values of the list option `name`, or `default` if it wasn't given.
+fn parse_list<T>(matches: &ArgMatches, name: &str, default: &[T]) -> Vec<T>
+where
+    T: FromStr + Clone,
+    T::Err: Debug,
+{
+    match matches.values_of(name) {
+        Some(values) => values
+            .map(|value| value.parse().expect("Clap ensures values are valid"))
+            .collect(),
+        None => default.to_vec(),
+    }
+}
+
+/// Flashes `firmware` once with `setting`, leaving the probe in DFU mode.
+fn run(dev: &mut BmpDevice, firmware: &[u8], setting: Setting) -> Result<Measurement, Error>
+{
+    let options = DownloadOptions::new()
+        .transfer_size(Some(setting.transfer_size))
+        .poll_strategy(setting.poll)
+        .reboot_to(RebootTarget::Dfu);
+    let times = PhaseTimes::default();
+    let used_transfer_size = Cell::new(setting.transfer_size);
+
+    let transfers_before = telemetry::snapshot();
+    let start = Instant::now();
+    let length = u32::try_from(firmware.len())
+        .map_err(|_| {
+            ErrorKind::InvalidFirmware(Some(format!("{} bytes is more than a probe's address space", firmware.len())))
+                .error()
+                .with_ctx("checking firmware size")
+        })?;
+    dev.download(firmware, length, FirmwareType::Application, &options, |progress| {
+        used_transfer_size.set(progress.transfer_size);
+        times.record(progress);
+    })?;
+    let end = Instant::now();
+    let transfers = telemetry::snapshot().since(&transfers_before);
+
+    let write_start = times.write_start.get().unwrap_or(start);
+    let erase_start = times.erase_start.get().unwrap_or(write_start);
+
+    Ok(Measurement {
+        setting,
+        transfer_size: used_transfer_size.get(),
+        erase: write_start - erase_start,
+        erased: times.erased.get(),
+        write: end - write_start,
+        written: firmware.len(),
+        transfer_time: transfers.transfer_time,
+        transfers: transfers.control_transfers,
+    })
+}
+
+fn format_throughput(bytes: usize, time: Duration) -> String
+{
+    if bytes == 0 || time.is_zero() {
+        return String::from("-");
+    }
+
+    format!("{:.1} KiB/s", bytes as f64 / 1024.0 / time.as_secs_f64())
+}
+
+fn print_summary(measurements: &[Measurement])
+{
+    println!();
+    println!("{:>8}  {:<12} {:>8} {:>12} {:>8} {:>12} {:>10} {:>9}", "Transfer", "Polling", "Erase", "", "Write", "", "USB time", "Transfers");
+    for m in measurements {
+        println!(
+            "{:>8}  {:<12} {:>7.2}s {:>12} {:>7.2}s {:>12} {:>9.2}s {:>9}",
+            m.transfer_size,
+            m.setting.poll.to_string(),
+            m.erase.as_secs_f64(),
+            format_throughput(m.erased, m.erase),
+            m.write.as_secs_f64(),
+            format_throughput(m.written, m.write),
+            m.transfer_time.as_secs_f64(),
+            m.transfers,
+        );
+    }
+
+    let fastest = measurements
+        .iter()
+        .max_by(|a, b| a.write_throughput().total_cmp(&b.write_throughput()));
+    if let Some(fastest) = fastest {
+        println!(
+            "\nFastest write: {} byte transfers, polling {}, at {}.",
+            fastest.transfer_size,
+            fastest.setting.poll,
+            format_throughput(fastest.written, fastest.write),
+        );
+    }
+}
+
+/// Implements `bmputil benchmark`.
+pub fn benchmark_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let filename = matches.value_of("firmware_binary")
+        .expect("No firmware file was specified!"); // Should be impossible, thanks to clap.
+    let firmware = crate::signature_policy(None)
+        .and_then(|policy| crate::read_signed_firmware_file(filename, &policy))
+        .map_err(|e| e.with_ctx("reading firmware file to benchmark with"))?;
+
+    let transfer_sizes: Vec<u16> = parse_list(matches, "transfer-sizes", DEFAULT_TRANSFER_SIZES);
+    let mut polls = vec![PollStrategy::Requested];
+    polls.extend(
+        parse_list(matches, "poll-intervals", DEFAULT_POLL_INTERVALS)
+            .into_iter()
+            .map(|ms| PollStrategy::Interval(Duration::from_millis(ms))),
+    );
+    let runs: usize = matches.value_of("runs").map_or(1, |runs| runs.parse().expect("Clap ensures a valid count"));
+
+    let matcher = crate::matcher_from_cli_args(matches);
+    let mut results = crate::find_probes(&matcher, matches)?;
+    let mut dev = results.pop_single("benchmark")?;
+
+    FirmwareType::validate_application(dev.profile(), &firmware)
+        .map_err(|e| e.with_ctx("validating firmware image"))?;
+    firmware.check_address(dev.profile(), FirmwareType::Application)?;
+
+    let settings: Vec<Setting> = transfer_sizes
+        .iter()
+        .flat_map(|&transfer_size| polls.iter().map(move |&poll| Setting { transfer_size, poll }))
+        .collect();
+    let total_runs = settings.len() * runs;
+    ConfirmationPolicy::from_cli_args(matches).confirm(
+        AuthorizationLevel::Destructive,
+        "benchmarking",
+        &format!(
+            "Benchmarking flashes {} onto the probe {} times, replacing the firmware it runs.",
+            filename,
+            total_runs,
+        ),
+    )?;
+
+    if dev.operating_mode() == DfuOperatingMode::Runtime {
+        println!("Switching the probe to DFU mode...");
+        dev.detach_and_enumerate()
+            .map_err(|e| e.with_ctx("switching the probe to DFU mode"))?;
+    }
+
+    let mut measurements: Vec<Measurement> = Vec::with_capacity(total_runs);
+    for (index, setting) in settings.iter().cycle().take(total_runs).enumerate() {
+        // The probe caps the transfer size; there's no point repeating a size it already capped.
+        let capped = measurements.iter().any(|m| {
+            m.setting.poll == setting.poll && m.transfer_size < setting.transfer_size && m.transfer_size < m.setting.transfer_size
+        });
+        if capped {
+            continue;
+        }
+
+        println!(
+            "Run {}/{}: {} byte transfers, polling {}...",
+            index + 1,
+            total_runs,
+            setting.transfer_size,
+            setting.poll,
+        );
+        let measurement = run(&mut dev, &firmware, *setting)
+            .map_err(|e| e.with_ctx(&format!("benchmarking {} byte transfers, polling {}", setting.transfer_size, setting.poll)))?;
+        measurements.push(measurement);
+    }
+
+    if measurements.is_empty() {
+        return Err(ErrorKind::OperationNotSupported(String::from("benchmarking with none of the given settings")).error());
+    }
+    print_summary(&measurements);
+
+    // Every run wrote the whole image, so it only needs starting.
+    println!("\nRebooting the probe into its firmware...");
+    dev.detach_and_enumerate()
+        .map_err(|e| e.with_ctx("rebooting the probe after benchmarking"))?;
+
+    Ok(())
+}
@@ -0,0 +1,252 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for noticing the Black Magic Debug App (BMDA) using a probe we're about to update.
+//!
+//! BMDA, the PC-hosted build of Black Magic Debug, can drive a Black Magic Probe through its GDB
+//! serial port (the "remote" protocol) rather than running the debugger on the probe itself. While it
+//! does, it has the probe open, and rebooting the probe into DFU mode pulls it out from under BMDA,
+//! possibly partway through writing a target's flash. So before flashing, we look for BMDA processes
+//! and tell the user exactly which one to stop, rather than letting the two silently fight.
+//!
+//! On Linux, we can tell whether a BMDA process has this probe open from `/proc`; elsewhere, any
+//! BMDA process running is assumed to possibly be using it.
+
+use std::fmt::{self, Display, Formatter};
+#[cfg(not(target_os = "linux"))]
+use std::process::Command;
+
+use log::debug;
+
+use bmputil::bmp::BmpDevice;
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::serial_port::{self, ProbePort};
+use bmputil::warnings::{self, WarningCode};
+
+use crate::confirm::{AuthorizationLevel, ConfirmationPolicy};
+
+/// Names BMDA's executable has had, without any `.exe`.
+const BMDA_NAMES: &[&str] = &["blackmagic", "blackmagic-bmda", "blackmagic_bmda", "bmda"];
+
+
+/// A running BMDA process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BmdaProcess
+{
+    pid: u32,
+    /// Its command line, or just its name where the command line can't be read.
+    command: String,
+}
+
+impl BmdaProcess
+{
+    /// How to stop this process from a shell on this platform.
+    fn stop_command(&self) -> String
+    {
+        if cfg!(windows) {
+            format!("taskkill /PID {}", self.pid)
+        } else {
+            format!("kill {}", self.pid)
+        }
+    }
+}
+
+impl Display for BmdaProcess
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        write!(f, "PID {}, `{}`", self.pid, self.command)
+    }
+}
+
+fn is_bmda_name(name: &str) -> bool
+{
+    let name = name.trim();
+    let name = name.strip_suffix(".exe").unwrap_or(name);
+    BMDA_NAMES.iter().any(|bmda| name.eq_ignore_ascii_case(bmda))
+}
+
+/// Finds running BMDA processes.
+#[cfg(target_os = "linux")]
+fn running() -> Vec<BmdaProcess>
+{
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let name = std::fs::read_to_string(entry.path().join("comm")).ok()?;
+            if !is_bmda_name(&name) {
+                return None;
+            }
+            let command = std::fs::read(entry.path().join("cmdline"))
+                .ok()
+                .map(|cmdline| String::from_utf8_lossy(&cmdline).split('\0').filter(|arg| !arg.is_empty()).collect::<Vec<_>>().join(" "))
+                .filter(|command| !command.is_empty())
+                .unwrap_or_else(|| name.trim().to_string());
+            Some(BmdaProcess { pid, command })
+        })
+        .collect()
+}
+
+/// Finds running BMDA processes.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn running() -> Vec<BmdaProcess>
+{
+    let Some(output) = Command::new("ps")
+        .args(["-A", "-o", "pid=,comm="])
+        .output()
+        .inspect_err(|e| debug!("Failed to run ps: {}", e))
+        .ok()
+        .filter(|output| output.status.success())
+    else {
+        return Vec::new();
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (pid, command) = line.trim().split_once(char::is_whitespace)?;
+            let name = command.trim().rsplit('/').next()?;
+            if !is_bmda_name(name) {
+                return None;
+            }
+            Some(BmdaProcess { pid: pid.parse().ok()?, command: command.trim().to_string() })
+        })
+        .collect()
+}
+
+/// Finds running BMDA processes.
+#[cfg(windows)]
+fn running() -> Vec<BmdaProcess>
+{
+    let Some(output) = Command::new("tasklist")
+        .args(["/FO", "CSV", "/NH"])
+        .output()
+        .inspect_err(|e| debug!("Failed to run tasklist: {}", e))
+        .ok()
+        .filter(|output| output.status.success())
+    else {
+        return Vec::new();
+    };
+
+    // Each line is like `"blackmagic.exe","1234","Console","1","12,345 K"`.
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(',').map(|field| field.trim_matches('"'));
+            let name = fields.next()?;
+            let pid = fields.next()?.parse().ok()?;
+            is_bmda_name(name).then(|| BmdaProcess { pid, command: name.to_string() })
+        })
+        .collect()
+}
+
+/// Whether process `pid` has any of `paths` open, or `None` if that can't be told on this platform.
+#[cfg(target_os = "linux")]
+fn has_open(pid: u32, paths: &[std::path::PathBuf]) -> Option<bool>
+{
+    let fds = std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .inspect_err(|e| debug!("Can't list the open files of process {}: {}", pid, e))
+        .ok()?;
+
+    Some(
+        fds.flatten()
+            .filter_map(|fd| std::fs::read_link(fd.path()).ok())
+            .any(|target| paths.contains(&target)),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_open(_pid: u32, _paths: &[std::path::PathBuf]) -> Option<bool>
+{
+    None
+}
+
+/// Finds the BMDA processes that are, or might be, using `dev`.
+fn using(dev: &BmpDevice) -> Vec<BmdaProcess>
+{
+    let processes = running();
+    if processes.is_empty() {
+        return processes;
+    }
+
+    // BMDA talks to a probe through its GDB serial port, but a build with libusb support can open
+    // the probe's USB device node directly, too.
+    let mut paths = Vec::new();
+    if let Some(gdb_port) = dev.serial_number().ok().and_then(|serial| serial_port::find_serial_port(&serial, ProbePort::Gdb).ok()) {
+        paths.extend(std::fs::canonicalize(&gdb_port).ok());
+        paths.push(gdb_port.into());
+    }
+    let device = dev.device();
+    paths.push(format!("/dev/bus/usb/{:03}/{:03}", device.bus_number(), device.address()).into());
+
+    processes
+        .into_iter()
+        .filter(|process| has_open(process.pid, &paths).unwrap_or(true))
+        .collect()
+}
+
+/// Prints what to do about `processes` to release the probe they're using.
+fn print_release_instructions(processes: &[BmdaProcess])
+{
+    for process in processes {
+        eprintln!(
+            "note: quit the Black Magic Debug App ({}) with Ctrl-C in its terminal, or with `{}`, \
+            to release the probe, and restart it once bmputil is done.",
+            process,
+            process.stop_command(),
+        );
+    }
+}
+
+/// Checks that no BMDA process is using `dev` before it is flashed, asking `policy` whether to
+/// go ahead anyway (unless `force`, as with `--force`) if one is.
+pub fn check_released(policy: &ConfirmationPolicy, force: bool, dev: &BmpDevice) -> Result<(), Error>
+{
+    let processes = using(dev);
+    if processes.is_empty() {
+        return Ok(());
+    }
+
+    let which: Vec<String> = processes.iter().map(ToString::to_string).collect();
+    warnings::emit(
+        WarningCode::BmdaRunning,
+        format!("The Black Magic Debug App may be using this probe ({})", which.join("; ")),
+    );
+    print_release_instructions(&processes);
+    if force {
+        return Ok(());
+    }
+
+    policy.confirm(
+        AuthorizationLevel::Destructive,
+        "flashing a probe the Black Magic Debug App may be using",
+        "Flashing reboots the probe, cutting off the Black Magic Debug App, which may be in the \
+        middle of writing to a target's flash.",
+    )
+}
+
+/// Whether `e` is the kind of error opening a probe gives when another program has it.
+pub fn is_busy_error(e: &Error) -> bool
+{
+    matches!(
+        e.kind,
+        ErrorKind::External(ErrorSource::Libusb(rusb::Error::Access | rusb::Error::Busy))
+    )
+}
+
+/// Points out any BMDA processes running, as the likely reason a probe couldn't be opened.
+pub fn print_busy_hint()
+{
+    let processes = running();
+    if processes.is_empty() {
+        return;
+    }
+
+    eprintln!("note: the Black Magic Debug App is running, and may have the probe open.");
+    print_release_instructions(&processes);
+}
@@ -5,24 +5,33 @@ use std::mem;
 use std::thread;
 use std::io::Read;
 use std::cell::{RefCell, Ref, RefMut};
-use std::str::FromStr;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use std::fmt::{self, Display, Formatter};
 use std::array::TryFromSliceError;
+use std::ops::RangeBounds;
+use std::sync::Arc;
 
-use clap::ArgMatches;
-use dfu_core::DfuIo;
-use dfu_core::DfuProtocol;
-use dfu_core::sync::DfuSync;
 use log::{trace, debug, info, warn, error};
+use serde::Serialize;
 use rusb::{UsbContext, Direction, RequestType, Recipient};
-use dfu_libusb::{DfuLibusb, Error as DfuLibusbError};
-use dfu_core::{State as DfuState, Error as DfuCoreError};
 
-use crate::{libusb_cannot_fail, S};
-use crate::error::{Error, ErrorKind, ErrorSource, ResErrorKind};
-use crate::usb::{DfuFunctionalDescriptor, InterfaceClass, InterfaceSubClass, GenericDescriptorRef, DfuRequest};
-use crate::usb::{Vid, Pid, DfuOperatingMode};
+use crate::S;
+use crate::error::{DeviceContext, Error, ErrorKind, ErrorSource, InvalidDeviceData};
+use crate::usb::{DfuFunctionalDescriptor, InterfaceClass, InterfaceSubClass, DfuRequest};
+use crate::usb::{sanitize_descriptor_string, Descriptor, ExtraDescriptors};
+use crate::usb::{Vid, Pid, DfuOperatingMode, UsbDeviceHandle};
+use crate::snapshot::EnumerationSnapshot;
+use crate::os_serial;
+use crate::profiles::{self, ProbeProfile};
+use crate::quirks::{self, HubQuirks};
+use crate::serial_port::{self, ProbePort};
+use crate::retry::RetryPolicy;
+use crate::timeouts::Timeouts;
+use crate::dfu::{DfuInterface, DfuProtocol, DfuError, DownloadPhase, DownloadProgress, EraseStrategy, PollStrategy};
+use crate::version::FirmwareVersion;
+use crate::hardware::HardwareTarget;
+use crate::warnings::{self, WarningCode};
+use crate::flasher::{self, Clock, ProbeBackend, UsbBackend, SystemClock};
 
 type UsbDevice = rusb::Device<rusb::Context>;
 type UsbHandle = rusb::DeviceHandle<rusb::Context>;
@@ -38,24 +47,32 @@ pub struct BmpDevice
     /// The operating mode (application or DFU) the BMP is currently in.
     mode: DfuOperatingMode,
 
-    /// The platform this BMP is running on.
-    platform: BmpPlatform,
+    /// The profile describing this BMP's hardware, and the platform it's running on.
+    profile: &'static ProbeProfile,
 
     /// RefCell for interior-mutability-based caching.
     serial: RefCell<Option<String>>,
 
     /// RefCell for interior-mutability-based caching.
     port: RefCell<Option<String>>,
+
+    /// Timeouts for talking to and waiting for this device.
+    timeouts: Timeouts,
+
+    /// Workarounds for the hubs this device is attached through.
+    quirks: HubQuirks,
+
+    /// The USB IDs the device enumerated with.
+    ids: (Vid, Pid),
 }
 
 impl BmpDevice
 {
     pub fn from_usb_device(device: UsbDevice) -> Result<Self, Error>
     {
-        let desc = device.device_descriptor()
-            .expect(libusb_cannot_fail!("libusb_get_device_descriptor()"));
+        let desc = device.device_descriptor()?;
         let (vid, pid) = (Vid(desc.vendor_id()), Pid(desc.product_id()));
-        let (platform, mode) = BmpPlatform::from_vid_pid(vid, pid).ok_or_else(|| {
+        let (profile, mode) = profiles::find(vid, pid).ok_or_else(|| {
             warn!("Device passed to BmpDevice::from_usb_device() does not seem to be a BMP device!");
             warn!("The logic for finding this device is probably incorrect!");
             ErrorKind::DeviceNotFound.error()
@@ -63,28 +80,91 @@ impl BmpDevice
 
         let handle = device.open()?;
 
+        let quirks = quirks::for_device(&device);
+        if !quirks.is_empty() {
+            info!("Device is attached through a hub with quirks: {:?}", quirks);
+        }
 
-        Ok(Self {
+        let mut dev = Self {
             device: RefCell::new(Some(device)),
             mode,
-            platform,
+            profile,
             handle: RefCell::new(Some(handle)),
             serial: RefCell::new(None),
             port: RefCell::new(None),
-        })
+            timeouts: quirks.apply(Timeouts::default()),
+            quirks,
+            ids: (vid, pid),
+        };
+
+        // Some hardware shares its IDs with other hardware, and only its product string tells.
+        if profiles::needs_product(vid, pid) {
+            match dev.product_string() {
+                Ok(product) => {
+                    if let Some((profile, _mode)) = profiles::find_for_product(vid, pid, &product) {
+                        dev.profile = profile;
+                    }
+                },
+                Err(e) => debug!("Failed to read product string to tell the hardware apart, assuming {}: {}", profile.name, e),
+            }
+        }
+
+        Ok(dev)
+    }
+
+    /// Sets the timeouts for talking to and waiting for this device. Hub quirks can lengthen them.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts)
+    {
+        self.timeouts = self.quirks.apply(timeouts);
+    }
+
+    /// Returns the timeouts for talking to and waiting for this device.
+    #[allow(dead_code)]
+    pub fn timeouts(&self) -> Timeouts
+    {
+        self.timeouts
+    }
+
+    /// Returns the workarounds for the hubs this device is attached through.
+    pub fn quirks(&self) -> HubQuirks
+    {
+        self.quirks
+    }
+
+    /// A device with just a serial number and port, and no USB device behind it, for tests without
+    /// a real probe.
+    #[cfg(test)]
+    pub(crate) fn detached(serial: &str, port: &str) -> Self
+    {
+        let profile = &profiles::registry()[0];
+        Self {
+            device: RefCell::new(None),
+            handle: RefCell::new(None),
+            mode: DfuOperatingMode::Runtime,
+            profile,
+            serial: RefCell::new(Some(serial.to_string())),
+            port: RefCell::new(Some(port.to_string())),
+            timeouts: Timeouts::default(),
+            quirks: HubQuirks::default(),
+            ids: profile.dfu_ids,
+        }
     }
 
     /// Get the [`rusb::Device<rusb::Context>`] associated with the connected Black Magic Probe.
     #[allow(dead_code)]
-    pub fn device(&self) -> Ref<UsbDevice>
+    pub fn device(&self) -> Ref<'_, UsbDevice>
     {
         let dev = self.device.borrow();
         Ref::map(dev, |d| d.as_ref().expect("Unreachable: self.device is None"))
     }
 
     /// Violate struct invariants if you want. I'm not the boss of you.
+    ///
+    /// # Safety
+    /// Nothing here is memory-unsafe, but the cached descriptors, serial number, and operating
+    /// mode are not updated to match whatever you do to the device.
     #[allow(dead_code)]
-    pub unsafe fn device_mut(&mut self) -> RefMut<UsbDevice>
+    pub unsafe fn device_mut(&mut self) -> RefMut<'_, UsbDevice>
     {
         let dev = self.device.borrow_mut();
         RefMut::map(dev, |d| d.as_mut().expect("Unreachable: self.device is None"))
@@ -92,22 +172,26 @@ impl BmpDevice
 
     /// Get the [`rusb::DeviceHandle<rusb::Context>`] associated with the connected Black Magic Probe.
     #[allow(dead_code)]
-    pub fn handle(&self) -> Ref<UsbHandle>
+    pub fn handle(&self) -> Ref<'_, UsbHandle>
     {
         let handle = self.handle.borrow();
         Ref::map(handle, |h| h.as_ref().expect("Unreachable: self.handle is None"))
     }
 
     /// Violate struct invariants if you want. I'm not the boss of you.
+    ///
+    /// # Safety
+    /// Nothing here is memory-unsafe, but the cached descriptors, serial number, and operating
+    /// mode are not updated to match whatever you do with the handle.
     #[allow(dead_code)]
-    pub unsafe fn handle_mut(&mut self) -> RefMut<UsbHandle>
+    pub unsafe fn handle_mut(&mut self) -> RefMut<'_, UsbHandle>
     {
         let handle = self.handle.borrow_mut();
         RefMut::map(handle, |h| h.as_mut().expect("Unreachable: self.handle is None"))
     }
 
     /// The safe but internal version of [handle_mut].
-    fn _handle_mut(&mut self) -> RefMut<UsbHandle>
+    fn _handle_mut(&mut self) -> RefMut<'_, UsbHandle>
     {
         unsafe { self.handle_mut() }
     }
@@ -119,7 +203,13 @@ impl BmpDevice
 
     pub fn platform(&self) -> BmpPlatform
     {
-        self.platform
+        self.profile.platform
+    }
+
+    /// Returns the profile describing this device's hardware.
+    pub fn profile(&self) -> &'static ProbeProfile
+    {
+        self.profile
     }
 
     /// Returns a the serial number string for this device.
@@ -127,7 +217,32 @@ impl BmpDevice
     /// This struct caches the serial number in an [`std::cell::RefCell`],
     /// and thus returns a `Ref<str>` rather than the `&str` directly.
     /// Feel free to clone the result if you want a directly referenceable value.
-    pub fn serial_number(&self) -> Result<Ref<str>, Error>
+    pub fn serial_number(&self) -> Result<Ref<'_, str>, Error>
+    {
+        self.read_serial_number_with(self.timeouts.get_control(), RetryPolicy::default())
+    }
+
+    /// Like [`BmpDevice::serial_number`], but gives up after roughly `timeout` instead of retrying
+    /// slow or failing reads, for callers that can't block for long (e.g. a UI listing devices).
+    ///
+    /// If reading it from the device times out, the serial number the OS recorded is still used, if
+    /// there is one.
+    pub fn try_serial_number(&self, timeout: Duration) -> Result<Ref<'_, str>, Error>
+    {
+        // Reading the serial number takes two transfers: the supported languages, then the string.
+        self.read_serial_number_with(timeout / 2, RetryPolicy::new().retries(0))
+    }
+
+    /// Returns the serial number if it's already known, without making any requests to the device.
+    ///
+    /// It is known once it has been read, or if it was found in the enumeration snapshot when the
+    /// device was found.
+    pub fn cached_serial_number(&self) -> Option<String>
+    {
+        self.serial.borrow().clone()
+    }
+
+    fn read_serial_number_with(&self, timeout: Duration, retry: RetryPolicy) -> Result<Ref<'_, str>, Error>
     {
         let serial = self.serial.borrow();
         if serial.is_some() {
@@ -137,23 +252,13 @@ impl BmpDevice
         // self.serial as mutable later.
         drop(serial);
 
-        let languages = self.handle().read_languages(Duration::from_secs(2))?;
-        if languages.is_empty() {
-            return Err(
-                ErrorKind::DeviceSeemsInvalid(String::from("no string descriptor languages"))
-                    .error()
-            );
-        }
-
-        let language = languages.first().unwrap(); // Okay as we proved len > 0.
+        let serial = read_serial_number(&self.device(), &self.handle(), timeout, retry)
+            .or_else(|e| serial_number_from_os_or(&self.device(), e))?;
 
-        let serial = self
-            .handle()
-            .read_serial_number_string(
-                *language,
-                &self.device().device_descriptor().unwrap(),
-                Duration::from_secs(2),
-            )?;
+        // Let later invocations skip reading it again.
+        let mut snapshot = EnumerationSnapshot::load();
+        snapshot.record(self.device().bus_number(), self.device().address(), &self.port(), &serial);
+        snapshot.save();
 
         // Finally, now that we have the serial number, cache it...
         *self.serial.borrow_mut() = Some(serial);
@@ -163,6 +268,16 @@ impl BmpDevice
     }
 
 
+    /// Describes this device for attaching to errors with [`Error::with_device`], without making any
+    /// requests to it.
+    pub fn error_context(&self) -> DeviceContext
+    {
+        DeviceContext {
+            serial: self.cached_serial_number(),
+            port: Some(self.port()),
+        }
+    }
+
     /// Returns a string that represents the full port of the device, in the format of
     /// `<bus>-<port>.<subport>.<subport...>`.
     ///
@@ -173,54 +288,215 @@ impl BmpDevice
             return port.to_string();
         }
 
-        let bus = self.device().bus_number();
-        let path = self
-            .device()
-            .port_numbers()
-            .expect("unreachable: rusb always provides a properly sized array to libusb_get_port_numbers()")
-            .into_iter()
-            .map(|v| v.to_string())
-            .collect::<Vec<String>>()
-            .as_slice()
-            .join(".");
-
-        let port = format!("{}-{}", bus, path);
+        let port = usb_port_path(&self.device());
         let ret = port.clone();
         self.port.replace(Some(port));
 
         ret
     }
 
-    /// Return a string suitable for display to the user.
+    /// Returns the USB 3 container ID of the device, if it has one.
+    pub fn container_id(&self) -> Option<[u8; 16]>
+    {
+        read_container_id(&self.device(), &self.handle(), self.timeouts.get_control())
+            .inspect_err(|e| trace!("Could not read container ID of device at {}: {}", self.port(), e))
+            .ok()
+            .flatten()
+    }
+
+    /// Returns what we know about this device for finding it again after it re-enumerates.
+    pub fn identity(&self) -> ProbeIdentity
+    {
+        ProbeIdentity {
+            port: self.port(),
+            serial: self.serial_number().ok().map(|s| s.to_string()),
+            container_id: self.container_id(),
+            incarnation: Some(self.incarnation()),
+            departed: None,
+        }
+    }
+
+    /// Returns where on the bus this device currently is, and in which mode.
+    fn incarnation(&self) -> Incarnation
+    {
+        let device = self.device();
+        Incarnation {
+            bus: device.bus_number(),
+            address: device.address(),
+            mode: self.mode,
+        }
+    }
+
+    /// Return a string suitable for display to the user, failing if any of it can't be read. The
+    /// [`Display`] impl shows what it can instead.
     ///
     /// Note: this performs USB IO to retrieve the necessary string descriptors, if those strings
     /// have not yet been retrieved previously (and thus not yet cached).
     pub fn display(&self) -> Result<String, Error>
+    {
+        let product_string = self.product_string()?;
+        let serial = self.serial_number()?;
+
+        Ok(format!("{}\n  Serial: {}\n  Port:  {}", product_string, serial, self.port()))
+    }
+
+    /// Reads the product string descriptor of the device, e.g. `Black Magic Probe v1.9.2`.
+    pub fn product_string(&self) -> Result<String, Error>
     {
         let handle = self.handle();
         let mut languages = handle
-            .read_languages(Duration::from_secs(2))
+            .read_languages(self.timeouts.get_control())
             .map_err(|e| Error::from(e).with_ctx("reading supported string descriptor langauges"))?;
 
         let first_lang = languages.pop()
-            .ok_or_else(|| ErrorKind::DeviceSeemsInvalid(S!("no supported string descriptor languages")).error())?;
+            .ok_or_else(|| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingStringLanguages).error())?;
 
         let dev_desc = &self
             .device()
-            .device_descriptor()
-            .expect(libusb_cannot_fail!("libusb_get_device_descriptor()"));
+            .device_descriptor()?;
 
         let product_string = handle
             .read_product_string(
                 first_lang,
                 dev_desc,
-                Duration::from_secs(2),
+                self.timeouts.get_control(),
             )
-            .map_err(|e| ErrorKind::DeviceSeemsInvalid(S!("no product string descriptor")).error_from(e))?;
+            .map_err(|e| {
+                ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingProductString)
+                    .error_from(e)
+                    .with_device(self.error_context())
+            })?;
 
-        let serial = self.serial_number()?;
+        Ok(sanitize_descriptor_string(&product_string))
+    }
 
-        Ok(format!("{}\n  Serial: {}\n  Port:  {}", product_string, serial, self.port()))
+    /// Reads the firmware version out of the device's product string, if it has one we can parse.
+    pub fn firmware_version(&self) -> Option<FirmwareVersion>
+    {
+        self.product_string()
+            .ok()
+            .and_then(|product| FirmwareVersion::from_product_string(&product))
+    }
+
+    /// Returns the hardware the probe's firmware was built for, if it's in runtime mode and says.
+    ///
+    /// In DFU mode, the product string is the bootloader's, which doesn't.
+    pub fn hardware_target(&self) -> Option<HardwareTarget>
+    {
+        if self.mode != DfuOperatingMode::Runtime {
+            return None;
+        }
+
+        self.product_string()
+            .ok()
+            .and_then(|product| HardwareTarget::from_product_string(&product))
+    }
+
+    /// Finds the serial port the OS created for `port` of this probe.
+    ///
+    /// Returns `None` if the probe isn't in runtime mode (where it has no serial ports), has no
+    /// readable serial number, or the OS hasn't bound a serial port driver to that interface.
+    pub fn serial_port(&self, port: ProbePort) -> Option<String>
+    {
+        if self.mode != DfuOperatingMode::Runtime {
+            return None;
+        }
+
+        let serial = self.serial_number().ok()?.to_string();
+        self.serial_port_for(&serial, port)
+    }
+
+    fn serial_port_for(&self, serial: &str, port: ProbePort) -> Option<String>
+    {
+        if self.mode != DfuOperatingMode::Runtime {
+            return None;
+        }
+
+        serial_port::find_serial_port(serial, port)
+            .inspect_err(|e| debug!("Could not find {} of probe {}: {}", port, serial, e))
+            .ok()
+    }
+
+    /// Gathers machine-readable information about the device.
+    ///
+    /// Unlike [`BmpDevice::display`], this does not fail if the string descriptors can't be read;
+    /// the corresponding fields are simply left empty.
+    pub fn info(&self) -> ProbeInfo
+    {
+        let product = self.product_string()
+            .inspect_err(|e| warn!("Failed to read product string of device at {}: {}", self.port(), e))
+            .ok();
+        let firmware_version = product
+            .as_deref()
+            .and_then(FirmwareVersion::from_product_string)
+            .map(|version| version.to_string());
+        let hardware = product
+            .as_deref()
+            .filter(|_| self.mode == DfuOperatingMode::Runtime)
+            .and_then(HardwareTarget::from_product_string)
+            .map(|hardware| hardware.to_string());
+        let serial = self.serial_number()
+            .inspect_err(|e| warn!("Failed to read serial number of device at {}: {}", self.port(), e))
+            .ok()
+            .map(|serial| serial.to_string());
+
+        ProbeInfo {
+            product,
+            firmware_version,
+            hardware,
+            ..self.info_without_strings(serial)
+        }
+    }
+
+    /// Like [`BmpDevice::info`], but without making any requests to the device, so it returns
+    /// immediately even for slow devices.
+    ///
+    /// Fields that would need a request are left empty and listed in [`ProbeInfo::pending`], for
+    /// callers to show a partial listing straight away and fill it in later with
+    /// [`BmpDevice::info`].
+    pub fn partial_info(&self) -> ProbeInfo
+    {
+        let serial = self.cached_serial_number();
+
+        let mut pending = vec!["product", "firmware_version", "hardware"];
+        if serial.is_none() {
+            pending.insert(0, "serial");
+            if self.mode == DfuOperatingMode::Runtime {
+                pending.extend(["gdb_port", "uart_port"]);
+            }
+        }
+
+        ProbeInfo {
+            pending,
+            ..self.info_without_strings(serial)
+        }
+    }
+
+    /// Fills in everything in [`ProbeInfo`] that doesn't need the string descriptors.
+    fn info_without_strings(&self, serial: Option<String>) -> ProbeInfo
+    {
+        let (Vid(vid), Pid(pid)) = self.ids;
+        let serial_port = |port| serial.as_deref().and_then(|serial| self.serial_port_for(serial, port));
+
+        ProbeInfo {
+            gdb_port: serial_port(ProbePort::Gdb),
+            uart_port: serial_port(ProbePort::Uart),
+            serial,
+            mode: match self.mode {
+                DfuOperatingMode::Runtime => "runtime",
+                DfuOperatingMode::FirmwareUpgrade => "dfu",
+            },
+            platform: self.profile.platform.to_string(),
+            profile: self.profile.name.clone(),
+            bus: self.device().bus_number(),
+            port: self.port(),
+            product: None,
+            firmware_version: None,
+            hardware: None,
+            vid: format!("{:04x}", vid),
+            pid: format!("{:04x}", pid),
+            pending: Vec::new(),
+        }
     }
 
     /// Find and return the DFU functional descriptor and its interface number for the connected Black Magic Probe device.
@@ -252,9 +528,9 @@ impl BmpDevice
                     Ok(d) => d,
                     Err(e) => {
                         return Err(
-                            ErrorKind::DeviceSeemsInvalid(
-                                String::from("no configuration descriptor exists")
-                            ).error_from(e)
+                            ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingConfiguration)
+                                .error_from(e)
+                                .with_device(self.error_context())
                         );
                     },
                 }
@@ -266,37 +542,37 @@ impl BmpDevice
 
         let dfu_interface_descriptor = configuration
             .interfaces()
-            .map(|interface| {
-                interface
-                .descriptors()
-                .next()
-                .unwrap() // Unwrap fine as we've already established that there is at least one interface.
-            })
+            .filter_map(|interface| interface.descriptors().next())
             .find(|desc| {
                 desc.class_code() == InterfaceClass::APPLICATION_SPECIFIC.0 &&
                     desc.sub_class_code() == InterfaceSubClass::DFU.0
 
             })
-            .ok_or_else(|| ErrorKind::DeviceSeemsInvalid(String::from("no DFU interfaces")).error())?;
-
-        // Get the data for all the "extra" descriptors that follow the interface descriptor.
-        let extra_descriptors: Vec<_> = GenericDescriptorRef::multiple_from_bytes(dfu_interface_descriptor.extra());
-
-        // Iterate through all the "extra" descriptors to find the DFU functional descriptor.
-        let dfu_func_desc_bytes: &[u8; DfuFunctionalDescriptor::LENGTH as usize] = extra_descriptors
-            .into_iter()
-            .find(|descriptor| descriptor.descriptor_type() == DfuFunctionalDescriptor::TYPE)
-            .expect("DFU interface does not have a DFU functional descriptor! This shouldn't be possible!")
-            .raw[0..DfuFunctionalDescriptor::LENGTH as usize]
-            .try_into() // Convert &[u8] to &[u8; LENGTH].
-            .unwrap(); // Unwrap fine as we already set the length two lines above.
-
-        let dfu_func_desc = DfuFunctionalDescriptor::copy_from_bytes(dfu_func_desc_bytes)
-            .map_err(|source| {
-                ErrorKind::DeviceSeemsInvalid(String::from("DFU functional descriptor"))
-                    .error_from(source)
+            .ok_or_else(|| {
+                ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingDfuInterface)
+                    .error()
+                    .with_device(self.error_context())
             })?;
 
+        // Find the DFU functional descriptor among the "extra" descriptors following the interface descriptor.
+        let dfu_func_desc = ExtraDescriptors::new(dfu_interface_descriptor.extra())
+            .iter()
+            .find_map(|descriptor| match descriptor {
+                Ok(Descriptor::DfuFunctional(desc)) => Some(Ok(desc)),
+                Ok(_) => None,
+                Err(source) => Some(Err(source)),
+            })
+            .ok_or_else(|| {
+                ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingFunctionalDescriptor {
+                    interface: dfu_interface_descriptor.interface_number(),
+                })
+                .error()
+            })
+            .and_then(|res| {
+                res.map_err(|source| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::BadFunctionalDescriptor).error_from(source))
+            })
+            .map_err(|e| e.with_device(self.error_context()))?;
+
         Ok((dfu_interface_descriptor.interface_number(), dfu_func_desc))
     }
 
@@ -314,13 +590,14 @@ impl BmpDevice
         );
 
         // Perform the zero-length DFU_DNLOAD request.
-        let _response = self.handle().write_control(
+        let _response = UsbDeviceHandle::write_control(
+            &*self.handle(),
             request_type, // bmRequestType
             DfuRequest::Dnload as u8, // bRequest
             0, // wValue
             0, // wIndex
             &[], // data
-            Duration::from_secs(2),
+            self.timeouts.get_control(),
         )?;
 
         // Then perform a DFU_GETSTATUS request to complete the leave "request".
@@ -331,13 +608,14 @@ impl BmpDevice
         );
 
         let mut buf: [u8; 6] = [0; 6];
-        let status = self.handle().read_control(
+        let status = UsbDeviceHandle::read_control(
+            &*self.handle(),
             request_type, // bmRequestType
             DfuRequest::GetStatus as u8, // bRequest
             0, // wValue
             iface_number as u16, // wIndex
             &mut buf,
-            Duration::from_secs(2),
+            self.timeouts.get_control(),
         )?;
 
         trace!("Device status after zero-length DNLOAD is 0x{:02x}", status);
@@ -366,13 +644,14 @@ impl BmpDevice
         );
         let timeout_ms = func_desc.wDetachTimeOut;
 
-        let _response = self.handle().write_control(
+        let _response = UsbDeviceHandle::write_control(
+            &*self.handle(),
             request_type, // bmpRequestType
             DfuRequest::Detach as u8, // bRequest
             timeout_ms, // wValue
             iface_number as u16, // wIndex
             &[], // buffer
-            Duration::from_secs(1), // timeout for libusb
+            self.timeouts.get_control(), // timeout for libusb
         )
         .map_err(Error::from)
         .map_err(|e| e.with_ctx("sending control request"))?;
@@ -388,12 +667,16 @@ impl BmpDevice
         Ok(())
     }
 
-    /// Requests the Black Magic Probe device to detach, switching from DFU mode to runtime mode or vice versa. You probably want [`detach_and_enumerate`].
+    /// Requests the Black Magic Probe device to detach, switching from DFU mode to runtime mode or vice versa. You probably want [`Self::detach_and_enumerate`].
     ///
     /// This function does not re-enumerate the device and re-initialize this structure, and thus after
     /// calling this function, the this [`BmpDevice`] instance will not be in a correct state
     /// if the device successfully detached. Further requests will fail, and functions like
     /// `dfu_descriptors()` may return now-incorrect data.
+    ///
+    /// # Safety
+    /// The caller must not use this [`BmpDevice`] for anything but dropping it afterwards, unless
+    /// the device failed to detach.
     pub unsafe fn request_detach(&mut self) -> Result<(), Error>
     {
         use DfuOperatingMode::*;
@@ -409,19 +692,19 @@ impl BmpDevice
         Ok(())
     }
 
-    /// Requests the Black Magic Probe to detach, and re-initializes this struct with the new
-    /// device.
-    pub fn detach_and_enumerate(&mut self) -> Result<(), Error>
+    /// Requests the Black Magic Probe to detach, tolerating the spurious errors some platforms
+    /// report when the device disconnects in response.
+    ///
+    /// Like [`BmpDevice::request_detach`], this leaves this instance in an incorrect state if the
+    /// device successfully detached.
+    pub fn send_detach(&mut self) -> Result<(), Error>
     {
-        // Save the port for finding the device again after.
-        let port = self.port();
-
         if cfg!(not(windows)) {
             unsafe { self.request_detach()? };
         } else {
             // HACK: WinUSB seems to have a race condition where it can spuriously give ERROR_GEN_FAILURE
             // (which becomes LIBUSB_ERROR_PIPE) when a control request results in a device disconnect.
-            use crate::ErrorSource::Libusb;
+            use crate::error::ErrorSource::Libusb;
             let res = unsafe { self.request_detach() };
             if let Err(e @ Error { kind: ErrorKind::External(Libusb(rusb::Error::Pipe)), .. }) = res {
                 warn!("Possibly spurious error from Windows when attempting to detach: {}", e);
@@ -430,6 +713,19 @@ impl BmpDevice
             }
         }
 
+        Ok(())
+    }
+
+    /// Requests the Black Magic Probe to detach, and re-initializes this struct with the new
+    /// device.
+    pub fn detach_and_enumerate(&mut self) -> Result<(), Error>
+    {
+        // Save what we know about the device for finding it again after.
+        let mut identity = self.identity();
+
+        self.send_detach()?;
+        identity.departing();
+
         // Now drop the device so libusb doesn't re-grab the same thing.
         drop(self.device.take());
         drop(self.handle.take());
@@ -437,8 +733,9 @@ impl BmpDevice
         // TODO: make this sleep() timeout configurable?
         thread::sleep(Duration::from_millis(500));
 
-        // Now try to find the device again on that same port.
-        let dev = wait_for_probe_reboot(&port, Duration::from_secs(5), "flash")?;
+        // Now try to find the device again.
+        let mut dev = wait_for_probe_reboot(&identity, self.timeouts, "flash")?;
+        dev.set_timeouts(self.timeouts);
 
         // If we've made it here, then we have successfully re-found the device.
         // Re-initialize this structure from the new data.
@@ -453,128 +750,308 @@ impl BmpDevice
     /// You'll just have to create another one.
     pub fn detach_and_destroy(mut self) -> Result<(), Error>
     {
-        if cfg!(not(windows)) {
-            unsafe { self.request_detach()? };
-        } else {
-            // HACK: WinUSB seems to have a race condition where it can spuriously give ERROR_GEN_FAILURE
-            // (which becomes LIBUSB_ERROR_PIPE) when a control request results in a device disconnect.
-            use crate::ErrorSource::Libusb;
-            let res = unsafe { self.request_detach() };
-            if let Err(e @ Error { kind: ErrorKind::External(Libusb(rusb::Error::Pipe)), .. }) = res {
-                warn!("Possibly spurious error from Windows when attempting to detach: {}", e);
-            } else {
-                res?;
-            }
+        self.send_detach()
+    }
+
+    /// Closes the handle to the device, so another program (e.g. dfu-util) can open it.
+    ///
+    /// # Safety
+    /// The caller must not use this [`BmpDevice`] for anything but dropping it afterwards.
+    pub unsafe fn close(&mut self)
+    {
+        drop(self.handle.take());
+    }
+
+    /// Selects the device's first configuration if the OS left it unconfigured, which some hubs and
+    /// OSes do to devices that re-enumerate quickly. Returns whether it had to.
+    pub fn ensure_configured(&mut self) -> Result<bool, Error>
+    {
+        match self.device().active_config_descriptor() {
+            Err(rusb::Error::NotFound) => (),
+            _ => return Ok(false),
         }
 
-        Ok(())
+        warn!("Black Magic Probe device is unconfigured; selecting its first configuration");
+        self._handle_mut().set_active_configuration(1)?;
+
+        Ok(true)
     }
 
-    fn try_download<'r, R, C>(&mut self, firmware: &'r R, length: u32, dfu_dev: &mut dfu_libusb::Dfu<C>) ->
-        Result<(), Error>
+    /// Runs `f` on the device's DFU interface, as used for flashing. The device must be in DFU mode.
+    pub fn with_dfu_interface<R, F>(&mut self, f: F) -> Result<R, Error>
     where
-        &'r R: Read,
-        R: ?Sized,
-        C: UsbContext,
+        F: FnOnce(&DfuInterface) -> Result<R, Error>,
     {
-        match dfu_dev.download(firmware, length) {
-            Ok(_) => if dfu_dev.will_detach() {
-            match dfu_dev.detach() {
-                    Err(source) => Err(ErrorKind::DeviceReboot.error_from(source)),
-                    _ => Ok(()),
-                }
-            } else {
-                Ok(())
-            },
-            Err(source) => Err(match source {
-                dfu_libusb::Error::LibUsb(rusb::Error::NoDevice) => {
+        if self.mode != DfuOperatingMode::FirmwareUpgrade {
+            return Err(ErrorKind::OperationNotSupported(S!("using the DFU interface outside of DFU mode")).error());
+        }
+
+        let (iface_number, func_desc) = self.dfu_descriptors()?;
+        let timeout = self.timeouts.get_control();
+        let handle = live_handle(&mut self.handle)?;
+
+        let dfu_iface = DfuInterface::open(handle, iface_number, func_desc, timeout)?;
+        let res = f(&dfu_iface);
+        if let Err(e) = dfu_iface.release() {
+            debug!("Failed to release DFU interface: {}", e);
+        }
+
+        res
+    }
+
+    /// Runs `f` on the DFU interface switched to the alternate setting whose name starts with `name`
+    /// (e.g. `@Option Bytes`), which DfuSe devices use for memories other than their flash.
+    ///
+    /// The device must be in DFU mode.
+    pub fn with_dfu_alt_setting<R, F>(&mut self, name: &str, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&DfuInterface) -> Result<R, Error>,
+    {
+        if self.mode != DfuOperatingMode::FirmwareUpgrade {
+            return Err(ErrorKind::OperationNotSupported(format!("accessing {} outside of DFU mode", name)).error());
+        }
+
+        let (iface_number, func_desc) = self.dfu_descriptors()?;
+        let settings: Vec<u8> = self.device()
+            .active_config_descriptor()?
+            .interfaces()
+            .filter(|interface| interface.number() == iface_number)
+            .flat_map(|interface| interface.descriptors().map(|desc| desc.setting_number()).collect::<Vec<_>>())
+            .collect();
+        let timeout = self.timeouts.get_control();
+        let handle = live_handle(&mut self.handle)?;
+
+        let setting = settings
+            .into_iter()
+            .find(|&setting| {
+                handle.read_alt_setting_name(iface_number, setting, timeout)
+                    .is_ok_and(|setting_name| setting_name.starts_with(name))
+            })
+            .ok_or_else(|| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingAltSetting(name.to_string())).error())?;
+
+        let dfu_iface = DfuInterface::open_alt_setting(handle, iface_number, setting, func_desc, timeout)?;
+        let res = f(&dfu_iface);
+        if let Err(e) = dfu_iface.release() {
+            debug!("Failed to release DFU interface: {}", e);
+        }
+
+        res
+    }
+
+    fn try_download<P>(
+        &mut self,
+        firmware: &[u8],
+        load_address: u32,
+        options: &DownloadOptions,
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Fn(DownloadProgress),
+    {
+        let (iface_number, func_desc) = self.dfu_descriptors()?;
+        let platform = self.platform();
+        let handle = live_handle(&mut self.handle)?;
+        let mut dfu_iface = DfuInterface::open(handle, iface_number, func_desc, self.timeouts.get_control())
+            .map_err(|source| flash_failed(source.into()))?;
+        dfu_iface.set_retry_policy(options.retry);
+        dfu_iface.set_erase_strategy(options.erase_strategy);
+        dfu_iface.set_mass_erase_supported(platform.supports_mass_erase());
+        dfu_iface.set_poll_strategy(options.poll_strategy);
+        if let Some(size) = options.transfer_size {
+            dfu_iface.limit_transfer_size(size);
+        }
+        if options.gentle {
+            dfu_iface.limit_transfer_size(GENTLE_TRANSFER_SIZE);
+            dfu_iface.set_block_delay(GENTLE_BLOCK_DELAY);
+        }
+
+        let is_dfuse = matches!(dfu_iface.protocol(), DfuProtocol::Dfuse(_));
+        if options.verify && !is_dfuse {
+            return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingDfuse).error()
+                .with_ctx("verifying written firmware"));
+        }
+
+        debug!("Load address: 0x{:08x}", load_address);
+        info!("Performing flash...");
+
+        if let Err(source) = dfu_iface.download_from(firmware, load_address, options.resume_from, &progress) {
+            return Err(match source {
+                DfuError::Usb(rusb::Error::NoDevice) => {
                     error!("Black Magic Probe device disconnected during the flash process!");
                     warn!(
                         "If the device now fails to enumerate, try holding down the button while plugging the device in order to enter the bootloader."
                     );
                     ErrorKind::DeviceDisconnectDuringOperation.error_from(source)
-                }
-                _ => source.into(),
-            })
+                },
+                _ => flash_failed(source.into()),
+            });
+        }
+
+        if options.verify {
+            info!("Verifying written firmware...");
+            if let Err(source) = dfu_iface.verify(firmware, load_address, &progress) {
+                error!("Firmware read back from the device does not match the image! The device will stay in DFU mode.");
+                return Err(flash_failed(source.into()).with_ctx("verifying written firmware"));
+            }
+        }
+
+        // Some bootloaders reset as soon as they enter manifestation, before they have acknowledged the
+        // final DFU_GETSTATUS (or the DFU_DETACH that follows it). As every byte has already been written
+        // at this point, that disconnect is most likely the device rebooting into the new firmware rather
+        // than a failure, and the caller is expected to confirm that by waiting for the device to re-enumerate.
+        let disconnected_in_manifest = |source: &DfuError| -> bool {
+            let is_disconnect = match source {
+                DfuError::Usb(rusb::Error::NoDevice) => true,
+                // HACK: WinUSB can report ERROR_GEN_FAILURE (which becomes LIBUSB_ERROR_PIPE) when a
+                // control request results in a device disconnect.
+                DfuError::Usb(rusb::Error::Pipe) => cfg!(windows),
+                _ => false,
+            };
+
+            is_disconnect && options.manifest_disconnect_ok
+        };
+
+        if options.reboot_to == RebootTarget::Dfu {
+            info!("Leaving the device in DFU mode, as requested.");
+            dfu_iface.abort().map_err(|source| flash_failed(source.into()))?;
+            if let Err(e) = dfu_iface.release() {
+                debug!("Failed to release DFU interface after download: {}", e);
+            }
+
+            return Ok(());
+        }
+
+        dfu_iface.set_force_reset(options.force_reset || self.quirks.force_reset);
+        progress(DownloadProgress::new(DownloadPhase::Manifest, 0, 0, dfu_iface.transfer_size()));
+        match dfu_iface.manifest(load_address) {
+            Err(source) if disconnected_in_manifest(&source) => {
+                info!("Device disconnected during manifestation after all data was written: {}", source);
+                info!("Treating this as success pending re-enumeration of the device.");
+            },
+            Err(source) => return Err(ErrorKind::DeviceReboot.error_from(source)),
+            Ok(()) => (),
+        };
+
+        if let Err(e) = dfu_iface.release() {
+            debug!("Failed to release DFU interface after manifestation: {}", e);
         }
+
+        Ok(())
     }
 
-    /// Downloads firmware onto the device, switching into DFU mode automatically if necessary.
+    /// Reads back the firmware region `firmware_type` of the device and compares it against
+    /// `firmware`, without writing anything, switching into DFU mode automatically if necessary.
     ///
-    /// `progress` is a callback of the form `fn(just_written: usize)`, for callers to keep track of
-    /// the flashing process.
-    pub fn download<'r, R, P>(&mut self, firmware: &'r R, length: u32, firmware_type: FirmwareType, progress: P) -> Result<(), Error>
+    /// A device that was in runtime mode is switched back afterwards, whether or not the firmware
+    /// matched. Transfers are retried according to `options`; its other settings are ignored.
+    /// `progress` is called as verification progresses.
+    pub fn verify<P>(
+        &mut self,
+        firmware: &[u8],
+        firmware_type: FirmwareType,
+        options: &DownloadOptions,
+        progress: P,
+    ) -> Result<(), Error>
     where
-        &'r R: Read,
-        R: ?Sized,
-        P: Fn(usize) + 'static,
+        P: Fn(DownloadProgress),
     {
-        if self.mode == DfuOperatingMode::Runtime {
+        let started_in_runtime = self.mode == DfuOperatingMode::Runtime;
+        if started_in_runtime {
             self.detach_and_enumerate()
-                .map_err(|e| e.with_ctx("detaching device for download"))?;
+                .map_err(|e| e.with_ctx("detaching device for verification"))?;
         }
 
-        let load_address = self.platform.load_address(firmware_type);
-
-        let io = DfuLibusb::from_usb_device(
-            self.device().clone(),
-            self.handle.take().expect("Must have a valid device handle"),
-            0,
-            0,
-        )?.into_inner();
+        let load_address = self.profile.load_address(firmware_type);
+        let res = self.try_verify(firmware, load_address, options, progress);
 
-        match io.protocol() {
-            DfuProtocol::Dfuse {
-                address: _,
-                memory_layout: _
-            } => println!("Erasing flash..."),
-            _ => {},
+        if started_in_runtime {
+            self.detach_and_enumerate()
+                .map_err(|e| e.with_ctx("returning device to runtime mode after verification"))?;
         }
 
-        let mut dfu_dev = DfuSync::new(io);
-        dfu_dev
-            .with_progress(progress)
-            .override_address(load_address);
+        res
+    }
 
-        debug!("Load address: 0x{:08x}", load_address);
-        info!("Performing flash...");
+    fn try_verify<P>(
+        &mut self,
+        firmware: &[u8],
+        load_address: u32,
+        options: &DownloadOptions,
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        P: Fn(DownloadProgress),
+    {
+        let (iface_number, func_desc) = self.dfu_descriptors()?;
+        let handle = live_handle(&mut self.handle)?;
+        let mut dfu_iface = DfuInterface::open(handle, iface_number, func_desc, self.timeouts.get_control())
+            .map_err(|source| flash_failed(source.into()))?;
+        dfu_iface.set_retry_policy(options.retry);
+        if options.gentle {
+            dfu_iface.limit_transfer_size(GENTLE_TRANSFER_SIZE);
+            dfu_iface.set_block_delay(GENTLE_BLOCK_DELAY);
+        }
 
-        let res = self.try_download(firmware, length, &mut dfu_dev);
+        if !matches!(dfu_iface.protocol(), DfuProtocol::Dfuse(_)) {
+            return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingDfuse).error()
+                .with_ctx("reading back firmware"));
+        }
 
-        if let Err(ErrorKind::External(ErrorSource::DfuLibusb(DfuLibusbError::Dfu(DfuCoreError::StateError(DfuState::DfuError))))) = res.err_kind() {
+        debug!("Load address: 0x{:08x}", load_address);
+        let res = dfu_iface.verify(firmware, load_address, &progress);
 
-            warn!("Device reported an error when trying to flash; going to clear status and try one more time...");
+        if let Err(e) = dfu_iface.release() {
+            debug!("Failed to release DFU interface after verification: {}", e);
+        }
 
-            thread::sleep(Duration::from_millis(250));
+        res.map_err(|source| flash_failed(source.into()).with_ctx("verifying firmware"))
+    }
 
-            let request_type = rusb::request_type(
-                Direction::Out,
-                RequestType::Class,
-                Recipient::Interface,
-            );
+    /// Downloads firmware onto the device, switching into DFU mode automatically if necessary.
+    ///
+    /// `progress` is called with a [`DownloadProgress`] at the start of each phase (erase, download,
+    /// verify, manifest) and as each phase progresses, for callers to keep track of the flashing process.
+    ///
+    /// If `options` allows it (the default), a device disconnect after the last block has been
+    /// written is treated as success, as some bootloaders reset during manifestation without
+    /// acknowledging it. Callers should confirm the outcome by waiting for the device to
+    /// re-enumerate (e.g. with [`wait_for_probe_reboot`]) before reporting success.
+    ///
+    /// If `options` asks for verification, the written data is read back and compared before the
+    /// device is allowed to leave DFU mode, so a failed write leaves the device in DFU mode rather
+    /// than rebooting it into a broken image.
+    pub fn download<'r, R, P>(
+        &mut self,
+        firmware: &'r R,
+        length: u32,
+        firmware_type: FirmwareType,
+        options: &DownloadOptions,
+        progress: P,
+    ) -> Result<(), Error>
+    where
+        &'r R: Read,
+        R: ?Sized,
+        P: Fn(DownloadProgress),
+    {
+        if self.mode == DfuOperatingMode::Runtime {
+            self.detach_and_enumerate()
+                .map_err(|e| e.with_ctx("detaching device for download"))?;
+        }
 
-            self.handle().write_control(
-                request_type,
-                DfuRequest::ClrStatus as u8,
-                0,
-                0, // iface number
-                &[],
-                Duration::from_secs(2),
-            )?;
+        let load_address = self.profile.load_address(firmware_type);
 
-            self.try_download(firmware, length, &mut dfu_dev)?;
-        } else {
-            res?;
-        }
+        let mut reader = firmware;
+        let mut data = Vec::with_capacity(length as usize);
+        reader.read_to_end(&mut data)
+            .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error())?;
 
-        info!("Flash complete!");
+        profiles::check_fits(self.profile, firmware_type, &data)?;
 
-        Ok(())
+        self.try_download(&data, load_address, options, progress)
     }
 
 
     /// Consume the structure and retrieve its parts.
-    #[allow(dead_code)]
     pub fn into_inner_parts(self) -> (UsbDevice, UsbHandle, DfuOperatingMode)
     {
         (
@@ -589,23 +1066,305 @@ impl Display for BmpDevice
 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error>
     {
-        let display_str = match self.display() {
-            Ok(s) => s,
+        // Display impls are only supposed to propagate formatter IO errors, e.g. from the write!()
+        // calls below, not internal errors, so each detail that can't be read is shown as such.
+        // https://doc.rust-lang.org/stable/std/fmt/index.html#formatting-traits.
+        let product = self.product_string().unwrap_or_else(|e| {
+            warn!("Failed to read product string of device at {}: {}", self.port(), e);
+            S!("Unknown Black Magic Probe (product string unreadable)")
+        });
+        write!(f, "{}", product)?;
+
+        match self.serial_number() {
+            Ok(serial) => write!(f, "\n  Serial: {}", &*serial)?,
             Err(e) => {
-                // Display impls are only supposed to propagate formatter IO errors, e.g.
-                // from the write!() call below, not internal errors.
-                // https://doc.rust-lang.org/stable/std/fmt/index.html#formatting-traits.
-                error!("Error formatting BlackMagicProbeDevice: {}", e);
-                S!("Unknown Black Magic Probe (error occurred fetching device details)")
-            }
-        };
-
-        write!(f, "{}", display_str)?;
+                warn!("Failed to read serial number of device at {}: {}", self.port(), e);
+                write!(f, "\n  Serial: (unreadable)")?;
+            },
+        }
+        write!(f, "\n  Port:  {}", self.port())?;
 
         Ok(())
     }
 }
 
+/// Machine-readable information about a Black Magic Probe device, as returned by [`BmpDevice::info`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProbeInfo
+{
+    pub serial: Option<String>,
+    /// `runtime` or `dfu`.
+    pub mode: &'static str,
+    pub platform: String,
+    /// Name of the [`ProbeProfile`] the device matched.
+    pub profile: String,
+    pub bus: u8,
+    /// The port path, in the same format as accepted by `--port`.
+    pub port: String,
+    pub product: Option<String>,
+    pub firmware_version: Option<String>,
+    /// The hardware the firmware was built for, e.g. `native`, if the probe is in runtime mode.
+    pub hardware: Option<String>,
+    /// Hexadecimal USB vendor ID.
+    pub vid: String,
+    /// Hexadecimal USB product ID.
+    pub pid: String,
+    /// The OS serial port of the GDB server, if the probe is in runtime mode and it could be found.
+    pub gdb_port: Option<String>,
+    /// The OS serial port of the UART passthrough, if the probe is in runtime mode and it could be found.
+    pub uart_port: Option<String>,
+    /// Fields not filled in yet, as returned by [`BmpDevice::partial_info`]. Empty otherwise.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<&'static str>,
+}
+
+
+/// What a device should be running after [`BmpDevice::download`], as set with
+/// [`DownloadOptions::reboot_to`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RebootTarget
+{
+    /// Manifest the new firmware and leave DFU mode, as usual.
+    Application,
+    /// Stay in DFU mode.
+    Dfu,
+}
+
+
+/// Transfer size used by [`DownloadOptions::gentle`].
+const GENTLE_TRANSFER_SIZE: u16 = 256;
+
+/// Pause before each block written by [`DownloadOptions::gentle`].
+const GENTLE_BLOCK_DELAY: Duration = Duration::from_millis(20);
+
+/// Options that control the behaviour of [`BmpDevice::download`].
+#[derive(Debug, Clone)]
+pub struct DownloadOptions
+{
+    /// Whether a device disconnect after the final block has been written counts as success.
+    manifest_disconnect_ok: bool,
+
+    /// Whether to read the written data back and compare it before letting the device reboot.
+    verify: bool,
+
+    /// How transfers that fail with a transient error are retried.
+    retry: RetryPolicy,
+
+    /// Whether to use small transfers with pauses between them, for marginally powered devices.
+    gentle: bool,
+
+    /// What the device should be running once the firmware has been written.
+    reboot_to: RebootTarget,
+
+    /// Whether to always reset the device after manifestation, rather than only when it asks for it.
+    force_reset: bool,
+
+    /// How the flash is erased before writing, on DfuSe devices.
+    erase_strategy: EraseStrategy,
+
+    /// How many bytes of the firmware an earlier, interrupted download already wrote.
+    resume_from: usize,
+
+    /// The most bytes to send in each DFU transfer, if fewer than the device takes.
+    transfer_size: Option<u16>,
+
+    /// How the device is polled while it writes or erases.
+    poll_strategy: PollStrategy,
+}
+
+impl DownloadOptions
+{
+    pub fn new() -> Self
+    {
+        Default::default()
+    }
+
+    /// Set whether a device disconnect during the manifestation phase (after every block has been
+    /// written) should be treated as success rather than as an error. Defaults to `true`.
+    #[must_use]
+    pub fn manifest_disconnect_ok(mut self, ok: bool) -> Self
+    {
+        self.manifest_disconnect_ok = ok;
+        self
+    }
+
+    /// Get the value previously set with `.manifest_disconnect_ok()`.
+    #[allow(dead_code)]
+    pub fn get_manifest_disconnect_ok(&self) -> bool
+    {
+        self.manifest_disconnect_ok
+    }
+
+    /// Set whether the written data should be read back and verified before the device is allowed
+    /// to leave DFU mode. If verification fails, the device is left in DFU mode so the operation
+    /// can be retried. Requires the device to speak DfuSe. Defaults to `false`.
+    #[must_use]
+    pub fn verify(mut self, verify: bool) -> Self
+    {
+        self.verify = verify;
+        self
+    }
+
+    /// Get the value previously set with `.verify()`.
+    #[allow(dead_code)]
+    pub fn get_verify(&self) -> bool
+    {
+        self.verify
+    }
+
+    /// Set how USB transfers that fail with a transient error (e.g. because of a flaky hub) are
+    /// retried during the download. Defaults to [`RetryPolicy::default`].
+    #[must_use]
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self
+    {
+        self.retry = retry;
+        self
+    }
+
+    /// Set whether to write (and read back) the firmware in small transfers, pausing between
+    /// them. Slower, but reduces the load on probes that brown out and disconnect partway through
+    /// flashing, e.g. on unpowered hubs. Defaults to `false`.
+    #[must_use]
+    pub fn gentle(mut self, gentle: bool) -> Self
+    {
+        self.gentle = gentle;
+        self
+    }
+
+    /// Get the value previously set with `.gentle()`.
+    #[allow(dead_code)]
+    pub fn get_gentle(&self) -> bool
+    {
+        self.gentle
+    }
+
+    /// Get the value previously set with `.retry_policy()`.
+    #[allow(dead_code)]
+    pub fn get_retry_policy(&self) -> RetryPolicy
+    {
+        self.retry
+    }
+
+    /// Set what the device should be running once the firmware has been written. With
+    /// [`RebootTarget::Dfu`], the firmware is not manifested and the device stays in DFU mode, e.g.
+    /// to flash something else straight after. Defaults to [`RebootTarget::Application`].
+    #[must_use]
+    pub fn reboot_to(mut self, target: RebootTarget) -> Self
+    {
+        self.reboot_to = target;
+        self
+    }
+
+    /// Get the value previously set with `.reboot_to()`.
+    #[allow(dead_code)]
+    pub fn get_reboot_to(&self) -> RebootTarget
+    {
+        self.reboot_to
+    }
+
+    /// Set whether to always finish with a USB reset after manifestation, so the bootloader does a
+    /// full reset of the device, rather than only resetting devices that ask for it. Ignored with
+    /// [`RebootTarget::Dfu`]. Defaults to `false`.
+    #[must_use]
+    pub fn force_reset(mut self, force_reset: bool) -> Self
+    {
+        self.force_reset = force_reset;
+        self
+    }
+
+    /// Get the value previously set with `.force_reset()`.
+    #[allow(dead_code)]
+    pub fn get_force_reset(&self) -> bool
+    {
+        self.force_reset
+    }
+
+    /// Set how the flash is erased before writing. Mass erasing is much faster than erasing page by
+    /// page, but only some bootloaders support it (see [`BmpPlatform::supports_mass_erase`]), and
+    /// it also erases anything else in flash. Defaults to [`EraseStrategy::Auto`].
+    #[must_use]
+    pub fn erase_strategy(mut self, strategy: EraseStrategy) -> Self
+    {
+        self.erase_strategy = strategy;
+        self
+    }
+
+    /// Get the value previously set with `.erase_strategy()`.
+    #[allow(dead_code)]
+    pub fn get_erase_strategy(&self) -> EraseStrategy
+    {
+        self.erase_strategy
+    }
+
+    /// Set how many bytes of the firmware an earlier, interrupted download is known to have
+    /// written, to pick up from there instead of starting over. Writing restarts from the
+    /// beginning of the flash page this falls in; plain DFU devices always start over. Defaults
+    /// to 0.
+    #[must_use]
+    pub fn resume_from(mut self, written: usize) -> Self
+    {
+        self.resume_from = written;
+        self
+    }
+
+    /// Get the value previously set with `.resume_from()`.
+    #[allow(dead_code)]
+    pub fn get_resume_from(&self) -> usize
+    {
+        self.resume_from
+    }
+
+    /// Limit each DFU transfer to `size` bytes, if the device takes more. Defaults to `None`,
+    /// using as much as the device takes (or less, with `.gentle()`).
+    #[must_use]
+    pub fn transfer_size(mut self, size: Option<u16>) -> Self
+    {
+        self.transfer_size = size;
+        self
+    }
+
+    /// Get the value previously set with `.transfer_size()`.
+    pub fn get_transfer_size(&self) -> Option<u16>
+    {
+        self.transfer_size
+    }
+
+    /// Set how the device is polled while it writes or erases. Defaults to
+    /// [`PollStrategy::Requested`].
+    #[must_use]
+    pub fn poll_strategy(mut self, strategy: PollStrategy) -> Self
+    {
+        self.poll_strategy = strategy;
+        self
+    }
+
+    /// Get the value previously set with `.poll_strategy()`.
+    pub fn get_poll_strategy(&self) -> PollStrategy
+    {
+        self.poll_strategy
+    }
+}
+
+impl Default for DownloadOptions
+{
+    fn default() -> Self
+    {
+        Self {
+            manifest_disconnect_ok: true,
+            verify: false,
+            retry: RetryPolicy::default(),
+            gentle: false,
+            reboot_to: RebootTarget::Application,
+            force_reset: false,
+            erase_strategy: EraseStrategy::Auto,
+            resume_from: 0,
+            transfer_size: None,
+            poll_strategy: PollStrategy::Requested,
+        }
+    }
+}
+
+
 /// Represents a conceptual Vector Table for Armv7 processors.
 pub struct Armv7mVectorTable<'b>
 {
@@ -636,7 +1395,6 @@ impl<'b> Armv7mVectorTable<'b>
         }
     }
 
-    #[allow(dead_code)]
     pub fn stack_pointer(&self) -> Result<u32, TryFromSliceError>
     {
         self.word(0)
@@ -667,14 +1425,24 @@ pub enum FirmwareType
 
 impl FirmwareType
 {
-    /// Detect the kind of firmware from the given binary by examining its reset vector address.
-    ///
-    /// This function panics if `firmware.len() < 8`.
-    pub fn detect_from_firmware(platform: BmpPlatform, firmware: &[u8]) -> Result<Self, Error>
+    /// The vector table at the start of `firmware`, or an [`ErrorKind::InvalidFirmware`] error if
+    /// it's too short to have one.
+    fn vector_table(firmware: &[u8]) -> Result<Armv7mVectorTable<'_>, Error>
     {
-        let buffer = &firmware[0..(4 * 2)];
+        if firmware.len() < 4 * 2 {
+            return Err(ErrorKind::InvalidFirmware(Some(format!(
+                "firmware image is only {} bytes, too short to have a vector table",
+                firmware.len(),
+            ))).error());
+        }
 
-        let vector_table = Armv7mVectorTable::from_bytes(buffer);
+        Ok(Armv7mVectorTable::from_bytes(&firmware[0..(4 * 2)]))
+    }
+
+    /// Detect the kind of firmware from the given binary by examining its reset vector address.
+    pub fn detect_from_firmware(profile: &ProbeProfile, firmware: &[u8]) -> Result<Self, Error>
+    {
+        let vector_table = Self::vector_table(firmware)?;
         let reset_vector = vector_table.reset_vector()
             .map_err(|e| ErrorKind::InvalidFirmware(Some(S!("vector table too short"))).error_from(e))?;
 
@@ -688,7 +1456,7 @@ impl FirmwareType
             ))).error());
         }
 
-        let app_start = platform.load_address(Self::Application);
+        let app_start = profile.load_address(Self::Application);
 
         if reset_vector > app_start {
             Ok(Self::Application)
@@ -696,6 +1464,97 @@ impl FirmwareType
             Ok(Self::Bootloader)
         }
     }
+
+    /// Check that `firmware` looks like application firmware that can run on the hardware `profile`
+    /// describes, so a wrong file is caught before the firmware already on the probe is erased.
+    ///
+    /// This checks that the image is long enough to have a vector table, that its initial stack
+    /// pointer points into SRAM, that its reset vector points into the image itself, and that it
+    /// fits in the flash the profile has for it (if known).
+    pub fn validate_application(profile: &ProbeProfile, firmware: &[u8]) -> Result<(), Error>
+    {
+        let vector_table = Self::vector_table(firmware)?;
+        profiles::check_fits(profile, Self::Application, firmware)?;
+
+        let stack_pointer = vector_table.stack_pointer()
+            .map_err(|e| ErrorKind::InvalidFirmware(Some(S!("vector table too short"))).error_from(e))?;
+        let reset_vector = vector_table.reset_vector()
+            .map_err(|e| ErrorKind::InvalidFirmware(Some(S!("vector table too short"))).error_from(e))?;
+
+        if (stack_pointer & 0xfff0_0000) != 0x2000_0000 {
+            return Err(ErrorKind::InvalidFirmware(Some(format!(
+                "initial stack pointer does not point into SRAM: 0x{:08x}",
+                stack_pointer,
+            ))).error());
+        }
+
+        // Mask off the Thumb bit before checking the address.
+        let reset_address = reset_vector & !1;
+        let image_start = profile.load_address(Self::Application);
+        let image_end = image_start as u64 + firmware.len() as u64;
+        if reset_address < image_start || reset_address as u64 >= image_end {
+            return Err(ErrorKind::InvalidFirmware(Some(format!(
+                "reset vector 0x{:08x} is outside of the image, which would be at 0x{:08x}..0x{:08x}; \
+                this firmware is not built for {}",
+                reset_vector,
+                image_start,
+                image_end,
+                profile.name,
+            ))).error());
+        }
+
+        Ok(())
+    }
+
+    /// Check that `firmware` looks like a Black Magic Debug bootloader that can safely be written to
+    /// the bootloader region of `platform`.
+    ///
+    /// This checks that the platform has an updatable bootloader region at all, that the image fits
+    /// in it, that its initial stack pointer points into SRAM, and that its reset vector points
+    /// into the bootloader region itself.
+    pub fn validate_bootloader(platform: BmpPlatform, firmware: &[u8]) -> Result<(), Error>
+    {
+        let region_size = platform.bootloader_size().ok_or_else(|| {
+            ErrorKind::OperationNotSupported(format!("updating the bootloader of a {} device", platform)).error()
+        })?;
+        let region_start = platform.load_address(Self::Bootloader);
+        let region_end = region_start + region_size;
+
+        if firmware.len() > region_size as usize {
+            return Err(ErrorKind::InvalidFirmware(Some(format!(
+                "bootloader image is {} bytes, but the bootloader region is only {} bytes",
+                firmware.len(),
+                region_size,
+            ))).error());
+        }
+
+        let vector_table = Self::vector_table(firmware)?;
+        let stack_pointer = vector_table.stack_pointer()
+            .map_err(|e| ErrorKind::InvalidFirmware(Some(S!("vector table too short"))).error_from(e))?;
+        let reset_vector = vector_table.reset_vector()
+            .map_err(|e| ErrorKind::InvalidFirmware(Some(S!("vector table too short"))).error_from(e))?;
+
+        if (stack_pointer & 0xfff0_0000) != 0x2000_0000 {
+            return Err(ErrorKind::InvalidFirmware(Some(format!(
+                "bootloader initial stack pointer does not point into SRAM: 0x{:08x}",
+                stack_pointer,
+            ))).error());
+        }
+
+        // Mask off the Thumb bit before checking the address.
+        let reset_address = reset_vector & !1;
+        if reset_address < region_start || reset_address >= region_end {
+            return Err(ErrorKind::InvalidFirmware(Some(format!(
+                "reset vector 0x{:08x} is outside of the bootloader region (0x{:08x}..0x{:08x}); \
+                this does not look like a bootloader",
+                reset_vector,
+                region_start,
+                region_end,
+            ))).error());
+        }
+
+        Ok(())
+    }
 }
 
 impl Display for FirmwareType
@@ -733,6 +1592,10 @@ pub enum FirmwareFormat
 
     /// Intel HEX. Typical file extensions: `.hex`, `.ihex`.
     IntelHex,
+
+    /// Motorola S-record. Made with `objcopy -O srec`. Typical file extensions: `.srec`, `.s19`,
+    /// `.s28`, `.s37`, `.mot`.
+    Srec,
 }
 
 impl FirmwareFormat
@@ -747,6 +1610,8 @@ impl FirmwareFormat
             FirmwareFormat::Elf
         } else if &firmware[0..1] == b":" {
             FirmwareFormat::IntelHex
+        } else if firmware[0] == b'S' && firmware[1].is_ascii_digit() {
+            FirmwareFormat::Srec
         } else {
             FirmwareFormat::Binary
         }
@@ -755,12 +1620,104 @@ impl FirmwareFormat
 
 
 
+/// A condition on devices for [`BmpMatcher::filter`], beyond the serial number, port, and index.
+///
+/// Predicates are built from a closure with [`DevicePredicate::new`], or with one of the
+/// constructors for common conditions, and combined with [`and`](Self::and), [`or`](Self::or), and
+/// [`not`](Self::not):
+///
+/// ```no_run
+/// use bmputil::bmp::{BmpMatcher, DevicePredicate};
+/// use bmputil::usb::DfuOperatingMode;
+/// use bmputil::version::FirmwareVersion;
+///
+/// let old = DevicePredicate::firmware_version(.."v1.10.0".parse::<FirmwareVersion>().unwrap());
+/// let stuck = DevicePredicate::mode(DfuOperatingMode::FirmwareUpgrade);
+/// let results = BmpMatcher::new()
+///     .filter(old.or(stuck).and(DevicePredicate::product_contains("ST-Link").not()))
+///     .find_matching_probes();
+/// ```
+#[derive(Clone)]
+pub struct DevicePredicate(Arc<dyn Fn(&BmpDevice) -> bool + Send + Sync>);
+
+impl DevicePredicate
+{
+    pub fn new<F>(predicate: F) -> Self
+    where
+        F: Fn(&BmpDevice) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(predicate))
+    }
+
+    /// Matches devices whose product string contains `needle`, ignoring case. Devices whose product
+    /// string can't be read don't match.
+    pub fn product_contains(needle: &str) -> Self
+    {
+        let needle = needle.to_ascii_lowercase();
+        Self::new(move |dev| dev.product_string().is_ok_and(|product| product.to_ascii_lowercase().contains(&needle)))
+    }
+
+    /// Matches devices running a firmware version in `range`. Devices whose version is unknown
+    /// (including those in DFU mode) don't match.
+    pub fn firmware_version<R>(range: R) -> Self
+    where
+        R: RangeBounds<FirmwareVersion> + Send + Sync + 'static,
+    {
+        Self::new(move |dev| dev.operating_mode() == DfuOperatingMode::Runtime
+            && dev.firmware_version().is_some_and(|version| range.contains(&version)))
+    }
+
+    /// Matches devices in `mode`.
+    pub fn mode(mode: DfuOperatingMode) -> Self
+    {
+        Self::new(move |dev| dev.operating_mode() == mode)
+    }
+
+    /// Matches devices matching both this and `other`.
+    #[must_use]
+    pub fn and(self, other: Self) -> Self
+    {
+        Self::new(move |dev| self.matches(dev) && other.matches(dev))
+    }
+
+    /// Matches devices matching this, `other`, or both.
+    #[must_use]
+    pub fn or(self, other: Self) -> Self
+    {
+        Self::new(move |dev| self.matches(dev) || other.matches(dev))
+    }
+
+    /// Matches devices not matching this.
+    #[must_use]
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self
+    {
+        Self::new(move |dev| !self.matches(dev))
+    }
+
+    pub fn matches(&self, dev: &BmpDevice) -> bool
+    {
+        (self.0)(dev)
+    }
+}
+
+impl fmt::Debug for DevicePredicate
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        f.write_str("DevicePredicate(..)")
+    }
+}
+
+
 #[derive(Debug, Clone, Default)]
 pub struct BmpMatcher
 {
     index: Option<usize>,
-    serial: Option<String>,
-    port: Option<String>,
+    serials: Vec<String>,
+    ports: Vec<String>,
+    predicates: Vec<DevicePredicate>,
+    timeouts: Timeouts,
 }
 impl BmpMatcher
 {
@@ -769,14 +1726,6 @@ impl BmpMatcher
         Default::default()
     }
 
-    pub(crate) fn from_cli_args(matches: &ArgMatches) -> Self
-    {
-        Self::new()
-            .index(matches.value_of("index").map(|arg| usize::from_str(arg).unwrap()))
-            .serial(matches.value_of("serial_number"))
-            .port(matches.value_of("port"))
-    }
-
     /// Set the index to match against.
     #[must_use]
     pub fn index(mut self, idx: Option<usize>) -> Self
@@ -786,11 +1735,26 @@ impl BmpMatcher
     }
 
     /// Set the serial number to match against.
+    ///
+    /// This can also be just the start of the serial number, or a glob with `*` (any run of
+    /// characters) and `?` (any one character), e.g. `7BB1*`, as serial numbers are long and easy to
+    /// mistype from a label. Either way, case doesn't matter, and a serial number given in full
+    /// selects just that probe, even if another probe's serial number starts with it.
     #[must_use]
     pub fn serial<'s, IntoOptStrT>(mut self, serial: IntoOptStrT) -> Self
         where IntoOptStrT: Into<Option<&'s str>>
     {
-        self.serial = serial.into().map(|s| s.to_string());
+        self.serials = serial.into().map(|s| s.to_string()).into_iter().collect();
+        self
+    }
+
+    /// Set several serial numbers to match against, each as for `.serial()`, matching devices with
+    /// any of them.
+    #[must_use]
+    pub fn serials<'s, I>(mut self, serials: I) -> Self
+        where I: IntoIterator<Item = &'s str>
+    {
+        self.serials = serials.into_iter().map(|s| s.to_string()).collect();
         self
     }
 
@@ -799,10 +1763,77 @@ impl BmpMatcher
     pub fn port<'s, IntoOptStrT>(mut self, port: IntoOptStrT) -> Self
         where IntoOptStrT: Into<Option<&'s str>>
     {
-        self.port = port.into().map(|s| s.to_string());
+        self.ports = port.into().map(|s| s.to_string()).into_iter().collect();
+        self
+    }
+
+    /// Set several port paths to match against, matching devices on any of them.
+    #[must_use]
+    pub fn ports<'s, I>(mut self, ports: I) -> Self
+        where I: IntoIterator<Item = &'s str>
+    {
+        self.ports = ports.into_iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Add a condition devices have to meet, on top of the serial numbers, ports, and index.
+    /// Devices have to meet every condition added.
+    ///
+    /// Predicates are checked after the index, so the index counts devices that fail them. Checking
+    /// them may need requests to the device (e.g. for its product string).
+    #[must_use]
+    pub fn filter(mut self, predicate: DevicePredicate) -> Self
+    {
+        self.predicates.push(predicate);
         self
     }
 
+    /// Whether a device with `serial` (`None` if unknown) matches the serial numbers given, which
+    /// it does if none were.
+    fn matches_serial(&self, serial: Option<&str>) -> bool
+    {
+        self.serials.is_empty() || serial.is_some_and(|serial| {
+            self.serials.iter().any(|pattern| serial_matches(pattern, serial))
+        })
+    }
+
+    /// Narrows the probes found down to those whose serial number is exactly one of those given
+    /// (ignoring case), if any are: a probe's full serial number mustn't become ambiguous just
+    /// because another probe's serial number starts with it.
+    fn prefer_exact_serials(&self, results: &mut BmpMatchResults)
+    {
+        let is_exact = |dev: &BmpDevice| {
+            dev.cached_serial_number()
+                .is_some_and(|serial| self.serials.iter().any(|pattern| pattern.eq_ignore_ascii_case(&serial)))
+        };
+
+        if results.found.iter().any(is_exact) {
+            results.found.retain(is_exact);
+        }
+    }
+
+    /// Get the predicates previously added with `.filter()`.
+    #[allow(dead_code)]
+    pub fn get_filters(&self) -> &[DevicePredicate]
+    {
+        &self.predicates
+    }
+
+    /// Set the timeouts for reading serial numbers while matching, and for the devices found.
+    #[must_use]
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self
+    {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Get the timeouts previously set with `.timeouts()`.
+    #[allow(dead_code)]
+    pub fn get_timeouts(&self) -> Timeouts
+    {
+        self.timeouts
+    }
+
     /// Get any index previously set with `.index()`.
     #[allow(dead_code)]
     pub fn get_index(&self) -> Option<usize>
@@ -810,24 +1841,25 @@ impl BmpMatcher
         self.index
     }
 
-    /// Get any serial number previously set with `.serial()`.
+    /// Get the serial numbers previously set with `.serial()` or `.serials()`.
     #[allow(dead_code)]
-    pub fn get_serial(&self) -> Option<&str>
+    pub fn get_serials(&self) -> &[String]
     {
-        self.serial.as_deref()
+        &self.serials
     }
 
-    /// Get any port path previously set with `.port()`.
+    /// Get the port paths previously set with `.port()` or `.ports()`.
     #[allow(dead_code)]
-    pub fn get_port(&self) -> Option<&str>
+    pub fn get_ports(&self) -> &[String]
     {
-        self.port.as_deref()
+        &self.ports
     }
 
     /// Find all connected Black Magic Probe devices that match from the command-line criteria.
     ///
     /// This uses the `serial_number`, `index`, and `port` values from `matches`, treating any that
-    /// were not provided as always matching.
+    /// were not provided as always matching. A device matches several serial numbers or ports if it
+    /// matches any of them, so the results are the union of what each would find.
     ///
     /// This function returns all found devices and all errors that occurred during the search.
     /// This is so errors are not hidden, but also do not prevent matching devices from being found.
@@ -843,125 +1875,217 @@ impl BmpMatcher
             errors: Vec::new(),
         };
 
-        let context = match rusb::Context::new() {
-            Ok(c) => c,
-            Err(e) => {
-                results.errors.push(e.into());
-                return results;
-            },
+        for item in ProbeScanner::scan(self) {
+            match item {
+                ScanItem::Found(dev) => results.found.push(dev),
+                ScanItem::FilteredOut(dev) => results.filtered_out.push(dev),
+                ScanItem::Error { error, .. } => results.errors.push(error),
+            }
+        }
+        self.prefer_exact_serials(&mut results);
+
+        // Now, after all this, return all the devices we found, what devices were filtered out, and any errors that
+        // occured along the way.
+        results
+    }
+
+    /// Like [`find_matching_probes()`](Self::find_matching_probes), but if no probe matches, waits
+    /// for one to be connected, for up to `timeout` (or forever, if `None`).
+    ///
+    /// Where libusb supports hotplug notifications, the probes are looked for again as soon as a
+    /// Black Magic Probe device arrives; otherwise, they're polled for. If the timeout passes first,
+    /// the results of the last search (with nothing found) are returned. Fails only if libusb can't
+    /// be initialised to wait with.
+    pub fn wait_for_matching_probes(&self, timeout: Option<Duration>) -> Result<BmpMatchResults, Error>
+    {
+        let clock = SystemClock;
+        let deadline = timeout.map(|timeout| clock.now() + timeout);
+        let mut backend = UsbBackend::new()
+            .map_err(|e| e.with_ctx("waiting for a probe to be connected"))?;
+
+        loop {
+            let results = self.find_matching_probes();
+            if !results.found.is_empty() {
+                return Ok(results);
+            }
+
+            let now = clock.now();
+            let remaining = match deadline {
+                Some(deadline) if now >= deadline => return Ok(results),
+                Some(deadline) => deadline - now,
+                None => Duration::MAX,
+            };
+            trace!("No matching probe yet, waiting");
+            backend.wait_for_change(&clock, remaining);
+        }
+    }
+}
+
+
+/// What [`ProbeScanner`] made of one device.
+#[derive(Debug)]
+pub enum ScanItem
+{
+    /// A probe matching the [`BmpMatcher`].
+    Found(BmpDevice),
+    /// A probe the [`BmpMatcher`] didn't match.
+    FilteredOut(UsbDevice),
+    /// A device that couldn't be checked against the [`BmpMatcher`], or opened.
+    Error
+    {
+        /// The USB port path of the device, or `None` if the error wasn't about a particular device
+        /// (e.g. libusb couldn't list the devices at all).
+        port: Option<String>,
+        error: Error,
+    },
+}
+
+/// Looks for the probes matching a [`BmpMatcher`], yielding each device as soon as it has been
+/// checked, rather than all of them at the end like [`BmpMatcher::find_matching_probes`].
+///
+/// This lets frontends show probes as they're found, so one slow device (or a large hub) doesn't
+/// hold up the rest:
+///
+/// ```no_run
+/// # use bmputil::bmp::{BmpMatcher, ProbeScanner, ScanItem};
+/// for item in ProbeScanner::scan(&BmpMatcher::new()) {
+///     match item {
+///         ScanItem::Found(probe) => println!("Found {}", probe),
+///         ScanItem::FilteredOut(_) => (),
+///         ScanItem::Error { port, error } => eprintln!("{}: {}", port.unwrap_or_default(), error),
+///     }
+/// }
+/// ```
+pub struct ProbeScanner
+{
+    matcher: BmpMatcher,
+    /// The devices with known probe IDs that are left to check, with their indices.
+    devices: std::iter::Enumerate<std::vec::IntoIter<UsbDevice>>,
+    /// An error listing the devices, to be yielded first.
+    error: Option<Error>,
+    snapshot: EnumerationSnapshot,
+}
+
+impl ProbeScanner
+{
+    /// Starts looking for the probes `matcher` matches. Nothing is read from the devices until the
+    /// scanner is iterated.
+    pub fn scan(matcher: &BmpMatcher) -> Self
+    {
+        let devices = crate::usb::new_context()
+            .and_then(|context| context.devices())
+            .map(|devices| {
+                // Filter out devices that don't match the Black Magic Probe's vid/pid in the first place.
+                devices
+                    .iter()
+                    .filter(|dev| {
+                        dev.device_descriptor().is_ok_and(|desc| {
+                            profiles::find(Vid(desc.vendor_id()), Pid(desc.product_id())).is_some()
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            });
+        let (devices, error) = match devices {
+            Ok(devices) => (devices, None),
+            Err(e) => (Vec::new(), Some(e.into())),
         };
 
-        let devices = match context.devices() {
-            Ok(d) => d,
-            Err(e) => {
-                results.errors.push(e.into());
-                return results;
-            },
-        };
+        Self {
+            matcher: matcher.clone(),
+            devices: devices.into_iter().enumerate(),
+            error,
+            // Serial numbers we've recently seen at the same location don't need to be read again.
+            snapshot: EnumerationSnapshot::load(),
+        }
+    }
 
-        // Filter out devices that don't match the Black Magic Probe's vid/pid in the first place.
-        let devices = devices
-            .iter()
-            .filter(|dev| {
-                let desc = dev.device_descriptor()
-                    .expect(libusb_cannot_fail!("libusb_get_device_descriptor()"));
+    /// Checks one device against the matcher.
+    fn check(&mut self, index: usize, dev: UsbDevice) -> ScanItem
+    {
+        let matcher = &self.matcher;
+        let port_path = usb_port_path(&dev);
+
+        // If we've seen this exact device before, we already know its serial number.
+        let mut serial = self.snapshot
+            .serial_for(dev.bus_number(), dev.address(), &port_path)
+            .map(String::from);
+
+        // If we're trying to match against a serial number and don't know it yet, we need to
+        // open the device and read it.
+        if !matcher.serials.is_empty() && serial.is_none() {
+            let res = dev.open()
+                .map_err(Error::from)
+                .and_then(|handle| read_serial_number(&dev, &handle, matcher.timeouts.get_control(), RetryPolicy::default()))
+                .or_else(|e| serial_number_from_os_or(&dev, e));
+            match res {
+                Ok(s) => {
+                    self.snapshot.record(dev.bus_number(), dev.address(), &port_path, &s);
+                    serial = Some(s);
+                },
+                Err(error) => {
+                    return ScanItem::Error {
+                        port: Some(port_path),
+                        error,
+                    };
+                },
+            }
+        }
 
-                let (vid, pid) = (desc.vendor_id(), desc.product_id());
-                BmpPlatform::from_vid_pid(Vid(vid), Pid(pid)).is_some()
-            });
+        let serial_matches = matcher.matches_serial(serial.as_deref());
 
-        for (index, dev) in devices.enumerate() {
+        // Consider the index to match if it equals that of the device or if one was not specified at all.
+        let index_matches = matcher.index.is_none_or(|needle| needle == index);
 
-            // Note: the control flow in this function is kind of weird, due to the lack of early returns
-            // (since we're returning all successes and errors).
+        // Consider the port to match if any equals that of the device or if none were specified at all.
+        let port_matches = matcher.ports.is_empty() || matcher.ports.contains(&port_path);
 
-            // If we're trying to match against a serial number, we need to open the device.
-            let handle = if self.serial.is_some() {
-                match dev.open() {
-                    Ok(h) => Some(h),
-                    Err(e) => {
-                        results.errors.push(e.into());
-                        continue;
-                    },
-                }
-            } else {
-                None
-            };
+        // Finally, check the provided matchers.
+        if !(index_matches && port_matches && serial_matches) {
+            return ScanItem::FilteredOut(dev);
+        }
 
-            // If we opened the device and now have that handle, try to get the device's first language, which we need
-            // to request the string descriptor that contains the serial number.
-            let lang = if let Some(handle) = handle.as_ref() {
-                match handle.read_languages(Duration::from_secs(2)) {
-                    Ok(mut l) => Some(l.remove(0)),
-                    Err(e) => {
-                        results.errors.push(e.into());
-                        continue;
-                    }
+        match BmpDevice::from_usb_device(dev) {
+            Ok(mut bmpdev) => {
+                bmpdev.set_timeouts(matcher.timeouts);
+                // Save the device having to read the serial number again.
+                bmpdev.serial.replace(serial);
+                if !matcher.predicates.iter().all(|predicate| predicate.matches(&bmpdev)) {
+                    let (dev, _handle, _mode) = bmpdev.into_inner_parts();
+                    return ScanItem::FilteredOut(dev);
                 }
-            } else {
-                None
-            };
+                ScanItem::Found(bmpdev)
+            },
+            Err(error) => ScanItem::Error {
+                port: Some(port_path),
+                error,
+            },
+        }
+    }
+}
 
-            // And finally, if we have successfully read that language, read and match the serial number.
-            let serial_matches = if let Some(lang) = lang {
-                let handle = handle.unwrap();
-                let desc = dev.device_descriptor()
-                    .expect(libusb_cannot_fail!("libusb_get_device_descriptor"));
-                match handle.read_serial_number_string(lang, &desc, Duration::from_secs(2)) {
-                    Ok(s) => Some(s) == self.serial,
-                    Err(e) => {
-                        results.errors.push(e.into());
-                        continue;
-                    },
-                }
-            } else if self.serial.is_none() {
-                // If no serial number was specified, treat as matching.
-                true
-            } else {
-                // If we can't get the serial number because of previous errors, treat as non-matching.
-                false
-            };
+impl Iterator for ProbeScanner
+{
+    type Item = ScanItem;
 
-            // Consider the index to match if it equals that of the device or if one was not specified at all.
-            let index_matches = self.index.map_or(true, |needle| needle == index);
-
-            // Consider the port to match if it equals that of the device or if one was not specified at all.
-            let port_matches = self.port.as_ref().map_or(true, |p| {
-                let port_chain = dev
-                    .port_numbers()
-                    // Unwrap should be safe as the only possible error from libusb_get_port_numbers()
-                    // is LIBUSB_ERROR_OVERFLOW, and only if the buffer given to it is too small,
-                    // but rusb g ives it a buffer big enough for the maximum hub chain allowed by the spec.
-                    .expect("Could not get port numbers! Hub depth > 7 shouldn't be possible!")
-                    .into_iter()
-                    .map(|p| p.to_string())
-                    .collect::<Vec<String>>()
-                    .as_slice()
-                    .join(".");
-
-                let port_path = format!("{}-{}", dev.bus_number(), port_chain);
-
-                p == &port_path
+    fn next(&mut self) -> Option<ScanItem>
+    {
+        if let Some(error) = self.error.take() {
+            return Some(ScanItem::Error {
+                port: None,
+                error,
             });
-
-            // Finally, check the provided matchers.
-            if index_matches && port_matches && serial_matches {
-                match BmpDevice::from_usb_device(dev) {
-                    Ok(bmpdev) => results.found.push(bmpdev),
-                    Err(e) => {
-                        results.errors.push(e);
-                        continue;
-                    },
-                };
-            } else {
-                results.filtered_out.push(dev);
-            }
         }
 
+        let (index, dev) = self.devices.next()?;
+        Some(self.check(index, dev))
+    }
+}
 
-        // Now, after all this, return all the devices we found, what devices were filtered out, and any errors that
-        // occured along the way.
-        results
+impl Drop for ProbeScanner
+{
+    fn drop(&mut self)
+    {
+        self.snapshot.save();
     }
 }
 
@@ -977,66 +2101,57 @@ pub struct BmpMatchResults
 impl BmpMatchResults
 {
     /// Pops all found devices, handling printing error and warning cases.
-    pub(crate) fn pop_all(&mut self) -> Result<Vec<BmpDevice>, Error>
+    pub fn pop_all(&mut self) -> Result<Vec<BmpDevice>, Error>
     {
         if self.found.is_empty() {
 
             // If there was only one, print that one for the user.
             if self.filtered_out.len() == 1 {
-                if let Ok(bmpdev) = BmpDevice::from_usb_device(self.filtered_out.pop().unwrap()) {
-                    warn!(
-                        "Matching device not found, but and the following Black Magic Probe device was filtered out: {}",
+                let message = match BmpDevice::from_usb_device(self.filtered_out.pop().unwrap()) {
+                    Ok(bmpdev) => format!(
+                        "Matching device not found, but the following Black Magic Probe device was filtered out: {}",
                         &bmpdev,
-                    );
-                } else {
-                    warn!("Matching device not found but 1 Black Magic Probe device was filtered out.");
-                }
-                warn!("Filter arguments (--serial, --index, --port) may be incorrect.");
+                    ),
+                    Err(_) => S!("Matching device not found but 1 Black Magic Probe device was filtered out."),
+                };
+                warnings::emit(
+                    WarningCode::FilteredOut,
+                    message + " Filter arguments (--serial, --index, --port) may be incorrect.",
+                );
             } else if self.filtered_out.len() > 1 {
-                warn!(
-                    "Matching devices not found but {} Black Magic Probe devices were filtered out.",
+                warnings::emit(WarningCode::FilteredOut, format!(
+                    "Matching devices not found but {} Black Magic Probe devices were filtered out. \
+                    Filter arguments (--serial, --index, --port) may be incorrect.",
                     self.filtered_out.len(),
-                );
-                warn!("Filter arguments (--serial, --index, --port) may be incorrect.");
+                ));
             }
 
-
-            if !self.errors.is_empty() {
-                warn!("Device not found and errors occurred when searching for devices.");
-                warn!("One of these may be why the Black Magic Probe device was not found: {:?}", self.errors.as_slice());
-            }
-            return Err(ErrorKind::DeviceNotFound.error());
+            self.warn_search_errors();
+            return Err(self.not_found_error());
         }
 
-        if !self.errors.is_empty() {
-            warn!("Matching device found but errors occurred when searching for devices.");
-            warn!("It is unlikely but possible that the incorrect device was selected!");
-            warn!("Other device errors: {:?}", self.errors.as_slice());
-        }
+        self.warn_ambiguous_match();
 
         Ok(mem::take(&mut self.found))
     }
 
     /// Pops a single found device, handling printing error and warning cases.
-    pub(crate) fn pop_single(&mut self, operation: &str) -> Result<BmpDevice, Error>
+    pub fn pop_single(&mut self, operation: &str) -> Result<BmpDevice, Error>
     {
         if self.found.is_empty() {
             if !self.filtered_out.is_empty() {
                 let (suffix, verb) = if self.filtered_out.len() > 1 { ("s", "were") } else { ("", "was") };
-                warn!(
-                    "Matching device not found and {} Black Magic Probe device{} {} filtered out.",
+                warnings::emit(WarningCode::FilteredOut, format!(
+                    "Matching device not found and {} Black Magic Probe device{} {} filtered out. \
+                    Filter arguments (--serial, --index, --port) may be incorrect.",
                     self.filtered_out.len(),
                     suffix,
                     verb,
-                );
-                warn!("Filter arguments (--serial, --index, --port may be incorrect.");
+                ));
             }
 
-            if !self.errors.is_empty() {
-                warn!("Device not found and errors occurred when searching for devices.");
-                warn!("One of these may be why the Black Magic Probe device was not found: {:?}", self.errors.as_slice());
-            }
-            return Err(ErrorKind::DeviceNotFound.error());
+            self.warn_search_errors();
+            return Err(self.not_found_error());
         }
 
         if self.found.len() > 1 {
@@ -1045,84 +2160,484 @@ impl BmpMatchResults
                 operation,
                 self.found.len()
             );
+            for dev in &self.found {
+                error!("  {}", dev);
+            }
             error!("Hint: try bmputil info and revise your filter arguments (--serial, --index, --port).");
             return Err(ErrorKind::TooManyDevices.error());
         }
 
+        self.warn_ambiguous_match();
+
+        Ok(self.found.remove(0))
+    }
+
+    /// Warns that errors occurred while searching, which may be why no device was found.
+    fn warn_search_errors(&self)
+    {
         if !self.errors.is_empty() {
-            warn!("Matching device found but errors occurred when searching for devices.");
-            warn!("It is unlikely but possible that the incorrect device was selected!");
-            warn!("Other device errors: {:?}", self.errors.as_slice());
+            warnings::emit(WarningCode::SearchErrors, format!(
+                "Device not found and errors occurred when searching for devices. \
+                One of these may be why the Black Magic Probe device was not found: {:?}",
+                self.errors.as_slice(),
+            ));
         }
+    }
 
-        Ok(self.found.remove(0))
+    /// Warns that errors occurred while searching, so the device found may not be the one meant.
+    fn warn_ambiguous_match(&self)
+    {
+        if !self.errors.is_empty() {
+            warnings::emit(WarningCode::AmbiguousMatch, format!(
+                "Matching device found but errors occurred when searching for devices. \
+                It is unlikely but possible that the incorrect device was selected! Other device errors: {:?}",
+                self.errors.as_slice(),
+            ));
+        }
     }
 
     /// Like `pop_single()`, but does not print helpful diagnostics for edge cases.
-    pub(crate) fn pop_single_silent(&mut self) -> Result<BmpDevice, Error>
+    pub fn pop_single_silent(&mut self) -> Result<BmpDevice, Error>
     {
         if self.found.len() > 1 {
             return Err(ErrorKind::TooManyDevices.error());
         } else if self.found.is_empty() {
-            return Err(ErrorKind::DeviceNotFound.error());
+            return Err(self.not_found_error());
         }
 
         Ok(self.found.remove(0))
     }
+
+    /// The error for not finding a matching device: [`ErrorKind::DeviceNotFound`], unless a device
+    /// could not be opened for lack of permissions, which is then the more useful thing to report.
+    fn not_found_error(&self) -> Error
+    {
+        let access_denied = self.errors
+            .iter()
+            .any(|e| matches!(e.kind, ErrorKind::External(ErrorSource::Libusb(rusb::Error::Access))));
+
+        if access_denied {
+            ErrorKind::External(ErrorSource::Libusb(rusb::Error::Access)).error()
+                .with_ctx("opening Black Magic Probe device")
+        } else {
+            ErrorKind::DeviceNotFound.error()
+        }
+    }
 }
 
 
-/// Waits for a Black Magic Probe to reboot, erroring after a timeout.
-///
-/// This function takes a port string to attempt to keep track of a single physical device
-/// across USB resets.
+/// Whether `serial` matches `pattern`, as set with [`BmpMatcher::serial`]: as a glob if it has any
+/// wildcards, and as a prefix otherwise, ignoring case.
+fn serial_matches(pattern: &str, serial: &str) -> bool
+{
+    let pattern: Vec<char> = pattern.to_ascii_uppercase().chars().collect();
+    let serial: Vec<char> = serial.to_ascii_uppercase().chars().collect();
+    if !pattern.iter().any(|&c| c == '*' || c == '?') {
+        return serial.starts_with(&pattern);
+    }
+
+    // Classic wildcard matching: on a mismatch, backtrack to just after the last `*`, letting it
+    // swallow one more character of the serial number.
+    let (mut p, mut s) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while s < serial.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, s));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == serial[s] => {
+                p += 1;
+                s += 1;
+            },
+            _ => match last_star {
+                Some((star_p, star_s)) => {
+                    last_star = Some((star_p, star_s + 1));
+                    p = star_p + 1;
+                    s = star_s + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+
+/// Returns a string that represents the full port of a USB device, in the format of
+/// `<bus>-<port>.<subport>.<subport...>`.
+fn usb_port_path(dev: &UsbDevice) -> String
+{
+    // The only possible error from libusb_get_port_numbers() is LIBUSB_ERROR_OVERFLOW, for hub
+    // chains deeper than the spec allows, which broken hubs can still report. Fall back to the
+    // device's address, which at least tells devices on the bus apart.
+    let ports = match dev.port_numbers() {
+        Ok(ports) => ports,
+        Err(e) => {
+            warn!("Could not get the port numbers of the device at address {}: {}", dev.address(), e);
+            return format!("{}-addr{}", dev.bus_number(), dev.address());
+        },
+    };
+    let port_chain = ports
+        .into_iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<String>>()
+        .as_slice()
+        .join(".");
+
+    format!("{}-{}", dev.bus_number(), port_chain)
+}
+
+/// Reads the serial number string descriptor of a USB device, using the first language it supports.
+fn read_serial_number(dev: &UsbDevice, handle: &UsbHandle, timeout: Duration, retry: RetryPolicy) -> Result<String, Error>
+{
+    let languages = retry.run("reading string descriptor languages", || handle.read_languages(timeout))?;
+    let lang = languages
+        .first()
+        .ok_or_else(|| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingStringLanguages).error())?;
+
+    let desc = dev.device_descriptor()?;
+    let serial = retry.run("reading serial number", || {
+        handle.read_serial_number_string(*lang, &desc, timeout)
+    })?;
+
+    Ok(sanitize_descriptor_string(&serial))
+}
+
+/// Falls back to the serial number the OS recorded for `dev`, for when reading it from the
+/// device itself failed with `e`.
+fn serial_number_from_os_or(dev: &UsbDevice, e: Error) -> Result<String, Error>
+{
+    match os_serial::serial_number(dev) {
+        Some(serial) => {
+            debug!("Failed to read serial number from device ({}), using the one the OS recorded instead", e);
+            Ok(serial)
+        },
+        None => Err(e),
+    }
+}
+
+/// Reads the Container ID from the BOS descriptor of a USB device, if it has one.
 ///
-/// This would take a serial number, but serial numbers can actually change between firmware
-/// versions, and thus also between application and bootloader mode, so serial number is not a
-/// reliable way to keep track of a single device across USB resets.
-// TODO: test how reliable the port path is on multiple platforms.
-pub fn wait_for_probe_reboot(port: &str, timeout: Duration, operation: &str) -> Result<BmpDevice, Error>
+/// Only devices reporting USB 2.1 or later can have a BOS descriptor.
+fn read_container_id(dev: &UsbDevice, handle: &UsbHandle, timeout: Duration) -> Result<Option<[u8; 16]>, Error>
 {
-    let silence_timeout = timeout / 2;
+    const DESCRIPTOR_TYPE_BOS: u8 = 0x0f;
+
+    let desc = dev.device_descriptor()?;
+    let version = desc.usb_version();
+    if (version.major(), version.minor()) < (2, 1) {
+        return Ok(None);
+    }
 
-    let matcher = BmpMatcher {
-        index: None,
-        serial: None,
-        port: Some(port.to_string()),
+    let request_type = rusb::request_type(Direction::In, RequestType::Standard, Recipient::Device);
+    let read_bos = |buf: &mut [u8]| {
+        UsbDeviceHandle::read_control(
+            handle,
+            request_type,
+            rusb::constants::LIBUSB_REQUEST_GET_DESCRIPTOR,
+            (DESCRIPTOR_TYPE_BOS as u16) << 8,
+            0,
+            buf,
+            timeout,
+        )
     };
 
-    let start = Instant::now();
+    // Read the header first to find out how long the whole thing is.
+    let mut header: [u8; 5] = [0; 5];
+    if read_bos(&mut header)? < header.len() {
+        return Ok(None);
+    }
+    let total_length = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let mut bos = vec![0u8; total_length];
+    let read = read_bos(&mut bos)?;
+    bos.truncate(read);
+
+    // Then look through the device capability descriptors following the header.
+    let container_id = ExtraDescriptors::new(bos.get(header[0] as usize..).unwrap_or_default())
+        .iter()
+        .map_while(Result::ok)
+        .find_map(|descriptor| match descriptor {
+            Descriptor::ContainerId(id) => Some(id),
+            _ => None,
+        });
+
+    Ok(container_id)
+}
 
-    let mut dev = matcher.find_matching_probes().pop_single_silent();
 
-    while let Err(ErrorKind::DeviceNotFound) = dev.err_kind() {
+/// What we know about a physical Black Magic Probe, for finding it again after it re-enumerates
+/// (e.g. when switching between runtime and DFU mode).
+///
+/// The port path alone is usually enough, but some USB 3.x hubs assign a different port number
+/// to a device after it resets, so this also remembers the serial number and USB 3 container ID
+/// where available.
+///
+/// It also tracks which enumeration of the probe it was taken from, so that once the probe has
+/// been asked to detach, neither the old enumeration lingering on the bus, nor a different device
+/// plugged in meanwhile, is mistaken for the probe having come back (see [`Self::departing`]).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProbeIdentity
+{
+    port: String,
+    serial: Option<String>,
+    container_id: Option<[u8; 16]>,
+    incarnation: Option<Incarnation>,
+    departed: Option<Incarnation>,
+}
 
-        trace!("Waiting for probe reboot: {} ms", Instant::now().duration_since(start).as_millis());
+/// One enumeration of a device: its bus number and address, which change each time it
+/// enumerates, and the mode it was in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Incarnation
+{
+    bus: u8,
+    address: u8,
+    mode: DfuOperatingMode,
+}
 
-        // If it's been more than the timeout length, error out.
-        if Instant::now().duration_since(start) > timeout {
-            error!(
-                "Timed-out waiting for Black Magic Probe to re-enumerate!"
-            );
-            return Err(ErrorKind::DeviceReboot.error_from(dev.unwrap_err()));
+impl ProbeIdentity
+{
+    /// An identity for a probe at `port`, with nothing else known about it, for tests without a
+    /// real device.
+    #[cfg(test)]
+    pub(crate) fn at_port(port: &str) -> Self
+    {
+        Self {
+            port: port.to_string(),
+            serial: None,
+            container_id: None,
+            incarnation: None,
+            departed: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn port(&self) -> &str
+    {
+        &self.port
+    }
+
+    /// Notes that the probe is going away, as it has been asked to detach, and should come back
+    /// in the other mode.
+    ///
+    /// Until the identity is [updated](Self::update_from) with the probe's new enumeration,
+    /// [`Self::select`] passes over devices still in the mode the probe left: either the probe
+    /// hasn't gone yet, or it's a different device. This makes waiting for the probe safe against
+    /// it not having detached yet, and against the user plugging probes in and out meanwhile.
+    pub fn departing(&mut self)
+    {
+        self.departed = self.incarnation;
+    }
+
+    /// Follows the probe to where it was found again, keeping anything the new identity lacks.
+    ///
+    /// The serial number is kept from the original identity, as it identifies the probe's
+    /// runtime firmware, which is what we'll be looking for again eventually.
+    pub fn update_from(&mut self, newer: &ProbeIdentity)
+    {
+        self.incarnation = newer.incarnation;
+        self.departed = None;
+        self.port = newer.port.clone();
+        if self.serial.is_none() {
+            self.serial = newer.serial.clone();
         }
+        if newer.container_id.is_some() {
+            self.container_id = newer.container_id;
+        }
+    }
+
+    /// Returns the port chain of a device at `port`, without the bus number.
+    ///
+    /// The bus number is left out as USB 3.x hubs are really two hubs on two separate buses,
+    /// and the device may come back on the other one.
+    fn port_chain(port: &str) -> &str
+    {
+        port.split_once('-').map_or(port, |(_bus, chain)| chain)
+    }
+
+    /// Returns the port chain of the hub a device at `port` is plugged into, without the bus number.
+    fn hub_chain(port: &str) -> &str
+    {
+        Self::port_chain(port).rsplit_once('.').map_or("", |(hub, _port)| hub)
+    }
 
-        // Wait 200 milliseconds between checks. Hardware is a bottleneck and we
-        // don't need to peg the CPU waiting for it to come back up.
-        // TODO: make this configurable and/or optimize?
-        thread::sleep(Duration::from_millis(200));
+    /// Whether `dev` can't be this probe's new enumeration, after it was asked to detach.
+    fn is_left_behind(&self, dev: &BmpDevice) -> bool
+    {
+        let Some(departed) = self.departed else {
+            return false;
+        };
+        if dev.operating_mode() != departed.mode {
+            return false;
+        }
 
-        // If we've been trying for over half the full timeout, start logging warnings.
-        if Instant::now().duration_since(start) > silence_timeout {
-            dev = matcher.find_matching_probes().pop_single(operation);
+        let incarnation = dev.incarnation();
+        if (incarnation.bus, incarnation.address) == (departed.bus, departed.address) {
+            trace!("Probe at port {} has not gone away yet", self.port);
         } else {
-            dev = matcher.find_matching_probes().pop_single_silent();
+            debug!(
+                "Ignoring device at port {} (bus {} address {}), still in the mode the probe left",
+                dev.port(),
+                incarnation.bus,
+                incarnation.address,
+            );
+        }
+
+        true
+    }
+
+    /// Whether `dev` has a different container ID, and so is definitely a different device.
+    fn is_other_device(&self, dev: &BmpDevice) -> bool
+    {
+        matches!((self.container_id, dev.container_id()), (Some(ours), Some(theirs)) if ours != theirs)
+    }
+
+    /// Whether serial numbers `a` and `b` are the same, but one was cut short. They have to share
+    /// at least [`MIN_TRUNCATED_SERIAL`] characters, so short serials don't match by chance.
+    fn is_truncated_serial(a: &str, b: &str) -> bool
+    {
+        let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+
+        short.len() >= MIN_TRUNCATED_SERIAL
+            && short.len() < long.len()
+            && long.get(..short.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(short))
+    }
+
+    /// Narrows `candidates` down to the device(s) most likely to be this probe.
+    pub fn select(&self, mut candidates: Vec<BmpDevice>) -> Vec<BmpDevice>
+    {
+        candidates.retain(|dev| !self.is_left_behind(dev) && !self.is_other_device(dev));
+
+        // The port path is what identifies the probe most reliably, when it works.
+        if candidates.iter().any(|dev| dev.port() == self.port) {
+            return candidates.into_iter().filter(|dev| dev.port() == self.port).collect();
+        }
+
+        if candidates.is_empty() {
+            return candidates;
+        }
+
+        // Container IDs are unique per physical device, and don't change between modes.
+        if let Some(container_id) = self.container_id {
+            let matching: Vec<bool> = candidates
+                .iter()
+                .map(|dev| dev.container_id() == Some(container_id))
+                .collect();
+            if matching.contains(&true) {
+                debug!("Found probe previously at port {} by its container ID", self.port);
+                return filter_by(candidates, &matching);
+            }
+        }
+
+        // Serial numbers can change between firmware versions (and thus between modes), but if
+        // it's still the same, it's still the same probe.
+        if let Some(serial) = &self.serial {
+            let matching: Vec<bool> = candidates
+                .iter()
+                .map(|dev| dev.serial_number().is_ok_and(|s| &*s == serial))
+                .collect();
+            if matching.contains(&true) {
+                debug!("Found probe previously at port {} by its serial number", self.port);
+                return filter_by(candidates, &matching);
+            }
+
+            // Some bootloaders report a truncated serial number, or the firmware a longer one.
+            let matching: Vec<bool> = candidates
+                .iter()
+                .map(|dev| dev.serial_number().is_ok_and(|s| Self::is_truncated_serial(&s, serial)))
+                .collect();
+            if matching.iter().filter(|&&m| m).count() == 1 {
+                debug!("Found probe previously at port {} by a truncated serial number", self.port);
+                return filter_by(candidates, &matching);
+            }
         }
+
+        // The same ports on another bus are the same place, on the other half of a USB 3.x hub.
+        let chain = Self::port_chain(&self.port);
+        let matching: Vec<bool> = candidates
+            .iter()
+            .map(|dev| Self::port_chain(&dev.port()) == chain)
+            .collect();
+        if matching.iter().filter(|&&m| m).count() == 1 {
+            debug!("Found probe previously at port {} at the same port on another bus", self.port);
+            return filter_by(candidates, &matching);
+        }
+
+        // Finally, a device plugged into the same hub is probably the same one, but only if it's
+        // the only one there.
+        let hub = Self::hub_chain(&self.port);
+        let matching: Vec<bool> = candidates
+            .iter()
+            .map(|dev| Self::hub_chain(&dev.port()) == hub)
+            .collect();
+        if matching.iter().filter(|&&m| m).count() == 1 {
+            debug!("Assuming the only probe on the same hub as port {} is the same probe", self.port);
+            return filter_by(candidates, &matching);
+        }
+
+        Vec::new()
     }
+}
+
+/// How many characters a truncated serial number must keep to still be matched on; see
+/// [`ProbeIdentity::select`].
+const MIN_TRUNCATED_SERIAL: usize = 6;
+
+/// Keeps the items of `items` for which the corresponding element of `keep` is true.
+fn filter_by<T>(items: Vec<T>, keep: &[bool]) -> Vec<T>
+{
+    items
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(item, &keep)| keep.then_some(item))
+        .collect()
+}
+
+
+/// Waits for a Black Magic Probe to reboot, erroring after a timeout.
+///
+/// This function takes a [`ProbeIdentity`] to attempt to keep track of a single physical device
+/// across USB resets. The port path is tried first; as it can change with some hubs, the
+/// container ID, serial number (also if truncated), and the ports the probe is plugged in through
+/// are used as fallbacks.
+///
+/// The serial number can't be relied on by itself, as serial numbers can actually change between
+/// firmware versions, and thus also between application and bootloader mode.
+///
+/// Where libusb supports hotplug notifications, the device is looked for again as soon as it
+/// arrives; otherwise, this polls (see [`UsbBackend`]).
+pub fn wait_for_probe_reboot(identity: &ProbeIdentity, timeouts: Timeouts, operation: &str) -> Result<BmpDevice, Error>
+{
+    let mut backend = UsbBackend::new()?.timeouts(timeouts);
+    flasher::wait_for_probe(&mut backend, &SystemClock, identity, timeouts.get_enumerate(), operation)
+}
 
-    let dev = dev?;
 
-    Ok(dev)
+/// The handle of a [`BmpDevice`], for talking to it. The handle is only gone if the device was given
+/// up on: [closed](BmpDevice::close), or lost while [re-enumerating](BmpDevice::detach_and_enumerate),
+/// which is reported as it disconnecting.
+///
+/// This takes the field rather than the device, so the rest of the device can still be used while
+/// the handle is borrowed.
+fn live_handle(handle: &mut RefCell<Option<UsbHandle>>) -> Result<&mut UsbHandle, Error>
+{
+    handle
+        .get_mut()
+        .as_mut()
+        .ok_or_else(|| ErrorKind::DeviceDisconnectDuringOperation.error().with_ctx("using a device that was given up on"))
+}
+
+/// Makes a USB error from once writing or reading back firmware has started a
+/// [`ErrorKind::FlashFailed`], so that e.g. the probe disconnecting partway through isn't reported
+/// as there being no probe. Other errors (such as a verification mismatch) are left as they are.
+fn flash_failed(source: Error) -> Error
+{
+    match source.kind {
+        ErrorKind::DeviceNotFound | ErrorKind::External(ErrorSource::Libusb(_)) => ErrorKind::FlashFailed.error_from(source),
+        _ => source,
+    }
 }
 
 
@@ -1192,6 +2707,27 @@ impl BmpPlatform
         }
     }
 
+    /// Get the size of the bootloader region on this platform, or `None` if the bootloader of this
+    /// platform can't be updated over DFU.
+    pub const fn bootloader_size(self) -> Option<u32>
+    {
+        use BmpPlatform::*;
+
+        match self {
+            // The Black Magic Debug bootloader occupies the flash below the application.
+            BlackMagicDebug | STM32DeviceDFU => Some(0x2000),
+            // dragonBoot protects itself, and isn't ours to replace anyway.
+            DragonBoot => None,
+        }
+    }
+
+    /// Whether the bootloader accepts a DfuSe mass erase. Only the STM32 system bootloader does,
+    /// which lives in ROM and so can't erase itself.
+    pub const fn supports_mass_erase(self) -> bool
+    {
+        matches!(self, BmpPlatform::STM32DeviceDFU)
+    }
+
     /// Get the load address for firmware of `firm_type` on this platform.
     pub const fn load_address(self, firm_type: FirmwareType) -> u32
     {
@@ -1209,6 +2745,20 @@ impl BmpPlatform
     }
 }
 
+impl Display for BmpPlatform
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        match self {
+            Self::BlackMagicDebug => write!(f, "Black Magic Debug")?,
+            Self::DragonBoot => write!(f, "dragonBoot")?,
+            Self::STM32DeviceDFU => write!(f, "STM32 built-in DFU")?,
+        };
+
+        Ok(())
+    }
+}
+
 /// Defaults to [`BmpPlatform::BlackMagicDebug`].
 impl Default for BmpPlatform
 {
@@ -1218,3 +2768,117 @@ impl Default for BmpPlatform
         BmpPlatform::BlackMagicDebug
     }
 }
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::error::ExitCode;
+
+    #[test]
+    fn waiting_fails_normally_without_usb()
+    {
+        // Where libusb can't be initialised (as in a sandbox without USB), waiting for a probe
+        // must be an ordinary error, rather than a panic (exit code 101).
+        let matcher = BmpMatcher::new().serial("no such probe");
+        match matcher.wait_for_matching_probes(Some(Duration::ZERO)) {
+            Ok(results) => assert!(results.found.is_empty()),
+            Err(e) => {
+                assert!(matches!(e.kind, ErrorKind::External(ErrorSource::Libusb(_))), "{}", e);
+                assert_eq!(e.exit_code(), ExitCode::Failure);
+            },
+        }
+    }
+
+    #[test]
+    fn short_firmware_is_invalid_not_a_panic()
+    {
+        // What's left of an S-record file with a single 4 byte data record.
+        let firmware = [0x00, 0x50, 0x00, 0x20];
+        let profile = &profiles::registry()[0];
+
+        for res in [
+            FirmwareType::detect_from_firmware(profile, &firmware).map(|_| ()),
+            FirmwareType::validate_application(profile, &firmware),
+            FirmwareType::validate_bootloader(BmpPlatform::BlackMagicDebug, &firmware),
+        ] {
+            let e = res.unwrap_err();
+            assert!(matches!(e.kind, ErrorKind::InvalidFirmware(_)), "{}", e);
+        }
+    }
+
+    /// Finds which of `probes` (serial number, port) `matcher` selects, as a search would.
+    fn match_detached(matcher: &BmpMatcher, probes: &[(&str, &str)]) -> BmpMatchResults
+    {
+        let found = probes
+            .iter()
+            .filter(|(serial, _)| matcher.matches_serial(Some(serial)))
+            .map(|(serial, port)| BmpDevice::detached(serial, port))
+            .collect();
+
+        let mut results = BmpMatchResults { found, filtered_out: Vec::new(), errors: Vec::new() };
+        matcher.prefer_exact_serials(&mut results);
+        results
+    }
+
+    #[test]
+    fn serial_matches_exactly()
+    {
+        assert!(serial_matches("7BB180B4", "7BB180B4"));
+        assert!(!serial_matches("7BB180B5", "7BB180B4"));
+        assert!(!serial_matches("7BB180B4", "7BB180"));
+    }
+
+    #[test]
+    fn serial_matches_prefix_and_glob()
+    {
+        assert!(serial_matches("7BB1", "7BB180B4"));
+        assert!(serial_matches("7BB1*", "7BB180B4"));
+        assert!(serial_matches("*80B4", "7BB180B4"));
+        assert!(serial_matches("7B?180B4", "7BB180B4"));
+        // Unlike a plain prefix, a glob has to match the whole serial number.
+        assert!(!serial_matches("7BB1?", "7BB180B4"));
+        assert!(!serial_matches("*80B5", "7BB180B4"));
+    }
+
+    #[test]
+    fn serial_matches_ignore_case()
+    {
+        assert!(serial_matches("7bb180b4", "7BB180B4"));
+        assert!(serial_matches("7BB1*", "7bb180b4"));
+    }
+
+    #[test]
+    fn serial_matches_no_serial_when_one_is_given()
+    {
+        assert!(BmpMatcher::new().matches_serial(None));
+        assert!(!BmpMatcher::new().serial("7BB1").matches_serial(None));
+    }
+
+    #[test]
+    fn exact_serial_wins_over_prefix_of_another_probes()
+    {
+        let probes = [("7BB180B4", "1-1"), ("7BB180B40", "1-2"), ("E3C09FA2", "1-3")];
+
+        // The full serial number selects its probe, even though it starts the other one's.
+        let mut results = match_detached(&BmpMatcher::new().serial("7BB180B4"), &probes);
+        assert_eq!(results.pop_single("test").unwrap().port(), "1-1");
+        let mut results = match_detached(&BmpMatcher::new().serial("7bb180b4"), &probes);
+        assert_eq!(results.pop_single("test").unwrap().port(), "1-1");
+
+        // But a prefix of both is still ambiguous.
+        let mut results = match_detached(&BmpMatcher::new().serial("7BB180B"), &probes);
+        let e = results.pop_single("test").unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::TooManyDevices), "{}", e);
+
+        // An exact match for one serial doesn't hide probes another serial given matches exactly.
+        let results = match_detached(&BmpMatcher::new().serials(["7BB180B4", "E3C09FA2"]), &probes);
+        assert_eq!(results.found.len(), 2);
+
+        // A glob has to match the whole serial number, so one can pick out the longer of the two.
+        let mut results = match_detached(&BmpMatcher::new().serial("7BB180B4?"), &probes);
+        let probe = results.pop_single("test").unwrap();
+        assert_eq!(probe.port(), "1-2");
+    }
+}
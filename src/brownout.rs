@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for spotting probes that brown out while being flashed.
+//!
+//! Erasing and writing flash draws noticeably more current than anything else a probe does, so a
+//! probe on an unpowered hub or a long, thin cable can drop off the bus partway through flashing.
+//! When that happens at about the same point twice in a row, a power problem is much more likely
+//! than a flaky transfer, and we say so. Disconnects are remembered in a small
+//! [state file](bmputil::paths::state_file), for a short while.
+
+use std::fs;
+use std::time::Duration;
+
+use log::debug;
+
+use bmputil::paths::{self, state_timestamp};
+
+/// How long a disconnect is remembered for.
+const DISCONNECT_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How close two disconnects have to be to count as happening at the same point. Generous, as we
+/// only learn the offset of the last block the probe acknowledged.
+const SAME_OFFSET_TOLERANCE: usize = 4096;
+
+/// Name of the [state file](paths::state_file) the last disconnect is remembered in.
+const DISCONNECT_FILE: &str = "disconnect";
+
+/// Records that the probe with serial number `serial` disconnected after `offset` bytes had been
+/// written, returning whether it recently did the same at about the same offset.
+pub fn record_disconnect(serial: &str, offset: usize) -> bool
+{
+    let Some(path) = paths::state_file(DISCONNECT_FILE) else {
+        return false;
+    };
+    let previous = fs::read_to_string(&path).unwrap_or_default();
+
+    let mut fields = previous.trim().splitn(3, '\t');
+    let recorded: Option<u64> = fields.next().and_then(|recorded| recorded.parse().ok());
+    let previous_offset: Option<usize> = fields.next().and_then(|offset| offset.parse().ok());
+    let previous_serial = fields.next();
+
+    let repeated = match (recorded, previous_offset) {
+        (Some(recorded), Some(previous_offset)) => {
+            recorded >= state_timestamp().saturating_sub(DISCONNECT_TTL.as_secs())
+                && previous_serial == Some(serial)
+                && previous_offset.abs_diff(offset) <= SAME_OFFSET_TOLERANCE
+        },
+        _ => false,
+    };
+
+    let contents = format!("{}\t{}\t{}\n", state_timestamp(), offset, serial);
+    if let Err(e) = paths::write_state_file(&path, contents.as_bytes()) {
+        debug!("Failed to record disconnect in {}: {}", path.display(), e);
+    }
+
+    repeated
+}
+
+/// Prints advice for a probe that keeps disconnecting at the same point while being flashed.
+pub fn print_power_hint(offset: usize, gentle: bool)
+{
+    eprintln!(
+        "note: the probe disconnected at about the same point (0x{:x} bytes in) as last time. This usually \
+        means it is not getting enough power while writing flash.",
+        offset,
+    );
+    eprintln!("note: try plugging it directly into the computer or into a powered hub, or using a shorter cable.");
+    if !gentle {
+        eprintln!("note: flashing with --gentle uses smaller, slower transfers, which may also help.");
+    }
+}
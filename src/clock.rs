@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for the sources of time and randomness that timing-dependent code (waiting for probes to
+//! re-enumerate, retrying transfers, pacing DFU polling and blocks) goes through.
+//!
+//! Everything defaults to [`SystemClock`] and [`SystemRandom`], but takes any [`Clock`] and
+//! [`RandomSource`], so that a test or simulation can run it with a [`SeededRandom`] and, with
+//! the `simulation` feature, a [`ManualClock`] that only moves when told to:
+//!
+//! ```
+//! # #[cfg(feature = "simulation")]
+//! # {
+//! use std::time::Duration;
+//! use bmputil::clock::{Clock, ManualClock, SeededRandom};
+//! use bmputil::retry::RetryPolicy;
+//!
+//! let clock = ManualClock::new();
+//! let start = clock.now();
+//! let res: Result<(), rusb::Error> = RetryPolicy::new()
+//!     .run_with(&clock, &SeededRandom::new(1), "test", || Err(rusb::Error::Io));
+//! assert!(res.is_err());
+//! // Two retries, after 50 and 100 ms, without actually waiting.
+//! assert_eq!(clock.now() - start, Duration::from_millis(150));
+//! # }
+//! ```
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Source of the current time, and of waiting.
+pub trait Clock
+{
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SystemClock;
+
+impl Clock for SystemClock
+{
+    fn now(&self) -> Instant
+    {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration)
+    {
+        thread::sleep(duration);
+    }
+}
+
+/// Lets code that takes a clock by value share one, e.g. with a test that checks the time after.
+impl<C: Clock + ?Sized> Clock for &C
+{
+    fn now(&self) -> Instant
+    {
+        (**self).now()
+    }
+
+    fn sleep(&self, duration: Duration)
+    {
+        (**self).sleep(duration)
+    }
+}
+
+/// A clock that only moves forward when slept on, or [advanced](Self::advance), for running
+/// timing-dependent code instantly and reproducibly.
+#[cfg(any(test, feature = "simulation"))]
+#[derive(Debug, Clone)]
+pub struct ManualClock
+{
+    start: Instant,
+    elapsed: Cell<Duration>,
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl ManualClock
+{
+    pub fn new() -> Self
+    {
+        Self {
+            start: Instant::now(),
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration)
+    {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl Default for ManualClock
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "simulation"))]
+impl Clock for ManualClock
+{
+    fn now(&self) -> Instant
+    {
+        self.start + self.elapsed.get()
+    }
+
+    fn sleep(&self, duration: Duration)
+    {
+        self.advance(duration);
+    }
+}
+
+
+/// Source of random numbers, for spreading out retries (jitter). Not suitable for anything
+/// security related.
+pub trait RandomSource
+{
+    fn next_u64(&self) -> u64;
+
+    /// Returns a random duration between zero and `max`, inclusive.
+    fn duration_up_to(&self, max: Duration) -> Duration
+    {
+        let nanos = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+        match nanos.checked_add(1) {
+            Some(range) => Duration::from_nanos(self.next_u64() % range),
+            None => Duration::from_nanos(self.next_u64()),
+        }
+    }
+}
+
+/// Random numbers that differ between runs.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SystemRandom;
+
+impl RandomSource for SystemRandom
+{
+    fn next_u64(&self) -> u64
+    {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        // Each RandomState is randomly keyed, which is all the randomness jitter needs.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.finish()
+    }
+}
+
+/// Random numbers that are the same every run with the same seed (SplitMix64).
+///
+/// ```
+/// # use bmputil::clock::{RandomSource, SeededRandom};
+/// let a = SeededRandom::new(42);
+/// let b = SeededRandom::new(42);
+/// assert_eq!(a.next_u64(), b.next_u64());
+/// ```
+#[derive(Debug, Clone)]
+pub struct SeededRandom
+{
+    state: Cell<u64>,
+}
+
+impl SeededRandom
+{
+    pub fn new(seed: u64) -> Self
+    {
+        Self { state: Cell::new(seed) }
+    }
+}
+
+impl RandomSource for SeededRandom
+{
+    fn next_u64(&self) -> u64
+    {
+        let state = self.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.state.set(state);
+
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
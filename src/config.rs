@@ -0,0 +1,421 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for the user's configuration file, which holds defaults for command line options.
+//!
+//! The file is `config.toml` in the [config directory](crate::paths::config_dir), or wherever
+//! `--config` points. Everything in it is optional, and anything given on the command line wins:
+//!
+//! ```toml
+//! # Where cached files go, instead of the platform's cache directory.
+//! cache_dir = "/var/cache/bmputil"
+//!
+//! # Which probe to use when none of --serial, --port, or --index are given.
+//! [probe]
+//! serial = ["7BB180B4"]
+//! # port = ["1-4.2"]
+//! # index = 0
+//!
+//! [timeouts]
+//! # As for --timeout, in seconds, and --transfer-timeout, in milliseconds.
+//! reboot = 10
+//! transfer = 5000
+//!
+//! [output]
+//! # "text" or "json", for the commands with a --format option.
+//! format = "json"
+//!
+//! # Firmware signatures, checked before flashing; see `bmputil flash --help`.
+//! [signing]
+//! trusted_keys = ["RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3"]
+//! require_signed = true
+//!
+//! # Names for probes, for `bmputil use`.
+//! [nicknames]
+//! bench = "7BB180B4"
+//! ```
+//!
+//! The probe chosen with `bmputil use` is kept apart, in `selection.toml`, as bmputil rewrites it.
+//! So are the settings `bmputil terminal` remembers for each probe, in `terminal.toml`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind, ErrorSource};
+use crate::paths;
+use crate::timeouts::Timeouts;
+
+/// Name of the configuration file in the config directory.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Name of the file in the config directory holding the probe chosen with `bmputil use`.
+pub const SELECTION_FILE: &str = "selection.toml";
+
+/// Name of the file in the config directory holding the settings `bmputil terminal` remembers.
+pub const TERMINAL_SETTINGS_FILE: &str = "terminal.toml";
+
+/// The default probe filters, used when none are given on the command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeDefaults
+{
+    #[serde(default)]
+    pub serial: Vec<String>,
+    #[serde(default)]
+    pub port: Vec<String>,
+    #[serde(default)]
+    pub index: Option<usize>,
+}
+
+impl ProbeDefaults
+{
+    /// Whether any filter is set.
+    pub fn is_empty(&self) -> bool
+    {
+        self.serial.is_empty() && self.port.is_empty() && self.index.is_none()
+    }
+}
+
+/// The default timeouts, used for any not given on the command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutDefaults
+{
+    /// How long to wait for a device to come back after it reboots, in seconds.
+    #[serde(default)]
+    pub reboot: Option<u64>,
+    /// Timeout for each individual USB transfer, in milliseconds.
+    #[serde(default)]
+    pub transfer: Option<u64>,
+}
+
+impl TimeoutDefaults
+{
+    /// Applies the timeouts that are set to `timeouts`.
+    pub fn apply(&self, mut timeouts: Timeouts) -> Timeouts
+    {
+        if let Some(secs) = self.reboot {
+            timeouts = timeouts.enumerate(Duration::from_secs(secs));
+        }
+        if let Some(ms) = self.transfer {
+            timeouts = timeouts.control(Duration::from_millis(ms));
+        }
+
+        timeouts
+    }
+}
+
+/// Output formats for the commands that have more than one.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat
+{
+    #[default]
+    Text,
+    Json,
+}
+
+/// The default output settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputDefaults
+{
+    #[serde(default)]
+    pub format: Option<OutputFormat>,
+}
+
+/// The firmware signature settings; see [`SignaturePolicy`](crate::signature::SignaturePolicy).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningDefaults
+{
+    /// minisign public keys, or paths to files holding them, that images may be signed with.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+    /// Whether to refuse to flash images without a valid signature.
+    #[serde(default)]
+    pub require_signed: bool,
+}
+
+/// The contents of the configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config
+{
+    #[serde(default)]
+    pub probe: ProbeDefaults,
+    #[serde(default)]
+    pub timeouts: TimeoutDefaults,
+    #[serde(default)]
+    pub output: OutputDefaults,
+    #[serde(default)]
+    pub signing: SigningDefaults,
+    /// Where cached files go, instead of [`paths::cache_dir`]. Must be absolute.
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Serial numbers of probes, by nickname.
+    #[serde(default)]
+    pub nicknames: BTreeMap<String, String>,
+}
+
+/// Returns where the configuration file is, if the config directory could be determined.
+pub fn default_path() -> Option<PathBuf>
+{
+    paths::config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+fn no_config_dir() -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(io::Error::new(
+        io::ErrorKind::NotFound,
+        "could not determine the user config directory",
+    )))
+    .error()
+}
+
+/// The probe chosen with `bmputil use`, which commands act on when not given any probe filters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Selection
+{
+    pub serial: String,
+    /// The nickname it was chosen by, if it was.
+    #[serde(default)]
+    pub nickname: Option<String>,
+}
+
+impl Selection
+{
+    /// Returns where the selection is kept, if the config directory could be determined.
+    pub fn path() -> Option<PathBuf>
+    {
+        paths::config_dir().map(|dir| dir.join(SELECTION_FILE))
+    }
+
+    /// Reads the selection, if one has been made.
+    pub fn load() -> Result<Option<Self>, Error>
+    {
+        let Some(path) = Self::path() else {
+            return Ok(None);
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(ErrorKind::External(ErrorSource::StdIo(e)).error()
+                    .with_ctx(&format!("reading the default probe from {}", path.display())));
+            },
+        };
+
+        toml::from_str(&contents)
+            .map(Some)
+            .map_err(|e| ErrorKind::External(ErrorSource::Toml(Box::new(e))).error()
+                .with_ctx(&format!("parsing the default probe in {}", path.display())))
+    }
+
+    /// Makes this the selection.
+    pub fn save(&self) -> Result<(), Error>
+    {
+        let path = Self::path().ok_or_else(no_config_dir)?;
+        let contents = toml::to_string(self).expect("serializing a probe selection cannot fail");
+
+        path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&path, contents))
+            .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error()
+                .with_ctx(&format!("writing the default probe to {}", path.display())))
+    }
+
+    /// Forgets the selection. Returns whether there was one.
+    pub fn clear() -> Result<bool, Error>
+    {
+        let path = Self::path().ok_or_else(no_config_dir)?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(ErrorKind::External(ErrorSource::StdIo(e)).error()
+                .with_ctx(&format!("removing the default probe in {}", path.display()))),
+        }
+    }
+}
+
+/// What the Enter key sends in `bmputil terminal`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding
+{
+    /// A carriage return, as the terminal sends it.
+    #[default]
+    Cr,
+    Lf,
+    CrLf,
+}
+
+impl LineEnding
+{
+    pub const NAMES: &'static [&'static str] = &["cr", "lf", "crlf"];
+
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name {
+            "cr" => Some(Self::Cr),
+            "lf" => Some(Self::Lf),
+            "crlf" => Some(Self::CrLf),
+            _ => None,
+        }
+    }
+
+    /// The bytes sent for Enter.
+    pub fn bytes(self) -> &'static [u8]
+    {
+        match self {
+            Self::Cr => b"\r",
+            Self::Lf => b"\n",
+            Self::CrLf => b"\r\n",
+        }
+    }
+}
+
+/// The settings `bmputil terminal` remembers for one probe. Unset ones fall back to the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TerminalSettings
+{
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baud: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_ending: Option<LineEnding>,
+    /// File the target's output is appended to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<PathBuf>,
+}
+
+impl TerminalSettings
+{
+    /// Whether no setting is set.
+    pub fn is_empty(&self) -> bool
+    {
+        self.baud.is_none() && self.line_ending.is_none() && self.log.is_none()
+    }
+
+    /// Returns these settings, with the ones set in `newer` replacing them.
+    pub fn merge(&self, newer: &Self) -> Self
+    {
+        Self {
+            baud: newer.baud.or(self.baud),
+            line_ending: newer.line_ending.or(self.line_ending),
+            log: newer.log.clone().or_else(|| self.log.clone()),
+        }
+    }
+
+    /// Returns where the settings of every probe are kept, if the config directory could be
+    /// determined.
+    pub fn path() -> Option<PathBuf>
+    {
+        paths::config_dir().map(|dir| dir.join(TERMINAL_SETTINGS_FILE))
+    }
+
+    fn load_all(path: &Path) -> Result<BTreeMap<String, Self>, Error>
+    {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => {
+                return Err(ErrorKind::External(ErrorSource::StdIo(e)).error()
+                    .with_ctx(&format!("reading terminal settings from {}", path.display())));
+            },
+        };
+
+        toml::from_str(&contents)
+            .map_err(|e| ErrorKind::External(ErrorSource::Toml(Box::new(e))).error()
+                .with_ctx(&format!("parsing terminal settings in {}", path.display())))
+    }
+
+    fn save_all(path: &Path, all: &BTreeMap<String, Self>) -> Result<(), Error>
+    {
+        let contents = toml::to_string(all).expect("serializing terminal settings cannot fail");
+
+        path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, contents))
+            .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error()
+                .with_ctx(&format!("writing terminal settings to {}", path.display())))
+    }
+
+    /// Reads the settings remembered for the probe with serial number `serial`.
+    pub fn load(serial: &str) -> Result<Self, Error>
+    {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+
+        Ok(Self::load_all(&path)?.remove(serial).unwrap_or_default())
+    }
+
+    /// Remembers these settings for the probe with serial number `serial`.
+    pub fn save(&self, serial: &str) -> Result<(), Error>
+    {
+        let path = Self::path().ok_or_else(no_config_dir)?;
+        let mut all = Self::load_all(&path)?;
+        all.insert(serial.to_string(), self.clone());
+
+        Self::save_all(&path, &all)
+    }
+
+    /// Forgets the settings of the probe with serial number `serial`. Returns whether there were any.
+    pub fn clear(serial: &str) -> Result<bool, Error>
+    {
+        let path = Self::path().ok_or_else(no_config_dir)?;
+        let mut all = Self::load_all(&path)?;
+        if all.remove(serial).is_none() {
+            return Ok(false);
+        }
+
+        Self::save_all(&path, &all).map(|_| true)
+    }
+}
+
+fn invalid_config(path: &Path, why: String) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(io::Error::new(io::ErrorKind::InvalidData, why)))
+        .error()
+        .with_ctx(&format!("reading configuration from {}", path.display()))
+}
+
+impl Config
+{
+    /// Parses a configuration file's contents.
+    pub fn from_toml(toml: &str) -> Result<Self, Error>
+    {
+        toml::from_str(toml).map_err(|e| ErrorKind::External(ErrorSource::Toml(Box::new(e))).error())
+    }
+
+    /// Reads the configuration file at `path`.
+    ///
+    /// If `required` is false, a missing file is an empty configuration, as for the default
+    /// location; otherwise (e.g. for a path given with `--config`) it is an error.
+    pub fn load(path: &Path, required: bool) -> Result<Self, Error>
+    {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Self::default()),
+            Err(e) => {
+                return Err(ErrorKind::External(ErrorSource::StdIo(e)).error()
+                    .with_ctx(&format!("reading configuration from {}", path.display())));
+            },
+        };
+
+        let config = Self::from_toml(&contents)
+            .map_err(|e| e.with_ctx(&format!("parsing configuration in {}", path.display())))?;
+        if config.cache_dir.as_ref().is_some_and(|dir| !dir.is_absolute()) {
+            return Err(invalid_config(path, String::from("cache_dir must be an absolute path")));
+        }
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for asking the user to confirm risky operations.
+//!
+//! Every operation that can lose data or brick a probe goes through [`ConfirmationPolicy::confirm`]
+//! with the [`AuthorizationLevel`] it needs, so that all of them ask, and can be pre-approved, the
+//! same way.
+
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use clap::ArgMatches;
+use log::warn;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+use bmputil::error::{Error, ErrorKind};
+
+/// Environment variable that, when set to `1`, makes `--assume-yes` the default.
+const ASSUME_YES_ENV: &str = "BMPUTIL_ASSUME_YES";
+
+/// How much authorization an operation needs before it can go ahead.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AuthorizationLevel
+{
+    /// The operation loses data or changes the probe in a way that's hard to undo, but can't leave
+    /// it unbootable (e.g. erasing or downgrading firmware).
+    ///
+    /// Needs confirmation, either interactively or with `--assume-yes`.
+    Destructive,
+
+    /// The operation can leave the probe unbootable, possibly only recoverable with a second debugger
+    /// (e.g. overwriting the bootloader).
+    ///
+    /// Needs `--allow-dangerous-options=really` on top of confirmation.
+    Dangerous,
+}
+
+/// How risky operations should be confirmed, as configured by the user.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ConfirmationPolicy
+{
+    /// Treat every confirmation prompt as answered with "yes".
+    assume_yes: bool,
+    /// Whether `--allow-dangerous-options=really` was passed.
+    allow_dangerous: bool,
+    /// Whether we can prompt the user at all.
+    interactive: bool,
+}
+
+impl ConfirmationPolicy
+{
+    pub fn from_cli_args(matches: &ArgMatches) -> Self
+    {
+        let assume_yes = matches.is_present("assume-yes")
+            || env::var(ASSUME_YES_ENV).map(|v| v == "1").unwrap_or(false);
+
+        Self {
+            assume_yes,
+            allow_dangerous: matches.value_of("allow-dangerous-options") == Some("really"),
+            interactive: io::stdin().is_terminal() && io::stderr().is_terminal(),
+        }
+    }
+
+    /// Never prompts on the terminal, for callers the user isn't looking at the terminal for (e.g.
+    /// the GUI): anything needing confirmation is refused, unless `--assume-yes` was given.
+    #[cfg(feature = "gui")]
+    #[must_use]
+    pub fn without_prompts(mut self) -> Self
+    {
+        self.interactive = false;
+        self
+    }
+
+    /// Asks the user to confirm `operation`, which needs the given level of authorization.
+    ///
+    /// `explanation` should describe what can go wrong. It is shown before prompting, and when
+    /// refusing a dangerous operation because `--allow-dangerous-options=really` wasn't passed.
+    ///
+    /// Returns [`ErrorKind::NotConfirmed`] if the operation must not go ahead.
+    pub fn confirm(&self, level: AuthorizationLevel, operation: &str, explanation: &str) -> Result<(), Error>
+    {
+        if level == AuthorizationLevel::Dangerous && !self.allow_dangerous {
+            print_warning(&format!(
+                "{}\n\nIf you are sure this is really what you want to do, run again with --allow-dangerous-options=really",
+                explanation,
+            ));
+            return Err(ErrorKind::NotConfirmed(operation.to_string()).error());
+        }
+
+        if self.assume_yes {
+            warn!("Proceeding with {} without confirmation, as --assume-yes was given.", operation);
+            return Ok(());
+        }
+
+        if !self.interactive {
+            return Err(
+                ErrorKind::NotConfirmed(operation.to_string()).error()
+                    .with_ctx("cannot prompt for confirmation without a terminal (pass --assume-yes to confirm)")
+            );
+        }
+
+        print_warning(explanation);
+        if prompt(&format!("Continue with {}? [y/N] ", operation)) {
+            Ok(())
+        } else {
+            Err(ErrorKind::NotConfirmed(operation.to_string()).error())
+        }
+    }
+
+    /// Asks the user a yes/no question about something that isn't risky either way, e.g. offering a
+    /// shortcut.
+    ///
+    /// With `--assume-yes` the answer is yes; without a terminal to ask on, it's no.
+    pub fn ask(&self, question: &str) -> bool
+    {
+        if self.assume_yes {
+            return true;
+        }
+
+        self.interactive && prompt(&format!("{} [y/N] ", question))
+    }
+}
+
+/// Prints a highlighted warning to stderr.
+fn print_warning(message: &str)
+{
+    // We're ignoring errors for setting the color because the most important thing is
+    // getting the message itself out.
+    // If the messages themselves don't write, though, then we might as well just panic.
+    let mut stderr = StandardStream::stderr(ColorChoice::Auto);
+    let _res = stderr.set_color(ColorSpec::new().set_fg(Some(Color::Red)));
+    write!(&mut stderr, "WARNING: ").expect("failed to write to stderr");
+    let _res = stderr.reset();
+    writeln!(&mut stderr, "{}", message).expect("failed to write to stderr");
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no.
+fn prompt(question: &str) -> bool
+{
+    eprint!("{}", question);
+    let _ = io::stderr().flush();
+
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,1494 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing the host side of the USB DFU 1.1 protocol, and ST's DfuSe extensions to it.
+//!
+//! \[[USB DFU Device Class Spec](https://usb.org/sites/default/files/DFU_1.1.pdf)\],
+//! \[[ST AN3156: USB DFU protocol used in the STM32 bootloader](https://www.st.com/resource/en/application_note/an3156-usb-dfu-protocol-used-in-the-stm32-bootloader-stmicroelectronics.pdf)\].
+//!
+//! [DfuInterface] drives a device that is already in DFU mode: it negotiates the transfer size from
+//! the DFU functional descriptor, polls DFU_GETSTATUS honoring the device's bwPollTimeout (unless
+//! told otherwise with a [PollStrategy]), recovers
+//! from error states and stalls with DFU_CLRSTATUS, and (for DfuSe devices) handles erasing and
+//! setting the address pointer.
+
+use std::time::Duration;
+use std::fmt::{self, Display, Formatter};
+
+use log::{trace, debug, info, warn};
+use rusb::{Direction, RequestType, Recipient};
+use thiserror::Error;
+
+use crate::usb::{DfuFunctionalDescriptor, DfuRequest, UsbDeviceHandle};
+use crate::retry::RetryPolicy;
+use crate::clock::{Clock, RandomSource, SystemClock, SystemRandom};
+
+type UsbHandle = rusb::DeviceHandle<rusb::Context>;
+
+/// bcdDFUVersion reported by devices implementing ST's DfuSe extensions.
+pub(crate) const DFUSE_VERSION: u16 = 0x011a;
+
+/// Transfer size assumed for devices that don't report one.
+pub const DEFAULT_TRANSFER_SIZE: u16 = 1024;
+
+/// The largest transfer size used, whatever the device advertises: Linux's usbfs rejects control
+/// transfers larger than a page, and WinUSB those larger than 4 KiB.
+pub const MAX_TRANSFER_SIZE: u16 = 4096;
+
+/// The most pages a DfuSe memory layout may describe. Real devices have a few thousand at most.
+const MAX_DFUSE_PAGES: usize = 1 << 16;
+
+/// DfuSe command byte for setting the address pointer with a DFU_DNLOAD to block 0.
+const DFUSE_SET_ADDRESS: u8 = 0x21;
+/// DfuSe command byte for erasing a page with a DFU_DNLOAD to block 0, or the whole device when
+/// sent without an address.
+const DFUSE_ERASE_PAGE: u8 = 0x41;
+/// DfuSe command byte for removing read protection with a DFU_DNLOAD to block 0, which also mass
+/// erases the device.
+const DFUSE_READ_UNPROTECT: u8 = 0x92;
+
+
+/// The phases of writing firmware to a device, in the order they happen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DownloadPhase
+{
+    /// Erasing the pages the firmware will be written to (DfuSe devices only).
+    Erase,
+    /// Erasing the whole device at once, instead of page by page (DfuSe devices only).
+    MassErase,
+    /// Writing the firmware.
+    Download,
+    /// Reading the written firmware back and comparing it.
+    Verify,
+    /// Letting the device manifest the new firmware, after which it usually reboots.
+    Manifest,
+}
+
+impl Display for DownloadPhase
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        use DownloadPhase::*;
+        let description = match self {
+            Erase => "Erasing",
+            MassErase => "Mass erasing",
+            Download => "Flashing",
+            Verify => "Verifying",
+            Manifest => "Rebooting",
+        };
+
+        write!(f, "{}", description)
+    }
+}
+
+/// Progress through writing firmware to a device.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DownloadProgress
+{
+    pub phase: DownloadPhase,
+    /// Bytes of this phase completed so far.
+    pub done: usize,
+    /// Bytes this phase covers in total, or 0 if it can't be measured (e.g. manifestation).
+    pub total: usize,
+    /// The DFU transfer size negotiated with the device, in bytes.
+    pub transfer_size: u16,
+}
+
+impl DownloadProgress
+{
+    pub const fn new(phase: DownloadPhase, done: usize, total: usize, transfer_size: u16) -> Self
+    {
+        Self {
+            phase,
+            done,
+            total,
+            transfer_size,
+        }
+    }
+}
+
+
+/// How the flash is erased before a DfuSe download, as set with [`DfuInterface::set_erase_strategy`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum EraseStrategy
+{
+    /// Mass erase if the device supports it and the firmware starts at the beginning of its memory
+    /// segment, so nothing before it would be lost, and erase page by page otherwise.
+    #[default]
+    Auto,
+    /// Always erase page by page, only touching the pages the firmware is written to.
+    Sector,
+    /// Always mass erase, failing if the device doesn't support it.
+    Mass,
+}
+
+impl Display for EraseStrategy
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        let name = match self {
+            EraseStrategy::Auto => "auto",
+            EraseStrategy::Sector => "sector",
+            EraseStrategy::Mass => "mass",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+
+/// How DFU_GETSTATUS is polled while the device is busy, as set with
+/// [`DfuInterface::set_poll_strategy`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum PollStrategy
+{
+    /// Wait as long as the device asks for (its bwPollTimeout) between polls, as the DFU
+    /// specification says.
+    #[default]
+    Requested,
+    /// Poll at a fixed interval, whatever the device asks for. Devices often ask for more time
+    /// than they need, so polling sooner can be faster, but some slow down when polled too often.
+    Interval(Duration),
+}
+
+impl Display for PollStrategy
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        match self {
+            PollStrategy::Requested => write!(f, "requested"),
+            PollStrategy::Interval(interval) => write!(f, "every {} ms", interval.as_millis()),
+        }
+    }
+}
+
+
+/// States a DFU-class device can be in, as reported by DFU_GETSTATUS and DFU_GETSTATE.
+///
+/// \[[USB DFU Device Class Spec § 6.1.2](https://usb.org/sites/default/files/DFU_1.1.pdf#page=22)\]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DfuState
+{
+    AppIdle,
+    AppDetach,
+    DfuIdle,
+    DfuDnloadSync,
+    DfuDnbusy,
+    DfuDnloadIdle,
+    DfuManifestSync,
+    DfuManifest,
+    DfuManifestWaitReset,
+    DfuUploadIdle,
+    DfuError,
+    Other(u8),
+}
+
+impl From<u8> for DfuState
+{
+    fn from(state: u8) -> Self
+    {
+        use DfuState::*;
+        match state {
+            0 => AppIdle,
+            1 => AppDetach,
+            2 => DfuIdle,
+            3 => DfuDnloadSync,
+            4 => DfuDnbusy,
+            5 => DfuDnloadIdle,
+            6 => DfuManifestSync,
+            7 => DfuManifest,
+            8 => DfuManifestWaitReset,
+            9 => DfuUploadIdle,
+            10 => DfuError,
+            other => Other(other),
+        }
+    }
+}
+
+impl Display for DfuState
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        use DfuState::*;
+        match self {
+            AppIdle => write!(f, "appIDLE")?,
+            AppDetach => write!(f, "appDETACH")?,
+            DfuIdle => write!(f, "dfuIDLE")?,
+            DfuDnloadSync => write!(f, "dfuDNLOAD-SYNC")?,
+            DfuDnbusy => write!(f, "dfuDNBUSY")?,
+            DfuDnloadIdle => write!(f, "dfuDNLOAD-IDLE")?,
+            DfuManifestSync => write!(f, "dfuMANIFEST-SYNC")?,
+            DfuManifest => write!(f, "dfuMANIFEST")?,
+            DfuManifestWaitReset => write!(f, "dfuMANIFEST-WAIT-RESET")?,
+            DfuUploadIdle => write!(f, "dfuUPLOAD-IDLE")?,
+            DfuError => write!(f, "dfuERROR")?,
+            Other(state) => write!(f, "unknown state {}", state)?,
+        };
+
+        Ok(())
+    }
+}
+
+
+/// Status codes a DFU-class device can report with DFU_GETSTATUS.
+///
+/// \[[USB DFU Device Class Spec § 6.1.2](https://usb.org/sites/default/files/DFU_1.1.pdf#page=21)\]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DfuStatus
+{
+    Ok,
+    ErrTarget,
+    ErrFile,
+    ErrWrite,
+    ErrErase,
+    ErrCheckErased,
+    ErrProg,
+    ErrVerify,
+    ErrAddress,
+    ErrNotDone,
+    ErrFirmware,
+    ErrVendor,
+    ErrUsbReset,
+    ErrPowerOnReset,
+    ErrUnknown,
+    ErrStalledPacket,
+    Other(u8),
+}
+
+impl From<u8> for DfuStatus
+{
+    fn from(status: u8) -> Self
+    {
+        use DfuStatus::*;
+        match status {
+            0x00 => Ok,
+            0x01 => ErrTarget,
+            0x02 => ErrFile,
+            0x03 => ErrWrite,
+            0x04 => ErrErase,
+            0x05 => ErrCheckErased,
+            0x06 => ErrProg,
+            0x07 => ErrVerify,
+            0x08 => ErrAddress,
+            0x09 => ErrNotDone,
+            0x0a => ErrFirmware,
+            0x0b => ErrVendor,
+            0x0c => ErrUsbReset,
+            0x0d => ErrPowerOnReset,
+            0x0e => ErrUnknown,
+            0x0f => ErrStalledPacket,
+            other => Other(other),
+        }
+    }
+}
+
+impl Display for DfuStatus
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        use DfuStatus::*;
+        let description = match self {
+            Ok => "no error",
+            ErrTarget => "file is not targeted for use by this device",
+            ErrFile => "file fails a vendor-specific verification test",
+            ErrWrite => "device is unable to write memory",
+            ErrErase => "memory erase failed",
+            ErrCheckErased => "memory erase check failed",
+            ErrProg => "program memory function failed",
+            ErrVerify => "programmed memory failed verification",
+            ErrAddress => "received address is out of range",
+            ErrNotDone => "received end of download before all data was received",
+            ErrFirmware => "device firmware is corrupt",
+            ErrVendor => "vendor-specific error",
+            ErrUsbReset => "device detected an unexpected USB reset",
+            ErrPowerOnReset => "device detected an unexpected power on reset",
+            ErrUnknown => "unknown error",
+            ErrStalledPacket => "device stalled an unexpected request",
+            Other(status) => return write!(f, "unknown status {}", status),
+        };
+
+        write!(f, "{}", description)
+    }
+}
+
+
+/// Response to a DFU_GETSTATUS request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct DfuStatusResponse
+{
+    pub status: DfuStatus,
+    /// How long the host should wait before sending the next DFU_GETSTATUS request.
+    pub poll_timeout: Duration,
+    pub state: DfuState,
+    /// Index of a string descriptor describing the status, if any.
+    pub string_index: u8,
+}
+
+impl DfuStatusResponse
+{
+    pub const LENGTH: usize = 6;
+
+    pub fn from_bytes(bytes: &[u8; Self::LENGTH]) -> Self
+    {
+        Self {
+            status: DfuStatus::from(bytes[0]),
+            poll_timeout: Duration::from_millis(u32::from_le_bytes([bytes[1], bytes[2], bytes[3], 0]) as u64),
+            state: DfuState::from(bytes[4]),
+            string_index: bytes[5],
+        }
+    }
+}
+
+
+/// Errors that can occur while talking to a device in DFU mode.
+#[derive(Debug, Error)]
+pub enum DfuError
+{
+    #[error(transparent)]
+    Usb(#[from] rusb::Error),
+
+    #[error("device reported an error ({status}) while in state {state}")]
+    ErrorStatus
+    {
+        status: DfuStatus,
+        state: DfuState,
+    },
+
+    #[error("device is in state {got}, but was expected to be in {expected}")]
+    UnexpectedState
+    {
+        got: DfuState,
+        expected: DfuState,
+    },
+
+    #[error("DFU_GETSTATUS response too short ({0} bytes)")]
+    ShortStatusResponse(usize),
+
+    #[error("invalid DfuSe memory layout string {0:?}")]
+    InvalidMemoryLayout(String),
+
+    #[error("address 0x{0:08x} is not within the device's memory layout")]
+    AddressOutOfRange(u32),
+
+    #[error("data read back at 0x{0:08x} does not match what was written")]
+    VerificationMismatch(u32),
+
+    #[error("device does not support mass erase")]
+    MassEraseUnsupported,
+}
+
+
+/// A region of memory a DfuSe device exposes, made up of pages that can be individually erased.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MemorySegment
+{
+    pub start: u32,
+    /// The size of each page of the segment, in order.
+    pub pages: Vec<u32>,
+}
+
+impl MemorySegment
+{
+    /// Parses the memory layout part of a DfuSe interface string, e.g. `8*001Ka,120*001Kg`.
+    fn parse_pages(layout: &str) -> Option<Vec<u32>>
+    {
+        let mut pages = Vec::new();
+        for sector in layout.split(',') {
+            let (count, size) = sector.trim().split_once('*')?;
+            let count: u32 = count.parse().ok()?;
+
+            // The size is followed by a multiplier character and a memory type character.
+            let digits_end = size.find(|c: char| !c.is_ascii_digit())?;
+            let (size, suffix) = size.split_at(digits_end);
+            let size: u32 = size.parse().ok()?;
+            let multiplier = match suffix.chars().next()? {
+                'K' => 1024,
+                'M' => 1024 * 1024,
+                // No multiplier, but the memory type character still follows.
+                ' ' | 'B' => 1,
+                _ => return None,
+            };
+
+            // The layout comes from the device, so don't let it make us allocate without bound.
+            let size = size.checked_mul(multiplier).filter(|&size| size != 0)?;
+            if pages.len() + count as usize > MAX_DFUSE_PAGES {
+                return None;
+            }
+            pages.extend(std::iter::repeat_n(size, count as usize));
+        }
+
+        Some(pages)
+    }
+
+    /// Returns the address just past the end of this segment.
+    pub fn end(&self) -> u64
+    {
+        self.start as u64 + self.pages.iter().map(|&p| p as u64).sum::<u64>()
+    }
+
+    /// Iterates over the start address and size of each page in this segment.
+    pub fn page_ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_
+    {
+        self.pages.iter().scan(self.start, |addr, &size| {
+            let start = *addr;
+            *addr = addr.wrapping_add(size);
+            Some((start, size))
+        })
+    }
+}
+
+
+/// The variant of the DFU protocol a device speaks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DfuProtocol
+{
+    /// Plain USB DFU 1.1.
+    Dfu,
+    /// ST's DfuSe extensions, with the memory layout described by the interface string.
+    Dfuse(Vec<MemorySegment>),
+}
+
+impl DfuProtocol
+{
+    /// Parses a DfuSe interface string, e.g. `@Internal Flash   /0x08000000/8*001Ka,120*001Kg`.
+    pub fn parse_dfuse_layout(interface_string: &str) -> Result<Self, DfuError>
+    {
+        let invalid = || DfuError::InvalidMemoryLayout(interface_string.to_string());
+
+        // The name comes first, followed by pairs of start addresses and page layouts.
+        let mut fields = interface_string.split('/').skip(1);
+        let mut segments = Vec::new();
+        while let Some(address) = fields.next() {
+            let start = address
+                .trim()
+                .strip_prefix("0x")
+                .and_then(|a| u32::from_str_radix(a, 16).ok())
+                .ok_or_else(invalid)?;
+            let pages = fields
+                .next()
+                .and_then(MemorySegment::parse_pages)
+                .ok_or_else(invalid)?;
+
+            let segment = MemorySegment {
+                start,
+                pages,
+            };
+            // A segment running past the 32-bit address space can't be real.
+            if segment.end() > 1 << 32 {
+                return Err(invalid());
+            }
+            segments.push(segment);
+        }
+
+        if segments.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self::Dfuse(segments))
+    }
+}
+
+
+/// A DFU interface of a device that is in DFU mode, and the state needed to drive it.
+pub struct DfuInterface<'h, H: UsbDeviceHandle = UsbHandle>
+{
+    handle: &'h mut H,
+    interface: u8,
+    functional_descriptor: DfuFunctionalDescriptor,
+    protocol: DfuProtocol,
+    transfer_size: u16,
+    retry: RetryPolicy,
+    /// Timeout for each individual control transfer.
+    timeout: Duration,
+    /// How long to wait before writing each block.
+    block_delay: Duration,
+    /// Whether to reset the device after manifestation even if it doesn't ask for it.
+    force_reset: bool,
+    erase_strategy: EraseStrategy,
+    /// Whether the device is known to accept a DfuSe mass erase.
+    mass_erase_supported: bool,
+    poll_strategy: PollStrategy,
+    /// What waiting (for polls, between blocks, and before retries) goes through.
+    clock: &'h dyn Clock,
+    /// Where retry jitter comes from.
+    random: &'h dyn RandomSource,
+}
+
+impl<'h, H: UsbDeviceHandle> DfuInterface<'h, H>
+{
+    /// Claims the DFU interface `interface` of the device open as `handle`, and determines the
+    /// protocol variant and memory layout it uses.
+    ///
+    /// `timeout` applies to each individual control transfer.
+    pub fn open(
+        handle: &'h mut H,
+        interface: u8,
+        functional_descriptor: DfuFunctionalDescriptor,
+        timeout: Duration,
+    ) -> Result<Self, DfuError>
+    {
+        handle.claim_interface(interface)?;
+
+        let protocol = if functional_descriptor.bcdDFUVersion == DFUSE_VERSION {
+            let interface_string = handle.read_interface_name(interface, timeout)?;
+            debug!("DfuSe interface string: {:?}", interface_string);
+
+            DfuProtocol::parse_dfuse_layout(&interface_string)?
+        } else {
+            DfuProtocol::Dfu
+        };
+
+        Ok(Self::with_protocol(handle, interface, functional_descriptor, protocol, timeout))
+    }
+
+    /// Like [DfuInterface::open], but selects alternate setting `setting` of the interface first.
+    /// DfuSe devices use alternate settings for other memories, e.g. the option bytes.
+    pub fn open_alt_setting(
+        handle: &'h mut H,
+        interface: u8,
+        setting: u8,
+        functional_descriptor: DfuFunctionalDescriptor,
+        timeout: Duration,
+    ) -> Result<Self, DfuError>
+    {
+        handle.claim_interface(interface)?;
+        handle.set_alternate_setting(interface, setting)?;
+
+        let protocol = if functional_descriptor.bcdDFUVersion == DFUSE_VERSION {
+            let interface_string = handle.read_alt_setting_name(interface, setting, timeout)?;
+            debug!("DfuSe interface string for alternate setting {}: {:?}", setting, interface_string);
+
+            DfuProtocol::parse_dfuse_layout(&interface_string)?
+        } else {
+            DfuProtocol::Dfu
+        };
+
+        Ok(Self::with_protocol(handle, interface, functional_descriptor, protocol, timeout))
+    }
+
+    fn with_protocol(
+        handle: &'h mut H,
+        interface: u8,
+        functional_descriptor: DfuFunctionalDescriptor,
+        protocol: DfuProtocol,
+        timeout: Duration,
+    ) -> Self
+    {
+        // Use however much the device says it can take in one go, within what the host side can do.
+        let transfer_size = match functional_descriptor.wTransferSize {
+            0 => {
+                warn!("Device reports a wTransferSize of 0! Assuming {} bytes.", DEFAULT_TRANSFER_SIZE);
+                DEFAULT_TRANSFER_SIZE
+            },
+            size if size > MAX_TRANSFER_SIZE => {
+                debug!("Device reports a wTransferSize of {} bytes, capping it to {}", size, MAX_TRANSFER_SIZE);
+                MAX_TRANSFER_SIZE
+            },
+            size => size,
+        };
+        info!("Using DFU transfer size of {} bytes", transfer_size);
+
+        Self {
+            handle,
+            interface,
+            functional_descriptor,
+            protocol,
+            transfer_size,
+            retry: RetryPolicy::default(),
+            timeout,
+            block_delay: Duration::ZERO,
+            force_reset: false,
+            erase_strategy: EraseStrategy::default(),
+            mass_erase_supported: false,
+            poll_strategy: PollStrategy::default(),
+            clock: &SystemClock,
+            random: &SystemRandom,
+        }
+    }
+
+    /// Sets how transfers that fail with a transient error are retried.
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy)
+    {
+        self.retry = retry;
+    }
+
+    /// Limits the number of bytes sent or requested in each DFU_DNLOAD or DFU_UPLOAD to `max`, for
+    /// devices that struggle with the size they advertise.
+    pub fn limit_transfer_size(&mut self, max: u16)
+    {
+        if max < self.transfer_size {
+            debug!("Limiting DFU transfer size to {} bytes", max);
+            self.transfer_size = max;
+        }
+    }
+
+    /// Sets how long to wait before writing each block, giving the device time to recover between
+    /// flash writes. Defaults to no delay.
+    pub fn set_block_delay(&mut self, delay: Duration)
+    {
+        self.block_delay = delay;
+    }
+
+    /// Sets whether [`DfuInterface::manifest`] always ends with a USB reset, rather than only when
+    /// the device needs one to leave DFU mode. Resetting makes the bootloader reboot the device,
+    /// where a manifestation tolerant device would otherwise stay in DFU mode, or one that detaches
+    /// by itself would only re-enter its firmware. Defaults to `false`.
+    pub fn set_force_reset(&mut self, force_reset: bool)
+    {
+        self.force_reset = force_reset;
+    }
+
+    /// Sets how the flash is erased before a download. Defaults to [`EraseStrategy::Auto`].
+    pub fn set_erase_strategy(&mut self, strategy: EraseStrategy)
+    {
+        self.erase_strategy = strategy;
+    }
+
+    /// Sets whether the device accepts a DfuSe mass erase. Nothing in the descriptors says so, so
+    /// this has to come from knowing the bootloader. Defaults to `false`.
+    pub fn set_mass_erase_supported(&mut self, supported: bool)
+    {
+        self.mass_erase_supported = supported;
+    }
+
+    /// Sets how the device is polled while it's busy. Defaults to [`PollStrategy::Requested`].
+    pub fn set_poll_strategy(&mut self, strategy: PollStrategy)
+    {
+        self.poll_strategy = strategy;
+    }
+
+    /// Sets the clock all waiting goes through, and the source of retry jitter. Defaults to
+    /// [`SystemClock`] and [`SystemRandom`]; see [`crate::clock`].
+    pub fn set_clock(&mut self, clock: &'h dyn Clock, random: &'h dyn RandomSource)
+    {
+        self.clock = clock;
+        self.random = random;
+    }
+
+    pub fn protocol(&self) -> &DfuProtocol
+    {
+        &self.protocol
+    }
+
+    #[allow(dead_code)]
+    pub fn functional_descriptor(&self) -> &DfuFunctionalDescriptor
+    {
+        &self.functional_descriptor
+    }
+
+    /// The number of bytes sent or requested in each DFU_DNLOAD or DFU_UPLOAD.
+    pub fn transfer_size(&self) -> u16
+    {
+        self.transfer_size
+    }
+
+    /// Sends a class request to the DFU interface.
+    ///
+    /// Transient errors are retried, except for DFU_DNLOAD, which the device may well have acted on
+    /// anyway; [DfuInterface::download_block] recovers from those instead.
+    fn control_out(&self, request: DfuRequest, value: u16, data: &[u8]) -> Result<usize, DfuError>
+    {
+        let request_type = rusb::request_type(Direction::Out, RequestType::Class, Recipient::Interface);
+        let transfer = || {
+            self.handle.write_control(
+                request_type,
+                request as u8,
+                value,
+                self.interface as u16,
+                data,
+                self.timeout,
+            )
+        };
+
+        let written = if request == DfuRequest::Dnload {
+            transfer()?
+        } else {
+            self.retry.run_with(self.clock, self.random, &format!("{:?} request", request), transfer)?
+        };
+
+        Ok(written)
+    }
+
+    fn control_in(&self, request: DfuRequest, value: u16, buf: &mut [u8]) -> Result<usize, DfuError>
+    {
+        let request_type = rusb::request_type(Direction::In, RequestType::Class, Recipient::Interface);
+        let read = self.retry.run_with(self.clock, self.random, &format!("{:?} request", request), || {
+            self.handle.read_control(
+                request_type,
+                request as u8,
+                value,
+                self.interface as u16,
+                buf,
+                self.timeout,
+            )
+        })?;
+
+        Ok(read)
+    }
+
+    /// Performs a DFU_GETSTATUS request.
+    pub fn get_status(&self) -> Result<DfuStatusResponse, DfuError>
+    {
+        let mut buf: [u8; DfuStatusResponse::LENGTH] = [0; DfuStatusResponse::LENGTH];
+        let read = self.control_in(DfuRequest::GetStatus, 0, &mut buf)?;
+        if read < DfuStatusResponse::LENGTH {
+            return Err(DfuError::ShortStatusResponse(read));
+        }
+
+        let status = DfuStatusResponse::from_bytes(&buf);
+        trace!(
+            "DFU_GETSTATUS: status {:?}, state {}, poll timeout {} ms",
+            status.status,
+            status.state,
+            status.poll_timeout.as_millis(),
+        );
+
+        Ok(status)
+    }
+
+    /// Performs a DFU_CLRSTATUS request, moving the device from dfuERROR back to dfuIDLE.
+    pub fn clear_status(&self) -> Result<(), DfuError>
+    {
+        self.control_out(DfuRequest::ClrStatus, 0, &[])?;
+        Ok(())
+    }
+
+    /// Performs a DFU_ABORT request, moving the device from any of the idle states back to dfuIDLE.
+    pub fn abort(&self) -> Result<(), DfuError>
+    {
+        self.control_out(DfuRequest::Abort, 0, &[])?;
+        Ok(())
+    }
+
+    /// Polls DFU_GETSTATUS for as long as the device reports it is busy, waiting between each poll
+    /// as the [`PollStrategy`] says, and returns the state it settled in.
+    ///
+    /// If the device ends up in dfuERROR, its status is cleared so it can accept further requests,
+    /// and the reported error is returned.
+    pub fn wait_while_busy(&self) -> Result<DfuState, DfuError>
+    {
+        loop {
+            let status = self.get_status()?;
+            match status.state {
+                DfuState::DfuDnloadSync | DfuState::DfuDnbusy | DfuState::DfuManifest => {
+                    self.clock.sleep(match self.poll_strategy {
+                        PollStrategy::Requested => status.poll_timeout,
+                        PollStrategy::Interval(interval) => interval,
+                    });
+                },
+                DfuState::DfuError => {
+                    warn!("Device reported DFU error: {}", status.status);
+                    self.clear_status()?;
+                    return Err(DfuError::ErrorStatus {
+                        status: status.status,
+                        state: status.state,
+                    });
+                },
+                other => return Ok(other),
+            }
+        }
+    }
+
+    /// Brings the device to dfuIDLE from whatever state a previous (possibly interrupted) operation
+    /// left it in.
+    pub fn ensure_idle(&self) -> Result<(), DfuError>
+    {
+        let status = self.get_status()?;
+        match status.state {
+            DfuState::DfuIdle => return Ok(()),
+            DfuState::DfuError => {
+                debug!("Device was left in dfuERROR ({}); clearing status", status.status);
+                self.clear_status()?;
+            },
+            DfuState::DfuDnloadIdle | DfuState::DfuUploadIdle => {
+                debug!("Device was left in {}; aborting", status.state);
+                self.abort()?;
+            },
+            other => {
+                return Err(DfuError::UnexpectedState {
+                    got: other,
+                    expected: DfuState::DfuIdle,
+                });
+            },
+        }
+
+        match self.get_status()?.state {
+            DfuState::DfuIdle => Ok(()),
+            other => Err(DfuError::UnexpectedState {
+                got: other,
+                expected: DfuState::DfuIdle,
+            }),
+        }
+    }
+
+    /// Sends a DfuSe command (set address pointer, erase page) and waits for the device to
+    /// complete it.
+    fn dfuse_command(&self, command: u8, address: u32) -> Result<(), DfuError>
+    {
+        let mut payload: [u8; 5] = [command, 0, 0, 0, 0];
+        payload[1..].copy_from_slice(&address.to_le_bytes());
+        self.control_out(DfuRequest::Dnload, 0, &payload)?;
+        self.wait_while_busy()?;
+
+        Ok(())
+    }
+
+    /// Sets the DfuSe address pointer, which subsequent DFU_DNLOAD and DFU_UPLOAD blocks are relative to.
+    pub fn dfuse_set_address(&self, address: u32) -> Result<(), DfuError>
+    {
+        trace!("Setting DfuSe address pointer to 0x{:08x}", address);
+        self.dfuse_command(DFUSE_SET_ADDRESS, address)
+    }
+
+    /// Erases the DfuSe page containing `address`.
+    pub fn dfuse_erase_page(&self, address: u32) -> Result<(), DfuError>
+    {
+        trace!("Erasing page at 0x{:08x}", address);
+        self.dfuse_command(DFUSE_ERASE_PAGE, address)
+    }
+
+    /// Erases the whole device with a DfuSe erase command without an address.
+    pub fn dfuse_mass_erase(&self) -> Result<(), DfuError>
+    {
+        trace!("Mass erasing");
+        self.control_out(DfuRequest::Dnload, 0, &[DFUSE_ERASE_PAGE])?;
+        self.wait_while_busy()?;
+
+        Ok(())
+    }
+
+    /// Removes the read protection of an STM32 with the DfuSe Read Unprotect command.
+    ///
+    /// The device mass erases its flash, and then resets, so it's normal for it to disconnect
+    /// without answering; that is not treated as an error.
+    pub fn dfuse_read_unprotect(&self) -> Result<(), DfuError>
+    {
+        trace!("Removing read protection");
+        self.ensure_idle()?;
+        self.control_out(DfuRequest::Dnload, 0, &[DFUSE_READ_UNPROTECT])?;
+        match self.wait_while_busy() {
+            Ok(_) | Err(DfuError::Usb(rusb::Error::NoDevice | rusb::Error::Pipe | rusb::Error::Io)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads `length` bytes from `address` of a DfuSe device with DFU_UPLOAD.
+    pub fn dfuse_upload(&self, address: u32, length: usize) -> Result<Vec<u8>, DfuError>
+    {
+        // As with verify(), setting the address pointer leaves the device in dfuDNLOAD-IDLE.
+        self.ensure_idle()?;
+        self.dfuse_set_address(address)?;
+        self.abort()?;
+
+        let transfer_size = self.transfer_size as usize;
+        let mut data = vec![0u8; length];
+        let mut read = 0;
+        for (index, chunk) in data.chunks_mut(transfer_size).enumerate() {
+            let block_num = ((index + 2) % (u16::MAX as usize + 1)) as u16;
+            let got = self.control_in(DfuRequest::Upload, block_num, chunk)?;
+            read += got;
+            if got < chunk.len() {
+                break;
+            }
+        }
+        data.truncate(read);
+        self.abort()?;
+
+        Ok(data)
+    }
+
+    /// Writes `data` to `address` of a DfuSe device as a single block, without erasing first.
+    ///
+    /// This is meant for the option bytes of an STM32, which its bootloader erases by itself. The
+    /// device applies new option bytes by resetting, so it disconnecting is not treated as an error.
+    pub fn dfuse_write_block(&self, address: u32, data: &[u8]) -> Result<(), DfuError>
+    {
+        self.ensure_idle()?;
+        self.dfuse_set_address(address)?;
+        self.control_out(DfuRequest::Dnload, 2, data)?;
+        match self.wait_while_busy() {
+            Ok(_) | Err(DfuError::Usb(rusb::Error::NoDevice | rusb::Error::Pipe | rusb::Error::Io)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Erases every page that overlaps `length` bytes starting at `address`, or the whole device,
+    /// as the erase strategy says.
+    fn dfuse_erase<P>(&self, segments: &[MemorySegment], address: u32, length: u32, progress: &P) -> Result<(), DfuError>
+    where
+        P: Fn(DownloadProgress),
+    {
+        let end = address as u64 + length as u64;
+        let segment = segments
+            .iter()
+            .find(|segment| segment.start <= address && end <= segment.end())
+            .ok_or(DfuError::AddressOutOfRange(address))?;
+
+        let mass_erase = match self.erase_strategy {
+            EraseStrategy::Sector => false,
+            EraseStrategy::Mass if !self.mass_erase_supported => return Err(DfuError::MassEraseUnsupported),
+            EraseStrategy::Mass => true,
+            EraseStrategy::Auto => self.mass_erase_supported && address == segment.start,
+        };
+        if mass_erase {
+            debug!("Mass erasing instead of erasing page by page");
+            progress(DownloadProgress::new(DownloadPhase::MassErase, 0, 0, self.transfer_size));
+            return self.dfuse_mass_erase();
+        }
+
+        let pages: Vec<(u32, u32)> = segment
+            .page_ranges()
+            .filter(|&(page_start, page_size)| {
+                let page_end = page_start as u64 + page_size as u64;
+                page_end > address as u64 && (page_start as u64) < end
+            })
+            .collect();
+        let total: usize = pages.iter().map(|&(_start, size)| size as usize).sum();
+
+        let mut erased = 0;
+        progress(DownloadProgress::new(DownloadPhase::Erase, erased, total, self.transfer_size));
+        for (page_start, page_size) in pages {
+            self.dfuse_erase_page(page_start)?;
+            erased += page_size as usize;
+            progress(DownloadProgress::new(DownloadPhase::Erase, erased, total, self.transfer_size));
+        }
+
+        Ok(())
+    }
+
+    /// Sends one block of data with DFU_DNLOAD and waits for the device to finish writing it.
+    ///
+    /// If the transfer fails transiently or the device reports an error, its status is cleared and,
+    /// for DfuSe devices, the block is retried as the retry policy allows (re-setting the address
+    /// pointer, as the device's state was reset).
+    fn download_block(&self, block_num: u16, block_address: u32, data: &[u8]) -> Result<(), DfuError>
+    {
+        if !self.block_delay.is_zero() {
+            self.clock.sleep(self.block_delay);
+        }
+
+        let mut retry = 0;
+        loop {
+            let res = self
+                .control_out(DfuRequest::Dnload, block_num, data)
+                .and_then(|_| self.wait_while_busy());
+
+            let err = match res {
+                Ok(_) => return Ok(()),
+                // Plain DFU has no way to resume from the middle of a download.
+                Err(e) if self.protocol == DfuProtocol::Dfu || retry >= self.retry.get_retries() => return Err(e),
+                // A stall leaves the device in dfuERROR, and other transient errors leave it in an
+                // unknown state, both of which ensure_idle() below sorts out.
+                Err(DfuError::Usb(usb_err)) if RetryPolicy::is_transient(&usb_err) => DfuError::Usb(usb_err),
+                Err(e @ DfuError::ErrorStatus { .. }) => e,
+                Err(e) => return Err(e),
+            };
+
+            retry += 1;
+            warn!(
+                "Error writing block at 0x{:08x} (retry {}/{}): {}",
+                block_address,
+                retry,
+                self.retry.get_retries(),
+                err,
+            );
+            self.clock.sleep(self.retry.delay_with(retry, self.random));
+
+            self.ensure_idle()?;
+            self.dfuse_set_address(block_address - (block_num as u32 - 2) * self.transfer_size as u32)?;
+        }
+    }
+
+    /// Writes `firmware` to the device, erasing the affected pages first if the device uses DfuSe.
+    ///
+    /// `address` is only used for DfuSe devices; plain DFU devices decide for themselves where the
+    /// firmware goes. `progress` is called at the start of each phase and after each page or block.
+    ///
+    /// This does not manifest the new firmware; call [DfuInterface::manifest] (possibly after
+    /// [DfuInterface::verify]) to do that.
+    pub fn download<P>(&self, firmware: &[u8], address: u32, progress: P) -> Result<(), DfuError>
+    where
+        P: Fn(DownloadProgress),
+    {
+        self.download_from(firmware, address, 0, progress)
+    }
+
+    /// Returns where a download of firmware at `address` can be resumed, given the first `written`
+    /// bytes of it made it onto the device: the start of the flash page `written` bytes in, as
+    /// everything from there on is erased again. Always 0 for plain DFU devices, which can only
+    /// start over.
+    pub fn resume_point(&self, address: u32, written: usize) -> usize
+    {
+        let DfuProtocol::Dfuse(segments) = &self.protocol else {
+            return 0;
+        };
+        let Some(target) = u32::try_from(written).ok().and_then(|written| address.checked_add(written)) else {
+            return 0;
+        };
+
+        segments
+            .iter()
+            .flat_map(MemorySegment::page_ranges)
+            .find(|&(start, size)| start <= target && (target - start) < size)
+            .map_or(0, |(page_start, _)| page_start.saturating_sub(address) as usize)
+    }
+
+    /// Like [DfuInterface::download], but skips the first `written` bytes of `firmware`, which an
+    /// earlier, interrupted download already wrote. The download picks up from
+    /// [DfuInterface::resume_point]; progress is still reported relative to the whole of `firmware`.
+    pub fn download_from<P>(&self, firmware: &[u8], address: u32, written: usize, progress: P) -> Result<(), DfuError>
+    where
+        P: Fn(DownloadProgress),
+    {
+        self.ensure_idle()?;
+
+        let transfer_size = self.transfer_size as usize;
+        let total = firmware.len();
+
+        match &self.protocol {
+            DfuProtocol::Dfuse(segments) => {
+                let mut written = self.resume_point(address, written.min(total));
+                if written > 0 {
+                    info!("Resuming download at 0x{:08x}", address + written as u32);
+                }
+                let (address, firmware) = (address + written as u32, &firmware[written..]);
+
+                self.dfuse_erase(segments, address, firmware.len() as u32, &progress)?;
+                progress(DownloadProgress::new(DownloadPhase::Download, written, total, self.transfer_size));
+
+                // Block numbers start at 2 and are relative to the address pointer, which we keep
+                // re-setting so the block number never has to wrap around.
+                let blocks_per_window = (u16::MAX - 2) as usize;
+                for (window_index, window) in firmware.chunks(transfer_size * blocks_per_window).enumerate() {
+                    let window_address = address + (window_index * transfer_size * blocks_per_window) as u32;
+                    self.dfuse_set_address(window_address)?;
+
+                    for (index, chunk) in window.chunks(transfer_size).enumerate() {
+                        let block_address = window_address + (index * transfer_size) as u32;
+                        self.download_block((index + 2) as u16, block_address, chunk)?;
+                        written += chunk.len();
+                        progress(DownloadProgress::new(DownloadPhase::Download, written, total, self.transfer_size));
+                    }
+                }
+            },
+            DfuProtocol::Dfu => {
+                // Plain DFU devices erase by themselves, however they see fit.
+                if self.erase_strategy == EraseStrategy::Mass {
+                    return Err(DfuError::MassEraseUnsupported);
+                }
+                if written > 0 {
+                    debug!("Plain DFU can't resume a download, starting over");
+                }
+
+                let mut written = 0;
+                progress(DownloadProgress::new(DownloadPhase::Download, written, total, self.transfer_size));
+                for (index, chunk) in firmware.chunks(transfer_size).enumerate() {
+                    let block_num = (index % (u16::MAX as usize + 1)) as u16;
+                    self.download_block(block_num, (index * transfer_size) as u32, chunk)?;
+                    written += chunk.len();
+                    progress(DownloadProgress::new(DownloadPhase::Download, written, total, self.transfer_size));
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Reads the firmware back from the device with DFU_UPLOAD and compares it against `firmware`.
+    ///
+    /// `address` is only used for DfuSe devices. `progress` is called after each block.
+    pub fn verify<P>(&self, firmware: &[u8], address: u32, progress: P) -> Result<(), DfuError>
+    where
+        P: Fn(DownloadProgress),
+    {
+        // DFU_UPLOAD is only accepted from dfuIDLE, and setting the DfuSe address pointer leaves
+        // the device in dfuDNLOAD-IDLE, so abort out of that again.
+        self.ensure_idle()?;
+        let first_block = if let DfuProtocol::Dfuse(_) = self.protocol {
+            self.dfuse_set_address(address)?;
+            self.abort()?;
+            2
+        } else {
+            0
+        };
+
+        let transfer_size = self.transfer_size as usize;
+        let total = firmware.len();
+        let mut verified = 0;
+        progress(DownloadProgress::new(DownloadPhase::Verify, verified, total, self.transfer_size));
+
+        let mut buf = vec![0u8; transfer_size];
+        for (index, chunk) in firmware.chunks(transfer_size).enumerate() {
+            let block_num = ((index + first_block) % (u16::MAX as usize + 1)) as u16;
+            let read = self.control_in(DfuRequest::Upload, block_num, &mut buf[..chunk.len()])?;
+            let block_address = address + (index * transfer_size) as u32;
+
+            if let Some(offset) = buf[..read].iter().zip(chunk).position(|(a, b)| a != b) {
+                return Err(DfuError::VerificationMismatch(block_address + offset as u32));
+            }
+            if read < chunk.len() {
+                return Err(DfuError::VerificationMismatch(block_address + read as u32));
+            }
+
+            verified += chunk.len();
+            progress(DownloadProgress::new(DownloadPhase::Verify, verified, total, self.transfer_size));
+        }
+
+        self.abort()?;
+
+        Ok(())
+    }
+
+    /// Ends the download with a zero-length DFU_DNLOAD, letting the device manifest the new
+    /// firmware and leave DFU mode.
+    ///
+    /// For DfuSe devices, `address` is where the device should jump to.
+    ///
+    /// Note that many devices reset during manifestation without answering the final
+    /// DFU_GETSTATUS, which surfaces here as [rusb::Error::NoDevice].
+    pub fn manifest(&mut self, address: u32) -> Result<(), DfuError>
+    {
+        if let DfuProtocol::Dfuse(_) = self.protocol {
+            self.ensure_idle()?;
+            self.dfuse_set_address(address)?;
+        }
+
+        self.control_out(DfuRequest::Dnload, 0, &[])?;
+
+        let state = self.wait_while_busy()?;
+        debug!("Device state after manifestation: {}", state);
+
+        let needs_reset = state == DfuState::DfuManifestWaitReset || !self.functional_descriptor.manifestation_tolerant();
+        if needs_reset || self.force_reset {
+            if self.functional_descriptor.will_detach() && !self.force_reset {
+                // The device will detach and re-attach by itself once asked to.
+                self.control_out(DfuRequest::Detach, 0, &[])?;
+            } else {
+                // Otherwise the device is waiting for the host to reset it.
+                trace!("Resetting device after manifestation");
+                self.handle.reset()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Releases the DFU interface.
+    pub fn release(self) -> Result<(), DfuError>
+    {
+        match self.handle.release_interface(self.interface) {
+            // Ignore if the device has already disconnected.
+            Err(rusb::Error::NoDevice) => Ok(()),
+            other => Ok(other?),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::clock::{ManualClock, SeededRandom};
+    use crate::usb::{MockHandle, MockTransfer};
+
+    const IDLE: u8 = 2;
+    const DNLOAD_IDLE: u8 = 5;
+    const MANIFEST_SYNC: u8 = 6;
+    const ERROR: u8 = 10;
+
+    const FLASH: &str = "@Internal Flash  /0x08000000/4*001Ka";
+
+    /// A functional descriptor with bitCanDnload, bitCanUpload, and `attributes`, and a 4 byte
+    /// transfer size, so a few bytes of firmware take several blocks.
+    fn functional_descriptor(attributes: u8, version: u16) -> DfuFunctionalDescriptor
+    {
+        let [version_lo, version_hi] = version.to_le_bytes();
+        DfuFunctionalDescriptor::copy_from_bytes(&[0x09, 0x21, 0x03 | attributes, 0xff, 0x00, 0x04, 0x00, version_lo, version_hi])
+            .unwrap()
+    }
+
+    fn get_status(state: u8) -> MockTransfer
+    {
+        MockTransfer::control_in(DfuRequest::GetStatus as u8, 0, 0, &[0, 0, 0, 0, state, 0])
+    }
+
+    fn dnload(block: u16, data: &[u8]) -> MockTransfer
+    {
+        MockTransfer::control_out(DfuRequest::Dnload as u8, block, 0, data)
+    }
+
+    /// A DfuSe command to `address`, and the device finishing it.
+    fn dfuse_command(command: u8, address: u32) -> [MockTransfer; 2]
+    {
+        let [a, b, c, d] = address.to_le_bytes();
+        [dnload(0, &[command, a, b, c, d]), get_status(DNLOAD_IDLE)]
+    }
+
+    fn abort() -> MockTransfer
+    {
+        MockTransfer::control_out(DfuRequest::Abort as u8, 0, 0, &[])
+    }
+
+    fn upload(block: u16, data: &[u8]) -> MockTransfer
+    {
+        MockTransfer::control_in(DfuRequest::Upload as u8, block, 0, data)
+    }
+
+    fn dfuse_handle(script: impl IntoIterator<Item = MockTransfer>) -> MockHandle
+    {
+        script.into_iter().fold(MockHandle::new().interface_name(0, FLASH), MockHandle::expect)
+    }
+
+    #[test]
+    fn dfuse_download_erases_and_writes_each_block()
+    {
+        let firmware = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut handle = dfuse_handle(
+            [get_status(IDLE)]
+                .into_iter()
+                .chain(dfuse_command(DFUSE_ERASE_PAGE, 0x0800_0000))
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                .chain([dnload(2, &firmware[..4]), get_status(DNLOAD_IDLE)])
+                .chain([dnload(3, &firmware[4..]), get_status(DNLOAD_IDLE)]),
+        );
+
+        let dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(0, DFUSE_VERSION), Duration::from_secs(1)).unwrap();
+        let progress = RefCell::new(Vec::new());
+        dfu.download(&firmware, 0x0800_0000, |p| progress.borrow_mut().push((p.phase, p.done))).unwrap();
+        dfu.release().unwrap();
+
+        assert!(handle.is_done());
+        assert_eq!(
+            progress.into_inner(),
+            [
+                (DownloadPhase::Erase, 0),
+                (DownloadPhase::Erase, 1024),
+                (DownloadPhase::Download, 0),
+                (DownloadPhase::Download, 4),
+                (DownloadPhase::Download, 8),
+            ],
+        );
+    }
+
+    #[test]
+    fn dfuse_verify_reads_back_each_block()
+    {
+        let firmware = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut handle = dfuse_handle(
+            [get_status(IDLE)]
+                .into_iter()
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                .chain([abort(), upload(2, &firmware[..4]), upload(3, &firmware[4..]), abort()]),
+        );
+
+        let dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(0, DFUSE_VERSION), Duration::from_secs(1)).unwrap();
+        dfu.verify(&firmware, 0x0800_0000, |_| ()).unwrap();
+        dfu.release().unwrap();
+
+        assert!(handle.is_done());
+    }
+
+    #[test]
+    fn dfuse_verify_reports_first_mismatch()
+    {
+        let firmware = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut handle = dfuse_handle(
+            [get_status(IDLE)]
+                .into_iter()
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                .chain([abort(), upload(2, &firmware[..4]), upload(3, &[5, 6, 0xff, 8])]),
+        );
+
+        let dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(0, DFUSE_VERSION), Duration::from_secs(1)).unwrap();
+        let res = dfu.verify(&firmware, 0x0800_0000, |_| ());
+
+        assert!(matches!(res, Err(DfuError::VerificationMismatch(0x0800_0006))), "{:?}", res);
+    }
+
+    #[test]
+    fn dfuse_block_is_retried_after_stall()
+    {
+        let firmware = [1, 2, 3, 4];
+        let mut handle = dfuse_handle(
+            [get_status(IDLE)]
+                .into_iter()
+                .chain(dfuse_command(DFUSE_ERASE_PAGE, 0x0800_0000))
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                .chain([dnload(2, &firmware).fail(rusb::Error::Pipe)])
+                // The stall leaves the device in dfuERROR, which is cleared before trying again.
+                .chain([
+                    get_status(ERROR),
+                    MockTransfer::control_out(DfuRequest::ClrStatus as u8, 0, 0, &[]),
+                    get_status(IDLE),
+                ])
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                .chain([dnload(2, &firmware), get_status(DNLOAD_IDLE)]),
+        );
+
+        let clock = ManualClock::new();
+        let random = SeededRandom::new(0);
+        let start = clock.now();
+        let mut dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(0, DFUSE_VERSION), Duration::from_secs(1)).unwrap();
+        dfu.set_clock(&clock, &random);
+        dfu.download(&firmware, 0x0800_0000, |_| ()).unwrap();
+        dfu.release().unwrap();
+
+        assert!(handle.is_done());
+        assert_eq!(clock.now() - start, RetryPolicy::new().delay_before(1));
+    }
+
+    #[test]
+    fn dfuse_block_retries_stop_at_configured_count()
+    {
+        let firmware = [1, 2, 3, 4];
+        let stall_and_recover = [
+            dnload(2, &firmware).fail(rusb::Error::Pipe),
+            get_status(ERROR),
+            MockTransfer::control_out(DfuRequest::ClrStatus as u8, 0, 0, &[]),
+            get_status(IDLE),
+        ];
+        let mut handle = dfuse_handle(
+            [get_status(IDLE)]
+                .into_iter()
+                .chain(dfuse_command(DFUSE_ERASE_PAGE, 0x0800_0000))
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                .chain(stall_and_recover.clone())
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                .chain(stall_and_recover)
+                .chain(dfuse_command(DFUSE_SET_ADDRESS, 0x0800_0000))
+                // Out of retries, so this stall is passed up without recovering from it.
+                .chain([dnload(2, &firmware).fail(rusb::Error::Pipe)]),
+        );
+
+        let clock = ManualClock::new();
+        let random = SeededRandom::new(0);
+        let start = clock.now();
+        let policy = RetryPolicy::new().retries(2);
+        let mut dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(0, DFUSE_VERSION), Duration::from_secs(1)).unwrap();
+        dfu.set_clock(&clock, &random);
+        dfu.set_retry_policy(policy);
+        let res = dfu.download(&firmware, 0x0800_0000, |_| ());
+        dfu.release().unwrap();
+
+        assert!(matches!(res, Err(DfuError::Usb(rusb::Error::Pipe))), "{:?}", res);
+        assert!(handle.is_done());
+        assert_eq!(clock.now() - start, policy.delay_before(1) + policy.delay_before(2));
+    }
+
+    #[test]
+    fn failed_transfer_is_passed_up()
+    {
+        let mut handle = dfuse_handle([get_status(IDLE).fail(rusb::Error::NoDevice)]);
+
+        let dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(0, DFUSE_VERSION), Duration::from_secs(1)).unwrap();
+        let res = dfu.download(&[1, 2, 3, 4], 0x0800_0000, |_| ());
+        dfu.release().unwrap();
+
+        assert!(matches!(res, Err(DfuError::Usb(rusb::Error::NoDevice))), "{:?}", res);
+        assert!(handle.is_done());
+    }
+
+    #[test]
+    fn manifest_detaches_device_that_will_detach()
+    {
+        // bitWillDetach, but not bitManifestationTolerant.
+        let mut handle = MockHandle::new()
+            .expect(dnload(0, &[]))
+            .expect(get_status(MANIFEST_SYNC))
+            .expect(MockTransfer::control_out(DfuRequest::Detach as u8, 0, 0, &[]));
+
+        let mut dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(1 << 3, 0x0110), Duration::from_secs(1)).unwrap();
+        dfu.manifest(0).unwrap();
+        dfu.release().unwrap();
+
+        assert!(handle.is_done());
+        assert_eq!(handle.resets(), 0);
+    }
+
+    #[test]
+    fn manifest_resets_device_that_wont_detach()
+    {
+        let mut handle = MockHandle::new()
+            .expect(dnload(0, &[]))
+            .expect(get_status(MANIFEST_SYNC));
+
+        let mut dfu = DfuInterface::open(&mut handle, 0, functional_descriptor(0, 0x0110), Duration::from_secs(1)).unwrap();
+        dfu.manifest(0).unwrap();
+        dfu.release().unwrap();
+
+        assert!(handle.is_done());
+        assert_eq!(handle.resets(), 1);
+    }
+
+    fn segments(interface_string: &str) -> Vec<MemorySegment>
+    {
+        match DfuProtocol::parse_dfuse_layout(interface_string) {
+            Ok(DfuProtocol::Dfuse(segments)) => segments,
+            other => panic!("{:?} should parse as a DfuSe layout, but gave {:?}", interface_string, other),
+        }
+    }
+
+    #[test]
+    fn parse_pages_applies_multipliers()
+    {
+        assert_eq!(MemorySegment::parse_pages("2*001Ka"), Some(vec![1024, 1024]));
+        assert_eq!(MemorySegment::parse_pages("1*128Kg"), Some(vec![128 * 1024]));
+        assert_eq!(MemorySegment::parse_pages("1*002Mg"), Some(vec![2 * 1024 * 1024]));
+        assert_eq!(MemorySegment::parse_pages("3*040 e"), Some(vec![40, 40, 40]));
+        assert_eq!(MemorySegment::parse_pages("1*016Bg"), Some(vec![16]));
+        assert_eq!(MemorySegment::parse_pages("2*016Ka, 1*064Kg"), Some(vec![16 * 1024, 16 * 1024, 64 * 1024]));
+    }
+
+    #[test]
+    fn parse_pages_rejects_malformed_layouts()
+    {
+        for bad in ["", "4", "4x001Ka", "*001Ka", "four*001Ka", "4*Ka", "4*001", "4*001Ga", "4*000Ka", "4*001Ka,", "65537*001Ka"] {
+            assert_eq!(MemorySegment::parse_pages(bad), None, "{:?} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn parses_multi_segment_layout()
+    {
+        let parsed = segments("@Internal Flash   /0x08000000/04*016Kg,01*064Kg,07*128Kg/0x1fff0000/1*030Ke");
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].start, 0x0800_0000);
+        assert_eq!(parsed[0].pages.len(), 12);
+        assert_eq!(parsed[0].end(), 0x0810_0000);
+        assert_eq!(
+            parsed[0].page_ranges().take(6).collect::<Vec<_>>(),
+            [
+                (0x0800_0000, 0x4000),
+                (0x0800_4000, 0x4000),
+                (0x0800_8000, 0x4000),
+                (0x0800_c000, 0x4000),
+                (0x0801_0000, 0x1_0000),
+                (0x0802_0000, 0x2_0000),
+            ],
+        );
+        assert_eq!(parsed[1], MemorySegment { start: 0x1fff_0000, pages: vec![30 * 1024] });
+    }
+
+    #[test]
+    fn rejects_malformed_dfuse_layouts()
+    {
+        for bad in [
+            "@Internal Flash",
+            "@Internal Flash  /",
+            "@Internal Flash  /0x08000000",
+            "@Internal Flash  /0x08000000/",
+            "@Internal Flash  /08000000/4*001Ka",
+            "@Internal Flash  /0xnowhere/4*001Ka",
+            "@Internal Flash  /0x08000000/4001Ka",
+            "@Internal Flash  /0x08000000/4*001Ka/0x08001000",
+            "@Internal Flash  /0xffffff00/1*001Ka",
+        ] {
+            let res = DfuProtocol::parse_dfuse_layout(bad);
+            assert!(matches!(res, Err(DfuError::InvalidMemoryLayout(_))), "{:?} should be rejected, but gave {:?}", bad, res);
+        }
+    }
+}
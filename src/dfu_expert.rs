@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil dfu`, which sends single DFU requests to a probe's bootloader, for
+//! firmware developers poking at it from scripts.
+//!
+//! These are the same [`DfuInterface`] calls flashing is built from, but made one at a time, with
+//! none of flash's checks (image validation, address ranges, verification). Because of that, the
+//! whole command needs `--expert`, and writing additionally needs `--allow-dangerous-options=really`.
+
+use std::fs;
+use std::io::{self, Write};
+
+use clap::ArgMatches;
+
+use bmputil::bmp::BmpDevice;
+use bmputil::dfu::DfuInterface;
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::usb::DfuOperatingMode;
+
+use crate::confirm::{AuthorizationLevel, ConfirmationPolicy};
+
+/// How many bytes `read-block` prints per line of its hex dump.
+const HEX_DUMP_WIDTH: usize = 16;
+
+
+/// Parses an address or length given in decimal, or in hex with a `0x` prefix.
+pub fn parse_number(value: &str) -> Result<u32, String>
+{
+    let res = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    res.map_err(|e| format!("{:?} is not a number: {}", value, e))
+}
+
+fn number_arg(matches: &ArgMatches, name: &str) -> u32
+{
+    matches.value_of(name)
+        .map(|value| parse_number(value).expect("Clap ensures a valid number"))
+        .expect("Argument is required") // Should be impossible, thanks to clap.
+}
+
+fn print_hex_dump(address: u32, data: &[u8])
+{
+    for (index, line) in data.chunks(HEX_DUMP_WIDTH).enumerate() {
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        println!(
+            "{:08x}  {:<width$}  {}",
+            address as usize + index * HEX_DUMP_WIDTH,
+            hex.join(" "),
+            ascii,
+            width = HEX_DUMP_WIDTH * 3 - 1,
+        );
+    }
+}
+
+/// Runs the DFU request for `subcommand` on `dfu`.
+fn run(dfu: &DfuInterface, subcommand: &str, matches: &ArgMatches) -> Result<(), Error>
+{
+    match subcommand {
+        "get-status" => {
+            let status = dfu.get_status()?;
+            println!("Status:       {}", status.status);
+            println!("State:        {}", status.state);
+            println!("Poll timeout: {} ms", status.poll_timeout.as_millis());
+        },
+        "clr-status" => {
+            dfu.clear_status()?;
+            println!("Status cleared.");
+        },
+        "abort" => {
+            dfu.abort()?;
+            println!("Aborted.");
+        },
+        "set-address" => {
+            let address = number_arg(matches, "address");
+            dfu.dfuse_set_address(address)?;
+            println!("Address pointer set to 0x{:08x}.", address);
+        },
+        "read-block" => {
+            let address = number_arg(matches, "address");
+            let length = number_arg(matches, "length");
+            let data = dfu.dfuse_upload(address, length as usize)?;
+            if data.len() < length as usize {
+                eprintln!("Device returned {} of {} bytes.", data.len(), length);
+            }
+            match matches.value_of("output") {
+                Some("-") => io::stdout().write_all(&data)
+                    .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error().with_ctx("writing to stdout"))?,
+                Some(path) => fs::write(path, &data)
+                    .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error().with_ctx(&format!("writing {}", path)))?,
+                None => print_hex_dump(address, &data),
+            }
+        },
+        "write-block" => {
+            let address = number_arg(matches, "address");
+            let path = matches.value_of("file")
+                .expect("No file was specified!"); // Should be impossible, thanks to clap.
+            let data = fs::read(path)
+                .map_err(|e| ErrorKind::FirmwareFileIo(Some(path.to_string())).error_from(e))?;
+            if data.len() > dfu.transfer_size() as usize {
+                return Err(ErrorKind::OperationNotSupported(format!(
+                    "writing a {} byte block, more than the probe's transfer size of {} bytes",
+                    data.len(),
+                    dfu.transfer_size(),
+                )).error());
+            }
+            dfu.dfuse_write_block(address, &data)?;
+            println!("Wrote {} bytes at 0x{:08x}.", data.len(), address);
+        },
+        other => unreachable!("Unhandled dfu subcommand {:?}", other),
+    }
+
+    Ok(())
+}
+
+/// Implements `bmputil dfu`.
+pub fn dfu_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let (subcommand, subcommand_matches) = matches.subcommand()
+        .expect("No subcommand given!"); // Should be impossible, thanks to clap.
+
+    let matcher = crate::matcher_from_cli_args(matches);
+    let mut results = crate::find_probes(&matcher, matches)?;
+    let mut dev: BmpDevice = results.pop_single("raw DFU request")?;
+    eprintln!("Found: {}", dev);
+
+    if subcommand == "write-block" {
+        ConfirmationPolicy::from_cli_args(matches).confirm(
+            AuthorizationLevel::Dangerous,
+            "writing a raw DFU block",
+            "write-block writes wherever it's told to, without erasing first or checking the address.\n\
+            Writing over the bootloader or the option bytes can leave the probe unbootable.",
+        )?;
+    }
+
+    if dev.operating_mode() == DfuOperatingMode::Runtime {
+        eprintln!("Switching the probe to DFU mode...");
+        dev.detach_and_enumerate()
+            .map_err(|e| e.with_ctx("switching the probe to DFU mode"))?;
+    }
+
+    dev.with_dfu_interface(|dfu| run(dfu, subcommand, subcommand_matches))
+        .map_err(|e| e.with_ctx(&format!("sending DFU {}", subcommand)))
+}
@@ -0,0 +1,373 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for flashing with an external dfu-util (`--backend dfu-util`), as an escape hatch for
+//! platforms where talking DFU through our libusb fails but dfu-util's works.
+//!
+//! Everything but the download itself (finding the probe, detaching it, and waiting for it to
+//! re-enumerate) still goes through [`UsbBackend`]. For the download, our handle to the probe is
+//! closed, and dfu-util is pointed at the same probe, interface, address, and transfer size we would
+//! have used. Its progress output is translated into [`DownloadProgress`] updates, so the progress
+//! bars, flash journal, and reports work as with the native backend.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, trace};
+
+use bmputil::bmp::{BmpDevice, DownloadOptions, FirmwareType, ProbeIdentity, RebootTarget};
+use bmputil::dfu::{DownloadPhase, DownloadProgress, EraseStrategy};
+use bmputil::error::{Error, ErrorKind, ErrorSource, InvalidDeviceData};
+use bmputil::flasher::{self, Clock, ProbeBackend, SystemClock, UsbBackend};
+use bmputil::profiles;
+use bmputil::usb::{DfuOperatingMode, Pid, Vid};
+
+/// Environment variable naming the dfu-util to run, for when it isn't on the `PATH`.
+pub const DFU_UTIL_ENV: &str = "BMPUTIL_DFU_UTIL";
+
+/// bcdDFUVersion of devices speaking ST's DfuSe extensions, which need an address.
+const DFUSE_VERSION: u16 = 0x011a;
+
+/// How many lines of dfu-util's error output to include when it fails.
+const ERROR_LINES: usize = 5;
+
+/// Where and how to download to a probe in DFU mode, in dfu-util's terms.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target
+{
+    ids: (Vid, Pid),
+    port: String,
+    serial: Option<String>,
+    interface: u8,
+    transfer_size: u16,
+    dfuse: bool,
+    address: u32,
+}
+
+impl Target
+{
+    fn for_probe(probe: &BmpDevice, firmware_type: FirmwareType) -> Result<Self, Error>
+    {
+        let desc = probe.device().device_descriptor()?;
+        let (interface, func_desc) = probe.dfu_descriptors()?;
+
+        Ok(Self {
+            ids: (Vid(desc.vendor_id()), Pid(desc.product_id())),
+            port: probe.port(),
+            serial: probe.serial_number().ok().map(|serial| serial.to_string()),
+            interface,
+            transfer_size: func_desc.wTransferSize,
+            dfuse: func_desc.bcdDFUVersion == DFUSE_VERSION,
+            address: probe.profile().load_address(firmware_type),
+        })
+    }
+
+    /// The arguments selecting the probe's DFU interface, and the transfer size.
+    fn device_args(&self) -> Vec<OsString>
+    {
+        let (Vid(vid), Pid(pid)) = self.ids;
+        let mut args: Vec<OsString> = vec![
+            "-d".into(), format!("{:04x}:{:04x}", vid, pid).into(),
+            "-p".into(), self.port.clone().into(),
+            "-i".into(), self.interface.to_string().into(),
+            "-a".into(), "0".into(),
+            "-t".into(), self.transfer_size.to_string().into(),
+        ];
+        if let Some(serial) = &self.serial {
+            args.extend(["-S".into(), serial.into()]);
+        }
+
+        args
+    }
+
+    /// The DfuSe `-s` argument, e.g. `0x08002000:leave`.
+    fn dfuse_address(&self, modifiers: &[&str]) -> OsString
+    {
+        let mut arg = format!("0x{:08x}", self.address);
+        for modifier in modifiers {
+            arg.push(':');
+            arg.push_str(modifier);
+        }
+
+        arg.into()
+    }
+}
+
+/// A line of dfu-util's progress output, e.g. `Download [=====     ]  42%    21504 bytes`.
+fn parse_progress(line: &str) -> Option<(DownloadPhase, u8)>
+{
+    let line = line.trim_start();
+    let phase = if line.starts_with("Erase") {
+        DownloadPhase::Erase
+    } else if line.starts_with("Download") {
+        DownloadPhase::Download
+    } else if line.starts_with("Upload") {
+        DownloadPhase::Verify
+    } else {
+        return None;
+    };
+    let percent = line.split_whitespace()
+        .find_map(|word| word.strip_suffix('%'))
+        .and_then(|percent| percent.parse::<u8>().ok())?;
+
+    Some((phase, percent.min(100)))
+}
+
+/// A temporary file for passing data to and from dfu-util, removed when dropped.
+struct TempFile(PathBuf);
+
+impl TempFile
+{
+    /// A path for a file that doesn't exist yet, as dfu-util won't upload over an existing one.
+    fn new(purpose: &str) -> Self
+    {
+        let name = format!("bmputil-{}-{}-{}.bin", std::process::id(), purpose, unique_suffix());
+        Self(env::temp_dir().join(name))
+    }
+
+    fn path(&self) -> &Path
+    {
+        &self.0
+    }
+}
+
+impl Drop for TempFile
+{
+    fn drop(&mut self)
+    {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Something to tell apart temporary files from the same process.
+fn unique_suffix() -> u128
+{
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or_default()
+}
+
+fn io_error(e: io::Error, ctx: &str) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(e)).error().with_ctx(ctx)
+}
+
+
+/// A [`ProbeBackend`] that downloads with dfu-util, and does everything else with [`UsbBackend`].
+pub struct DfuUtilBackend
+{
+    usb: UsbBackend,
+    program: OsString,
+}
+
+impl DfuUtilBackend
+{
+    /// Runs `$BMPUTIL_DFU_UTIL` if set, and otherwise `dfu-util` from the `PATH`.
+    pub fn new(usb: UsbBackend) -> Self
+    {
+        Self {
+            usb,
+            program: env::var_os(DFU_UTIL_ENV).unwrap_or_else(|| "dfu-util".into()),
+        }
+    }
+
+    /// Runs dfu-util with `args`, calling `progress` for each progress update it prints, which are
+    /// taken to be out of `total` bytes.
+    fn run(&self, args: &[OsString], total: usize, transfer_size: u16, progress: &dyn Fn(DownloadProgress)) -> Result<(), Error>
+    {
+        debug!("Running {} {:?}", self.program.to_string_lossy(), args);
+        let mut child = Command::new(&self.program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io_error(e, &format!("running {} (set {} to its path)", self.program.to_string_lossy(), DFU_UTIL_ENV)))?;
+
+        // Errors go to stderr, which has to be drained at the same time as stdout.
+        let stderr = child.stderr.take().expect("stderr was piped");
+        let errors = thread::spawn(move || {
+            BufReader::new(stderr)
+                .lines()
+                .map_while(Result::ok)
+                .inspect(|line| debug!("dfu-util: {}", line))
+                .collect::<Vec<_>>()
+        });
+
+        // Progress bars are redrawn with carriage returns rather than newlines.
+        let mut stdout = child.stdout.take().expect("stdout was piped");
+        let mut output = Vec::new();
+        let mut chunk = [0; 256];
+        loop {
+            let read = stdout.read(&mut chunk)
+                .map_err(|e| io_error(e, "reading dfu-util output"))?;
+            if read == 0 {
+                break;
+            }
+            for &byte in &chunk[..read] {
+                if byte != b'\r' && byte != b'\n' {
+                    output.push(byte);
+                    continue;
+                }
+                let line = String::from_utf8_lossy(&output);
+                match parse_progress(&line) {
+                    Some((phase, percent)) => {
+                        trace!("dfu-util: {}", line);
+                        progress(DownloadProgress::new(phase, total * percent as usize / 100, total, transfer_size));
+                    },
+                    None if !line.trim().is_empty() => debug!("dfu-util: {}", line),
+                    None => (),
+                }
+                output.clear();
+            }
+        }
+
+        let status = child.wait()
+            .map_err(|e| io_error(e, "waiting for dfu-util"))?;
+        let errors = errors.join().unwrap_or_default();
+        if status.success() {
+            return Ok(());
+        }
+
+        let last_lines = errors[errors.len().saturating_sub(ERROR_LINES)..].join("\n");
+        Err(io_error(
+            io::Error::other(format!("dfu-util failed ({}):\n{}", status, last_lines)),
+            "flashing with dfu-util",
+        ))
+    }
+
+    /// Reads `length` bytes back from the probe with dfu-util, leaving it in DFU mode.
+    fn upload(&self, target: &Target, length: usize, progress: &dyn Fn(DownloadProgress)) -> Result<Vec<u8>, Error>
+    {
+        let file = TempFile::new("verify");
+        let mut args = target.device_args();
+        args.extend([
+            "-s".into(), target.dfuse_address(&[&length.to_string()]),
+            "-U".into(), file.path().into(),
+        ]);
+        self.run(&args, length, target.transfer_size, progress)?;
+
+        fs::read(file.path()).map_err(|e| io_error(e, "reading firmware read back by dfu-util"))
+    }
+
+    /// Makes a DfuSe probe leave DFU mode, which dfu-util only does at the end of a transfer, so a
+    /// few bytes are read back to have one.
+    fn leave(&self, target: &Target) -> Result<(), Error>
+    {
+        let file = TempFile::new("leave");
+        let mut args = target.device_args();
+        args.extend([
+            "-s".into(), target.dfuse_address(&["4", "leave"]),
+            "-U".into(), file.path().into(),
+        ]);
+
+        self.run(&args, 0, target.transfer_size, &|_| ())
+    }
+}
+
+impl ProbeBackend for DfuUtilBackend
+{
+    type Probe = BmpDevice;
+
+    fn operating_mode(&self, probe: &BmpDevice) -> DfuOperatingMode
+    {
+        self.usb.operating_mode(probe)
+    }
+
+    fn identity(&self, probe: &BmpDevice) -> ProbeIdentity
+    {
+        self.usb.identity(probe)
+    }
+
+    fn detach(&mut self, probe: BmpDevice) -> Result<(), Error>
+    {
+        self.usb.detach(probe)
+    }
+
+    fn find(&mut self, identity: &ProbeIdentity, operation: &str, verbose: bool) -> Result<BmpDevice, Error>
+    {
+        self.usb.find(identity, operation, verbose)
+    }
+
+    fn download(
+        &mut self,
+        probe: &mut BmpDevice,
+        firmware: &[u8],
+        firmware_type: FirmwareType,
+        options: &DownloadOptions,
+        progress: &dyn Fn(DownloadProgress),
+    ) -> Result<(), Error>
+    {
+        profiles::check_fits(probe.profile(), firmware_type, firmware)?;
+        let target = Target::for_probe(probe, firmware_type)?;
+        if !target.dfuse && options.get_verify() {
+            return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingDfuse).error()
+                .with_ctx("verifying written firmware"));
+        }
+        if options.get_resume_from() > 0 {
+            debug!("dfu-util can't resume, so writing the whole image");
+        }
+        let identity = self.usb.identity(probe);
+        let timeouts = probe.timeouts();
+        let leave = options.get_reboot_to() != RebootTarget::Dfu && !options.get_verify();
+
+        let file = TempFile::new("firmware");
+        fs::write(file.path(), firmware).map_err(|e| io_error(e, "writing firmware for dfu-util"))?;
+
+        let mut args = target.device_args();
+        if target.dfuse {
+            let mut modifiers = Vec::new();
+            if options.get_erase_strategy() == EraseStrategy::Mass {
+                modifiers.extend(["mass-erase", "force"]);
+            }
+            if leave {
+                modifiers.push("leave");
+            }
+            args.extend(["-s".into(), target.dfuse_address(&modifiers)]);
+        } else if leave {
+            args.push("-R".into());
+        }
+        args.extend(["-D".into(), file.path().into()]);
+
+        // dfu-util needs the probe to itself.
+        unsafe { probe.close() };
+        self.run(&args, firmware.len(), target.transfer_size, progress)?;
+
+        if options.get_verify() {
+            let read_back = self.upload(&target, firmware.len(), progress)?;
+            if read_back != firmware {
+                error!("Firmware read back from the device does not match the image! The device will stay in DFU mode.");
+                return Err(ErrorKind::FirmwareVerificationFailed(target.address).error()
+                    .with_ctx("verifying written firmware"));
+            }
+            if options.get_reboot_to() != RebootTarget::Dfu {
+                progress(DownloadProgress::new(DownloadPhase::Manifest, 0, 0, target.transfer_size));
+                self.leave(&target)?;
+            }
+        }
+
+        // A probe left in DFU mode has to be opened again, for the caller to carry on with it.
+        if options.get_reboot_to() == RebootTarget::Dfu {
+            *probe = flasher::wait_for_probe(
+                &mut self.usb,
+                &SystemClock,
+                &identity,
+                timeouts.get_enumerate(),
+                "flash",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn wait_for_change<C: Clock>(&mut self, clock: &C, max: Duration)
+    {
+        self.usb.wait_for_change(clock, max)
+    }
+}
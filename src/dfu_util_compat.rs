@@ -0,0 +1,424 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for running bmputil with dfu-util's arguments, so that Makefiles and vendor instructions
+//! written for dfu-util work by swapping the command for `bmputil dfu-util` (or by running bmputil
+//! through a link named `dfu-util`).
+//!
+//! dfu-util's flags don't fit alongside bmputil's own (its `-s` and `-p` mean something else), so
+//! rather than being a subcommand clap parses, the arguments are translated into the equivalent
+//! bmputil command line first, e.g. `-d 1d50:6018 -a 0 -s 0x08002000:leave -D blackmagic.bin`
+//! becomes `flash blackmagic.bin --load-address 0x08002000`. Anything dfu-util can do that bmputil
+//! doesn't (uploading, other alt settings, other devices) is an error, rather than being ignored.
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use bmputil::{profiles, S};
+use bmputil::usb::{Pid, Vid};
+
+/// The subcommand dfu-util's arguments are given after.
+pub const SUBCOMMAND: &str = "dfu-util";
+
+const USAGE: &str = "\
+Usage: bmputil dfu-util [options] ...
+
+Runs bmputil with the dfu-util options below, for scripts written for dfu-util.
+
+  -h --help                     Print this help message
+  -V --version                  Print the version number
+  -v --verbose                  Print more information; may be given more than once
+  -l --list                     List the Black Magic Probes connected
+  -e --detach                   Switch the probe to DFU mode
+  -d --device <vid>:<pid>[,<vid>:<pid>]
+                                Only use a probe with these IDs (runtime, DFU mode)
+  -p --path <bus-port. ... .port>
+                                Only use the probe on this USB port
+  -S --serial <serial>[,<serial_dfu>]
+                                Only use the probe with this serial number
+  -a --alt <alt>                Alt setting; only 0 (the internal flash) is supported
+  -t --transfer-size <size>     Ignored; the probe's own transfer size is used
+  -w --wait                     Wait for the probe to be connected
+  -R --reset                    Start the firmware when done
+  -D --download <file>          Flash the firmware in <file>
+  -s --dfuse-address <address>[:leave|:force|:mass-erase][...]
+                                Check that <file> goes at <address>; leave starts the firmware
+";
+
+/// Whether bmputil was run as dfu-util, through a link (or copy) with that name.
+fn invoked_as_dfu_util(argv0: &OsStr) -> bool
+{
+    Path::new(argv0)
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case(SUBCOMMAND))
+}
+
+/// If `args` (including the program name) are dfu-util's, returns the bmputil arguments to run
+/// instead, or why they can't be run.
+pub fn translate_args(args: &[OsString]) -> Option<Result<Vec<OsString>, String>>
+{
+    let (argv0, rest) = args.split_first()?;
+    let dfu_args = if invoked_as_dfu_util(argv0) {
+        rest
+    } else if rest.first().is_some_and(|arg| arg == SUBCOMMAND) {
+        &rest[1..]
+    } else {
+        return None;
+    };
+
+    Some(DfuUtilArgs::parse(dfu_args).map(|parsed| {
+        let mut translated = vec![argv0.clone()];
+        translated.extend(parsed.into_bmputil_args());
+        translated
+    }))
+}
+
+
+/// The parts of a dfu-util command line bmputil can act on.
+#[derive(Debug, Default)]
+struct DfuUtilArgs
+{
+    help: bool,
+    version: bool,
+    verbosity: usize,
+    list: bool,
+    detach: bool,
+    serial: Option<String>,
+    path: Option<String>,
+    wait: bool,
+    reset: bool,
+    download: Option<OsString>,
+    address: Option<u32>,
+    leave: bool,
+    force: bool,
+    mass_erase: bool,
+}
+
+impl DfuUtilArgs
+{
+    fn parse(args: &[OsString]) -> Result<Self, String>
+    {
+        let mut parsed = Self::default();
+        let mut args: VecDeque<OsString> = args.iter().cloned().collect();
+
+        while let Some(arg) = args.pop_front() {
+            let Some(text) = arg.to_str() else {
+                return Err(format!("unexpected argument {:?}", arg));
+            };
+
+            // Options are `-x value`, `-xvalue`, `--long value`, or `--long=value`, and short options
+            // without values can be bundled (`-Rw`), as with getopt.
+            let (flag, mut inline, long) = if let Some(long) = text.strip_prefix("--") {
+                match long.split_once('=') {
+                    Some((name, value)) => (long_to_short(name)?, Some(value.to_string()), true),
+                    None => (long_to_short(long)?, None, true),
+                }
+            } else if let Some(short) = text.strip_prefix('-').and_then(|short| short.chars().next()) {
+                let rest = &text[1 + short.len_utf8()..];
+                (short, (!rest.is_empty()).then(|| rest.to_string()), false)
+            } else {
+                return Err(format!("unexpected argument {:?} (dfu-util takes no positional arguments)", text));
+            };
+
+            let mut value = || -> Result<OsString, String> {
+                inline.take()
+                    .map(OsString::from)
+                    .or_else(|| args.pop_front())
+                    .ok_or_else(|| format!("option -{} needs a value", flag))
+            };
+
+            match flag {
+                'h' => parsed.help = true,
+                'V' => parsed.version = true,
+                'v' => parsed.verbosity += 1,
+                'l' => parsed.list = true,
+                'e' => parsed.detach = true,
+                'w' => parsed.wait = true,
+                'R' => parsed.reset = true,
+                'd' => check_device_ids(&utf8(value()?)?)?,
+                'a' => check_alt(&utf8(value()?)?)?,
+                // Each probe reports its own transfer size, which is what flashing uses.
+                't' => {
+                    let _ = value()?;
+                    eprintln!("note: ignoring -t; bmputil uses the transfer size the probe reports");
+                },
+                'p' => parsed.path = Some(utf8(value()?)?),
+                'S' => {
+                    // The second serial number is for DFU mode, which a probe reports the same.
+                    let serial = utf8(value()?)?;
+                    parsed.serial = serial.split(',').next().map(str::to_string).filter(|serial| !serial.is_empty());
+                },
+                'D' => parsed.download = Some(value()?),
+                's' => parsed.parse_dfuse_address(&utf8(value()?)?)?,
+                'U' | 'Z' => return Err(S!("reading firmware back from the probe (-U) isn't supported; use `bmputil verify` to compare it with a file")),
+                'E' | 'c' | 'i' | 'n' | 'y' | 'G' => {
+                    return Err(format!("option -{} isn't supported by bmputil's dfu-util mode", flag));
+                },
+                other => return Err(format!("unknown option -{}", other)),
+            }
+
+            match inline {
+                Some(_) if long => return Err(format!("option -{} doesn't take a value", flag)),
+                Some(rest) => args.push_front(format!("-{}", rest).into()),
+                None => (),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Parses the `address[:modifier...]` given with `-s`.
+    fn parse_dfuse_address(&mut self, value: &str) -> Result<(), String>
+    {
+        let mut parts = value.split(':');
+        let address = parts.next().unwrap_or_default();
+        if !address.is_empty() {
+            self.address = Some(crate::dfu_expert::parse_number(address)?);
+        }
+
+        for modifier in parts {
+            match modifier {
+                "leave" => self.leave = true,
+                "force" => self.force = true,
+                "mass-erase" => self.mass_erase = true,
+                // dfu-util has no use for the rest when downloading, other than length limiting
+                // uploads, so say so rather than silently doing something different.
+                other => return Err(format!("-s modifier {:?} isn't supported by bmputil's dfu-util mode", other)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The bmputil arguments that do what these dfu-util arguments would.
+    fn into_bmputil_args(self) -> Vec<OsString>
+    {
+        let mut args: Vec<OsString> = Vec::new();
+
+        if self.help {
+            print!("{}", USAGE);
+            std::process::exit(0);
+        }
+        if self.version {
+            println!("bmputil {} (dfu-util compatible mode)", env!("CARGO_PKG_VERSION"));
+            std::process::exit(0);
+        }
+
+        match (self.download, self.list, self.detach, self.reset) {
+            (Some(file), ..) => {
+                args.extend(["flash".into(), file]);
+                if let Some(address) = self.address {
+                    args.extend(["--load-address".into(), format!("0x{:08x}", address).into()]);
+                }
+                // Without :leave or -R, dfu-util leaves the device in DFU mode.
+                if !self.leave && !self.reset {
+                    args.extend(["--reboot-to".into(), "dfu".into()]);
+                }
+                if self.force {
+                    args.push("--force".into());
+                }
+                if self.mass_erase {
+                    args.extend(["--erase-strategy".into(), "mass".into()]);
+                }
+            },
+            (None, true, ..) => args.push("list".into()),
+            (None, false, true, _) => args.extend(["switch".into(), "--to".into(), "dfu".into()]),
+            (None, false, false, true) => args.push("reboot".into()),
+            // Nothing to do: show how to use it, as dfu-util does.
+            (None, false, false, false) => {
+                eprint!("{}", USAGE);
+                std::process::exit(64);
+            },
+        }
+
+        if let Some(serial) = self.serial {
+            args.extend(["--serial".into(), serial.into()]);
+        }
+        if let Some(path) = self.path {
+            args.extend(["--port".into(), path.into()]);
+        }
+        if self.wait {
+            args.push("--wait".into());
+        }
+        args.extend((0..self.verbosity).map(|_| OsString::from("-v")));
+
+        args
+    }
+}
+
+fn long_to_short(name: &str) -> Result<char, String>
+{
+    let short = match name {
+        "help" => 'h',
+        "version" => 'V',
+        "verbose" => 'v',
+        "list" => 'l',
+        "detach" => 'e',
+        "detach-delay" => 'E',
+        "device" => 'd',
+        "path" => 'p',
+        "cfg" => 'c',
+        "intf" => 'i',
+        "devnum" => 'n',
+        "alt" => 'a',
+        "transfer-size" => 't',
+        "upload" => 'U',
+        "upload-size" => 'Z',
+        "download" => 'D',
+        "reset" => 'R',
+        "dfuse-address" => 's',
+        "serial" => 'S',
+        "wait" => 'w',
+        "yes" => 'y',
+        other => return Err(format!("unknown option --{}", other)),
+    };
+
+    Ok(short)
+}
+
+fn utf8(value: OsString) -> Result<String, String>
+{
+    value.into_string().map_err(|value| format!("{:?} is not valid UTF-8", value))
+}
+
+/// Checks that the `vid:pid[,vid:pid]` given with `-d` can match a Black Magic Probe, as bmputil
+/// only ever finds those.
+fn check_device_ids(value: &str) -> Result<(), String>
+{
+    let known: Vec<(Vid, Pid)> = profiles::registry()
+        .iter()
+        .flat_map(|profile| profile.runtime_ids.into_iter().chain([profile.dfu_ids]))
+        .collect();
+
+    // Either half of an ID may be left out, or be `*`, to match anything.
+    let parse = |part: &str| -> Result<Option<u16>, String> {
+        match part {
+            "" | "*" => Ok(None),
+            hex => u16::from_str_radix(hex, 16).map(Some).map_err(|e| format!("invalid ID {:?} given with -d: {}", hex, e)),
+        }
+    };
+
+    for ids in value.split(',') {
+        let (vid, pid) = ids.split_once(':').unwrap_or((ids, ""));
+        let (vid, pid) = (parse(vid)?, parse(pid)?);
+        let matches = |(Vid(known_vid), Pid(known_pid)): &(Vid, Pid)| {
+            vid.is_none_or(|vid| vid == *known_vid) && pid.is_none_or(|pid| pid == *known_pid)
+        };
+        if known.iter().any(matches) {
+            return Ok(());
+        }
+    }
+
+    Err(format!("{} is not the ID of a Black Magic Probe (see `bmputil profiles`)", value))
+}
+
+/// Checks that the alt setting given with `-a` is the probe's internal flash, the only one bmputil
+/// writes.
+fn check_alt(value: &str) -> Result<(), String>
+{
+    let internal_flash = match value.parse::<u8>() {
+        Ok(number) => number == 0,
+        Err(_) => value.starts_with("@Internal Flash"),
+    };
+
+    if internal_flash {
+        Ok(())
+    } else {
+        Err(format!("alt setting {:?} isn't supported; bmputil only writes the internal flash (-a 0)", value))
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn translate(args: &[&str]) -> Option<Result<Vec<String>, String>>
+    {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        translate_args(&args).map(|res| {
+            res.map(|args| args.into_iter().map(|arg| arg.into_string().unwrap()).collect())
+        })
+    }
+
+    #[test]
+    fn translates_dfu_util_command_lines()
+    {
+        let cases: &[(&[&str], &[&str])] = &[
+            (
+                &["bmputil", "dfu-util", "-D", "blackmagic.bin"],
+                &["bmputil", "flash", "blackmagic.bin", "--reboot-to", "dfu"],
+            ),
+            (
+                &["bmputil", "dfu-util", "-d", "1d50:6018", "-a", "0", "-s", "0x08002000:leave", "-D", "blackmagic.bin"],
+                &["bmputil", "flash", "blackmagic.bin", "--load-address", "0x08002000"],
+            ),
+            (
+                &["bmputil", "dfu-util", "--alt=0", "--dfuse-address", "0x08002000:force:mass-erase", "-R", "--download=fw.bin"],
+                &["bmputil", "flash", "fw.bin", "--load-address", "0x08002000", "--force", "--erase-strategy", "mass"],
+            ),
+            (
+                &["bmputil", "dfu-util", "-Rw", "-Dfw.bin", "-S", "7BB180B4,7BB180B4", "-p", "1-2", "-vv"],
+                &["bmputil", "flash", "fw.bin", "--serial", "7BB180B4", "--port", "1-2", "--wait", "-v", "-v"],
+            ),
+            (
+                &["dfu-util", "-d", "1d50:", "-l"],
+                &["dfu-util", "list"],
+            ),
+            (
+                &["/usr/local/bin/dfu-util", "-a", "@Internal Flash  /0x08000000/8*001Kg", "-e"],
+                &["/usr/local/bin/dfu-util", "switch", "--to", "dfu"],
+            ),
+            (
+                &["bmputil", "dfu-util", "-t", "1024", "-R"],
+                &["bmputil", "reboot"],
+            ),
+        ];
+
+        for (args, expected) in cases {
+            let translated = translate(args)
+                .unwrap_or_else(|| panic!("{:?} should be dfu-util arguments", args))
+                .unwrap_or_else(|e| panic!("{:?} should translate: {}", args, e));
+            assert_eq!(translated, *expected, "translating {:?}", args);
+        }
+    }
+
+    #[test]
+    fn rejects_what_bmputil_does_not_do()
+    {
+        let cases: &[(&[&str], &str)] = &[
+            (&["dfu-util", "-U", "backup.bin"], "-U"),
+            (&["dfu-util", "--upload-size=1024", "-D", "fw.bin"], "-U"),
+            (&["dfu-util", "-c", "1", "-D", "fw.bin"], "-c"),
+            (&["dfu-util", "--detach-delay", "5", "-e"], "-E"),
+            (&["dfu-util", "-x"], "-x"),
+            (&["dfu-util", "--frobnicate"], "--frobnicate"),
+            (&["dfu-util", "-a", "1", "-D", "fw.bin"], "alt setting"),
+            (&["dfu-util", "-a", "@Option Bytes", "-D", "fw.bin"], "alt setting"),
+            (&["dfu-util", "-d", "1234:5678", "-D", "fw.bin"], "not the ID of a Black Magic Probe"),
+            (&["dfu-util", "-d", "xyz:6018", "-D", "fw.bin"], "invalid ID"),
+            (&["dfu-util", "-s", "0x08002000:will-reset", "-D", "fw.bin"], "will-reset"),
+            (&["dfu-util", "-s", "nowhere", "-D", "fw.bin"], "is not a number"),
+            (&["dfu-util", "-D"], "needs a value"),
+            (&["dfu-util", "--reset=yes"], "doesn't take a value"),
+            (&["dfu-util", "fw.bin"], "positional"),
+        ];
+
+        for (args, expected) in cases {
+            let e = translate(args)
+                .unwrap_or_else(|| panic!("{:?} should be dfu-util arguments", args))
+                .expect_err(&format!("{:?} should be rejected", args));
+            assert!(e.contains(expected), "error for {:?} should mention {:?}, but is {:?}", args, expected, e);
+        }
+    }
+
+    #[test]
+    fn leaves_other_command_lines_alone()
+    {
+        assert!(translate(&[]).is_none());
+        assert!(translate(&["bmputil", "flash", "fw.bin"]).is_none());
+        assert!(translate(&["bmputil", "-v", "dfu-util", "-l"]).is_none());
+    }
+}
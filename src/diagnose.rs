@@ -0,0 +1,469 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil diagnose`, which shows every USB descriptor of a probe as a tree,
+//! decoded as far as bmputil understands it, and points out anything unusual.
+//!
+//! Where `bmputil dump-descriptors` is for bmputil to read back, this is for people to read: the
+//! device, each configuration, and the interfaces and endpoints in it, with the class-specific
+//! descriptors following each. Anomalies (a DFU interface without its functional descriptor, class
+//! codes no Black Magic Probe uses, counts that don't add up) are marked where they occur and listed
+//! again at the end. The same can be done for saved dumps with `--dump`.
+
+use std::path::Path;
+
+use clap::ArgMatches;
+
+use bmputil::error::Error;
+use bmputil::profiles;
+use bmputil::usb::dump::{DescriptorDump, DumpBytes};
+use bmputil::usb::{
+    sanitize_descriptor_string, Descriptor, DfuFunctionalDescriptor, ExtraDescriptors, InterfaceClass,
+    InterfaceSubClass, Pid, Vid,
+};
+use bmputil::S;
+
+const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_TYPE_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_TYPE_ENDPOINT: u8 = 0x05;
+const DESCRIPTOR_TYPE_INTERFACE_ASSOCIATION: u8 = 0x0b;
+const DESCRIPTOR_TYPE_BOS: u8 = 0x0f;
+const DESCRIPTOR_TYPE_CS_INTERFACE: u8 = 0x24;
+
+const CLASS_PER_INTERFACE: u8 = 0x00;
+const CLASS_CDC: u8 = 0x02;
+const CLASS_CDC_DATA: u8 = 0x0a;
+const CLASS_MISCELLANEOUS: u8 = 0xef;
+const CLASS_VENDOR_SPECIFIC: u8 = 0xff;
+
+/// The tree for one device, built up as its descriptors are walked.
+#[derive(Debug, Default)]
+struct Report
+{
+    lines: Vec<String>,
+    anomalies: Vec<String>,
+}
+
+impl Report
+{
+    fn line(&mut self, depth: usize, text: String)
+    {
+        self.lines.push(format!("{:width$}{}", "", text, width = depth * 2));
+    }
+
+    /// Marks an anomaly at this point in the tree, and remembers it for the summary.
+    fn flag(&mut self, depth: usize, anomaly: String)
+    {
+        self.line(depth, format!("! {}", anomaly));
+        self.anomalies.push(anomaly);
+    }
+
+    fn print(&self)
+    {
+        for line in &self.lines {
+            println!("{}", line);
+        }
+        if self.anomalies.is_empty() {
+            println!("No anomalies found.");
+        } else {
+            println!("{} anomalies found:", self.anomalies.len());
+            for anomaly in &self.anomalies {
+                println!("  - {}", anomaly);
+            }
+        }
+    }
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str
+{
+    match (class, subclass) {
+        (CLASS_PER_INTERFACE, _) => "defined per interface",
+        (CLASS_CDC, 0x02) => "CDC ACM",
+        (CLASS_CDC, _) => "CDC",
+        (CLASS_CDC_DATA, _) => "CDC data",
+        (CLASS_MISCELLANEOUS, _) => "miscellaneous",
+        (CLASS_VENDOR_SPECIFIC, _) => "vendor specific",
+        (c, s) if c == InterfaceClass::APPLICATION_SPECIFIC.0 && s == InterfaceSubClass::DFU.0 => "DFU",
+        (c, _) if c == InterfaceClass::APPLICATION_SPECIFIC.0 => "application specific",
+        _ => "unknown",
+    }
+}
+
+fn hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+/// Formats a string index with the string it refers to, if the dump has it.
+fn string(dump: &DescriptorDump, serial_index: u8, index: u8) -> String
+{
+    match index {
+        0 => S!("none"),
+        _ if index == serial_index => format!("{} (serial number, left out)", index),
+        _ => match dump.strings.get(&index) {
+            Some(string) => format!("{} \"{}\"", index, sanitize_descriptor_string(string)),
+            None => format!("{} (unreadable)", index),
+        },
+    }
+}
+
+/// The interface descriptor currently being walked, and what has followed it so far.
+struct Interface
+{
+    number: u8,
+    alternate: u8,
+    is_dfu: bool,
+    endpoints_expected: u8,
+    endpoints: u8,
+    has_functional: bool,
+}
+
+impl Interface
+{
+    /// Checks what followed the interface descriptor, once the next one (or the end) is reached.
+    fn finish(self, report: &mut Report)
+    {
+        let name = format!("interface {} alt {}", self.number, self.alternate);
+        if self.endpoints != self.endpoints_expected {
+            report.flag(3, format!(
+                "{} claims {} endpoints, but {} follow it",
+                name,
+                self.endpoints_expected,
+                self.endpoints,
+            ));
+        }
+        if self.is_dfu && !self.has_functional {
+            report.flag(3, format!("{} is a DFU interface without a DFU functional descriptor", name));
+        }
+    }
+}
+
+fn describe_dfu_functional(report: &mut Report, functional: &DfuFunctionalDescriptor)
+{
+    let mut capabilities = Vec::new();
+    if functional.can_download() {
+        capabilities.push("download");
+    }
+    if functional.can_upload() {
+        capabilities.push("upload");
+    }
+    if functional.manifestation_tolerant() {
+        capabilities.push("manifestation tolerant");
+    }
+    if functional.will_detach() {
+        capabilities.push("will detach");
+    }
+    report.line(3, format!(
+        "DFU functional: DFU {:x}.{:02x}, transfer size {}, detach timeout {} ms, attributes {:#04x} ({})",
+        functional.bcdDFUVersion >> 8,
+        functional.bcdDFUVersion & 0xff,
+        functional.wTransferSize,
+        functional.wDetachTimeOut,
+        functional.bmAttributes,
+        capabilities.join(", "),
+    ));
+
+    if !matches!(functional.bcdDFUVersion, 0x0100 | 0x0101 | 0x0110 | 0x011a) {
+        report.flag(3, format!("unknown DFU version {:#06x}", functional.bcdDFUVersion));
+    }
+    if functional.wTransferSize == 0 {
+        report.flag(3, S!("DFU transfer size is 0"));
+    }
+    if !functional.can_download() {
+        report.flag(3, S!("DFU interface cannot be downloaded to, so it cannot be flashed"));
+    }
+}
+
+fn describe_configuration(report: &mut Report, dump: &DescriptorDump, serial_index: u8, index: usize, configuration: &[u8])
+{
+    if configuration.len() < 9 || configuration[1] != DESCRIPTOR_TYPE_CONFIGURATION {
+        report.flag(1, format!("configuration {} is malformed: {}", index, hex(configuration)));
+        return;
+    }
+
+    let total_length = u16::from_le_bytes([configuration[2], configuration[3]]);
+    let num_interfaces = configuration[4];
+    report.line(1, format!(
+        "Configuration {}: value {}, {} interfaces, name {}, attributes {:#04x}, max power {} mA",
+        index,
+        configuration[5],
+        num_interfaces,
+        string(dump, serial_index, configuration[6]),
+        configuration[7],
+        configuration[8] as u32 * 2,
+    ));
+    if total_length as usize != configuration.len() {
+        report.flag(2, format!(
+            "wTotalLength is {}, but the configuration is {} bytes",
+            total_length,
+            configuration.len(),
+        ));
+    }
+
+    let mut interface: Option<Interface> = None;
+    let mut interface_numbers = Vec::new();
+    for descriptor in ExtraDescriptors::new(&configuration[9..]) {
+        let descriptor = match descriptor {
+            Ok(descriptor) => descriptor,
+            Err(e) => {
+                report.flag(2, format!("the rest of the configuration can't be parsed: {}", e));
+                break;
+            },
+        };
+
+        let generic = match descriptor {
+            Descriptor::DfuFunctional(functional) => match interface.as_mut() {
+                Some(current) if current.is_dfu => {
+                    describe_dfu_functional(report, &functional);
+                    current.has_functional = true;
+                    continue;
+                },
+                _ => {
+                    report.flag(3, S!("DFU functional descriptor outside of a DFU interface"));
+                    continue;
+                },
+            },
+            Descriptor::Other(generic) => generic,
+            other => {
+                report.flag(3, format!("unexpected descriptor in configuration: {:?}", other));
+                continue;
+            },
+        };
+        let raw = generic.raw;
+
+        match generic.descriptor_type() {
+            DESCRIPTOR_TYPE_INTERFACE if generic.length() >= 9 => {
+                if let Some(previous) = interface.take() {
+                    previous.finish(report);
+                }
+                let (number, alternate, class, subclass, protocol) = (raw[2], raw[3], raw[5], raw[6], raw[7]);
+                if !interface_numbers.contains(&number) {
+                    interface_numbers.push(number);
+                }
+                report.line(2, format!(
+                    "Interface {} alt {}: class {:#04x}/{:#04x}/{:#04x} ({}), {} endpoints, name {}",
+                    number,
+                    alternate,
+                    class,
+                    subclass,
+                    protocol,
+                    class_name(class, subclass),
+                    raw[4],
+                    string(dump, serial_index, raw[8]),
+                ));
+
+                let is_dfu = class == InterfaceClass::APPLICATION_SPECIFIC.0 && subclass == InterfaceSubClass::DFU.0;
+                let expected = matches!(class, CLASS_CDC | CLASS_CDC_DATA | CLASS_VENDOR_SPECIFIC) || is_dfu;
+                if !expected {
+                    report.flag(3, format!(
+                        "interface {} has class {:#04x}/{:#04x}, which no Black Magic Probe interface uses",
+                        number,
+                        class,
+                        subclass,
+                    ));
+                }
+                interface = Some(Interface {
+                    number,
+                    alternate,
+                    is_dfu,
+                    endpoints_expected: raw[4],
+                    endpoints: 0,
+                    has_functional: false,
+                });
+            },
+            DESCRIPTOR_TYPE_ENDPOINT if generic.length() >= 7 => {
+                let address = raw[2];
+                let transfer_type = match raw[3] & 0x03 {
+                    0 => "control",
+                    1 => "isochronous",
+                    2 => "bulk",
+                    _ => "interrupt",
+                };
+                report.line(3, format!(
+                    "Endpoint {:#04x} {} {}, max packet {}, interval {}",
+                    address,
+                    if address & 0x80 != 0 { "IN" } else { "OUT" },
+                    transfer_type,
+                    u16::from_le_bytes([raw[4], raw[5]]) & 0x7ff,
+                    raw[6],
+                ));
+                match interface.as_mut() {
+                    Some(current) => current.endpoints += 1,
+                    None => report.flag(3, format!("endpoint {:#04x} is outside of any interface", address)),
+                }
+            },
+            DESCRIPTOR_TYPE_INTERFACE_ASSOCIATION if generic.length() >= 8 => {
+                report.line(2, format!(
+                    "Interface association: interfaces {}-{}, class {:#04x}/{:#04x}/{:#04x} ({}), name {}",
+                    raw[2],
+                    (raw[2] as u16 + raw[3] as u16).saturating_sub(1),
+                    raw[4],
+                    raw[5],
+                    raw[6],
+                    class_name(raw[4], raw[5]),
+                    string(dump, serial_index, raw[7]),
+                ));
+            },
+            DESCRIPTOR_TYPE_CS_INTERFACE => {
+                report.line(3, format!("Class-specific interface descriptor: {}", hex(raw)));
+            },
+            DESCRIPTOR_TYPE_INTERFACE | DESCRIPTOR_TYPE_ENDPOINT | DESCRIPTOR_TYPE_INTERFACE_ASSOCIATION => {
+                report.flag(3, format!("descriptor of type {:#04x} is too short: {}", generic.descriptor_type(), hex(raw)));
+            },
+            other => {
+                let depth = if interface.is_some() { 3 } else { 2 };
+                report.line(depth, format!("Descriptor of type {:#04x}: {}", other, hex(raw)));
+            },
+        }
+    }
+    if let Some(last) = interface {
+        last.finish(report);
+    }
+
+    if interface_numbers.len() != num_interfaces as usize {
+        report.flag(2, format!(
+            "bNumInterfaces is {}, but the configuration has {} interfaces",
+            num_interfaces,
+            interface_numbers.len(),
+        ));
+    }
+}
+
+fn describe_bos(report: &mut Report, bos: &[u8])
+{
+    if bos.len() < 5 || bos[1] != DESCRIPTOR_TYPE_BOS {
+        report.flag(1, format!("BOS descriptor is malformed: {}", hex(bos)));
+        return;
+    }
+    report.line(1, format!("BOS: {} device capabilities", bos[4]));
+
+    for descriptor in ExtraDescriptors::new(bos.get(bos[0] as usize..).unwrap_or_default()) {
+        match descriptor {
+            Ok(Descriptor::ContainerId(id)) => report.line(2, format!("Container ID: {}", hex(&id))),
+            Ok(Descriptor::DeviceCapability { capability_type, raw }) => {
+                report.line(2, format!("Device capability {:#04x}: {}", capability_type, hex(raw)));
+            },
+            Ok(Descriptor::Other(generic)) => {
+                report.line(2, format!("Descriptor of type {:#04x}: {}", generic.descriptor_type(), hex(generic.raw)));
+            },
+            Ok(other) => report.flag(2, format!("unexpected descriptor in BOS: {:?}", other)),
+            Err(e) => {
+                report.flag(2, format!("the rest of the BOS descriptor can't be parsed: {}", e));
+                break;
+            },
+        }
+    }
+}
+
+/// Builds the tree for a dump.
+fn diagnose(dump: &DescriptorDump, bytes: &DumpBytes) -> Report
+{
+    let mut report = Report::default();
+    let device = &bytes.device;
+    if device.len() != 18 || device[1] != DESCRIPTOR_TYPE_DEVICE {
+        report.flag(0, format!("device descriptor is malformed: {}", hex(device)));
+        return report;
+    }
+
+    let vid = Vid(u16::from_le_bytes([device[8], device[9]]));
+    let pid = Pid(u16::from_le_bytes([device[10], device[11]]));
+    let bcd_usb = u16::from_le_bytes([device[2], device[3]]);
+    let bcd_device = u16::from_le_bytes([device[12], device[13]]);
+    let (class, subclass, protocol) = (device[4], device[5], device[6]);
+    let serial_index = device[16];
+
+    let profile = match profiles::find(vid, pid) {
+        Some((profile, mode)) => format!("{} in {}", profile.name, crate::mode_description(mode)),
+        None => S!("not a known probe"),
+    };
+    report.line(0, format!("Device {:04x}:{:04x} ({})", vid.0, pid.0, profile));
+    report.line(1, format!(
+        "USB {:x}.{:02x}, class {:#04x}/{:#04x}/{:#04x} ({}), max packet size {}, device version {:x}.{:02x}",
+        bcd_usb >> 8,
+        bcd_usb & 0xff,
+        class,
+        subclass,
+        protocol,
+        class_name(class, subclass),
+        device[7],
+        bcd_device >> 8,
+        bcd_device & 0xff,
+    ));
+    report.line(1, format!(
+        "Manufacturer {}, product {}, {} configurations",
+        string(dump, serial_index, device[14]),
+        string(dump, serial_index, device[15]),
+        device[17],
+    ));
+
+    if profiles::find(vid, pid).is_none() {
+        report.flag(1, format!("{:04x}:{:04x} does not match any probe profile", vid.0, pid.0));
+    }
+    if !matches!(class, CLASS_PER_INTERFACE | CLASS_CDC | CLASS_MISCELLANEOUS) {
+        report.flag(1, format!("device class {:#04x}/{:#04x} is unusual for a Black Magic Probe", class, subclass));
+    }
+    if !matches!(device[7], 8 | 16 | 32 | 64) {
+        report.flag(1, format!("max packet size {} for endpoint 0 is not allowed by USB 2.0", device[7]));
+    }
+    if device[17] as usize != bytes.configurations.len() {
+        report.flag(1, format!(
+            "bNumConfigurations is {}, but {} configurations were read",
+            device[17],
+            bytes.configurations.len(),
+        ));
+    }
+
+    for (index, configuration) in bytes.configurations.iter().enumerate() {
+        describe_configuration(&mut report, dump, serial_index, index, configuration);
+    }
+    match &bytes.bos {
+        Some(bos) => describe_bos(&mut report, bos),
+        None if bcd_usb >= 0x0201 => report.flag(1, S!("USB 2.01 or later, but no BOS descriptor could be read")),
+        None => (),
+    }
+
+    report
+}
+
+/// Implements `bmputil diagnose`.
+pub fn diagnose_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let mut dumps = Vec::new();
+    match matches.values_of("dump") {
+        Some(paths) => {
+            for path in paths {
+                dumps.push((S!(path), DescriptorDump::load(Path::new(path))?));
+            }
+        },
+        None => {
+            let mut results = crate::find_probes(&crate::matcher_from_cli_args(matches), matches)?;
+            for dev in results.pop_all()? {
+                let dump = DescriptorDump::capture(&dev.handle(), String::new(), dev.timeouts().get_control())
+                    .map_err(|e| e.with_ctx(&format!("reading descriptors of {}", dev)))?;
+                dumps.push((dev.to_string(), dump));
+            }
+        },
+    }
+
+    let mut anomalies = 0;
+    for (index, (name, dump)) in dumps.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!("{}:", name);
+        let report = diagnose(dump, &dump.bytes()?);
+        report.print();
+        anomalies += report.anomalies.len();
+    }
+
+    if anomalies > 0 {
+        println!();
+        println!(
+            "When reporting a problem with a probe, include this output, and a dump from \
+            `bmputil dump-descriptors -o descriptors.json`."
+        );
+    }
+
+    Ok(())
+}
@@ -8,9 +8,10 @@ use std::fmt::{Display, Formatter};
 use std::backtrace::{Backtrace, BacktraceStatus};
 use std::error::Error as StdError;
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::S;
+use crate::dfu::DfuError;
 
 /// More convenient alias for `Box<dyn StdError + Send + Sync>`,
 /// which shows up in a few signatures and structs.
@@ -27,6 +28,15 @@ pub enum ErrorKind
     /// Specified firmware seems invalid.
     InvalidFirmware(/** why **/ Option<String>),
 
+    /// Firmware image's signature is missing, invalid, or not from a trusted key.
+    FirmwareSignature(/** why **/ String),
+
+    /// Firmware read back from the device after writing did not match what was written.
+    FirmwareVerificationFailed(/** address of first mismatch **/ u32),
+
+    /// The requested operation is not supported by this Black Magic Probe device.
+    OperationNotSupported(/** what **/ String),
+
     /// Current operation only supports one Black Magic Probe but more tha none device was found.
     TooManyDevices,
 
@@ -36,6 +46,10 @@ pub enum ErrorKind
     /// Black Magic Probe found disconnected during an ongoing operation.
     DeviceDisconnectDuringOperation,
 
+    /// A USB transfer failed after writing (or reading back) firmware had started, so the probe's
+    /// flash may be partly written. The USB error is the source.
+    FlashFailed,
+
     /// Black Magic Probe device did not come back online (e.g. after switching to DFU mode
     /// or flashing firmware).
     DeviceReboot,
@@ -44,7 +58,16 @@ pub enum ErrorKind
     ///
     /// This generally shouldn't be possible, but could happen if the cable is bad, the OS is
     /// messing with things, or the firmware on the device is corrupted.
-    DeviceSeemsInvalid(/** invalid thing **/ String),
+    DeviceSeemsInvalid(InvalidDeviceData),
+
+    /// The GDB server of the Black Magic Probe device did not respond as expected.
+    GdbProtocol(/** what happened **/ String),
+
+    /// The user did not confirm a risky operation, so it was not performed.
+    NotConfirmed(/** operation **/ String),
+
+    /// A thread running an operation in the background panicked before finishing it.
+    TaskPanicked(/** operation **/ String, /** panic message **/ String),
 
     /// Unhandled external error.
     External(ErrorSource),
@@ -56,7 +79,10 @@ impl ErrorKind
     ///
     /// Enables convenient code like:
     /// ```
+    /// # use bmputil::error::{Error, ErrorKind};
+    /// # fn find() -> Result<(), Error> {
     /// return Err(ErrorKind::DeviceNotFound.error());
+    /// # }
     /// ```
     #[inline(always)]
     pub fn error(self) -> Error
@@ -68,16 +94,193 @@ impl ErrorKind
     ///
     /// Enables convenient code like:
     /// ```
-    /// # let operation = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+    /// # use bmputil::error::{Error, ErrorKind};
+    /// # fn find() -> Result<(), Error> {
+    /// # let operation = || Err::<(), _>(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
     /// operation().map_err(|e| ErrorKind::DeviceNotFound.error_from(e))?;
+    /// # Ok(())
+    /// # }
     /// ```
     #[inline(always)]
     pub fn error_from<E: StdError + Send + Sync + 'static>(self, source: E) -> Error
     {
         Error::new(self, Some(Box::new(source)))
     }
+
+    /// A short, stable name for this kind of error, without any of the details it carries.
+    pub fn name(&self) -> &'static str
+    {
+        use ErrorKind::*;
+        match self {
+            FirmwareFileIo(_) => "FirmwareFileIo",
+            InvalidFirmware(_) => "InvalidFirmware",
+            FirmwareSignature(_) => "FirmwareSignature",
+            FirmwareVerificationFailed(_) => "FirmwareVerificationFailed",
+            OperationNotSupported(_) => "OperationNotSupported",
+            TooManyDevices => "TooManyDevices",
+            DeviceNotFound => "DeviceNotFound",
+            DeviceDisconnectDuringOperation => "DeviceDisconnectDuringOperation",
+            FlashFailed => "FlashFailed",
+            DeviceReboot => "DeviceReboot",
+            DeviceSeemsInvalid(_) => "DeviceSeemsInvalid",
+            GdbProtocol(_) => "GdbProtocol",
+            NotConfirmed(_) => "NotConfirmed",
+            TaskPanicked(..) => "TaskPanicked",
+            External(ErrorSource::StdIo(_)) => "External(StdIo)",
+            External(ErrorSource::Libusb(_)) => "External(Libusb)",
+            External(ErrorSource::Dfu(_)) => "External(Dfu)",
+            External(ErrorSource::Goblin(_)) => "External(Goblin)",
+            External(ErrorSource::Toml(_)) => "External(Toml)",
+        }
+    }
+}
+
+/// What was wrong with the data a Black Magic Probe device reported, for [ErrorKind::DeviceSeemsInvalid].
+///
+/// Where there is an underlying error (e.g. from parsing a descriptor), it is the [Error]'s source.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+#[non_exhaustive]
+pub enum InvalidDeviceData
+{
+    /// The device has no configuration descriptor, even for its first configuration.
+    #[error("no configuration descriptor exists")]
+    MissingConfiguration,
+
+    /// None of the device's interfaces are DFU interfaces.
+    #[error("no DFU interfaces")]
+    MissingDfuInterface,
+
+    /// The DFU interface has no alternate setting whose name starts with this.
+    #[error("no {0} DFU alternate setting")]
+    MissingAltSetting(String),
+
+    /// A DFU interface isn't followed by its DFU functional descriptor.
+    #[error("no DFU functional descriptor for interface {interface}")]
+    MissingFunctionalDescriptor
+    {
+        interface: u8,
+    },
+
+    /// The DFU functional descriptor could not be parsed.
+    #[error("DFU functional descriptor")]
+    BadFunctionalDescriptor,
+
+    /// The device reports no languages for its string descriptors.
+    #[error("no supported string descriptor languages")]
+    MissingStringLanguages,
+
+    /// The product string descriptor could not be read.
+    #[error("no product string descriptor")]
+    MissingProductString,
+
+    /// The serial number contains characters no Black Magic Probe uses.
+    #[error("serial number {0:?} contains unexpected characters")]
+    BadSerialNumber(String),
+
+    /// The DFU interface doesn't support the DfuSe extensions the operation needs.
+    #[error("DFU interface without DfuSe support")]
+    MissingDfuse,
+
+    /// The DfuSe memory layout in the DFU interface's name could not be parsed, or is inconsistent.
+    #[error("DFU interface memory layout string")]
+    BadMemoryLayout,
+
+    /// The option bytes interface of the STM32 bootloader has no memory segments.
+    #[error("option bytes interface without memory")]
+    MissingOptionBytes,
+
+    /// Fewer option bytes than expected were read.
+    #[error("option bytes too short")]
+    ShortOptionBytes,
+
+    /// A descriptor is shorter than its header says, or than its type requires.
+    #[error("descriptor of type {descriptor_type:#04x} is truncated")]
+    TruncatedDescriptor
+    {
+        descriptor_type: u8,
+    },
+
+    /// The device descriptor has the wrong length or type.
+    #[error("malformed device descriptor")]
+    MalformedDeviceDescriptor,
+
+    /// A configuration descriptor, or one of the descriptors following it, could not be parsed.
+    #[error("malformed configuration descriptor {0}")]
+    MalformedConfiguration(usize),
+
+    /// The BOS descriptor, or one of its device capabilities, could not be parsed.
+    #[error("malformed BOS descriptor")]
+    MalformedBos,
 }
 
+
+/// Which device an [Error] happened with, so it can be told apart from other connected probes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DeviceContext
+{
+    /// The serial number, if it was known when the error happened.
+    pub serial: Option<String>,
+    /// The USB port path, in the format of `<bus>-<port>.<subport>`.
+    pub port: Option<String>,
+}
+
+impl Display for DeviceContext
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        match (&self.serial, &self.port) {
+            (Some(serial), Some(port)) => write!(f, "serial {}, port {}", serial, port),
+            (Some(serial), None) => write!(f, "serial {}", serial),
+            (None, Some(port)) => write!(f, "port {}", port),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
+
+
+/// Breadcrumbs for where in an operation an [Error] happened, so a failure partway through a
+/// multi-phase operation (e.g. detaching a probe, then flashing it) can be pinned down from the
+/// error output or a report alone.
+///
+/// Each field is filled in by the innermost code that knows it, as the error passes up through it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OperationContext
+{
+    /// The command being run, e.g. `flash`.
+    pub command: Option<String>,
+    /// The phase of the operation, e.g. `detach` or `download`.
+    pub phase: Option<String>,
+    /// The serial number of the probe being operated on.
+    pub serial: Option<String>,
+    /// Which attempt at the operation failed, counting from 1, if it was retried.
+    pub attempt: Option<u32>,
+}
+
+impl OperationContext
+{
+    /// Whether none of the breadcrumbs are known.
+    pub fn is_empty(&self) -> bool
+    {
+        self == &Self::default()
+    }
+}
+
+impl Display for OperationContext
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        let fields = [
+            self.command.as_ref().map(|command| format!("command {}", command)),
+            self.phase.as_ref().map(|phase| format!("phase {}", phase)),
+            self.serial.as_ref().map(|serial| format!("probe {}", serial)),
+            self.attempt.map(|attempt| format!("attempt {}", attempt)),
+        ];
+        let fields: Vec<String> = fields.into_iter().flatten().collect();
+        write!(f, "{}", fields.join(", "))
+    }
+}
+
+
 /// Constructs an [Error] for this [ErrorKind].
 impl From<ErrorKind> for Error
 {
@@ -99,6 +302,7 @@ impl Display for ErrorKind
             TooManyDevices => write!(f, "current operation only supports one Black Magic Probe device but more than one device was found")?,
             DeviceNotFound => write!(f, "Black Magic Probe device not found (check connection?)")?,
             DeviceDisconnectDuringOperation => write!(f, "Black Magic Probe device found disconnected")?,
+            FlashFailed => write!(f, "USB communication with the Black Magic Probe device failed partway through")?,
             DeviceReboot => write!(f, "Black Magic Probe device did not come back online (invalid firmware?)")?,
             DeviceSeemsInvalid(thing) => {
                 write!(
@@ -110,6 +314,14 @@ impl Display for ErrorKind
             },
             InvalidFirmware(None) => write!(f, "specified firmware does not seem valid")?,
             InvalidFirmware(Some(why)) => write!(f, "specified firmware does not seem valid: {}", why)?,
+            FirmwareSignature(why) => write!(f, "firmware signature check failed: {}", why)?,
+            FirmwareVerificationFailed(address) => {
+                write!(f, "firmware verification failed: data read back at 0x{:08x} does not match what was written", address)?;
+            },
+            GdbProtocol(what) => write!(f, "unexpected response from Black Magic Probe GDB server: {}", what)?,
+            OperationNotSupported(what) => write!(f, "operation not supported by this Black Magic Probe device: {}", what)?,
+            NotConfirmed(operation) => write!(f, "{} was not confirmed, so nothing was done", operation)?,
+            TaskPanicked(operation, message) => write!(f, "{} panicked: {}", operation, message)?,
             External(source) => {
                 use ErrorSource::*;
                 match source {
@@ -119,15 +331,15 @@ impl Display for ErrorKind
                     Libusb(e) => {
                         write!(f, "unhandled libusb error: {}", e)?;
                     },
-                    DfuLibusb(e) => {
-                        write!(f, "unhandled dfu_libusb error: {}", e)?;
-                    },
-                    DfuCore(e) => {
-                        write!(f, "unhandled dfu_core error: {}", e)?;
+                    Dfu(e) => {
+                        write!(f, "unhandled DFU error: {}", e)?;
                     },
                     Goblin(e) => {
                         write!(f, "unhandled ELF parsing error: {}", e)?;
                     },
+                    Toml(e) => {
+                        write!(f, "invalid TOML: {}", e)?;
+                    },
                 };
             },
         };
@@ -153,6 +365,14 @@ pub struct Error
     ///
     /// Example: "reading current firmware version".
     pub context: Option<String>,
+
+    /// The device this error happened with, if it happened with one.
+    ///
+    /// Boxed to keep this struct small, like the backtrace.
+    pub device: Option<Box<DeviceContext>>,
+
+    /// Where in the operation this error happened; see [Error::breadcrumbs].
+    pub operation: Option<Box<OperationContext>>,
 }
 
 impl Error
@@ -164,6 +384,8 @@ impl Error
             kind,
             source,
             context: None,
+            device: None,
+            operation: None,
             #[cfg(feature = "backtrace")]
             backtrace: Box::new(Backtrace::capture()),
         }
@@ -179,6 +401,54 @@ impl Error
         self
     }
 
+    /// Records which device this error happened with, unless that's already known.
+    ///
+    /// Errors pass through several layers on the way up, and the innermost one to know the device
+    /// knows the most about it, so it isn't overwritten.
+    pub fn with_device(mut self, device: DeviceContext) -> Self
+    {
+        self.device.get_or_insert_with(|| Box::new(device));
+        self
+    }
+
+    fn operation_mut(&mut self) -> &mut OperationContext
+    {
+        self.operation.get_or_insert_with(Default::default)
+    }
+
+    /// Records the command this error happened in, unless that's already known.
+    pub fn in_command(mut self, command: &str) -> Self
+    {
+        self.operation_mut().command.get_or_insert_with(|| command.to_string());
+        self
+    }
+
+    /// Records the phase of the operation this error happened in, unless that's already known.
+    pub fn in_phase(mut self, phase: &str) -> Self
+    {
+        self.operation_mut().phase.get_or_insert_with(|| phase.to_string());
+        self
+    }
+
+    /// Records which attempt at the operation this error happened on, unless that's already known.
+    pub fn on_attempt(mut self, attempt: u32) -> Self
+    {
+        self.operation_mut().attempt.get_or_insert(attempt);
+        self
+    }
+
+    /// Returns the breadcrumbs of where this error happened, with the probe's serial number from
+    /// [Error::device] if it wasn't recorded with the operation.
+    pub fn breadcrumbs(&self) -> Option<OperationContext>
+    {
+        let mut operation = self.operation.as_deref().cloned().unwrap_or_default();
+        if operation.serial.is_none() {
+            operation.serial = self.device.as_ref().and_then(|device| device.serial.clone());
+        }
+
+        (!operation.is_empty()).then_some(operation)
+    }
+
     #[allow(dead_code)]
     /// Removes previously added context.
     pub fn without_ctx(mut self) -> Self
@@ -205,6 +475,13 @@ impl Display for Error
             write!(f, "{}", self.kind)?;
         }
 
+        if let Some(operation) = self.operation.as_deref().filter(|operation| !operation.is_empty()) {
+            write!(f, "\nOperation: {}", operation)?;
+        }
+        if let Some(device) = &self.device {
+            write!(f, "\nProbe: {}", device)?;
+        }
+
         #[cfg(feature = "backtrace")]
         {
             if self.backtrace.status() == BacktraceStatus::Captured {
@@ -222,9 +499,15 @@ impl Display for Error
 
 impl StdError for Error
 {
+    /// The error this one was made from, or for an [ErrorKind::External] error without one, the
+    /// external error itself, so the whole chain can be walked to the root cause.
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
     {
-        self.source.as_deref().map(|e| e as &dyn StdError)
+        match (&self.source, &self.kind) {
+            (Some(source), _) => Some(source.as_ref() as &dyn StdError),
+            (None, ErrorKind::External(source)) => source.source_error(),
+            (None, _) => None,
+        }
     }
 }
 
@@ -240,61 +523,23 @@ impl From<rusb::Error> for Error
     }
 }
 
-impl From<dfu_libusb::Error> for Error
+impl From<DfuError> for Error
 {
-    fn from(other: dfu_libusb::Error) -> Self
+    fn from(other: DfuError) -> Self
     {
         use ErrorKind::*;
-        use dfu_libusb::Error as Source;
         match other {
-            Source::LibUsb(source) => {
-                External(ErrorSource::Libusb(source)).error_from(other)
-            },
-            Source::MissingLanguage => {
-                DeviceSeemsInvalid(S!("no string descriptor languages"))
-                    .error_from(other)
-            },
-            Source::InvalidAlt => {
-                DeviceSeemsInvalid(S!("DFU interface (alt mode) not found"))
+            DfuError::Usb(source) => Error::from(source),
+            DfuError::InvalidMemoryLayout(_) | DfuError::AddressOutOfRange(_) => {
+                DeviceSeemsInvalid(InvalidDeviceData::BadMemoryLayout)
                     .error_from(other)
             },
-            Source::InvalidInterface => {
-                DeviceSeemsInvalid(S!("DFU interface not found"))
-                    .error_from(other)
-            },
-            Source::FunctionalDescriptor(source) => {
-                DeviceSeemsInvalid(S!("DFU functional interface descriptor"))
-                    .error_from(source)
-            },
-            anything_else => {
-                External(ErrorSource::DfuLibusb(anything_else))
+            DfuError::VerificationMismatch(address) => {
+                FirmwareVerificationFailed(address)
                     .error()
             },
-        }
-    }
-}
-
-impl From<dfu_core::Error> for Error
-{
-    fn from(other: dfu_core::Error) -> Self
-    {
-        use ErrorKind::*;
-        use dfu_core::Error as Source;
-        match other {
-            Source::MemoryLayout(source) => {
-                DeviceSeemsInvalid(String::from("DFU interface memory layout string"))
-                    .error_from(source)
-            },
-            Source::InvalidAddress => {
-                DeviceSeemsInvalid(S!("DFU interface memory layout string"))
-                    .error_from(other)
-            },
-            Source::InvalidInterfaceString => {
-                DeviceSeemsInvalid(S!("DFU interface memory layout string"))
-                    .error_from(other)
-            },
             anything_else => {
-                External(ErrorSource::DfuCore(anything_else))
+                External(ErrorSource::Dfu(anything_else))
                     .error()
             },
         }
@@ -313,6 +558,78 @@ impl From<goblin::error::Error> for Error
 }
 
 
+/// Process exit codes, so scripts driving bmputil can tell failures apart.
+///
+/// These are part of bmputil's interface, and existing codes must not be renumbered.
+///
+/// | Code | Meaning |
+/// |------|---------|
+/// | 0    | Success |
+/// | 1    | Any other error |
+/// | 2    | Invalid command line usage, or a risky operation was not confirmed |
+/// | 3    | No matching Black Magic Probe device found |
+/// | 4    | More than one matching device found for an operation that needs exactly one |
+/// | 5    | Permission denied accessing the device or a file |
+/// | 6    | Firmware file unreadable, invalid, or not signed as required |
+/// | 7    | Flashing failed, e.g. the device disconnected or reported an error partway through; once firmware is being written (or read back), this is the code for any USB error |
+/// | 8    | Firmware read back from the device did not match what was written |
+/// | 9    | Device did not come back online after rebooting |
+/// | 10   | Operation not supported by the device or its firmware |
+/// | 11   | Device responded unexpectedly |
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum ExitCode
+{
+    Failure = 1,
+    Usage = 2,
+    DeviceNotFound = 3,
+    TooManyDevices = 4,
+    PermissionDenied = 5,
+    InvalidFirmware = 6,
+    FlashFailed = 7,
+    VerificationFailed = 8,
+    DeviceReboot = 9,
+    NotSupported = 10,
+    DeviceCommunication = 11,
+}
+
+impl ExitCode
+{
+    /// Exits the process with this code.
+    pub fn exit(self) -> !
+    {
+        std::process::exit(self as i32)
+    }
+}
+
+impl Error
+{
+    /// Returns the process exit code for this error.
+    pub fn exit_code(&self) -> ExitCode
+    {
+        use ErrorKind::*;
+        match &self.kind {
+            FirmwareFileIo(_) | InvalidFirmware(_) | FirmwareSignature(_) => ExitCode::InvalidFirmware,
+            FirmwareVerificationFailed(_) => ExitCode::VerificationFailed,
+            OperationNotSupported(_) => ExitCode::NotSupported,
+            TooManyDevices => ExitCode::TooManyDevices,
+            DeviceNotFound => ExitCode::DeviceNotFound,
+            DeviceDisconnectDuringOperation | FlashFailed => ExitCode::FlashFailed,
+            DeviceReboot => ExitCode::DeviceReboot,
+            DeviceSeemsInvalid(_) | GdbProtocol(_) => ExitCode::DeviceCommunication,
+            NotConfirmed(_) => ExitCode::Usage,
+            TaskPanicked(..) => ExitCode::Failure,
+            External(ErrorSource::Libusb(rusb::Error::Access)) => ExitCode::PermissionDenied,
+            External(ErrorSource::StdIo(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                ExitCode::PermissionDenied
+            },
+            External(ErrorSource::Dfu(_)) => ExitCode::FlashFailed,
+            External(_) => ExitCode::Failure,
+        }
+    }
+}
+
+
 /// Sources of external error in this library.
 #[derive(Debug, Error)]
 pub enum ErrorSource
@@ -324,13 +641,30 @@ pub enum ErrorSource
     Libusb(#[from] rusb::Error),
 
     #[error(transparent)]
-    DfuLibusb(#[from] dfu_libusb::Error),
+    Dfu(#[from] DfuError),
 
     #[error(transparent)]
-    DfuCore(#[from] dfu_core::Error),
+    Goblin(#[from] goblin::error::Error),
 
+    /// Boxed, as it is much larger than the other sources.
     #[error(transparent)]
-    Goblin(#[from] goblin::error::Error),
+    Toml(Box<toml::de::Error>),
+}
+
+impl ErrorSource
+{
+    /// The external error itself.
+    fn source_error(&self) -> Option<&(dyn StdError + 'static)>
+    {
+        use ErrorSource::*;
+        match self {
+            StdIo(e) => Some(e),
+            Libusb(e) => Some(e),
+            Dfu(e) => Some(e),
+            Goblin(e) => Some(e),
+            Toml(e) => Some(e.as_ref()),
+        }
+    }
 }
 
 
@@ -0,0 +1,642 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing the whole flashing process as an explicit pipeline of stages:
+//! detach into DFU mode, wait for the device to re-enumerate, download the firmware, and wait for
+//! the device to come back in runtime mode.
+//!
+//! The pipeline doesn't talk to USB or read the time directly, but goes through a [ProbeBackend]
+//! and a [Clock], so waiting for the probe and its timeouts can be exercised without real hardware.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{trace, debug, info, error};
+use rusb::{UsbContext, Hotplug, HotplugBuilder, Registration};
+
+pub use crate::clock::{Clock, SystemClock};
+use crate::profiles;
+use crate::bmp::{BmpDevice, BmpMatcher, DownloadOptions, FirmwareType, ProbeIdentity, RebootTarget};
+use crate::error::{Error, ErrorKind, ErrorSource, ResErrorKind};
+use crate::dfu::DownloadProgress;
+use crate::timeouts::Timeouts;
+use crate::usb::{DfuOperatingMode, Vid, Pid};
+
+type UsbDevice = rusb::Device<rusb::Context>;
+
+/// How often to look for a re-enumerating device without a hotplug notification.
+///
+/// This is the only way of noticing the device without hotplug support, and a safety net for
+/// missed notifications with it.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const HOTPLUG_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// The operations the flashing pipeline needs to perform on probes.
+pub trait ProbeBackend
+{
+    type Probe;
+
+    fn operating_mode(&self, probe: &Self::Probe) -> DfuOperatingMode;
+
+    fn identity(&self, probe: &Self::Probe) -> ProbeIdentity;
+
+    /// Asks the probe to switch between runtime and DFU mode, giving it up in the process.
+    fn detach(&mut self, probe: Self::Probe) -> Result<(), Error>;
+
+    /// Looks for the probe with the given identity once, returning [`ErrorKind::DeviceNotFound`]
+    /// if it isn't (yet) there.
+    ///
+    /// If `verbose` is set, diagnostics about why the probe couldn't be found are logged.
+    fn find(&mut self, identity: &ProbeIdentity, operation: &str, verbose: bool) -> Result<Self::Probe, Error>;
+
+    /// Downloads firmware onto a probe in DFU mode, including manifestation.
+    fn download(
+        &mut self,
+        probe: &mut Self::Probe,
+        firmware: &[u8],
+        firmware_type: FirmwareType,
+        options: &DownloadOptions,
+        progress: &dyn Fn(DownloadProgress),
+    ) -> Result<(), Error>;
+
+    /// Blocks until something may have changed on the bus, but for no longer than `max`.
+    fn wait_for_change<C: Clock>(&mut self, clock: &C, max: Duration)
+    {
+        clock.sleep(POLL_INTERVAL.min(max));
+    }
+}
+
+
+/// Hotplug callback that notes when any Black Magic Probe device arrives.
+struct ProbeArrivalWatcher
+{
+    arrived: Arc<AtomicBool>,
+}
+
+impl Hotplug<rusb::Context> for ProbeArrivalWatcher
+{
+    fn device_arrived(&mut self, device: UsbDevice)
+    {
+        let Ok(desc) = device.device_descriptor() else {
+            return;
+        };
+
+        if profiles::find(Vid(desc.vendor_id()), Pid(desc.product_id())).is_some() {
+            trace!("Hotplug: Black Magic Probe device arrived on bus {}", device.bus_number());
+            self.arrived.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn device_left(&mut self, _device: UsbDevice)
+    { }
+}
+
+
+/// The real USB backend, working with [BmpDevice]s.
+///
+/// Where libusb supports hotplug notifications, [ProbeBackend::wait_for_change] returns as soon
+/// as a probe arrives; otherwise, it polls every `POLL_INTERVAL`.
+pub struct UsbBackend
+{
+    hotplug: Option<(rusb::Context, Registration<rusb::Context>)>,
+    arrived: Arc<AtomicBool>,
+    /// Timeouts for the devices this backend finds.
+    timeouts: Timeouts,
+}
+
+impl UsbBackend
+{
+    /// Sets up a backend, failing only if libusb itself can't be initialised. If hotplug
+    /// notifications aren't available, the backend polls instead.
+    pub fn new() -> Result<Self, Error>
+    {
+        // Check libusb works at all first, as asking about hotplug support would otherwise panic.
+        let context = crate::usb::new_context()
+            .map_err(|e| ErrorKind::External(ErrorSource::Libusb(e)).error().with_ctx("initializing libusb"))?;
+
+        // Register for hotplug notifications up front, so we can't miss the device arriving
+        // between a scan and waiting for the next one.
+        let arrived = Arc::new(AtomicBool::new(false));
+        let hotplug = if crate::usb::has_hotplug(&context) {
+            let watcher = ProbeArrivalWatcher {
+                arrived: Arc::clone(&arrived),
+            };
+            HotplugBuilder::new()
+                .enumerate(false)
+                .register(&context, Box::new(watcher))
+                .inspect_err(|e| debug!("Failed to register for hotplug notifications, falling back to polling: {}", e))
+                .ok()
+                .map(|registration| (context, registration))
+        } else {
+            debug!("libusb does not support hotplug notifications on this platform, falling back to polling");
+            None
+        };
+
+        Ok(Self {
+            hotplug,
+            arrived,
+            timeouts: Timeouts::default(),
+        })
+    }
+
+    /// Set the timeouts for talking to the devices this backend finds.
+    #[must_use]
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self
+    {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Get the timeouts previously set with `.timeouts()`.
+    #[allow(dead_code)]
+    pub fn get_timeouts(&self) -> Timeouts
+    {
+        self.timeouts
+    }
+
+    /// Set whether to rely on hotplug notifications where libusb supports them. Defaults to true;
+    /// without them, the bus is polled.
+    #[must_use]
+    pub fn hotplug(mut self, enabled: bool) -> Self
+    {
+        if !enabled {
+            self.hotplug = None;
+        }
+        self
+    }
+
+    /// Get whether hotplug notifications are being relied on.
+    #[allow(dead_code)]
+    pub fn get_hotplug(&self) -> bool
+    {
+        self.hotplug.is_some()
+    }
+}
+
+impl ProbeBackend for UsbBackend
+{
+    type Probe = BmpDevice;
+
+    fn operating_mode(&self, probe: &BmpDevice) -> DfuOperatingMode
+    {
+        probe.operating_mode()
+    }
+
+    fn identity(&self, probe: &BmpDevice) -> ProbeIdentity
+    {
+        probe.identity()
+    }
+
+    fn detach(&mut self, mut probe: BmpDevice) -> Result<(), Error>
+    {
+        probe.send_detach()
+    }
+
+    fn find(&mut self, identity: &ProbeIdentity, operation: &str, verbose: bool) -> Result<BmpDevice, Error>
+    {
+        let mut results = BmpMatcher::new().timeouts(self.timeouts).find_matching_probes();
+        results.found = identity.select(std::mem::take(&mut results.found));
+
+        let probe = if verbose {
+            results.pop_single(operation)
+        } else {
+            results.pop_single_silent()
+        }?;
+        if probe.quirks().no_hotplug && self.hotplug.take().is_some() {
+            debug!("Probe is behind a hub with unreliable hotplug notifications, polling instead");
+        }
+
+        Ok(probe)
+    }
+
+    fn download(
+        &mut self,
+        probe: &mut BmpDevice,
+        firmware: &[u8],
+        firmware_type: FirmwareType,
+        options: &DownloadOptions,
+        progress: &dyn Fn(DownloadProgress),
+    ) -> Result<(), Error>
+    {
+        let length = u32::try_from(firmware.len())
+            .map_err(|_| {
+                ErrorKind::InvalidFirmware(Some(format!("{} bytes is more than a probe's address space", firmware.len())))
+                    .error()
+                    .with_ctx("checking firmware size")
+            })?;
+
+        probe.download(firmware, length, firmware_type, options, progress)
+    }
+
+    fn wait_for_change<C: Clock>(&mut self, clock: &C, max: Duration)
+    {
+        let (context, _registration) = match &self.hotplug {
+            Some(hotplug) => hotplug,
+            None => {
+                // Hardware is a bottleneck and we don't need to peg the CPU waiting for it to come back up.
+                clock.sleep(POLL_INTERVAL.min(max));
+                return;
+            },
+        };
+
+        // Block until something happens on the bus (or it's time for a safety-net rescan).
+        let rescan_at = clock.now() + HOTPLUG_RESCAN_INTERVAL.min(max);
+        while !self.arrived.swap(false, Ordering::SeqCst) {
+            let now = clock.now();
+            if now >= rescan_at {
+                break;
+            }
+            if let Err(e) = context.handle_events(Some(rescan_at - now)) {
+                debug!("Error handling libusb events while waiting for probe: {}", e);
+                clock.sleep(POLL_INTERVAL.min(rescan_at - now));
+            }
+        }
+    }
+}
+
+
+/// Waits for the probe with the given identity to (re-)appear, erroring after `timeout`.
+///
+/// Diagnostics about why the probe isn't found are only logged once over half of the timeout
+/// has passed, as the probe not being there is expected for a little while.
+pub fn wait_for_probe<B, C>(
+    backend: &mut B,
+    clock: &C,
+    identity: &ProbeIdentity,
+    timeout: Duration,
+    operation: &str,
+) -> Result<B::Probe, Error>
+where
+    B: ProbeBackend,
+    C: Clock,
+{
+    let start = clock.now();
+    let deadline = start + timeout;
+    let silence_deadline = start + timeout / 2;
+
+    loop {
+        let now = clock.now();
+        let res = backend.find(identity, operation, now > silence_deadline);
+        if !matches!(res.err_kind(), Err(ErrorKind::DeviceNotFound)) {
+            return res;
+        }
+
+        let now = clock.now();
+        trace!("Waiting for probe: {} ms", (now - start).as_millis());
+
+        // If it's been more than the timeout length, error out.
+        if now >= deadline {
+            error!("Timed-out waiting for Black Magic Probe to re-enumerate!");
+            return Err(ErrorKind::DeviceReboot.error_from(res.err().unwrap()));
+        }
+
+        backend.wait_for_change(clock, deadline - now);
+    }
+}
+
+
+/// The stages of the flashing pipeline, in order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FlashStage
+{
+    /// Asking the probe to switch from runtime to DFU mode.
+    Detach,
+    /// Waiting for the probe to re-enumerate in DFU mode.
+    WaitForDfu,
+    /// Erasing, writing, (optionally) verifying, and manifesting the firmware.
+    Download,
+    /// Waiting for the probe to re-enumerate running the new firmware.
+    WaitForRuntime,
+    /// Finished.
+    Done,
+}
+
+impl FlashStage
+{
+    /// The name of the stage, as recorded in the [breadcrumbs](Error::breadcrumbs) of errors in it.
+    pub fn name(&self) -> &'static str
+    {
+        match self {
+            FlashStage::Detach => "detach",
+            FlashStage::WaitForDfu => "wait-for-dfu",
+            FlashStage::Download => "download",
+            FlashStage::WaitForRuntime => "wait-for-runtime",
+            FlashStage::Done => "done",
+        }
+    }
+}
+
+
+/// The whole flashing process, from a probe in either mode to one running the new firmware.
+pub struct FlashPipeline<'f, B: ProbeBackend, C: Clock>
+{
+    backend: B,
+    clock: C,
+    firmware: &'f [u8],
+    firmware_type: FirmwareType,
+    options: DownloadOptions,
+    /// How long to give the probe to disappear from the bus after asking it to detach.
+    detach_settle_time: Duration,
+    /// How long to give the probe to disappear from the bus after downloading.
+    download_settle_time: Duration,
+    /// How long to wait for the probe to re-enumerate in each waiting stage.
+    enumerate_timeout: Duration,
+}
+
+impl<'f, B: ProbeBackend, C: Clock> FlashPipeline<'f, B, C>
+{
+    pub fn new(backend: B, clock: C, firmware: &'f [u8], firmware_type: FirmwareType, options: DownloadOptions) -> Self
+    {
+        Self {
+            backend,
+            clock,
+            firmware,
+            firmware_type,
+            options,
+            detach_settle_time: Duration::from_millis(500),
+            download_settle_time: Duration::from_millis(250),
+            enumerate_timeout: Timeouts::default().get_enumerate(),
+        }
+    }
+
+    #[must_use]
+    #[allow(dead_code)]
+    pub fn enumerate_timeout(mut self, timeout: Duration) -> Self
+    {
+        self.enumerate_timeout = timeout;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn get_enumerate_timeout(&self) -> Duration
+    {
+        self.enumerate_timeout
+    }
+
+    /// Runs the pipeline on `probe`, returning the probe running the new firmware (or still in DFU
+    /// mode, if the options asked for [`RebootTarget::Dfu`]).
+    ///
+    /// `progress` is called as the download progresses, as in [BmpDevice::download]. Errors record
+    /// the stage they happened in as their phase.
+    pub fn run<P>(mut self, probe: B::Probe, progress: P) -> Result<B::Probe, Error>
+    where
+        P: Fn(DownloadProgress),
+    {
+        let mut identity = self.backend.identity(&probe);
+        let mut stage = match self.backend.operating_mode(&probe) {
+            DfuOperatingMode::Runtime => FlashStage::Detach,
+            DfuOperatingMode::FirmwareUpgrade => FlashStage::Download,
+        };
+        let mut probe = Some(probe);
+
+        loop {
+            debug!("Flash stage: {:?}", stage);
+            if stage == FlashStage::Done {
+                return Ok(probe.take().expect("Done stage must have a probe"));
+            }
+
+            stage = self.run_stage(stage, &mut probe, &mut identity, &progress)
+                .map_err(|e| e.in_phase(stage.name()))?;
+        }
+    }
+
+    fn report_complete(&self)
+    {
+        if self.options.get_verify() {
+            info!("Flash complete and verified!");
+        } else {
+            info!("Flash complete!");
+        }
+    }
+
+    /// Runs one stage of the pipeline, returning the next.
+    fn run_stage<P>(
+        &mut self,
+        stage: FlashStage,
+        probe: &mut Option<B::Probe>,
+        identity: &mut ProbeIdentity,
+        progress: &P,
+    ) -> Result<FlashStage, Error>
+    where
+        P: Fn(DownloadProgress),
+    {
+        let next = match stage {
+            FlashStage::Detach => {
+                let probe = probe.take().expect("Detach stage must have a probe");
+                self.backend.detach(probe)
+                    .map_err(|e| e.with_ctx("detaching device for download"))?;
+                identity.departing();
+                self.clock.sleep(self.detach_settle_time);
+
+                FlashStage::WaitForDfu
+            },
+            FlashStage::WaitForDfu => {
+                let dfu_probe = wait_for_probe(
+                    &mut self.backend,
+                    &self.clock,
+                    identity,
+                    self.enumerate_timeout,
+                    "flash",
+                )?;
+
+                // The probe may well have moved, so follow it.
+                identity.update_from(&self.backend.identity(&dfu_probe));
+                *probe = Some(dfu_probe);
+
+                FlashStage::Download
+            },
+            FlashStage::Download => {
+                let mut dfu_probe = probe.take().expect("Download stage must have a probe");
+                self.backend.download(&mut dfu_probe, self.firmware, self.firmware_type, &self.options, progress)?;
+
+                if self.options.get_reboot_to() == RebootTarget::Dfu {
+                    // Nothing to wait for, as the probe stays in DFU mode.
+                    *probe = Some(dfu_probe);
+                    self.report_complete();
+
+                    FlashStage::Done
+                } else {
+                    // Force libusb to free the device before it re-enumerates.
+                    drop(dfu_probe);
+                    identity.departing();
+                    self.clock.sleep(self.download_settle_time);
+
+                    FlashStage::WaitForRuntime
+                }
+            },
+            FlashStage::WaitForRuntime => {
+                let runtime_probe = wait_for_probe(
+                    &mut self.backend,
+                    &self.clock,
+                    identity,
+                    self.enumerate_timeout,
+                    "flash",
+                )
+                .inspect_err(|_| {
+                    error!("Black Magic Probe did not re-enumerate after flashing! Invalid firmware?");
+                })?;
+                *probe = Some(runtime_probe);
+                // Only now that the probe is back is the flash known to have worked.
+                self.report_complete();
+
+                FlashStage::Done
+            },
+            FlashStage::Done => FlashStage::Done,
+        };
+
+        Ok(next)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::cell::Cell;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::clock::ManualClock;
+
+    struct FakeProbe
+    {
+        mode: DfuOperatingMode,
+    }
+
+    /// A probe that takes `reenumerate_after` to come back after detaching or downloading, and
+    /// whose first `download_failures` downloads fail.
+    struct FakeBackend<'c>
+    {
+        clock: &'c ManualClock,
+        /// How long the probe is gone for after detaching or downloading, or `None` for forever.
+        reenumerate_after: Option<Duration>,
+        /// When the probe is back, and in which mode.
+        back_at: Option<(Instant, DfuOperatingMode)>,
+        download_failures: u32,
+        /// Shared with the test, as the pipeline takes the backend.
+        download_attempts: &'c Cell<u32>,
+    }
+
+    impl<'c> FakeBackend<'c>
+    {
+        fn new(clock: &'c ManualClock, download_attempts: &'c Cell<u32>, reenumerate_after: Option<Duration>) -> Self
+        {
+            Self {
+                clock,
+                reenumerate_after,
+                back_at: None,
+                download_failures: 0,
+                download_attempts,
+            }
+        }
+
+        fn leave(&mut self, mode: DfuOperatingMode)
+        {
+            self.back_at = self.reenumerate_after.map(|after| (self.clock.now() + after, mode));
+        }
+    }
+
+    impl ProbeBackend for FakeBackend<'_>
+    {
+        type Probe = FakeProbe;
+
+        fn operating_mode(&self, probe: &FakeProbe) -> DfuOperatingMode
+        {
+            probe.mode
+        }
+
+        fn identity(&self, _probe: &FakeProbe) -> ProbeIdentity
+        {
+            ProbeIdentity::at_port("1-2")
+        }
+
+        fn detach(&mut self, _probe: FakeProbe) -> Result<(), Error>
+        {
+            self.leave(DfuOperatingMode::FirmwareUpgrade);
+            Ok(())
+        }
+
+        fn find(&mut self, _identity: &ProbeIdentity, _operation: &str, _verbose: bool) -> Result<FakeProbe, Error>
+        {
+            match self.back_at {
+                Some((at, mode)) if self.clock.now() >= at => Ok(FakeProbe { mode }),
+                _ => Err(ErrorKind::DeviceNotFound.error()),
+            }
+        }
+
+        fn download(
+            &mut self,
+            _probe: &mut FakeProbe,
+            _firmware: &[u8],
+            _firmware_type: FirmwareType,
+            _options: &DownloadOptions,
+            _progress: &dyn Fn(DownloadProgress),
+        ) -> Result<(), Error>
+        {
+            self.download_attempts.set(self.download_attempts.get() + 1);
+            if self.download_attempts.get() <= self.download_failures {
+                return Err(ErrorKind::FlashFailed.error());
+            }
+            self.leave(DfuOperatingMode::Runtime);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn wait_for_probe_times_out_at_deadline()
+    {
+        let clock = ManualClock::new();
+        let attempts = Cell::new(0);
+        let mut backend = FakeBackend::new(&clock, &attempts, None);
+        let start = clock.now();
+
+        // Not a multiple of the polling interval, so the last wait has to be cut short.
+        let timeout = Duration::from_millis(1050);
+        let res = wait_for_probe(&mut backend, &clock, &ProbeIdentity::at_port("1-2"), timeout, "test");
+
+        assert!(matches!(res.err_kind(), Err(ErrorKind::DeviceReboot)));
+        assert_eq!(clock.now() - start, timeout);
+    }
+
+    #[test]
+    fn failed_download_records_its_phase()
+    {
+        let clock = ManualClock::new();
+        let attempts = Cell::new(0);
+        let mut backend = FakeBackend::new(&clock, &attempts, Some(Duration::ZERO));
+        backend.download_failures = 1;
+
+        let pipeline = FlashPipeline::new(backend, &clock, &[0; 16], FirmwareType::Application, DownloadOptions::new());
+        let Err(err) = pipeline.run(FakeProbe { mode: DfuOperatingMode::FirmwareUpgrade }, |_| ()) else {
+            panic!("download should have failed");
+        };
+
+        assert!(matches!(err.kind, ErrorKind::FlashFailed));
+        assert_eq!(err.breadcrumbs().and_then(|crumbs| crumbs.phase).as_deref(), Some("download"));
+        // Retrying is up to the backend (see the DfuInterface tests), so the pipeline only tries once.
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn probe_reenumerating_before_deadline_succeeds()
+    {
+        let clock = ManualClock::new();
+        let attempts = Cell::new(0);
+        let backend = FakeBackend::new(&clock, &attempts, Some(Duration::from_millis(700)));
+        let start = clock.now();
+
+        let pipeline = FlashPipeline::new(backend, &clock, &[0; 16], FirmwareType::Application, DownloadOptions::new())
+            .enumerate_timeout(Duration::from_secs(5));
+        let probe = pipeline.run(FakeProbe { mode: DfuOperatingMode::Runtime }, |_| ()).unwrap();
+
+        assert_eq!(probe.mode, DfuOperatingMode::Runtime);
+        assert_eq!(attempts.get(), 1);
+        // Detached at 0 ms and back at 700 ms: found on the first poll after settling for 500 ms.
+        // The download ends at 700 ms, so the probe is back at 1400 ms, and found on the polls
+        // every 200 ms after settling for 250 ms, at 1550 ms.
+        assert_eq!(clock.now() - start, Duration::from_millis(1550));
+    }
+}
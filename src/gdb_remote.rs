@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing just enough of the GDB Remote Serial Protocol to run monitor commands on a
+//! Black Magic Probe over its GDB serial port.
+//!
+//! \[[GDB Remote Serial Protocol](https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html)\]
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use log::{trace, debug};
+
+use crate::S;
+use crate::error::{Error, ErrorKind, ErrorSource};
+use crate::serial_port::raw as serial;
+
+/// How long to wait for the probe to respond to a packet.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum size of the buffer we read serial data into at once.
+const READ_CHUNK_SIZE: usize = 1024;
+
+fn io_error(e: io::Error) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(e)).error()
+}
+
+
+/// A connection to the GDB server of a Black Magic Probe.
+pub struct GdbRemote
+{
+    port: File,
+    /// Data read from the port but not yet consumed.
+    pending: Vec<u8>,
+}
+
+impl GdbRemote
+{
+    /// Opens the GDB serial port at `path` (as returned by
+    /// [`find_serial_port`](crate::serial_port::find_serial_port)).
+    pub fn open(path: &str) -> Result<Self, Error>
+    {
+        debug!("Opening GDB serial port {}", path);
+        let port = serial::open(path)
+            .map_err(|e| io_error(e).with_ctx(&format!("opening GDB serial port {}", path)))?;
+
+        let mut remote = Self {
+            port,
+            pending: Vec::new(),
+        };
+
+        // Throw away anything left over from a previous session.
+        remote.fill(Duration::from_millis(50))?;
+        remote.pending.clear();
+
+        Ok(remote)
+    }
+
+    /// Reads whatever data arrives within `timeout` into `self.pending`, returning how much was read.
+    fn fill(&mut self, timeout: Duration) -> Result<usize, Error>
+    {
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        let read = serial::read_timeout(&mut self.port, &mut buf, timeout).map_err(io_error)?;
+        self.pending.extend_from_slice(&buf[..read]);
+
+        Ok(read)
+    }
+
+    /// Sends a packet with the given payload, and waits for the probe to acknowledge it.
+    fn send_packet(&mut self, payload: &[u8]) -> Result<(), Error>
+    {
+        let checksum = payload.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        let mut packet = Vec::with_capacity(payload.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(payload);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+
+        trace!("GDB -> {}", String::from_utf8_lossy(&packet));
+        self.port.write_all(&packet).map_err(io_error)?;
+
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        loop {
+            match self.pending.first() {
+                Some(b'+') => {
+                    self.pending.remove(0);
+                    return Ok(());
+                },
+                Some(b'-') => {
+                    return Err(ErrorKind::GdbProtocol(S!("packet was not acknowledged")).error());
+                },
+                Some(_) => {
+                    // Anything else before the acknowledgement is noise.
+                    self.pending.remove(0);
+                },
+                None => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ErrorKind::GdbProtocol(S!("timed out waiting for acknowledgement")).error());
+                    }
+                    self.fill(deadline - now)?;
+                },
+            }
+        }
+    }
+
+    /// Waits for a packet from the probe, acknowledges it, and returns its (unescaped) payload.
+    fn receive_packet(&mut self) -> Result<Vec<u8>, Error>
+    {
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        loop {
+            // Find a complete packet: `$`, payload, `#`, and two checksum digits.
+            if let Some(start) = self.pending.iter().position(|&b| b == b'$') {
+                if let Some(end) = self.pending[start..].iter().position(|&b| b == b'#').map(|end| start + end) {
+                    if self.pending.len() >= end + 3 {
+                        let raw: Vec<u8> = self.pending.drain(..end + 3).collect();
+                        let payload = &raw[start + 1..end];
+                        let checksum = std::str::from_utf8(&raw[end + 1..])
+                            .ok()
+                            .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                        trace!("GDB <- {}", String::from_utf8_lossy(&raw[start..]));
+
+                        if checksum != Some(payload.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))) {
+                            self.port.write_all(b"-").map_err(io_error)?;
+                            return Err(ErrorKind::GdbProtocol(S!("bad packet checksum")).error());
+                        }
+                        self.port.write_all(b"+").map_err(io_error)?;
+
+                        return Ok(unescape(payload));
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ErrorKind::GdbProtocol(S!("timed out waiting for response")).error());
+            }
+            self.fill(deadline - now)?;
+        }
+    }
+
+    /// Runs a monitor command (as in GDB's `monitor <command>`), and returns its output.
+    ///
+    /// Returns [`ErrorKind::OperationNotSupported`] if the firmware doesn't know the command.
+    pub fn monitor(&mut self, command: &str) -> Result<String, Error>
+    {
+        debug!("Running monitor command {:?}", command);
+        let mut payload = b"qRcmd,".to_vec();
+        payload.extend_from_slice(hex_encode(command.as_bytes()).as_bytes());
+        self.send_packet(&payload)?;
+
+        // The command's output comes as any number of `O` packets, followed by the result.
+        let mut output = Vec::new();
+        loop {
+            let packet = self.receive_packet()?;
+            match packet.as_slice() {
+                [b'O', b'K'] => break,
+                [b'O', hex @ ..] => {
+                    let decoded = hex_decode(hex)
+                        .ok_or_else(|| ErrorKind::GdbProtocol(S!("invalid hex in console output")).error())?;
+                    output.extend_from_slice(&decoded);
+                },
+                // An empty response means the command isn't supported.
+                [] => {
+                    return Err(ErrorKind::OperationNotSupported(format!("monitor command {:?}", command)).error());
+                },
+                [b'E', ..] => {
+                    let output = String::from_utf8_lossy(&output);
+                    return Err(ErrorKind::GdbProtocol(format!(
+                        "monitor command {:?} failed ({}): {}",
+                        command,
+                        String::from_utf8_lossy(&packet),
+                        output.trim(),
+                    )).error());
+                },
+                other => {
+                    return Err(ErrorKind::GdbProtocol(format!(
+                        "unexpected reply {:?} to monitor command",
+                        String::from_utf8_lossy(other),
+                    )).error());
+                },
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+}
+
+
+fn hex_encode(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &[u8]) -> Option<Vec<u8>>
+{
+    hex.chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// Undoes the escaping of `#`, `$`, `}`, and `*` in packet payloads.
+fn unescape(payload: &[u8]) -> Vec<u8>
+{
+    let mut unescaped = Vec::with_capacity(payload.len());
+    let mut bytes = payload.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'}' {
+            if let Some(&escaped) = bytes.next() {
+                unescaped.push(escaped ^ 0x20);
+            }
+        } else {
+            unescaped.push(b);
+        }
+    }
+
+    unescaped
+}
+
@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil gui`, a small graphical frontend for updating a probe's firmware
+//! without the command line. Only built with the `gui` feature.
+//!
+//! It lists the connected probes and their firmware versions, and flashes a chosen firmware file
+//! onto the selected one after the same checks and through the same [`FlashPipeline`] as
+//! `bmputil flash`, on a separate thread so the window stays responsive.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use clap::ArgMatches;
+use eframe::egui;
+
+use bmputil::bmp::{BmpMatcher, DownloadOptions, FirmwareType, ProbeInfo};
+use bmputil::dfu::DownloadProgress;
+use bmputil::error::{Error, ErrorKind};
+use bmputil::flasher::{FlashPipeline, SystemClock, UsbBackend};
+use bmputil::S;
+
+use crate::bmda;
+use crate::confirm::ConfirmationPolicy;
+
+/// A connected probe, as listed in the window.
+struct ListedProbe
+{
+    description: String,
+    info: ProbeInfo,
+}
+
+/// How an update started from the window is going.
+#[derive(Debug, Clone)]
+enum UpdateStatus
+{
+    Idle,
+    Running(Option<DownloadProgress>),
+    /// Finished, with the new firmware version.
+    Done(String),
+    Failed(String),
+}
+
+struct GuiApp
+{
+    probes: Vec<ListedProbe>,
+    /// Errors from the last time the probes were listed.
+    list_errors: Vec<String>,
+    selected: Option<usize>,
+    firmware_path: String,
+    status: Arc<Mutex<UpdateStatus>>,
+    /// What to do about checks that would ask for confirmation on the command line.
+    policy: ConfirmationPolicy,
+}
+
+impl GuiApp
+{
+    fn new(policy: ConfirmationPolicy) -> Self
+    {
+        let mut app = Self {
+            probes: Vec::new(),
+            list_errors: Vec::new(),
+            selected: None,
+            firmware_path: String::new(),
+            status: Arc::new(Mutex::new(UpdateStatus::Idle)),
+            policy,
+        };
+        app.refresh();
+
+        app
+    }
+
+    fn refresh(&mut self)
+    {
+        let results = BmpMatcher::new().find_matching_probes();
+        self.list_errors = results.errors.iter().map(ToString::to_string).collect();
+        self.probes = results.found
+            .iter()
+            .map(|dev| ListedProbe {
+                description: dev.to_string(),
+                info: dev.info(),
+            })
+            .collect();
+        self.selected = (self.probes.len() == 1).then_some(0);
+    }
+
+    fn is_running(&self) -> bool
+    {
+        matches!(*self.status.lock().unwrap(), UpdateStatus::Running(_))
+    }
+
+    fn start_update(&self, ctx: &egui::Context, probe: &ListedProbe)
+    {
+        *self.status.lock().unwrap() = UpdateStatus::Running(None);
+
+        let port = probe.info.port.clone();
+        let path = self.firmware_path.trim().to_string();
+        let status = Arc::clone(&self.status);
+        let ctx = ctx.clone();
+        let policy = self.policy;
+        thread::spawn(move || {
+            let res = {
+                let status = Arc::clone(&status);
+                let ctx = ctx.clone();
+                update_probe(&policy, &port, &path, move |progress| {
+                    *status.lock().unwrap() = UpdateStatus::Running(Some(progress));
+                    ctx.request_repaint();
+                })
+            };
+            *status.lock().unwrap() = match res {
+                Ok(version) => UpdateStatus::Done(version),
+                Err(e) => UpdateStatus::Failed(e.to_string()),
+            };
+            ctx.request_repaint();
+        });
+    }
+}
+
+/// Flashes the firmware at `path` onto the probe at USB port `port`, as `bmputil flash` would,
+/// returning the firmware version the probe then reports.
+///
+/// The image goes through the same checks as with `bmputil flash`, except that there's no
+/// `--force`: anything that would need it is an error, and anything that would ask for
+/// confirmation is refused, unless `policy` says yes.
+fn update_probe(policy: &ConfirmationPolicy, port: &str, path: &str, progress: impl Fn(DownloadProgress)) -> Result<String, Error>
+{
+    let firmware = crate::signature_policy(None)
+        .and_then(|policy| crate::read_signed_firmware_file(path, &policy))
+        .map_err(|e| e.with_ctx("reading firmware file to flash"))?;
+    let dev = BmpMatcher::new().port(port).find_matching_probes().pop_single("flash")?;
+
+    let firmware_type = FirmwareType::detect_from_firmware(dev.profile(), &firmware)
+        .map_err(|e| e.with_ctx("detecting firmware type"))?;
+    if firmware_type == FirmwareType::Bootloader {
+        return Err(ErrorKind::InvalidFirmware(Some(S!(
+            "firmware appears to be a bootloader; bootloaders can only be updated with `bmputil flash --bootloader`"
+        ))).error());
+    }
+    firmware.check_address(dev.profile(), firmware_type)?;
+    FirmwareType::validate_application(dev.profile(), &firmware)
+        .map_err(|e| e.with_ctx("validating firmware image"))?;
+    crate::check_hardware_target(policy, false, &dev, &firmware)?;
+    bmda::check_released(policy, false, &dev)?;
+
+    let pipeline = FlashPipeline::new(UsbBackend::new()?, SystemClock, &firmware, firmware_type, DownloadOptions::new());
+    let dev = pipeline.run(dev, progress)?;
+
+    Ok(dev.info().firmware_version.unwrap_or_else(|| S!("unknown")))
+}
+
+impl eframe::App for GuiApp
+{
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame)
+    {
+        // Accept a firmware file dropped onto the window.
+        if let Some(path) = ctx.input(|input| input.raw.dropped_files.first().and_then(|file| file.path.clone())) {
+            self.firmware_path = path.display().to_string();
+        }
+
+        let running = self.is_running();
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.heading("Connected probes");
+                if ui.add_enabled(!running, egui::Button::new("Refresh")).clicked() {
+                    self.refresh();
+                }
+            });
+
+            if self.probes.is_empty() {
+                ui.label("No Black Magic Probe found. Plug one in and press Refresh.");
+            }
+            for (index, probe) in self.probes.iter().enumerate() {
+                let version = probe.info.firmware_version.as_deref().unwrap_or("unknown");
+                let text = format!("{}\nFirmware: {}", probe.description, version);
+                ui.add_enabled_ui(!running, |ui| ui.radio_value(&mut self.selected, Some(index), text));
+            }
+            for error in &self.list_errors {
+                ui.colored_label(ui.visuals().warn_fg_color, error);
+            }
+
+            ui.separator();
+            ui.label("Firmware file (.bin or .elf), or drop one onto this window:");
+            ui.add_enabled(!running, egui::TextEdit::singleline(&mut self.firmware_path).desired_width(f32::INFINITY));
+
+            let selected = self.selected.and_then(|index| self.probes.get(index));
+            let can_update = !running && selected.is_some() && !self.firmware_path.trim().is_empty();
+            if ui.add_enabled(can_update, egui::Button::new("Update")).clicked() {
+                if let Some(probe) = selected {
+                    self.start_update(ctx, probe);
+                }
+            }
+
+            match self.status.lock().unwrap().clone() {
+                UpdateStatus::Idle => (),
+                UpdateStatus::Running(None) => {
+                    ui.label("Starting...");
+                },
+                UpdateStatus::Running(Some(progress)) if progress.total == 0 => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!("{}...", progress.phase));
+                    });
+                },
+                UpdateStatus::Running(Some(progress)) => {
+                    let fraction = progress.done as f32 / progress.total as f32;
+                    ui.add(egui::ProgressBar::new(fraction).show_percentage().text(progress.phase.to_string()));
+                },
+                UpdateStatus::Done(version) => {
+                    ui.label(format!("Update complete. The probe is now running firmware version {}.", version));
+                },
+                UpdateStatus::Failed(error) => {
+                    ui.colored_label(ui.visuals().error_fg_color, format!("Update failed: {}", error));
+                },
+            }
+        });
+
+        // Refresh the list once an update finishes, to show the new firmware version.
+        if running && !self.is_running() {
+            self.refresh();
+        }
+    }
+}
+
+/// Implements `bmputil gui`, returning once the window is closed.
+pub fn run(matches: &ArgMatches) -> Result<(), Error>
+{
+    let policy = ConfirmationPolicy::from_cli_args(matches).without_prompts();
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([480.0, 360.0]),
+        ..Default::default()
+    };
+
+    eframe::run_native(
+        "Black Magic Probe Firmware Manager",
+        options,
+        Box::new(move |_cc| Ok(Box::new(GuiApp::new(policy)))),
+    )
+    .map_err(|e| ErrorKind::OperationNotSupported(format!("graphical interface ({})", e)).error())
+}
@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for telling which hardware a Black Magic Probe is, and which hardware a firmware image
+//! was built for.
+//!
+//! Black Magic Debug is built separately for each kind of hardware it runs on (official probes,
+//! ST-Link clones, and so on), and each build names its hardware in its USB product string, e.g.
+//! `Black Magic Probe (ST-Link/v2) v1.10.0`. Older builds for official probes leave the name out.
+//! The same string is built into the image, so the two can be compared before flashing.
+//!
+//! Official probes also have a hardware revision, which their firmware reports in the output of
+//! `monitor version`, but which isn't part of the product string.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::version::{self, FirmwareVersion};
+
+/// Name of the hardware in product strings of builds for official probes that don't name it.
+const NATIVE: &str = "native";
+
+
+/// The hardware a firmware build is for, e.g. `native` (official probes) or `ST-Link/v2`.
+///
+/// ```
+/// # use bmputil::hardware::HardwareTarget;
+/// let probe = HardwareTarget::from_product_string("Black Magic Probe (ST-Link/v2) v1.10.0").unwrap();
+/// assert_eq!(probe.name(), "ST-Link/v2");
+///
+/// let image = b"\x00\x50\x00\x20Black Magic Probe v1.9.2\x00...";
+/// assert_eq!(HardwareTarget::from_image(image).unwrap().name(), "native");
+/// assert!(!probe.matches(&HardwareTarget::from_image(image).unwrap()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HardwareTarget(String);
+
+impl HardwareTarget
+{
+    /// Finds the hardware named in a Black Magic Probe product string.
+    ///
+    /// Returns `None` for anything other than a Black Magic Debug runtime product string (such as
+    /// a bootloader's), as those don't say which hardware they run on.
+    pub fn from_product_string(product: &str) -> Option<Self>
+    {
+        let rest = product.strip_prefix(version::PRODUCT_STRING_MARKER)?.trim_start();
+        FirmwareVersion::from_product_string(rest)?;
+
+        match rest.strip_prefix('(').and_then(|rest| rest.split_once(')')) {
+            // The bootloader's product string has the same shape, but isn't built for any one hardware.
+            Some((name, _version)) if name.trim().is_empty() || name.trim() == "Upgrade" => None,
+            Some((name, _version)) => Some(Self(name.trim().to_string())),
+            None => Some(Self(NATIVE.to_string())),
+        }
+    }
+
+    /// Finds the hardware a firmware image was built for, from the product string built into it.
+    pub fn from_image(image: &[u8]) -> Option<Self>
+    {
+        version::image_product_strings(image).find_map(Self::from_product_string)
+    }
+
+    pub fn name(&self) -> &str
+    {
+        &self.0
+    }
+
+    /// Whether firmware built for `other` runs on this hardware. Names are compared ignoring case,
+    /// as some builds have changed their capitalisation between releases.
+    pub fn matches(&self, other: &Self) -> bool
+    {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Display for HardwareTarget
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Finds the hardware revision of an official probe in the output of `monitor version`, which
+/// has a line like `Hardware Version 6`.
+///
+/// ```
+/// # use bmputil::hardware::revision_from_monitor_version;
+/// let output = "Black Magic Probe v1.10.0\nHardware Version 6\nCopyright (C) 2022 Black Magic Debug Project\n";
+/// assert_eq!(revision_from_monitor_version(output), Some(6));
+/// assert_eq!(revision_from_monitor_version("Black Magic Probe (ST-Link/v2) v1.10.0\n"), None);
+/// ```
+pub fn revision_from_monitor_version(output: &str) -> Option<u32>
+{
+    output
+        .lines()
+        .find_map(|line| line.split_once("Hardware Version"))
+        .and_then(|(_, revision)| revision.trim_start_matches([':', ' ']).split_whitespace().next())
+        .and_then(|revision| revision.parse().ok())
+}
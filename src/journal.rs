@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for the flash journal, which lets `bmputil flash` pick up where an interrupted run left
+//! off.
+//!
+//! While firmware is being written, how much of it the probe has confirmed is noted in a small file
+//! per probe in the [cache directory](bmputil::paths::cache_dir), next to which image it was. If
+//! bmputil crashes or is killed partway through, the next run flashing the same image onto the same
+//! probe finds the journal and can offer to resume rather than start over. The journal is removed
+//! once flashing succeeds.
+//!
+//! Only application firmware is journaled: a bootloader update is always written from the start.
+
+use std::cell::Cell;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use bmputil::paths;
+
+/// Name of the directory in the cache directory the journals are kept in.
+const JOURNAL_DIR: &str = "journal";
+
+/// How much has to have been written since the journal was last saved before saving it again.
+const SAVE_INTERVAL: usize = 16 * 1024;
+
+/// How long a journal stays valid. Anything older is most likely for firmware long since replaced.
+const JOURNAL_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct JournalEntry
+{
+    serial: String,
+    /// [`image_hash`] of the firmware being written.
+    image_hash: String,
+    image_length: usize,
+    /// How many bytes of the image the probe confirmed writing.
+    written: usize,
+    /// When this was last updated, in seconds since the Unix epoch.
+    updated: u64,
+}
+
+fn now() -> u64
+{
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Identifies a firmware image. 64-bit FNV-1a, which is plenty to tell images apart.
+fn image_hash(image: &[u8]) -> String
+{
+    let hash = image.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+
+    format!("fnv1a64:{:016x}", hash)
+}
+
+/// Where the journal for the probe with serial number `serial` is kept, if anywhere.
+fn journal_path(serial: &str) -> Option<PathBuf>
+{
+    // The serial number becomes a file name, so only use it if it can't point anywhere else.
+    if serial.is_empty() || !serial.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+
+    paths::cache_dir().map(|dir| dir.join(JOURNAL_DIR).join(format!("{}.json", serial)))
+}
+
+/// Returns how much of `image` an interrupted run already wrote to the probe with serial number
+/// `serial`, if the journal says one did.
+pub fn interrupted_flash(serial: &str, image: &[u8]) -> Option<usize>
+{
+    let path = journal_path(serial)?;
+    let contents = fs::read_to_string(&path).ok()?;
+    let entry: JournalEntry = match serde_json::from_str(&contents) {
+        Ok(entry) => entry,
+        Err(e) => {
+            debug!("Ignoring unreadable flash journal {}: {}", path.display(), e);
+            return None;
+        },
+    };
+
+    let current = entry.serial == serial
+        && entry.image_length == image.len()
+        && entry.image_hash == image_hash(image)
+        && entry.updated >= now().saturating_sub(JOURNAL_TTL.as_secs());
+    if !current {
+        debug!("Flash journal {} is for another image, or too old", path.display());
+        return None;
+    }
+
+    (entry.written > 0 && entry.written < image.len()).then_some(entry.written)
+}
+
+/// The journal of a flash in progress.
+pub struct FlashJournal
+{
+    path: PathBuf,
+    entry: JournalEntry,
+    /// How much had been written the last time the journal was saved.
+    saved: Cell<usize>,
+}
+
+impl FlashJournal
+{
+    /// Starts journaling the flashing of `image` onto the probe with serial number `serial`, of
+    /// which the first `written` bytes are already there (when resuming).
+    ///
+    /// Any earlier journal for the probe is replaced straight away, as flashing erases what it
+    /// describes. Returns `None` if there is nowhere to keep the journal; flashing goes ahead
+    /// without one.
+    pub fn start(serial: &str, image: &[u8], written: usize) -> Option<Self>
+    {
+        let path = journal_path(serial)?;
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                debug!("Not journaling flash, as {} could not be created: {}", dir.display(), e);
+                return None;
+            }
+        }
+
+        let journal = Self {
+            path,
+            entry: JournalEntry {
+                serial: serial.to_string(),
+                image_hash: image_hash(image),
+                image_length: image.len(),
+                written,
+                updated: now(),
+            },
+            saved: Cell::new(written),
+        };
+        journal.save(written);
+
+        Some(journal)
+    }
+
+    fn save(&self, written: usize)
+    {
+        let entry = JournalEntry {
+            written,
+            updated: now(),
+            ..self.entry.clone()
+        };
+        let json = serde_json::to_string(&entry).expect("Serializing a flash journal should not fail");
+        match fs::write(&self.path, json) {
+            Ok(()) => self.saved.set(written),
+            Err(e) => debug!("Failed to update flash journal {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Notes that the probe has confirmed writing the first `written` bytes of the image.
+    pub fn record(&self, written: usize)
+    {
+        if written < self.saved.get() + SAVE_INTERVAL && written < self.entry.image_length {
+            return;
+        }
+
+        self.save(written);
+    }
+
+    /// Removes the journal, as the flash finished, or as what was written turned out not to verify.
+    pub fn finish(self)
+    {
+        if let Err(e) = fs::remove_file(&self.path) {
+            debug!("Failed to remove flash journal {}: {}", self.path.display(), e);
+        }
+    }
+}
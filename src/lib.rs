@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+#![cfg_attr(feature = "backtrace", feature(backtrace))]
+//! Library for finding, inspecting, and flashing Black Magic Probe devices, used by the `bmputil`
+//! command line tool and usable on its own by other tools (e.g. for provisioning probes).
+//!
+//! The entry point is usually [`bmp::BmpMatcher`], which finds connected probes as
+//! [`bmp::BmpDevice`]s:
+//!
+//! ```no_run
+//! use bmputil::bmp::BmpMatcher;
+//!
+//! # fn main() -> Result<(), bmputil::error::Error> {
+//! let mut results = BmpMatcher::new().serial("97B6A11D").find_matching_probes();
+//! let probe = results.pop_single("inspect")?;
+//! println!("Found {}", probe);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To show probes as they're found rather than all at once, iterate a [`bmp::ProbeScanner`]
+//! instead.
+//!
+//! Flashing goes through [`flasher::FlashPipeline`] or [`bmp::BmpDevice::download`], and every
+//! fallible operation returns an [`error::Error`], whose [`error::ErrorKind`] says what went wrong.
+//! Errors about what a device reported carry an [`error::InvalidDeviceData`] to match on, and errors
+//! that happened with a particular probe say which in [`error::Error::device`]:
+//!
+//! ```
+//! # use bmputil::error::{Error, ErrorKind, InvalidDeviceData};
+//! fn explain(e: &Error)
+//! {
+//!     match &e.kind {
+//!         ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingDfuInterface) => {
+//!             println!("not a Black Magic Probe after all?");
+//!         },
+//!         _ => println!("{}", e),
+//!     }
+//!     if let Some(device) = &e.device {
+//!         println!("with the probe at port {}", device.port.as_deref().unwrap_or("unknown"));
+//!     }
+//! }
+//! ```
+
+pub mod usb;
+pub mod dfu;
+pub mod retry;
+pub mod clock;
+pub mod timeouts;
+pub mod error;
+pub mod bmp;
+pub mod elf;
+pub mod srec;
+pub mod hardware;
+pub mod signature;
+pub mod snapshot;
+pub mod paths;
+pub mod config;
+pub mod profiles;
+pub mod quirks;
+pub mod rdp;
+mod os_serial;
+pub mod serial_port;
+pub mod gdb_remote;
+pub mod settings;
+pub mod version;
+pub mod warnings;
+pub mod flasher;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! S
+{
+    ($expr:expr) => {
+        String::from($expr)
+    };
+}
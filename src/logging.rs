@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for setting up logging.
+//!
+//! What bmputil has to tell the user (results, prompts, errors and their hints) is printed
+//! directly. Everything else goes through [`log`], where how much reaches the terminal is set by
+//! `-q` and `-v` (and `RUST_LOG`, which has the final say):
+//!
+//! | Flags   | Shown                                                    |
+//! |---------|----------------------------------------------------------|
+//! | `-q`    | errors only                                              |
+//! | (none)  | warnings and errors                                      |
+//! | `-v`    | progress notes, and a summary of USB activity at the end |
+//! | `-vv`   | debug messages                                           |
+//! | `-vvv`  | trace messages                                           |
+//! | `-vvvv` | every USB control transfer, with its data                |
+//!
+//! `--log-file` additionally writes all of it, at every level and regardless of the above, to a
+//! file, so a failure can be reported in full after the fact.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use clap::ArgMatches;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::usb::TRANSFER_LOG_TARGET;
+
+/// The file given with `--log-file`, and when logging started.
+static LOG_FILE: OnceLock<(Mutex<LineWriter<File>>, Instant)> = OnceLock::new();
+
+/// Writes a line to the log file, if there is one.
+fn write_to_file(level: &dyn fmt::Display, target: &str, message: &fmt::Arguments)
+{
+    let Some((file, start)) = LOG_FILE.get() else {
+        return;
+    };
+
+    let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Nowhere to report failing to write the log to.
+    let _ = writeln!(file, "{:>10.3} {:<5} {}: {}", start.elapsed().as_secs_f64(), level, target, message);
+}
+
+/// Records something bmputil told the user directly (e.g. the error a command failed with) in the
+/// log file, if there is one, so the log tells the whole story.
+pub fn note_in_file(message: fmt::Arguments)
+{
+    write_to_file(&"USER", "bmputil", &message);
+}
+
+/// Logs to the terminal as filtered, and everything to the log file, if there is one.
+struct Logger
+{
+    console: env_logger::Logger,
+}
+
+impl Log for Logger
+{
+    fn enabled(&self, metadata: &Metadata) -> bool
+    {
+        LOG_FILE.get().is_some() || self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record)
+    {
+        if self.console.matches(record) {
+            self.console.log(record);
+        }
+        write_to_file(&record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self)
+    {
+        self.console.flush();
+        if let Some((file, _)) = LOG_FILE.get() {
+            let _ = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).flush();
+        }
+    }
+}
+
+/// The most the terminal shows for the `-v` and `-q` given.
+fn console_level(matches: &ArgMatches) -> LevelFilter
+{
+    if matches.is_present("quiet") {
+        return LevelFilter::Error;
+    }
+
+    match matches.occurrences_of("verbosity") {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Sets up logging for the `-v`, `-q`, and `--log-file` given for a (sub)command.
+pub fn init(matches: &ArgMatches, args: &[std::ffi::OsString]) -> Result<(), Error>
+{
+    if let Some(path) = matches.value_of("log-file") {
+        let mut file = File::create(path)
+            .map(LineWriter::new)
+            .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error().with_ctx(&format!("creating log file {}", path)))?;
+        let _ = writeln!(
+            file,
+            "bmputil {} ({}, {})\ncommand line: {:?}",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            args,
+        );
+        let _ = LOG_FILE.set((Mutex::new(file), Instant::now()));
+    }
+
+    // USB transfers would bury everything else at -vvv, so they take one more -v.
+    let transfers = if matches.occurrences_of("verbosity") >= 4 { LevelFilter::Trace } else { LevelFilter::Off };
+    let console = env_logger::Builder::new()
+        .filter_level(console_level(matches))
+        .filter_module(TRANSFER_LOG_TARGET, transfers)
+        .parse_default_env()
+        .build();
+
+    let max_level = if LOG_FILE.get().is_some() { LevelFilter::Trace } else { console.filter() };
+    let logger = Logger { console };
+    log::set_boxed_logger(Box::new(logger)).expect("The logger is only set up once");
+    log::set_max_level(max_level);
+
+    Ok(())
+}
@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for explaining why a probe can't be opened on macOS, and walking the user through fixing it.
+//!
+//! macOS has no udev-style permissions for USB devices. Instead, opening a probe fails with
+//! `LIBUSB_ERROR_ACCESS` (or `BUSY`) when a kernel driver has captured the interface we need, or
+//! when bmputil runs inside an app sandbox without the USB entitlement. Apple's own drivers only
+//! take the probe's CDC (serial port) interfaces, so it's usually a third-party serial or debugger
+//! driver that's in the way, which we can find in the I/O Registry with `ioreg`.
+
+use std::env;
+use std::process::Command;
+
+use log::{debug, error};
+
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+
+/// Set in the environment of processes running in an app sandbox.
+const SANDBOX_ENV: &str = "APP_SANDBOX_CONTAINER_ID";
+
+/// A driver in the I/O Registry that isn't Apple's, attached to a Black Magic Probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ThirdPartyDriver
+{
+    /// The driver's I/O Kit class, e.g. `com_example_driver_USBSerial`.
+    class: String,
+    /// The bundle identifier of the kernel extension providing it, if it could be found.
+    bundle: Option<String>,
+}
+
+/// Whether `e` is libusb failing to open or claim a device, as happens when something else has it.
+pub fn is_access_error(e: &Error) -> bool
+{
+    matches!(
+        e.kind,
+        ErrorKind::External(ErrorSource::Libusb(rusb::Error::Access | rusb::Error::Busy))
+    )
+}
+
+fn ioreg(args: &[&str]) -> Option<String>
+{
+    Command::new("ioreg")
+        .args(args)
+        .output()
+        .inspect_err(|e| debug!("Failed to run ioreg: {}", e))
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Picks the classes of everything attached to Black Magic Probes out of `ioreg -r -c IOUSBHostDevice`
+/// output, where each entry is a line like
+/// `  | +-o AppleUSBACMControl  <class AppleUSBACMControl, id 0x100000abc, registered, ...>`.
+fn probe_driver_classes(output: &str) -> Vec<String>
+{
+    let mut classes = Vec::new();
+    // The depth of the probe whose subtree we're in, if any.
+    let mut probe_depth = None;
+    for line in output.lines() {
+        let Some(depth) = line.find("+-o ") else {
+            continue;
+        };
+        let entry = &line[depth + 4..];
+        let name = entry.split("  <class ").next().unwrap_or_default();
+        let Some(class) = entry.split("<class ").nth(1).and_then(|rest| rest.split(',').next()) else {
+            continue;
+        };
+
+        match probe_depth {
+            Some(probe) if depth > probe => classes.push(class.to_string()),
+            _ if class == "IOUSBHostDevice" && name.contains("Black Magic") => probe_depth = Some(depth),
+            _ => probe_depth = None,
+        }
+    }
+
+    classes
+}
+
+/// Finds the drivers attached to connected probes that don't come with macOS.
+fn third_party_drivers() -> Vec<ThirdPartyDriver>
+{
+    let Some(output) = ioreg(&["-r", "-c", "IOUSBHostDevice", "-w0"]) else {
+        return Vec::new();
+    };
+
+    let mut classes = probe_driver_classes(&output);
+    classes.retain(|class| !class.starts_with("Apple") && !class.starts_with("IO"));
+    classes.sort_unstable();
+    classes.dedup();
+
+    classes
+        .into_iter()
+        .map(|class| {
+            let bundle = ioreg(&["-r", "-c", &class, "-l", "-w0"]).and_then(|details| {
+                details.lines().find_map(|line| {
+                    let (key, value) = line.split_once(" = ")?;
+                    key.trim_start_matches([' ', '|']).eq("\"CFBundleIdentifier\"")
+                        .then(|| value.trim_matches('"').to_string())
+                })
+            });
+            ThirdPartyDriver { class, bundle }
+        })
+        .collect()
+}
+
+/// Explains an access error, and how to fix it.
+pub fn print_access_hint()
+{
+    eprintln!("note: a Black Magic Probe was found, but it could not be opened.");
+
+    if env::var_os(SANDBOX_ENV).is_some() {
+        eprintln!(
+            "note: bmputil is running inside an app sandbox, which blocks USB devices unless the app has \
+            the com.apple.security.device.usb entitlement. Run bmputil from Terminal instead, or grant \
+            the entitlement to the app launching it."
+        );
+        return;
+    }
+
+    let drivers = third_party_drivers();
+    if drivers.is_empty() {
+        eprintln!(
+            "note: no other driver seems to have captured the probe, so another program probably has it \
+            open. Close any GDB sessions, serial terminals, or other copies of bmputil using it, then try again."
+        );
+        return;
+    }
+
+    eprintln!("note: these drivers, which don't come with macOS, have captured the probe:");
+    for driver in &drivers {
+        match &driver.bundle {
+            Some(bundle) => eprintln!("  {} (from {})", driver.class, bundle),
+            None => eprintln!("  {}", driver.class),
+        }
+    }
+    eprintln!("note: to release the probe until the next reboot, unload them, then replug the probe:");
+    for driver in &drivers {
+        match &driver.bundle {
+            Some(bundle) => eprintln!("  sudo kextunload -b {}", bundle),
+            None => eprintln!("  (find the bundle of {} with `kextstat | grep -i <vendor>`)", driver.class),
+        }
+    }
+    eprintln!(
+        "note: drivers installed as system extensions can't be unloaded this way; check \
+        `systemextensionsctl list`, and remove the app that installed them, or disable them in \
+        System Settings > General > Login Items & Extensions."
+    );
+}
+
+/// Offers to unload the third-party kernel extensions that have captured the probe, with `sudo`.
+pub fn offer_kext_unload(ask: impl FnOnce(&str) -> bool)
+{
+    let bundles: Vec<String> = third_party_drivers()
+        .into_iter()
+        .filter_map(|driver| driver.bundle)
+        .collect();
+    if bundles.is_empty() {
+        return;
+    }
+
+    if !ask(&format!("Unload {} now (needs administrator access)?", bundles.join(", "))) {
+        return;
+    }
+
+    for bundle in bundles {
+        match Command::new("sudo").args(["kextunload", "-b", &bundle]).status() {
+            Ok(status) if status.success() => println!("Unloaded {}.", bundle),
+            Ok(status) => error!("Unloading {} failed ({})", bundle, status),
+            Err(e) => error!("Failed to run `sudo kextunload -b {}`: {}", bundle, e),
+        }
+    }
+    println!("Replug the probe, then try again.");
+}
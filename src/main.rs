@@ -2,38 +2,84 @@
 // SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
 // SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
 #![cfg_attr(feature = "backtrace", feature(backtrace))]
+//! The `bmputil` command line tool, a thin layer over the [`bmputil`] library.
+
 #[cfg(feature = "backtrace")]
 use std::backtrace::BacktraceStatus;
 
-use std::thread;
-use std::rc::Rc;
 use std::io::Write;
 use std::io::Read;
 use std::str::FromStr;
+use std::cell::{Cell, RefCell};
 use std::time::Duration;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use clap::{Command, Arg, ArgMatches};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{debug, warn, error};
 
-mod usb;
-mod error;
-mod bmp;
-mod elf;
+use bmputil::{config, elf, paths, profiles, serial_port, srec, S};
+
+mod stats;
+mod brownout;
+mod confirm;
+mod shell;
+mod terminal;
+mod journal;
+mod logging;
+mod report;
+mod on_connect;
+mod porcelain;
+mod watch;
+mod tree;
+mod dfu_util;
+mod recover;
+mod diagnose;
+mod benchmark;
+mod bmda;
+mod dfu_expert;
+mod dfu_util_compat;
+#[cfg(feature = "gui")]
+mod gui;
 #[cfg(windows)]
 mod windows;
-use crate::bmp::{BmpDevice, BmpMatcher, FirmwareType, FirmwareFormat};
-use crate::error::{Error, ErrorKind, ErrorSource};
-
-#[macro_export]
-#[doc(hidden)]
-macro_rules! S
-{
-    ($expr:expr) => {
-        String::from($expr)
-    };
-}
+#[cfg(target_os = "linux")]
+mod udev;
+#[cfg(target_os = "linux")]
+mod wsl;
+#[cfg(target_os = "macos")]
+mod macos;
+use bmputil::bmp::{BmpDevice, BmpMatcher, BmpMatchResults, BmpPlatform, DownloadOptions, FirmwareType, FirmwareFormat, RebootTarget};
+use bmputil::error::{Error, ErrorKind, ErrorSource, ExitCode};
+use bmputil::serial_port::ProbePort;
+use bmputil::signature::SignaturePolicy;
+use bmputil::gdb_remote::GdbRemote;
+use bmputil::settings::{ProbeSetting, KNOWN_SETTINGS};
+use bmputil::usb::telemetry::{self, TransferStats};
+use bmputil::usb::{diagnostics, sanitize_descriptor_string, DfuOperatingMode, Pid, UsbDeviceHandle, Vid};
+#[cfg(windows)]
+use bmputil::usb::LibusbBackend;
+use bmputil::usb::dump::DescriptorDump;
+use bmputil::profiles::ProbeProfile;
+use bmputil::version::FirmwareVersion;
+use bmputil::hardware::HardwareTarget;
+use bmputil::warnings::{self, WarningCode};
+use bmputil::rdp::{self, RdpLevel};
+use bmputil::flasher::{FlashPipeline, UsbBackend, SystemClock};
+use crate::stats::UsageStats;
+use crate::confirm::{AuthorizationLevel, ConfirmationPolicy};
+use crate::journal::FlashJournal;
+use crate::dfu_util::DfuUtilBackend;
+use crate::report::{FlashRecord, FlashReport};
+use crate::porcelain::{status, Record};
+use bmputil::dfu::{DfuState, DownloadPhase, DownloadProgress, EraseStrategy};
+use bmputil::retry::RetryPolicy;
+use bmputil::timeouts::Timeouts;
+use bmputil::config::{Config, LineEnding, OutputFormat, Selection};
 
 
 fn intel_hex_error() -> !
@@ -54,17 +100,16 @@ fn intel_hex_error() -> !
     )
     .expect("failed to write to stderr");
 
-    std::process::exit(1);
+    ExitCode::InvalidFirmware.exit();
 }
 
-
 fn detach_command(matches: &ArgMatches) -> Result<(), Error>
 {
-    let matcher = BmpMatcher::from_cli_args(matches);
-    let mut results = matcher.find_matching_probes();
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
     let dev = results.pop_single("detach")?;
 
-    use crate::usb::DfuOperatingMode::*;
+    use bmputil::usb::DfuOperatingMode::*;
     match dev.operating_mode() {
         Runtime => println!("Requesting device detach from runtime mode to DFU mode..."),
         FirmwareUpgrade => println!("Requesting device detach from DFU mode to runtime mode..."),
@@ -77,19 +122,274 @@ fn detach_command(matches: &ArgMatches) -> Result<(), Error>
 }
 
 
-fn flash(matches: &ArgMatches) -> Result<(), Error>
+fn mode_description(mode: DfuOperatingMode) -> &'static str
+{
+    match mode {
+        DfuOperatingMode::Runtime => "runtime mode",
+        DfuOperatingMode::FirmwareUpgrade => "DFU mode",
+    }
+}
+
+
+fn switch_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let target = match matches.value_of("to").expect("No mode was specified!") {
+        "dfu" => DfuOperatingMode::FirmwareUpgrade,
+        "runtime" => DfuOperatingMode::Runtime,
+        other => unreachable!("Unhandled mode {:?}", other), // Should be impossible, thanks to clap.
+    };
+
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
+    let mut dev = results.pop_single("switch")?;
+
+    if dev.operating_mode() == target {
+        println!("Device is already in {}.", mode_description(target));
+        return Ok(());
+    }
+
+    println!("Switching device from {} to {}...", mode_description(dev.operating_mode()), mode_description(target));
+    dev.detach_and_enumerate()
+        .map_err(|e| e.with_ctx("switching device mode"))?;
+
+    println!("Device is now in {}.", mode_description(dev.operating_mode()));
+
+    Ok(())
+}
+
+
+/// Reboots a probe into its runtime firmware. A probe already running its firmware is detached
+/// twice: once into DFU mode, and once back.
+fn reboot_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
+    let mut dev = results.pop_single("reboot")?;
+
+    println!("Rebooting device...");
+    if dev.operating_mode() == DfuOperatingMode::Runtime {
+        dev.detach_and_enumerate()
+            .map_err(|e| e.with_ctx("rebooting device into DFU mode"))?;
+    }
+    dev.detach_and_enumerate()
+        .map_err(|e| e.with_ctx("rebooting device into runtime mode"))?;
+
+    println!("Device is now in {}.", mode_description(dev.operating_mode()));
+
+    Ok(())
+}
+
+
+/// The configuration file's contents, once it has been read.
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The probe chosen with `bmputil use`, once it has been read.
+static SELECTION: OnceLock<Option<Selection>> = OnceLock::new();
+
+/// Whether the notice that the probe chosen with `bmputil use` is being used has been printed.
+static SELECTION_NOTICE_PRINTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the configuration file's contents, or an empty configuration if it hasn't been read.
+pub(crate) fn config() -> &'static Config
+{
+    CONFIG.get_or_init(Config::default)
+}
+
+fn describe_selection(selection: &Selection) -> String
+{
+    match &selection.nickname {
+        Some(nickname) => format!("{} ({})", nickname, selection.serial),
+        None => selection.serial.clone(),
+    }
+}
+
+/// Reads the configuration file given with `--config`, or from the default location.
+fn load_config(matches: &ArgMatches) -> Result<(), Error>
+{
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path), true)?,
+        None => match config::default_path() {
+            Some(path) => Config::load(&path, false)?,
+            None => Config::default(),
+        },
+    };
+    if let Some(dir) = &config.cache_dir {
+        paths::set_cache_dir(dir.clone());
+    }
+    let _ = CONFIG.set(config);
+    let _ = SELECTION.set(Selection::load()?);
+
+    Ok(())
+}
+
+pub(crate) fn matcher_from_cli_args(matches: &ArgMatches) -> BmpMatcher
+{
+    let matcher = BmpMatcher::new().timeouts(timeouts_from_cli_args(matches));
+
+    // Filters on the command line replace the configured ones entirely, rather than adding to them.
+    let given = ["index", "serial_number", "port"].iter().any(|&arg| matches.is_present(arg));
+    if let (false, Some(Some(selection))) = (given, SELECTION.get()) {
+        if !SELECTION_NOTICE_PRINTED.swap(true, Ordering::Relaxed) {
+            eprintln!("Using default probe {}; change it with `bmputil use`", describe_selection(selection));
+        }
+        return matcher.serials([selection.serial.as_str()]);
+    }
+    let defaults = &config().probe;
+    if !given && !defaults.is_empty() {
+        debug!("Using the probe filters from the configuration file");
+        return matcher
+            .index(defaults.index)
+            .serials(defaults.serial.iter().map(String::as_str))
+            .ports(defaults.port.iter().map(String::as_str));
+    }
+
+    matcher
+        .index(matches.value_of("index").map(|arg| usize::from_str(arg).unwrap()))
+        .serials(matches.values_of("serial_number").into_iter().flatten())
+        .ports(matches.values_of("port").into_iter().flatten())
+}
+
+/// Returns the output format given with `--format`, or else the configured one.
+pub(crate) fn output_format(matches: &ArgMatches) -> OutputFormat
+{
+    match matches.value_of("format") {
+        // `--format` has a default value, so only take it if it was actually given.
+        Some("json") if matches.occurrences_of("format") > 0 => OutputFormat::Json,
+        Some(_) if matches.occurrences_of("format") > 0 => OutputFormat::Text,
+        _ => config().output.format.unwrap_or_default(),
+    }
+}
+
+
+/// Looks for the probes `matcher` matches, waiting for one to be connected first if `--wait` was
+/// given and none are.
+pub(crate) fn find_probes(matcher: &BmpMatcher, matches: &ArgMatches) -> Result<BmpMatchResults, Error>
+{
+    let results = matcher.find_matching_probes();
+    if !results.found.is_empty() || !matches.is_present("wait") {
+        return Ok(results);
+    }
+
+    // Validated by clap.
+    let timeout = matches.value_of("wait").map(|secs| Duration::from_secs(secs.parse().unwrap()));
+    eprintln!("Waiting for a matching Black Magic Probe to be connected...");
+    matcher.wait_for_matching_probes(timeout)
+}
+
+
+/// Reads the timeouts given with `--transfer-timeout` and `--timeout`, using the defaults for
+/// any not given.
+fn timeouts_from_cli_args(matches: &ArgMatches) -> Timeouts
+{
+    let mut timeouts = config().timeouts.apply(Timeouts::new());
+    // Both are validated by clap.
+    if let Some(ms) = matches.value_of("transfer-timeout") {
+        timeouts = timeouts.control(Duration::from_millis(ms.parse().unwrap()));
+    }
+    if let Some(secs) = matches.value_of("timeout") {
+        timeouts = timeouts.enumerate(Duration::from_secs(secs.parse().unwrap()));
+    }
+
+    timeouts
+}
+
+
+fn retry_policy_from_cli_args(matches: &ArgMatches) -> RetryPolicy
+{
+    let retries = matches.value_of("usb-retries")
+        .map(|retries| retries.parse().expect("Invalid retry count")) // Should be impossible, thanks to clap.
+        .unwrap_or_else(|| RetryPolicy::default().get_retries());
+
+    RetryPolicy::new().retries(retries)
+}
+
+
+/// A firmware image read from a file.
+pub(crate) struct FirmwareImage
+{
+    data: Vec<u8>,
+    /// Where the file says the image goes, for formats that say (S-record files).
+    address: Option<u32>,
+}
+
+impl FirmwareImage
+{
+    /// Checks that the image goes where firmware of `firmware_type` goes on a probe `profile`
+    /// describes, if the file says where it goes, as it would otherwise be written somewhere it
+    /// wasn't linked for.
+    pub(crate) fn check_address(&self, profile: &ProbeProfile, firmware_type: FirmwareType) -> Result<(), Error>
+    {
+        let load_address = profile.load_address(firmware_type);
+        match self.address {
+            Some(address) if address != load_address => Err(ErrorKind::InvalidFirmware(Some(format!(
+                "the image is for 0x{:08x}, but the {} goes at 0x{:08x} on this probe",
+                address,
+                firmware_type,
+                load_address,
+            ))).error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Deref for FirmwareImage
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8]
+    {
+        &self.data
+    }
+}
+
+/// Reads a firmware file, extracting the firmware image from ELF and S-record files.
+fn read_firmware_file(filename: &str) -> Result<FirmwareImage, Error>
+{
+    extract_firmware(read_raw_firmware_file(filename)?)
+}
+
+/// Reads a firmware file to flash, checking its signature against `policy` before using it.
+fn read_signed_firmware_file(filename: &str, policy: &SignaturePolicy) -> Result<FirmwareImage, Error>
+{
+    let firmware_data = read_raw_firmware_file(filename)?;
+    policy.check(Path::new(filename), &firmware_data)?;
+
+    extract_firmware(firmware_data)
+}
+
+/// Returns the firmware signature policy from the configuration file, with the `--trusted-key`
+/// and `--require-signed` options in `matches` added, if given.
+fn signature_policy(matches: Option<&ArgMatches>) -> Result<SignaturePolicy, Error>
+{
+    let defaults = &config().signing;
+    let require_signed = defaults.require_signed || matches.is_some_and(|m| m.is_present("require-signed"));
+    let mut policy = SignaturePolicy::new().require_signed(require_signed);
+
+    let cli_keys = matches.and_then(|m| m.values_of("trusted-key")).into_iter().flatten();
+    for key in defaults.trusted_keys.iter().map(String::as_str).chain(cli_keys) {
+        policy.trust(key)?;
+    }
+
+    Ok(policy)
+}
+
+fn read_raw_firmware_file(filename: &str) -> Result<Vec<u8>, Error>
 {
-    let filename = matches.value_of("firmware_binary")
-        .expect("No firmware file was specified!"); // Should be impossible, thanks to clap.
     let firmware_file = std::fs::File::open(filename)
-        .map_err(|source| ErrorKind::FirmwareFileIo(Some(filename.to_string())).error_from(source))
-        .map_err(|e| e.with_ctx("reading firmware file to flash"))?;
+        .map_err(|source| ErrorKind::FirmwareFileIo(Some(filename.to_string())).error_from(source))?;
 
     let mut firmware_file = std::io::BufReader::new(firmware_file);
 
     let mut firmware_data = Vec::new();
-    firmware_file.read_to_end(&mut firmware_data).unwrap();
+    firmware_file.read_to_end(&mut firmware_data)
+        .map_err(|source| ErrorKind::FirmwareFileIo(Some(filename.to_string())).error_from(source))?;
+
+    Ok(firmware_data)
+}
 
+/// Extracts the binary to flash from the contents of a firmware file, in whichever format it's in.
+fn extract_firmware(firmware_data: Vec<u8>) -> Result<FirmwareImage, Error>
+{
     // FirmwareFormat::detect_from_firmware() needs at least 4 bytes, and
     // FirmwareType::detect_from_firmware() needs at least 8 bytes,
     // but also if we don't even have 8 bytes there's _no way_ this is valid firmware.
@@ -101,53 +401,260 @@ fn flash(matches: &ArgMatches) -> Result<(), Error>
 
     // Extract the actual firmware data from the file, based on the format we're using.
     let format = FirmwareFormat::detect_from_firmware(&firmware_data);
-    let firmware_data = match format {
-        FirmwareFormat::Binary => firmware_data,
-        FirmwareFormat::Elf => elf::extract_binary(&firmware_data)?,
+    let (firmware_data, address) = match format {
+        FirmwareFormat::Binary => (firmware_data, None),
+        FirmwareFormat::Elf => (elf::extract_binary(&firmware_data)?, None),
         FirmwareFormat::IntelHex => intel_hex_error(), // FIXME: implement this.
+        FirmwareFormat::Srec => {
+            let image = srec::parse(&firmware_data)?;
+            debug!(
+                "S-record image is {} bytes at 0x{:08x}, entry point {}",
+                image.data.len(),
+                image.address,
+                image.entry.map_or_else(|| S!("not given"), |entry| format!("0x{:08x}", entry)),
+            );
+            (image.data, Some(image.address))
+        },
     };
 
+    // The file's container may have been long enough, but not what was in it.
+    if firmware_data.len() < 8 {
+        return Err(
+            ErrorKind::InvalidFirmware(Some(format!("firmware image is only {} bytes long", firmware_data.len()))).error()
+        );
+    }
+
+    Ok(FirmwareImage { data: firmware_data, address })
+}
+
+
+/// Progress bars for flashing or verifying, one per phase, so they stay on screen as a log of
+/// what happened and how long it took.
+struct PhaseProgressBars
+{
+    current: RefCell<Option<(DownloadPhase, ProgressBar)>>,
+    style: ProgressStyle,
+    firmware_type: FirmwareType,
+}
+
+impl PhaseProgressBars
+{
+    fn new(firmware_type: FirmwareType) -> Self
+    {
+        let style = ProgressStyle::default_bar()
+            .template("{msg:>9} {percent:>3}% |{bar:40}| {bytes}/{total_bytes} [{binary_bytes_per_sec}, ETA {eta}]")
+            .unwrap();
+
+        Self {
+            current: RefCell::new(None),
+            style,
+            firmware_type,
+        }
+    }
+
+    fn update(&self, progress: DownloadProgress)
+    {
+        let mut current = self.current.borrow_mut();
+        if let Some((phase, bar)) = current.as_ref() {
+            if *phase == progress.phase {
+                bar.set_position(progress.done as u64);
+                return;
+            }
+            bar.finish();
+        }
+
+        *current = None;
+        if progress.total == 0 {
+            // Nothing to measure (i.e. manifestation), so just say what's happening.
+            println!("{}...", progress.phase);
+            return;
+        }
+        if progress.phase == DownloadPhase::Download && self.firmware_type == FirmwareType::Bootloader {
+            println!("Flashing bootloader...");
+        }
+
+        let bar = ProgressBar::new(progress.total as u64)
+            .with_style(self.style.clone())
+            .with_message(progress.phase.to_string());
+        bar.set_position(progress.done as u64);
+        *current = Some((progress.phase, bar));
+    }
+
+    fn finish(&self)
+    {
+        if let Some((_phase, bar)) = self.current.take() {
+            bar.finish();
+        }
+    }
+}
+
+
+fn flash(matches: &ArgMatches) -> Result<(), Error>
+{
+    let filenames: Vec<&str> = matches.values_of("firmware_binary")
+        .expect("No firmware file was specified!") // Should be impossible, thanks to clap.
+        .collect();
+    let porcelain = porcelain::enable_from_cli_args(matches);
+    let policy = signature_policy(Some(matches))?;
+    let mut images = filenames
+        .iter()
+        .map(|&filename| read_signed_firmware_file(filename, &policy).map(|data| (filename, data)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.with_ctx("reading firmware file to flash"))?;
+
+    // Open the report first, so a problem with it shows up before anything is flashed.
+    let mut report = matches.value_of("report")
+        .map(|path| FlashReport::open(Path::new(path)))
+        .transpose()?;
+
+    if matches.is_present("on-connect") {
+        let [(filename, firmware_data)] = images.as_slice() else {
+            return Err(ErrorKind::OperationNotSupported(S!("choosing between several images with --on-connect")).error());
+        };
+        return on_connect::flash_on_connect(matches, firmware_data, filename, report.as_mut());
+    }
 
     // Try to find the Black Magic Probe device based on the filter arguments.
-    let matcher = BmpMatcher::from_cli_args(matches);
-    let mut results = matcher.find_matching_probes();
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
     // TODO: flashing to multiple BMPs at once should be supported, but maybe we should require some kind of flag?
-    let mut dev: BmpDevice = results.pop_single("flash")?;
+    let dev: BmpDevice = results.pop_single("flash")?;
+
+    let (filename, firmware_data) = images.swap_remove(choose_image(&dev, &images)?);
 
-    // Grab the platform, which we need for firmware type detection, and the port, which we need
-    // to find the probe after rebooting.
+    let record = (report.is_some() || porcelain).then(|| FlashRecord::start(&dev, filename, &firmware_data));
+    let res = flash_probe(matches, dev, &firmware_data);
+    if let Some(record) = record {
+        let record = record.finish(res.as_ref().cloned());
+        if porcelain {
+            // Print the breadcrumb keys even on success, so the keys don't depend on the result.
+            let mut fields = record.clone();
+            fields.error_context.get_or_insert_with(Default::default);
+            Record::new().fields_of(&fields).print();
+        }
+        if let Some(report) = &mut report {
+            report::add_or_warn(report, record, res.is_ok())?;
+        }
+    }
+
+    res.map(|_| ())
+}
+
+/// Picks which of `images` (file names and contents) to flash onto `dev`: the one built for its
+/// hardware. With only one image, that one is picked regardless, and checked later.
+fn choose_image(dev: &BmpDevice, images: &[(&str, FirmwareImage)]) -> Result<usize, Error>
+{
+    if images.len() == 1 {
+        return Ok(0);
+    }
+
+    let hardware = dev.hardware_target().ok_or_else(|| {
+        ErrorKind::OperationNotSupported(S!(
+            "choosing between several images for a probe whose hardware is unknown (e.g. as it's in DFU mode)"
+        )).error()
+    })?;
+    let targets: Vec<Option<HardwareTarget>> = images.iter().map(|(_, data)| HardwareTarget::from_image(data)).collect();
+    let matching: Vec<usize> = (0..images.len())
+        .filter(|&i| targets[i].as_ref().is_some_and(|target| hardware.matches(target)))
+        .collect();
+
+    match matching.as_slice() {
+        [index] => {
+            status!("Using {}, built for this probe's hardware ({}).", images[*index].0, hardware);
+            Ok(*index)
+        },
+        [] => {
+            let built_for: Vec<String> = images
+                .iter()
+                .zip(&targets)
+                .map(|((filename, _), target)| match target {
+                    Some(target) => format!("{} is for {}", filename, target),
+                    None => format!("{} is for unknown hardware", filename),
+                })
+                .collect();
+            Err(ErrorKind::InvalidFirmware(Some(format!(
+                "none of the images is built for this probe's hardware ({}): {}",
+                hardware,
+                built_for.join(", "),
+            ))).error())
+        },
+        _ => Err(ErrorKind::InvalidFirmware(Some(format!(
+            "more than one image is built for this probe's hardware ({}): {}",
+            hardware,
+            matching.iter().map(|&i| images[i].0).collect::<Vec<_>>().join(", "),
+        ))).error()),
+    }
+}
+
+/// Checks that `firmware_data` was built for the same hardware as `dev` runs on, if both are known.
+///
+/// If not, this warns, and asks `policy` for confirmation unless `force` (as with `--force`), as
+/// the probe would not work with the image (though it could still be reflashed from its bootloader).
+pub(crate) fn check_hardware_target(
+    policy: &ConfirmationPolicy,
+    force: bool,
+    dev: &BmpDevice,
+    firmware_data: &[u8],
+) -> Result<(), Error>
+{
+    let (Some(hardware), Some(target)) = (dev.hardware_target(), HardwareTarget::from_image(firmware_data)) else {
+        return Ok(());
+    };
+    if hardware.matches(&target) {
+        return Ok(());
+    }
+
+    warnings::emit(
+        WarningCode::HardwareMismatch,
+        format!("The image is built for {} hardware, but the probe's firmware is built for {}", target, hardware),
+    );
+    if force {
+        return Ok(());
+    }
+
+    policy.confirm(
+        AuthorizationLevel::Destructive,
+        "flashing firmware built for different hardware",
+        &format!(
+            "The image is built for {} hardware, but this probe runs firmware built for {}. The probe \
+            will most likely not work with it, and will need reflashing with the right image.",
+            target,
+            hardware,
+        ),
+    )
+}
+
+/// Flashes `firmware_data` onto `dev` as `bmputil flash` does, returning the firmware version it
+/// then runs, or `None` if it was left in DFU mode.
+fn flash_probe(matches: &ArgMatches, mut dev: BmpDevice, firmware_data: &FirmwareImage) -> Result<Option<String>, Error>
+{
+    // Grab the platform, which we need for validating bootloaders.
     let platform = dev.platform();
-    let port = dev.port();
 
-    // Detect what kind of firmware this is, using the platform to determine the link address.
-    let firmware_type = FirmwareType::detect_from_firmware(platform, &firmware_data)
+    // Detect what kind of firmware this is, using the probe's profile to determine the link address.
+    let firmware_type = FirmwareType::detect_from_firmware(dev.profile(), firmware_data)
         .map_err(|e| e.with_ctx("detecting firmware type"))?;
 
     debug!("Firmware file was detected as {}", firmware_type);
 
+    let bootloader_update = matches.is_present("bootloader");
+    let policy = ConfirmationPolicy::from_cli_args(matches);
+
     // But allow the user to override that type, if they *really* know what they are doing.
     let firmware_type = if let Some(location) = matches.value_of("override-firmware-type") {
-        if let Some("really") = matches.value_of("allow-dangerous-options") {
-            warn!("Overriding firmware-type detection and flashing to user-specified location ({}) instead!", location);
-        } else {
-            // We're ignoring errors for setting the color because the most important thing is
-            // getting the message itself out.
-            // If the messages themselves don't write, though, then we might as well just panic.
-            let mut stderr = StandardStream::stderr(ColorChoice::Auto);
-            let _res = stderr.set_color(ColorSpec::new().set_fg(Some(Color::Red)));
-            write!(&mut stderr, "WARNING: ").expect("failed to write to stderr");
-            let _res = stderr.reset();
-            writeln!(
-                &mut stderr,
-                "--override-firmware-type is used to override the firmware type detection and flash \
-                a firmware binary to a location other than the one that it seems to be designed for.\n\
-                This is a potentially destructive operation and can result in an unbootable device! \
-                (can require a second, external JTAG debugger and manual wiring to fix!)\n\
-                \nDo not use this option unless you are a firmware developer and really know what you are doing!\n\
-                \nIf you are sure this is really what you want to do, run again with --allow-dangerous-options=really"
-            ).expect("failed to write to stderr");
-            std::process::exit(1);
-        };
+        policy.confirm(
+            AuthorizationLevel::Dangerous,
+            "flashing with an overridden firmware type",
+            "--override-firmware-type is used to override the firmware type detection and flash \
+            a firmware binary to a location other than the one that it seems to be designed for.\n\
+            This is a potentially destructive operation and can result in an unbootable device! \
+            (can require a second, external JTAG debugger and manual wiring to fix!)\n\
+            \nDo not use this option unless you are a firmware developer and really know what you are doing!",
+        )?;
+        warnings::emit(
+            WarningCode::FirmwareTypeOverridden,
+            format!("Overriding firmware-type detection and flashing to user-specified location ({}) instead!", location),
+        );
         if location == "bootloader" {
             FirmwareType::Bootloader
         } else if location == "application" {
@@ -155,196 +662,1751 @@ fn flash(matches: &ArgMatches) -> Result<(), Error>
         } else {
             unreachable!("Clap ensures invalid option cannot be passed to --override-firmware-type");
         }
+    } else if bootloader_update {
+        // Make sure this really is a bootloader for this probe before we go anywhere near the
+        // bootloader region.
+        FirmwareType::validate_bootloader(platform, firmware_data)
+            .map_err(|e| e.with_ctx("validating bootloader image"))?;
+
+        policy.confirm(
+            AuthorizationLevel::Dangerous,
+            "bootloader update",
+            "--bootloader overwrites the bootloader of the Black Magic Probe. If this is interrupted \
+            or the image is wrong, the probe will not be able to boot or be updated again \
+            (can require a second, external JTAG debugger and manual wiring to fix!)\n\
+            \nThe written bootloader will be read back and verified before the probe is rebooted.",
+        )?;
+
+        warnings::emit(
+            WarningCode::BootloaderUpdate,
+            "Updating the bootloader of the Black Magic Probe. Do not disconnect it until this is complete!",
+        );
+        FirmwareType::Bootloader
+    } else if firmware_type == FirmwareType::Bootloader {
+        return Err(ErrorKind::InvalidFirmware(Some(S!(
+            "firmware appears to be a bootloader rather than an application (use --bootloader to update the bootloader)"
+        ))).error());
     } else {
         firmware_type
     };
 
-    let file_size = firmware_data.len();
-    let file_size = u32::try_from(file_size)
-        .expect("firmware filesize exceeded 32 bits! Firmware binary must be invalid");
+    if let Some(address) = matches.value_of("load-address") {
+        let address = dfu_expert::parse_number(address).expect("Clap ensures a valid address");
+        let load_address = dev.profile().load_address(firmware_type);
+        if address != load_address {
+            return Err(ErrorKind::InvalidFirmware(Some(format!(
+                "the {} goes at 0x{:08x} on this probe, not 0x{:08x} as given{}",
+                firmware_type,
+                load_address,
+                address,
+                if address == dev.profile().load_address(FirmwareType::Bootloader) {
+                    " (use --bootloader to update the bootloader)"
+                } else {
+                    ""
+                },
+            ))).error());
+        }
+    }
+    firmware_data.check_address(dev.profile(), firmware_type)?;
 
     // If we can't get the string descriptors, try to go ahead with flashing anyway.
     // It's unlikely that other control requests will succeed, but the OS might be messing with
     // the string descriptor stuff.
-    let _ = writeln!(std::io::stdout(), "Found: {}", dev)
+    let _ = writeln!(porcelain::status_out(), "Found: {}", dev)
         .map_err(|e| {
             error!("Failed to read string data from Black Magic Probe: {}\nTrying to continue anyway...", e);
         });
 
-    // We need an Rc<T> as [`dfu_core::sync::DfuSync`] requires `progress` to be 'static,
-    // so it must be moved into the closure. However, since we need to call .finish() here,
-    // it must be owned by both. Hence: Rc<T>.
-    // Default template: `{wide_bar} {pos}/{len}`.
-    let progress_bar = ProgressBar::new(file_size as u64)
-        .with_style(ProgressStyle::default_bar()
-            .template(" {percent:>3}% |{bar:50}| {bytes}/{total_bytes} [{binary_bytes_per_sec} {elapsed}]").unwrap()
-        );
-    let progress_bar = Rc::new(progress_bar);
-    let enclosed = Rc::clone(&progress_bar);
-
-    match dev.download(&*firmware_data, file_size, firmware_type, move |flash_pos_delta| {
-        // Don't actually print flashing until the erasing has finished.
-        if enclosed.position() == 0 {
-            if firmware_type == FirmwareType::Application {
-                enclosed.println("Flashing...");
-            } else {
-                enclosed.println("Flashing bootloader...");
-            }
+    let force = matches.is_present("force");
+    bmda::check_released(&policy, force, &dev)?;
+
+    if firmware_type == FirmwareType::Application && matches.value_of("override-firmware-type").is_none() {
+        validate_application(force, &dev, firmware_data)?;
+        check_hardware_target(&policy, force, &dev, firmware_data)?;
+    }
+
+    if firmware_type == FirmwareType::Application {
+        if let Some(version) = already_up_to_date(matches, &dev, firmware_data) {
+            status!("The probe already runs firmware version {}; not flashing it (use --force to flash anyway).", version);
+            return Ok(Some(version.to_string()));
         }
-        enclosed.inc(flash_pos_delta as u64);
-    }) {
-        Ok(()) => {
-            progress_bar.finish();
-            Ok(())
-        },
-        Err(e) => {
-            progress_bar.finish();
-            if progress_bar.position() == (file_size as u64) {
-                warn!("Possibly spurious error from OS at the very end of flashing: {}", e);
-                Ok(())
-            } else {
-                Err(e)
-            }
-        },
-    }?;
+    }
 
-    drop(dev); // Force libusb to free the device.
-    thread::sleep(Duration::from_millis(250));
+    let mut options = download_options_from_cli_args(matches).verify(bootloader_update);
 
-    let dev = bmp::wait_for_probe_reboot(&port, Duration::from_secs(5), "flash")
-        .map_err(|e| {
-            error!("Black Magic Probe did not re-enumerate after flashing! Invalid firmware?");
-            e
-        })?;
+    // A probe left in DFU mode may be partway through an earlier run that never finished.
+    let serial = dev.serial_number().ok().map(|serial| serial.to_string());
+    let interrupted = serial.as_deref()
+        .filter(|_| firmware_type == FirmwareType::Application && dev.operating_mode() == DfuOperatingMode::FirmwareUpgrade)
+        .and_then(|serial| journal::interrupted_flash(serial, firmware_data));
+    if let Some(written) = interrupted {
+        status!("An earlier run was interrupted after writing {} of {} bytes of this firmware to this probe.", written, firmware_data.len());
+        if policy.ask("Resume where it left off, rather than starting over?") {
+            // The part written before can't be trusted blindly, so check all of it afterwards.
+            options = options.resume_from(written).verify(true);
+        } else {
+            status!("Starting over.");
+        }
+    } else if firmware_type == FirmwareType::Application && dev.operating_mode() == DfuOperatingMode::FirmwareUpgrade {
+        if let Some(state) = interrupted_dfu_state(&mut dev) {
+            status!(
+                "The probe's bootloader was left in {}, so an earlier flash was most likely interrupted, \
+                leaving its firmware half-written. Writing it again from the start.",
+                state,
+            );
+        }
+    }
+    let resumed = options.get_resume_from() > 0;
 
+    let dev = run_flash_pipeline(dev, firmware_data, firmware_type, options, flash_backend_from_cli_args(matches)).inspect_err(|e| {
+        if platform == BmpPlatform::STM32DeviceDFU {
+            status!("note: the STM32 bootloader refuses to flash read protected chips; check with `bmputil rdp status`.");
+        }
+        if resumed && matches!(e.kind, ErrorKind::FirmwareVerificationFailed(_)) {
+            status!("note: what the interrupted run wrote didn't survive; run bmputil again to write the firmware from the start.");
+        }
+    })?;
 
-    let desc = dev.device().device_descriptor().unwrap();
+    if dev.operating_mode() == DfuOperatingMode::FirmwareUpgrade {
+        status!("Firmware written. The device stays in DFU mode; run `bmputil switch --to runtime` to start it.");
+        return Ok(None);
+    }
 
-    let product_string = dev
-        .handle()
-        .read_product_string_ascii(
-            &desc
-        )
-        .map_err(|e| {
-            error!("Error reading firmware version after flash! Invalid firmware?");
-            e
-        })?;
+    let version_string = firmware_version_after_flash(&dev)?;
+    status!("Black Magic Probe successfully rebooted into firmware version {}", version_string);
 
-    let version_string = product_string
-        .chars()
-        .skip("Black Magic Probe ".len())
-        .collect::<String>();
+    Ok(Some(version_string))
+}
 
-    println!("Black Magic Probe successfully rebooted into firmware version {}", version_string);
+/// Reads the download options for `flash` from the command line.
+pub(crate) fn download_options_from_cli_args(matches: &ArgMatches) -> DownloadOptions
+{
+    // Unless asked otherwise, a disconnect after the last block has been written is treated as the
+    // device rebooting during manifestation. Either way, we only report success after the device
+    // has re-enumerated.
+    let reboot_to = match matches.value_of("reboot-to") {
+        Some("dfu") => RebootTarget::Dfu,
+        _ => RebootTarget::Application,
+    };
+    let erase_strategy = match matches.value_of("erase-strategy") {
+        Some("sector") => EraseStrategy::Sector,
+        Some("mass") => EraseStrategy::Mass,
+        _ => EraseStrategy::Auto,
+    };
 
-    Ok(())
+    DownloadOptions::new()
+        .manifest_disconnect_ok(!matches.is_present("strict-manifest"))
+        .retry_policy(retry_policy_from_cli_args(matches))
+        .gentle(matches.is_present("gentle"))
+        .reboot_to(reboot_to)
+        .force_reset(matches.is_present("force-reset"))
+        .erase_strategy(erase_strategy)
 }
 
-fn info_command(matches: &ArgMatches) -> Result<(), Error>
+/// Checks `firmware_data` looks like application firmware for `dev` before anything is erased,
+/// unless `force` (as with `--force`) says to flash it anyway.
+pub(crate) fn validate_application(force: bool, dev: &BmpDevice, firmware_data: &[u8]) -> Result<(), Error>
 {
-    let matcher = BmpMatcher::from_cli_args(matches);
+    match FirmwareType::validate_application(dev.profile(), firmware_data) {
+        Err(e) if force => {
+            warnings::emit(WarningCode::ValidationOverridden, format!("Flashing anyway, as --force was given: {}", e));
+            Ok(())
+        },
+        res => res.map_err(|e| e.with_ctx("validating firmware image (use --force to flash it anyway)")),
+    }
+}
 
-    let mut results = matcher.find_matching_probes();
+/// Returns the firmware version `dev` runs, if `firmware_data` was built as the same version, so
+/// flashing it would change nothing. Always `None` with `--force`.
+pub(crate) fn already_up_to_date(matches: &ArgMatches, dev: &BmpDevice, firmware_data: &[u8]) -> Option<FirmwareVersion>
+{
+    // In DFU mode, the product string is the bootloader's.
+    if matches.is_present("force") || dev.operating_mode() != DfuOperatingMode::Runtime {
+        return None;
+    }
 
-    let devices = results.pop_all()?;
+    let image_version = FirmwareVersion::from_image(firmware_data)?;
+    // Two builds with uncommitted changes can differ despite having the same version.
+    if image_version.dirty {
+        return None;
+    }
 
-    let multiple = devices.len() > 1;
-    for (index, dev) in devices.iter().enumerate() {
+    let current = dev.firmware_version()?;
+    debug!("Probe runs firmware version {}, image is version {}", current, image_version);
 
-        println!("Found: {}", dev);
+    (current == image_version).then_some(current)
+}
 
-        // If we have multiple connected probes, then additionally display their index
-        // and print a trailing newline.
-        if multiple {
-            println!("  Index:  {}\n", index);
-        }
-    }
+/// What does the download when flashing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum FlashBackend
+{
+    /// Our own DFU implementation.
+    Native,
+    /// An external dfu-util, for when libusb misbehaves on a platform.
+    DfuUtil,
+}
 
-    Ok(())
+pub(crate) fn flash_backend_from_cli_args(matches: &ArgMatches) -> FlashBackend
+{
+    match matches.value_of("backend") {
+        Some("dfu-util") => FlashBackend::DfuUtil,
+        _ => FlashBackend::Native,
+    }
 }
 
-fn main()
+/// Flashes `firmware_data` onto `dev` with progress bars, returning the device once it has
+/// re-enumerated.
+pub(crate) fn run_flash_pipeline(
+    dev: BmpDevice,
+    firmware_data: &[u8],
+    firmware_type: FirmwareType,
+    options: DownloadOptions,
+    flash_backend: FlashBackend,
+) -> Result<BmpDevice, Error>
 {
-    env_logger::Builder::new()
-        .filter_level(log::LevelFilter::Warn)
-        .parse_default_env()
-        .init();
+    let gentle = options.get_gentle();
+    // Remember how far we got, to spot a probe that keeps disconnecting at the same point, and so a
+    // later run can pick up from there if this one is interrupted.
+    let serial = dev.serial_number().ok().map(|serial| serial.to_string());
+    let journal = serial.as_deref()
+        .filter(|_| firmware_type == FirmwareType::Application)
+        .and_then(|serial| FlashJournal::start(serial, firmware_data, options.get_resume_from()));
+
+    let progress_bars = PhaseProgressBars::new(firmware_type);
+    // The device's timeouts are those given on the command line, lengthened by any hub quirks.
+    let timeouts = dev.timeouts();
+    let backend = UsbBackend::new()?
+        .timeouts(timeouts)
+        .hotplug(!dev.quirks().no_hotplug);
+    let written = Cell::new(0);
+    let on_progress = |progress: DownloadProgress| {
+        if progress.phase == DownloadPhase::Download {
+            written.set(progress.done);
+            if let Some(journal) = &journal {
+                journal.record(progress.done);
+            }
+        }
+        UsageStats::note_transfer_size(progress.transfer_size);
+        progress_bars.update(progress);
+    };
+    let device_context = dev.error_context();
+    let res = match flash_backend {
+        FlashBackend::Native => FlashPipeline::new(backend, SystemClock, firmware_data, firmware_type, options)
+            .enumerate_timeout(timeouts.get_enumerate())
+            .run(dev, on_progress),
+        FlashBackend::DfuUtil => FlashPipeline::new(DfuUtilBackend::new(backend), SystemClock, firmware_data, firmware_type, options)
+            .enumerate_timeout(timeouts.get_enumerate())
+            .run(dev, on_progress),
+    };
+    let res = res.map_err(|e| e.with_device(device_context));
+    progress_bars.finish();
+
+    if let (Err(e), Some(serial)) = (&res, serial) {
+        if matches!(e.kind, ErrorKind::DeviceDisconnectDuringOperation) && brownout::record_disconnect(&serial, written.get()) {
+            brownout::print_power_hint(written.get(), gentle);
+        }
+    }
+    // A resumed flash that doesn't verify can't be resumed again, so the next run starts over.
+    let resumable = match &res {
+        Ok(_) => false,
+        Err(e) => !matches!(e.kind, ErrorKind::FirmwareVerificationFailed(_)),
+    };
+    if let (false, Some(journal)) = (resumable, journal) {
+        journal.finish();
+    }
+
+    res
+}
+
+/// Returns the state the bootloader of `dev`, in DFU mode, was left in, if it's one an interrupted
+/// download leaves it in (dfuDNLOAD-IDLE partway through, or dfuERROR after a failed write).
+fn interrupted_dfu_state(dev: &mut BmpDevice) -> Option<DfuState>
+{
+    dev.with_dfu_interface(|dfu| Ok(dfu.get_status()?.state))
+        .inspect_err(|e| debug!("Failed to read the DFU state of the probe: {}", e))
+        .ok()
+        .filter(|state| matches!(state, DfuState::DfuDnloadIdle | DfuState::DfuError))
+}
+
+/// Reads the firmware version a probe reports after being flashed, as it would be printed.
+pub(crate) fn firmware_version_after_flash(dev: &BmpDevice) -> Result<String, Error>
+{
+    let desc = dev.device().device_descriptor()?;
+
+    let product_string = dev
+        .handle()
+        .read_product_string_ascii(
+            &desc
+        )
+        .inspect_err(|_| {
+            error!("Error reading firmware version after flash! Invalid firmware?");
+        })?;
+
+    let product_string = sanitize_descriptor_string(&product_string);
+    let version_string = FirmwareVersion::from_product_string(&product_string)
+        .map_or_else(
+            || product_string.chars().skip("Black Magic Probe ".len()).collect::<String>(),
+            |version| version.to_string(),
+        );
+
+    Ok(version_string)
+}
+
+fn verify_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let filename = matches.value_of("firmware_binary")
+        .expect("No firmware file was specified!"); // Should be impossible, thanks to clap.
+    let firmware_data = read_firmware_file(filename)
+        .map_err(|e| e.with_ctx("reading firmware file to verify"))?;
+
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
+    let mut dev = results.pop_single("verify")?;
+
+    let firmware_type = FirmwareType::detect_from_firmware(dev.profile(), &firmware_data)
+        .map_err(|e| e.with_ctx("detecting firmware type"))?;
+    debug!("Firmware file was detected as {}", firmware_type);
+    firmware_data.check_address(dev.profile(), firmware_type)?;
+
+    println!("Found: {}", dev);
+
+    let options = DownloadOptions::new()
+        .retry_policy(retry_policy_from_cli_args(matches));
+    let progress_bars = PhaseProgressBars::new(firmware_type);
+    let res = dev.verify(&firmware_data, firmware_type, &options, |progress| {
+        UsageStats::note_transfer_size(progress.transfer_size);
+        progress_bars.update(progress);
+    });
+    progress_bars.finish();
+    res?;
+
+    println!("The {} on the device matches {}.", firmware_type, filename);
+
+    Ok(())
+}
+
+fn info_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let matcher = matcher_from_cli_args(matches);
+    let json = output_format(matches) == OutputFormat::Json;
+    // Keep warnings machine-readable too.
+    warnings::set_json(json);
+
+    let mut results = find_probes(&matcher, matches)?;
+
+    let devices = results.pop_all()?;
+
+    if porcelain::enable_from_cli_args(matches) {
+        for (index, dev) in devices.iter().enumerate() {
+            Record::new().field("index", index).fields_of(&dev.info()).print();
+        }
+
+        return Ok(());
+    }
+
+    if json {
+        let infos: Vec<_> = devices.iter().map(BmpDevice::info).collect();
+        let json = serde_json::to_string_pretty(&infos)
+            .expect("Serializing probe information to JSON should not fail");
+        println!("{}", json);
+
+        return Ok(());
+    }
+
+    let verbose = matches.is_present("verbose");
+    let multiple = devices.len() > 1;
+    for (index, mut dev) in devices.into_iter().enumerate() {
+
+        println!("Found: {}", dev);
+        if let Some(hardware) = dev.hardware_target() {
+            println!("  Hardware:  {}", hardware);
+        }
+        if verbose {
+            if let Some(revision) = hardware_revision(&dev) {
+                println!("  Hardware revision: {}", revision);
+            }
+        }
+        if let Some(port) = dev.serial_port(ProbePort::Gdb) {
+            println!("  GDB port:  {}", port);
+        }
+        if let Some(port) = dev.serial_port(ProbePort::Uart) {
+            println!("  UART port: {}", port);
+        }
+        if verbose {
+            print_usb_details(&mut dev);
+        }
+
+        // If we have multiple connected probes, then additionally display their index
+        // and print a trailing newline.
+        if multiple {
+            println!("  Index:  {}\n", index);
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks the firmware of `dev` for its hardware revision, which only official probes have.
+///
+/// This goes through the GDB server, so is `None` if the probe isn't in runtime mode, or its GDB
+/// port can't be opened (e.g. as GDB is using it).
+fn hardware_revision(dev: &BmpDevice) -> Option<u32>
+{
+    let port = dev.serial_port(ProbePort::Gdb)?;
+    let output = GdbRemote::open(&port)
+        .and_then(|mut remote| remote.monitor("version"))
+        .inspect_err(|e| debug!("Could not run `monitor version` on {}: {}", port, e))
+        .ok()?;
+
+    bmputil::hardware::revision_from_monitor_version(&output)
+}
+
+/// Prints the configuration, interfaces, and DFU details of `dev`, for `info --verbose`.
+///
+/// Anything that can't be read is reported in place rather than failing, as this is mostly used
+/// on probes that misbehave.
+fn print_usb_details(dev: &mut BmpDevice)
+{
+    let timeout = dev.timeouts().get_control();
+    match dev.device().active_config_descriptor() {
+        Ok(config) => {
+            println!("  Configuration {}:", config.number());
+            for interface in config.interfaces() {
+                for desc in interface.descriptors() {
+                    let name = dev.handle()
+                        .read_alt_setting_name(desc.interface_number(), desc.setting_number(), timeout)
+                        .map(|name| format!(" {:?}", sanitize_descriptor_string(&name)))
+                        .unwrap_or_default();
+                    println!(
+                        "    Interface {} alt {}: class {:02x}/{:02x}/{:02x}, {} endpoint(s){}",
+                        desc.interface_number(),
+                        desc.setting_number(),
+                        desc.class_code(),
+                        desc.sub_class_code(),
+                        desc.protocol_code(),
+                        desc.num_endpoints(),
+                        name,
+                    );
+                }
+            }
+        },
+        Err(e) => println!("  Configuration: unavailable ({})", e),
+    }
+
+    let func_desc = match dev.dfu_descriptors() {
+        Ok((interface, func_desc)) => {
+            println!("  DFU functional descriptor (interface {}):", interface);
+            func_desc
+        },
+        Err(e) => {
+            println!("  DFU functional descriptor: unavailable ({})", e);
+            return;
+        },
+    };
+    let flag = |set: bool| if set { "yes" } else { "no" };
+    let version = func_desc.bcdDFUVersion;
+    println!("    bcdDFUVersion:  {:x}.{:02x}{}", version >> 8, version & 0xff, if version == 0x011a { " (DfuSe)" } else { "" });
+    println!("    wTransferSize:  {} bytes", func_desc.wTransferSize);
+    println!("    wDetachTimeOut: {} ms", func_desc.wDetachTimeOut);
+    println!("    bmAttributes:   0x{:02x}", func_desc.bmAttributes);
+    println!("      can download:             {}", flag(func_desc.can_download()));
+    println!("      can upload:               {}", flag(func_desc.can_upload()));
+    println!("      manifestation tolerant:   {}", flag(func_desc.manifestation_tolerant()));
+    println!("      detaches by itself:       {}", flag(func_desc.will_detach()));
+
+    if dev.operating_mode() != DfuOperatingMode::FirmwareUpgrade {
+        println!("  DFU state: not queried, as the probe is in runtime mode");
+        return;
+    }
+    match dev.with_dfu_interface(|dfu| Ok(dfu.get_status()?)) {
+        Ok(status) => println!(
+            "  DFU state: {} (status: {}, poll timeout {} ms)",
+            status.state,
+            status.status,
+            status.poll_timeout.as_millis(),
+        ),
+        Err(e) => println!("  DFU state: unavailable ({})", e),
+    }
+}
+
+fn port_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let port = if matches.is_present("uart") {
+        ProbePort::Uart
+    } else {
+        ProbePort::Gdb
+    };
+
+    let serial = match matches.value_of("probe_serial") {
+        Some(probe) => {
+            let probe = config().nicknames.get(probe).map_or(probe, String::as_str);
+
+            // Skip enumerating probes over USB entirely if we were given a whole serial number.
+            match serial_port::find_serial_port(probe, port) {
+                Ok(path) => {
+                    println!("{}", path);
+                    return Ok(());
+                },
+                Err(e) => debug!("{} is not the whole serial number of a connected probe ({}); matching it as --serial would", probe, e),
+            }
+
+            // Otherwise, it can be the start of one, or a glob, as with --serial.
+            let matcher = BmpMatcher::new()
+                .serials([probe])
+                .timeouts(timeouts_from_cli_args(matches));
+            let mut results = find_probes(&matcher, matches)?;
+            let dev = results.pop_single("look up serial port")?;
+            let serial = dev.serial_number()?.to_string();
+            serial
+        },
+        None => {
+            let matcher = matcher_from_cli_args(matches);
+            let mut results = find_probes(&matcher, matches)?;
+            let dev = results.pop_single("look up serial port")?;
+            let serial = dev.serial_number()?.to_string();
+            serial
+        },
+    };
+
+    // Print only the port, so this can be used in shell substitutions.
+    println!("{}", serial_port::find_serial_port(&serial, port)?);
+
+    Ok(())
+}
+
+/// Implements `bmputil which`, printing which probe an operation would use, and how it would talk to
+/// it, without doing anything to the probe.
+fn which_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let operation = matches.value_of("operation");
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
+    let dev = results.pop_single(operation.unwrap_or("which"))?;
+
+    let mode = dev.operating_mode();
+    println!("{}", dev);
+    println!("  Serial: {}", dev.serial_number()?);
+    println!("  Port:   {}", dev.port());
+    println!("  Mode:   {}", mode_description(mode));
+
+    let wanted = |name: &str| operation.is_none() || operation == Some(name);
+    if wanted("flash") || wanted("verify") {
+        let (interface, _) = dev.dfu_descriptors()?;
+        let load_address = dev.profile().load_address(FirmwareType::Application);
+        let via = match mode {
+            DfuOperatingMode::Runtime => "after detaching into DFU mode",
+            DfuOperatingMode::FirmwareUpgrade => "directly",
+        };
+        for name in ["flash", "verify"].into_iter().filter(|name| wanted(name)) {
+            println!(
+                "{:<9} DFU, {} (currently interface {}); firmware at 0x{:08x}",
+                format!("{}:", name),
+                via,
+                interface,
+                load_address,
+            );
+        }
+    }
+
+    let ports = [("monitor", ProbePort::Gdb), ("terminal", ProbePort::Uart)];
+    for (name, port) in ports.into_iter().filter(|(name, _)| wanted(name)) {
+        let target = match mode {
+            DfuOperatingMode::Runtime => dev.serial_port(port)
+                .map_or_else(|| format!("{} (not found)", port), |path| format!("{} {}", port, path)),
+            DfuOperatingMode::FirmwareUpgrade => S!("unavailable in DFU mode"),
+        };
+        println!("{:<9} {}", format!("{}:", name), target);
+    }
+
+    Ok(())
+}
+
+/// Finds the single probe matching the command line, and connects to its GDB server for running
+/// monitor commands. Also returns its firmware version, if known.
+fn open_gdb_remote(matches: &ArgMatches, operation: &str) -> Result<(GdbRemote, Option<FirmwareVersion>), Error>
+{
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
+    let dev = results.pop_single(operation)?;
+
+    if dev.operating_mode() != DfuOperatingMode::Runtime {
+        return Err(ErrorKind::OperationNotSupported(format!("{} while in DFU mode", operation)).error());
+    }
+
+    let version = dev.firmware_version();
+    let serial = dev.serial_number()?.to_string();
+    let port = serial_port::find_serial_port(&serial, ProbePort::Gdb)?;
+    drop(dev);
+
+    Ok((GdbRemote::open(&port)?, version))
+}
+
+fn settings_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let (subcommand, subcommand_matches) = matches.subcommand()
+        .expect("No subcommand given!"); // Should be impossible, thanks to clap.
+
+    if subcommand == "list" {
+        for setting in KNOWN_SETTINGS {
+            let (major, minor) = setting.since();
+            let values = setting.values.map_or_else(|| S!("<value>"), |values| values.join("|"));
+            println!("{:<16} {:<18} (since v{}.{}) {}", setting.name, values, major, minor, setting.description);
+        }
+        return Ok(());
+    }
+
+    let name = subcommand_matches.value_of("setting").unwrap();
+    let setting = ProbeSetting::find(name)
+        .ok_or_else(|| ErrorKind::OperationNotSupported(format!("unknown setting {:?} (see bmputil settings list)", name)).error())?;
+
+    let (mut remote, version) = open_gdb_remote(matches, "settings")?;
+
+    let output = match subcommand {
+        "get" => setting.get(&mut remote, version.as_ref())?,
+        "set" => setting.set(&mut remote, version.as_ref(), subcommand_matches.value_of("value").unwrap())?,
+        other => unreachable!("Unhandled subcommand {:?}", other),
+    };
+
+    if !output.is_empty() {
+        println!("{}", output);
+    }
+
+    Ok(())
+}
+
+fn power_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let value = match matches.value_of("state").unwrap() {
+        "on" => "enable",
+        "off" => "disable",
+        other => unreachable!("Unhandled power state {:?}", other),
+    };
+    let setting = ProbeSetting::find("target-power").expect("target-power is a known setting");
+
+    let (mut remote, version) = open_gdb_remote(matches, "target power control")?;
+    let output = setting.set(&mut remote, version.as_ref(), value)?;
+    if !output.is_empty() {
+        println!("{}", output);
+    }
+
+    Ok(())
+}
+
+fn rdp_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let (subcommand, _) = matches.subcommand()
+        .expect("No subcommand given!"); // Should be impossible, thanks to clap.
+
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
+    let mut dev = results.pop_single("read protection")?;
+    println!("Found: {}", dev);
+
+    let policy = ConfirmationPolicy::from_cli_args(matches);
+    match subcommand {
+        "status" => {
+            let level = rdp::read_level(&mut dev)?;
+            println!("Read protection: {}", level);
+            if level != RdpLevel::Level0 {
+                println!("The probe can't be flashed until read protection is removed with `bmputil rdp disable`.");
+            }
+        },
+        "disable" => {
+            policy.confirm(
+                AuthorizationLevel::Destructive,
+                "removing read protection",
+                "REMOVING READ PROTECTION MASS ERASES THE WHOLE FLASH OF THE CHIP.\n\
+                The firmware on the probe, and anything else stored in its flash, will be gone for good; \
+                flash the probe again afterwards.",
+            )?;
+            rdp::remove(&mut dev)?;
+            println!("Read protection removed and flash erased. The probe resets; replug it if it doesn't come back in DFU mode.");
+        },
+        "enable" => {
+            policy.confirm(
+                AuthorizationLevel::Destructive,
+                "enabling read protection",
+                "Enabling read protection stops the probe's flash from being read or updated.\n\
+                The ONLY way to remove it again is `bmputil rdp disable`, which MASS ERASES THE WHOLE FLASH.",
+            )?;
+            rdp::enable(&mut dev)?;
+            println!("Read protection enabled. The probe resets to apply it.");
+        },
+        other => unreachable!("Unhandled rdp subcommand {:?}", other),
+    }
+
+    Ok(())
+}
+
+fn monitor_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let command: Vec<&str> = matches.values_of("command").unwrap().collect();
+
+    let (mut remote, _version) = open_gdb_remote(matches, "monitor")?;
+    let output = remote.monitor(&command.join(" "))?;
+    print!("{}", output);
+    if !output.is_empty() && !output.ends_with('\n') {
+        println!();
+    }
+
+    Ok(())
+}
+
+fn stats_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let (subcommand, _subcommand_matches) = matches.subcommand()
+        .expect("No subcommand given!"); // Should be impossible, thanks to clap.
+
+    match subcommand {
+        "enable" => {
+            UsageStats::enable()?;
+            println!("Usage statistics enabled. They are only stored locally; run `bmputil stats export` to see them.");
+        },
+        "disable" => {
+            UsageStats::disable()?;
+            println!("Usage statistics disabled, and any collected statistics deleted.");
+        },
+        "status" => {
+            if UsageStats::enabled() {
+                println!("Usage statistics are enabled.");
+            } else {
+                println!("Usage statistics are disabled.");
+            }
+        },
+        "export" => match UsageStats::load()? {
+            Some(stats) => println!("{}", stats.export()),
+            None => {
+                return Err(ErrorKind::OperationNotSupported(
+                    S!("exporting usage statistics, as they are disabled (see bmputil stats enable)")
+                ).error());
+            },
+        },
+        other => unreachable!("Unhandled subcommand {:?}", other),
+    };
+
+    Ok(())
+}
+
+
+fn profiles_command(_matches: &ArgMatches) -> Result<(), Error>
+{
+    // Read the user's profiles directly, so mistakes in them are reported rather than ignored.
+    let path = profiles::user_profiles_path();
+    let user_profiles = match &path {
+        Some(path) => profiles::load_user_profiles(path)?,
+        None => Vec::new(),
+    };
+    match &path {
+        Some(path) => println!("User profiles: {}", path.display()),
+        None => println!("User profiles: (config directory unknown)"),
+    }
+
+    let ids = |(Vid(vid), Pid(pid)): (Vid, Pid)| format!("{:04x}:{:04x}", vid, pid);
+    for profile in user_profiles.iter().chain(&ProbeProfile::built_in()) {
+        println!();
+        println!("{}{}", profile.name, if profile.user_defined { " (user)" } else { "" });
+        println!("  Bootloader:  {}", profile.platform);
+        println!("  DFU IDs:     {}", ids(profile.dfu_ids));
+        if let Some(runtime_ids) = profile.runtime_ids {
+            println!("  Runtime IDs: {}", ids(runtime_ids));
+        }
+        println!("  Flash base:  0x{:08x}", profile.flash_base);
+        if let Some(size) = profile.flash_size {
+            println!("  Flash size:  {} bytes", size);
+        }
+        if let Some(product) = &profile.product {
+            println!("  Product:     contains {:?}", product);
+        }
+    }
+
+    Ok(())
+}
+
+fn dump_descriptors_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    if let Some(dumps) = matches.values_of("check") {
+        return check_descriptor_dumps(dumps);
+    }
+
+    let mut results = find_probes(&matcher_from_cli_args(matches), matches)?;
+    let dev = results.pop_single("dump descriptors")?;
+    let source = matches.value_of("source").unwrap_or_default().to_string();
+    let dump = DescriptorDump::capture(&dev.handle(), source, dev.timeouts().get_control())
+        .map_err(|e| e.with_ctx("reading descriptors"))?;
+
+    match matches.value_of("output") {
+        Some(path) => {
+            std::fs::write(path, dump.to_json() + "\n")
+                .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error().with_ctx(&format!("writing {}", path)))?;
+            println!("Wrote descriptors of {} to {}", dev, path);
+        },
+        None => println!("{}", dump.to_json()),
+    }
+
+    Ok(())
+}
+
+/// Runs each descriptor dump through bmputil's parsers, reporting what it makes of them.
+fn check_descriptor_dumps<'a>(paths: impl Iterator<Item = &'a str>) -> Result<(), Error>
+{
+    let mut failures = 0;
+    for path in paths {
+        let summary = DescriptorDump::load(Path::new(path)).and_then(|dump| dump.check());
+        let summary = match summary {
+            Ok(summary) => summary,
+            Err(e) => {
+                println!("{}: FAILED: {}", path, e);
+                failures += 1;
+                continue;
+            },
+        };
+
+        let profile = match summary.profile {
+            Some((profile, mode)) => format!("{} in {}", profile.name, mode_description(mode)),
+            None => S!("not a known probe"),
+        };
+        println!("{}: ok, {:04x}:{:04x}, {}", path, summary.vid.0, summary.pid.0, profile);
+        for (interface, functional) in &summary.dfu_interfaces {
+            println!(
+                "  DFU interface {}: DFU {:x}.{:02x}, transfer size {}, attributes {:#04x}",
+                interface,
+                functional.bcdDFUVersion >> 8,
+                functional.bcdDFUVersion & 0xff,
+                functional.wTransferSize,
+                functional.bmAttributes,
+            );
+        }
+        if let Some(container_id) = summary.container_id {
+            let hex: String = container_id.iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("  Container ID: {}", hex);
+        }
+    }
+
+    if failures > 0 {
+        let e = std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} descriptor dump(s) failed to parse", failures));
+        return Err(ErrorKind::External(ErrorSource::StdIo(e)).error());
+    }
+
+    Ok(())
+}
+
+fn config_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let (subcommand, _subcommand_matches) = matches.subcommand()
+        .expect("No subcommand given!"); // Should be impossible, thanks to clap.
+
+    match subcommand {
+        "path" => {
+            let show = |dir: Option<PathBuf>| {
+                dir.map_or_else(|| S!("(unknown)"), |dir| dir.display().to_string())
+            };
+            println!("config:  {}", show(paths::config_dir()));
+            println!("file:    {}", match matches.value_of("config") {
+                Some(path) => S!(path),
+                None => show(config::default_path()),
+            });
+            println!("cache:   {}", show(paths::cache_dir()));
+            println!("data:    {}", show(paths::data_dir()));
+        },
+        other => unreachable!("Unhandled subcommand {:?}", other),
+    };
+
+    Ok(())
+}
+
+fn use_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    if matches.is_present("clear") {
+        if Selection::clear()? {
+            println!("Cleared the default probe; commands without a probe filter act on any probe again.");
+        } else {
+            println!("No default probe was set.");
+        }
+        return Ok(());
+    }
+
+    let Some(probe) = matches.value_of("probe") else {
+        match SELECTION.get().cloned().flatten() {
+            Some(selection) => println!("Default probe: {}", describe_selection(&selection)),
+            None => println!("No default probe is set; choose one with `bmputil use <serial|nickname>`."),
+        }
+        return Ok(());
+    };
+
+    let selection = match config().nicknames.get(probe) {
+        Some(serial) => Selection {
+            serial: serial.clone(),
+            nickname: Some(S!(probe)),
+        },
+        None => Selection {
+            serial: S!(probe),
+            nickname: None,
+        },
+    };
+
+    // Choosing a probe that isn't connected is fine (it may be plugged in later), but worth a mention.
+    let connected = BmpMatcher::new()
+        .serials([selection.serial.as_str()])
+        .timeouts(timeouts_from_cli_args(matches))
+        .find_matching_probes()
+        .found;
+    if connected.is_empty() {
+        println!("note: no probe with serial number {} is connected right now.", selection.serial);
+    }
+
+    selection.save()?;
+    println!(
+        "Commands given no --serial, --port, or --index now act on {}.",
+        describe_selection(&selection),
+    );
+
+    Ok(())
+}
+
+fn list_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let matcher = matcher_from_cli_args(matches);
+    let mut results = find_probes(&matcher, matches)?;
+    let devices = results.pop_all()?;
+
+    let mode_name = |dev: &BmpDevice| match dev.operating_mode() {
+        DfuOperatingMode::Runtime => "runtime",
+        DfuOperatingMode::FirmwareUpgrade => "dfu",
+    };
+
+    if porcelain::enable_from_cli_args(matches) {
+        for (index, dev) in devices.iter().enumerate() {
+            Record::new()
+                .field("index", index)
+                .optional_field("serial", dev.serial_number().ok())
+                .field("mode", mode_name(dev))
+                .field("port", dev.port())
+                .optional_field("firmware_version", dev.firmware_version())
+                .optional_field("gdb_port", dev.serial_port(ProbePort::Gdb))
+                .optional_field("uart_port", dev.serial_port(ProbePort::Uart))
+                .print();
+        }
+
+        return Ok(());
+    }
+
+    let header = [S!("INDEX"), S!("SERIAL"), S!("MODE"), S!("PORT"), S!("VERSION"), S!("GDB"), S!("UART")];
+    let rows: Vec<[String; 7]> = devices
+        .iter()
+        .enumerate()
+        .map(|(index, dev)| {
+            let serial = dev.serial_number()
+                .map(|serial| serial.to_string())
+                .unwrap_or_else(|_| S!("?"));
+            let mode = mode_name(dev);
+            let version = dev.firmware_version()
+                .map_or_else(|| S!("unknown"), |version| version.to_string());
+
+            let gdb_port = dev.serial_port(ProbePort::Gdb).unwrap_or_else(|| S!("-"));
+            let uart_port = dev.serial_port(ProbePort::Uart).unwrap_or_else(|| S!("-"));
+
+            [index.to_string(), serial, S!(mode), dev.port(), version, gdb_port, uart_port]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|column| column.len());
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+
+    Ok(())
+}
+
+/// Builds the command line interface, which the interactive shell reuses for its commands.
+fn cli() -> Command<'static>
+{
+    let mut parser = Command::new("Black Magic Probe Firmware Manager");
+    if cfg!(windows) {
+        parser = parser
+            .arg(Arg::new("windows-wdi-install-mode")
+                .long("windows-wdi-install-mode")
+                .required(false)
+                .takes_value(true)
+                .global(true)
+                .hide(true)
+                .help("Internal argument used when re-executing this command to acquire admin for installing drivers")
+            )
+            .arg(Arg::new("usb-backend")
+                .long("usb-backend")
+                .required(false)
+                .takes_value(true)
+                .global(true)
+                .possible_values(["native", "usbdk"])
+                .hide_short_help(true)
+                .help("libusb backend to use: native uses the driver bound to each device (WinUSB, libusbK, or libusb0), usbdk uses UsbDk, which must be installed")
+            );
+    }
+    parser = parser
+        .arg_required_else_help(true)
+        .arg(Arg::new("serial_number")
+            .short('s')
+            .long("serial")
+            .alias("serial-number")
+            .required(false)
+            .takes_value(true)
+            .global(true)
+            .multiple_occurrences(true)
+            .help("Use the device with the given serial number, or the one it starts with, or a glob like 7BB1*; may be given more than once")
+        )
+        .arg(Arg::new("index")
+            .long("index")
+            .required(false)
+            .takes_value(true)
+            .global(true)
+            .validator(usize::from_str)
+            .help("Use the nth found device (may be unstable!)")
+        )
+        .arg(Arg::new("port")
+            .short('p')
+            .long("port")
+            .required(false)
+            .takes_value(true)
+            .global(true)
+            .multiple_occurrences(true)
+            .help("Use the device on the given USB port; may be given more than once")
+        )
+        .arg(Arg::new("wait")
+            .long("wait")
+            .global(true)
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .value_name("seconds")
+            .validator(|secs| secs.parse::<u64>())
+            .help("If no matching device is connected, wait for one (for up to the given time)")
+        )
+        .arg(Arg::new("allow-dangerous-options")
+            .long("allow-dangerous-options")
+            .global(true)
+            .takes_value(true)
+            .possible_value("really")
+            .hide(true)
+            .help("Allow usage of advanced, dangerous options that can result in unbootable devices (use with heavy caution!)")
+        )
+        .arg(Arg::new("timeout")
+            .long("timeout")
+            .required(false)
+            .takes_value(true)
+            .global(true)
+            .value_name("seconds")
+            .validator(|secs| secs.parse::<u64>())
+            .help("How long to wait for a device to come back after it reboots (default: 5)")
+        )
+        .arg(Arg::new("transfer-timeout")
+            .long("transfer-timeout")
+            .required(false)
+            .takes_value(true)
+            .global(true)
+            .value_name("ms")
+            .validator(|ms| ms.parse::<u64>())
+            .hide_short_help(true)
+            .help("Timeout for each individual USB transfer, for slow hubs or VMs (default: 2000)")
+        )
+        .arg(Arg::new("suppress-warning")
+            .long("suppress-warning")
+            .global(true)
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .value_name("code")
+            .validator(|code| WarningCode::from_code(code).ok_or("unknown warning code"))
+            .hide_short_help(true)
+            .help("Don't print the warning with the given code (e.g. BMPW001); may be given more than once")
+        )
+        .arg(Arg::new("verbosity")
+            .short('v')
+            .global(true)
+            .takes_value(false)
+            .multiple_occurrences(true)
+            .help("Log more detail, and summarize USB activity at the end (-vv and -vvv for even more, -vvvv for every USB transfer)")
+        )
+        .arg(Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .global(true)
+            .takes_value(false)
+            .conflicts_with("verbosity")
+            .help("Only log errors")
+        )
+        .arg(Arg::new("log-file")
+            .long("log-file")
+            .global(true)
+            .takes_value(true)
+            .value_name("file")
+            .help("Write everything logged, down to each USB transfer, to this file (e.g. to attach to a bug report)")
+        )
+        .arg(Arg::new("assume-yes")
+            .short('y')
+            .long("assume-yes")
+            .global(true)
+            .takes_value(false)
+            .help("Answer yes to confirmation prompts for risky operations (or set BMPUTIL_ASSUME_YES=1)")
+        )
+        .arg(Arg::new("config")
+            .long("config")
+            .global(true)
+            .takes_value(true)
+            .value_name("file")
+            .hide_short_help(true)
+            .help("Read default options from this file instead of config.toml in the config directory")
+        )
+        .arg(Arg::new("usb-diagnostics")
+            .long("usb-diagnostics")
+            .global(true)
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .value_name("file")
+            .hide_short_help(true)
+            .help("On failure, report libusb's debug log with the error, or write it to the given file")
+        )
+        .subcommand(Command::new("info")
+            .display_order(0)
+            .about("Print information about connected Black Magic Probe devices")
+            .arg(Arg::new("format")
+                .long("format")
+                .required(false)
+                .takes_value(true)
+                .possible_values(["text", "json"])
+                .default_value("text")
+                .help("output format; json prints a machine-readable array of devices")
+            )
+            .arg(Arg::new("porcelain")
+                .long("porcelain")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("format")
+                .help("print stable key=value records, for scripts")
+            )
+            .arg(Arg::new("verbose")
+                .long("verbose")
+                .required(false)
+                .takes_value(false)
+                .help("also print each probe's USB interfaces, DFU functional descriptor, and DFU state, e.g. to debug a clone that fails to flash")
+            )
+        )
+        .subcommand(Command::new("list")
+            .display_order(0)
+            .about("List connected Black Magic Probe devices in a table, with their firmware versions")
+            .arg(Arg::new("porcelain")
+                .long("porcelain")
+                .required(false)
+                .takes_value(false)
+                .help("print stable key=value records instead of a table, for scripts")
+            )
+        )
+        .subcommand(Command::new("port")
+            .display_order(2)
+            .about("Print only the GDB serial port of a Black Magic Probe device, for use in shell substitutions")
+            .arg(Arg::new("probe_serial")
+                .takes_value(true)
+                .required(false)
+                .help("serial number of the probe (or its start, or a glob, as with --serial), or its nickname from the \
+                    [nicknames] table of the configuration file (defaults to the single probe matching the other options)")
+            )
+            .arg(Arg::new("uart")
+                .long("uart")
+                .required(false)
+                .takes_value(false)
+                .help("print the UART passthrough serial port instead")
+            )
+        )
+        .subcommand(Command::new("which")
+            .display_order(2)
+            .about("Print which Black Magic Probe device an operation would use, and how, without doing it")
+            .arg(Arg::new("operation")
+                .takes_value(true)
+                .required(false)
+                .possible_values(["flash", "verify", "terminal", "monitor"])
+                .help("only show what this operation would use (monitor also covers power and settings)")
+            )
+        )
+        .subcommand(Command::new("switch")
+            .display_order(2)
+            .about("Switch a Black Magic Probe device between runtime and DFU mode")
+            .arg(Arg::new("to")
+                .long("to")
+                .takes_value(true)
+                .required(true)
+                .possible_values(["dfu", "runtime"])
+                .help("the mode to switch the device to")
+            )
+        )
+        .subcommand(Command::new("reboot")
+            .display_order(2)
+            .about("Reboot a Black Magic Probe device into its runtime firmware")
+        )
+        .subcommand(Command::new("flash")
+            .display_order(1)
+            .about("Flash new firmware onto a Black Magic Probe device")
+            .arg(Arg::new("firmware_binary")
+                .takes_value(true)
+                .multiple_values(true)
+                .required(true)
+                .help("firmware image to flash; given several (e.g. builds for different hardware), the one built for the probe's hardware is used")
+            )
+            .arg(Arg::new("bootloader")
+                .long("bootloader")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("override-firmware-type")
+                .help("update the bootloader of the device (verified before rebooting; requires --allow-dangerous-options=really)")
+            )
+            .arg(Arg::new("override-firmware-type")
+                .long("override-firmware-type")
+                .required(false)
+                .takes_value(true)
+                .possible_values(["bootloader", "application"])
+                .hide_short_help(true)
+                .help("flash the specified firmware space regardless of autodetected firmware type")
+            )
+            .arg(Arg::new("load-address")
+                .long("load-address")
+                .required(false)
+                .takes_value(true)
+                .value_name("address")
+                .validator(dfu_expert::parse_number)
+                .hide_short_help(true)
+                .help("refuse to flash unless the image goes at this address (as dfu-util's -s gives it), in decimal or 0x-prefixed hex")
+            )
+            .arg(Arg::new("force-override-flash")
+                .long("force-override-flash")
+                .required(false)
+                .takes_value(true)
+                .possible_value("really")
+                .hide(true)
+                .help("forcibly override firmware-type autodetection and flash anyway (may result in an unbootable device!)")
+            )
+            .arg(Arg::new("strict-manifest")
+                .long("strict-manifest")
+                .required(false)
+                .takes_value(false)
+                .hide_short_help(true)
+                .help("treat the device disconnecting during the final manifestation phase as an error")
+            )
+            .arg(Arg::new("usb-retries")
+                .long("usb-retries")
+                .required(false)
+                .takes_value(true)
+                .value_name("count")
+                .validator(|count| count.parse::<u32>())
+                .hide_short_help(true)
+                .help("how many times to retry USB transfers that fail because of a transient error (default: 2)")
+            )
+            .arg(Arg::new("gentle")
+                .long("gentle")
+                .required(false)
+                .takes_value(false)
+                .help("flash in small, slower transfers, for probes that disconnect partway through (e.g. on unpowered hubs)")
+            )
+            .arg(Arg::new("reboot-to")
+                .long("reboot-to")
+                .required(false)
+                .takes_value(true)
+                .possible_values(["app", "dfu"])
+                .default_value("app")
+                .help("what the probe should be running after flashing; dfu leaves it in DFU mode without starting the new firmware")
+            )
+            .arg(Arg::new("backend")
+                .long("backend")
+                .required(false)
+                .takes_value(true)
+                .possible_values(["native", "dfu-util"])
+                .default_value("native")
+                .help("what writes the firmware; dfu-util runs an external dfu-util (or $BMPUTIL_DFU_UTIL), for when libusb misbehaves on this platform")
+            )
+            .arg(Arg::new("erase-strategy")
+                .long("erase-strategy")
+                .required(false)
+                .takes_value(true)
+                .possible_values(["auto", "sector", "mass"])
+                .default_value("auto")
+                .help("how to erase flash before writing; mass erases the whole chip at once where the bootloader supports it, which auto does for full images")
+            )
+            .arg(Arg::new("force-reset")
+                .long("force-reset")
+                .required(false)
+                .takes_value(false)
+                .help("always finish with a USB reset, so the bootloader fully resets the probe (ignored with --reboot-to dfu)")
+            )
+            .arg(Arg::new("force")
+                .long("force")
+                .required(false)
+                .takes_value(false)
+                .help("flash even if the probe already runs the same firmware version as the image, or the image fails sanity checks")
+            )
+            .arg(Arg::new("report")
+                .long("report")
+                .required(false)
+                .takes_value(true)
+                .value_name("file.json")
+                .help("add a machine-readable record of each probe flashed (serial, versions, image hash, result) to this file")
+            )
+            .arg(Arg::new("trusted-key")
+                .long("trusted-key")
+                .required(false)
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("key")
+                .help("check the image's minisign signature (the image's name plus .minisig) against this public key, or the key in this file; may be given more than once")
+            )
+            .arg(Arg::new("require-signed")
+                .long("require-signed")
+                .required(false)
+                .takes_value(false)
+                .help("refuse to flash an image without a valid signature from a trusted key")
+            )
+            .arg(Arg::new("on-connect")
+                .long("on-connect")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with_all(&["bootloader", "override-firmware-type", "wait"])
+                .help("keep running, flashing and verifying every matching probe that is plugged in, e.g. for production programming")
+            )
+            .arg(Arg::new("porcelain")
+                .long("porcelain")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("on-connect")
+                .help("print a stable key=value record of the result on stdout, and everything else on stderr, for scripts")
+            )
+        );
+
+    parser = parser.subcommand(Command::new("verify")
+        .display_order(1)
+        .about("Check the firmware on a Black Magic Probe device matches a file, without flashing anything")
+        .arg(Arg::new("firmware_binary")
+            .takes_value(true)
+            .required(true)
+        )
+        .arg(Arg::new("usb-retries")
+            .long("usb-retries")
+            .required(false)
+            .takes_value(true)
+            .value_name("count")
+            .validator(|count| count.parse::<u32>())
+            .hide_short_help(true)
+            .help("how many times to retry USB transfers that fail because of a transient error (default: 2)")
+        )
+    );
+
+    if cfg!(target_os = "linux") {
+        parser = parser.subcommand(Command::new("install-udev")
+            .display_order(4)
+            .about("Install udev rules so Black Magic Probe devices can be used without root (needs sudo)")
+            .arg(Arg::new("print")
+                .long("print")
+                .required(false)
+                .takes_value(false)
+                .help("print the rules instead of installing them")
+            )
+        );
+    }
 
-    let mut parser = Command::new("Black Magic Probe Firmware Manager");
     if cfg!(windows) {
-        parser = parser
-            .arg(Arg::new("windows-wdi-install-mode")
-                .long("windows-wdi-install-mode")
+        parser = parser.subcommand(Command::new("setup-driver")
+            .display_order(4)
+            .about("Install the WinUSB driver bmputil needs for Black Magic Probe devices (needs administrator access)")
+            .arg(Arg::new("force")
+                .long("force")
                 .required(false)
-                .takes_value(true)
-                .global(true)
-                .hide(true)
-                .help("Internal argument used when re-executing this command to acquire admin for installing drivers")
-            );
+                .takes_value(false)
+                .help("install the driver even if one is already installed")
+            )
+            .arg(Arg::new("check")
+                .long("check")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("force")
+                .help("only report which drivers are installed, without changing anything")
+            )
+        );
     }
-    parser = parser
+
+    parser = parser.subcommand(Command::new("settings")
+        .display_order(3)
+        .about("Read or change settings of a Black Magic Probe device through its monitor commands")
         .arg_required_else_help(true)
-        .arg(Arg::new("serial_number")
-            .short('s')
-            .long("serial")
-            .alias("serial-number")
+        .subcommand_required(true)
+        .subcommand(Command::new("list")
+            .about("List the settings bmputil knows about")
+        )
+        .subcommand(Command::new("get")
+            .about("Print the current value of a setting")
+            .arg(Arg::new("setting")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+        .subcommand(Command::new("set")
+            .about("Change a setting")
+            .arg(Arg::new("setting")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::new("value")
+                .takes_value(true)
+                .required(true)
+            )
+        )
+    );
+
+    parser = parser.subcommand(Command::new("power")
+        .display_order(3)
+        .about("Switch the power a Black Magic Probe device supplies to the target on or off")
+        .arg(Arg::new("state")
+            .takes_value(true)
+            .required(true)
+            .possible_values(["on", "off"])
+        )
+    );
+
+    parser = parser.subcommand(Command::new("rdp")
+        .display_order(3)
+        .about("Query or change the read protection of a probe in the STM32 built-in DFU bootloader")
+        .arg_required_else_help(true)
+        .subcommand_required(true)
+        .subcommand(Command::new("status")
+            .about("Print the read protection level")
+        )
+        .subcommand(Command::new("enable")
+            .about("Enable level 1 read protection (only removable by mass erasing the flash)")
+        )
+        .subcommand(Command::new("disable")
+            .about("Remove read protection, which MASS ERASES the whole flash")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("monitor")
+        .display_order(3)
+        .about("Run a monitor command (e.g. swdp_scan, version, frequency) on a Black Magic Probe device and print its output")
+        .arg(Arg::new("command")
+            .takes_value(true)
+            .multiple_values(true)
+            .required(true)
+            .help("the monitor command and its arguments, as typed after `monitor` in GDB")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("terminal")
+        .display_order(3)
+        .about("Open an interactive terminal on the UART passthrough of a Black Magic Probe device")
+        .arg(Arg::new("baud")
+            .long("baud")
+            .takes_value(true)
+            .validator(|value| value.parse::<u32>().map(|_| ()).map_err(|_| S!("must be a number")))
+            .help("baud rate of the target's UART (default: the last one used with this probe, or 115200)")
+        )
+        .arg(Arg::new("line-ending")
+            .long("line-ending")
+            .takes_value(true)
+            .possible_values(LineEnding::NAMES)
+            .help("what Enter sends (default: the last one used with this probe, or cr)")
+        )
+        .arg(Arg::new("log")
+            .long("log")
+            .takes_value(true)
+            .allow_invalid_utf8(true)
+            .value_name("file")
+            .help("append everything the target sends to this file (remembered for this probe)")
+        )
+        .arg(Arg::new("reset-settings")
+            .long("reset-settings")
+            .takes_value(false)
+            .help("forget the baud rate, line ending, and log file remembered for this probe")
+        )
+        .arg(Arg::new("echo")
+            .long("echo")
+            .takes_value(false)
+            .help("echo typed characters locally, for targets that don't echo them back")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("watch")
+        .display_order(3)
+        .about("Print a line each time a Black Magic Probe device is connected or disconnected, until interrupted")
+        .arg(Arg::new("format")
+            .long("format")
             .required(false)
             .takes_value(true)
-            .global(true)
-            .help("Use the device with the given serial number")
+            .possible_values(["text", "json"])
+            .default_value("text")
+            .help("output format; json prints one machine-readable event per line")
         )
-        .arg(Arg::new("index")
-            .long("index")
+    );
+
+    parser = parser.subcommand(Command::new("benchmark")
+        .display_order(3)
+        .about("Measure how fast a probe's bootloader erases and writes flash, with different DFU transfer sizes and polling")
+        .arg(Arg::new("firmware_binary")
+            .takes_value(true)
+            .required(true)
+            .help("firmware to flash for each run; the probe is left running it")
+        )
+        .arg(Arg::new("transfer-sizes")
+            .long("transfer-sizes")
             .required(false)
             .takes_value(true)
-            .global(true)
-            .validator(|arg| usize::from_str(arg))
-            .help("Use the nth found device (may be unstable!)")
+            .use_value_delimiter(true)
+            .value_name("bytes,...")
+            .validator(|size| size.parse::<u16>().map_err(|e| e.to_string()).and_then(|size| {
+                if size == 0 { Err(String::from("must be more than 0")) } else { Ok(()) }
+            }))
+            .help("DFU transfer sizes to try (default: 256,512,1024,2048); sizes the probe doesn't take are skipped")
         )
-        .arg(Arg::new("port")
-            .short('p')
-            .long("port")
+        .arg(Arg::new("poll-intervals")
+            .long("poll-intervals")
             .required(false)
             .takes_value(true)
-            .global(true)
-            .help("Use the device on the given USB port")
+            .use_value_delimiter(true)
+            .value_name("ms,...")
+            .validator(|ms| ms.parse::<u64>())
+            .help("intervals to poll the probe at while it's busy, besides as often as it asks (default: 0,1,5)")
         )
-        .arg(Arg::new("allow-dangerous-options")
-            .long("allow-dangerous-options")
-            .global(true)
+        .arg(Arg::new("runs")
+            .long("runs")
+            .required(false)
             .takes_value(true)
-            .possible_value("really")
-            .hide(true)
-            .help("Allow usage of advanced, dangerous options that can result in unbootable devices (use with heavy caution!)")
+            .value_name("count")
+            .validator(|runs| runs.parse::<usize>())
+            .help("how many times to try each combination (default: 1)")
         )
-        .subcommand(Command::new("info")
-            .display_order(0)
-            .about("Print information about connected Black Magic Probe devices")
+    );
+
+    parser = parser.subcommand(Command::new("recover")
+        .display_order(3)
+        .about("Check a probe stuck in DFU mode for the usual problems, fix them, and reflash it with a known-good image")
+        .arg(Arg::new("firmware_binary")
+            .takes_value(true)
+            .required(false)
+            .help("known-good firmware to flash; without it, the probe is only checked")
         )
-        .subcommand(Command::new("flash")
-            .display_order(1)
-            .about("Flash new firmware onto a Black Magic Probe device")
-            .arg(Arg::new("firmware_binary")
+        .arg(Arg::new("trusted-key")
+            .long("trusted-key")
+            .required(false)
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .value_name("key")
+            .help("check the image's minisign signature against this public key, or the key in this file, as for flash")
+        )
+        .arg(Arg::new("require-signed")
+            .long("require-signed")
+            .required(false)
+            .takes_value(false)
+            .help("refuse to flash an image without a valid signature from a trusted key")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("tree")
+        .display_order(3)
+        .about("Show the USB hubs Black Magic Probe devices are connected through, and their interfaces")
+        .arg(Arg::new("all")
+            .long("all")
+            .required(false)
+            .takes_value(false)
+            .help("show every USB device, not just the probes and the hubs leading to them")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("stats")
+        .display_order(4)
+        .about("Manage opt-in usage statistics, which are only ever stored locally")
+        .arg_required_else_help(true)
+        .subcommand_required(true)
+        .subcommand(Command::new("enable")
+            .about("Start counting which operations are run, how long they take, and how they fail")
+        )
+        .subcommand(Command::new("disable")
+            .about("Stop collecting usage statistics, and delete those already collected")
+        )
+        .subcommand(Command::new("status")
+            .about("Print whether usage statistics are being collected")
+        )
+        .subcommand(Command::new("export")
+            .about("Print the collected usage statistics as JSON, for sharing with the maintainers")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("use")
+        .display_order(4)
+        .about("Choose the probe commands act on when not given --serial, --port, or --index")
+        .arg(Arg::new("probe")
+            .takes_value(true)
+            .value_name("serial|nickname")
+            .conflicts_with("clear")
+            .help("serial number of the probe, or its nickname from the [nicknames] table of the configuration file; omit to show the current choice")
+        )
+        .arg(Arg::new("clear")
+            .long("clear")
+            .takes_value(false)
+            .help("forget the chosen probe")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("profiles")
+        .display_order(4)
+        .about("List the probe profiles used to recognise BMP-compatible hardware, including the user's own")
+    );
+
+    parser = parser.subcommand(Command::new("diagnose")
+        .display_order(10)
+        .about("Show every USB descriptor of the matched probes as a tree, pointing out anything unusual")
+        .arg(Arg::new("dump")
+            .long("dump")
+            .takes_value(true)
+            .multiple_values(true)
+            .value_name("DUMP")
+            .help("instead of reading probes, show descriptor dumps saved with `bmputil dump-descriptors`")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("dump-descriptors")
+        .display_order(10)
+        .about("Capture every USB descriptor of a probe into a file that can be shared in bug reports")
+        .arg(Arg::new("output")
+            .short('o')
+            .long("output")
+            .takes_value(true)
+            .help("write the dump to this file instead of stdout")
+        )
+        .arg(Arg::new("source")
+            .long("source")
+            .takes_value(true)
+            .help("describe the hardware and firmware in the dump, e.g. \"ST-Link v2 clone, BMD v1.10\"")
+        )
+        .arg(Arg::new("check")
+            .long("check")
+            .takes_value(true)
+            .multiple_values(true)
+            .value_name("DUMP")
+            .conflicts_with_all(&["output", "source"])
+            .help("instead of reading a probe, parse existing dumps as bmputil would the live device")
+        )
+    );
+
+    parser = parser.subcommand(Command::new("config")
+        .display_order(4)
+        .about("Inspect bmputil's own configuration")
+        .arg_required_else_help(true)
+        .subcommand_required(true)
+        .subcommand(Command::new("path")
+            .about("Print the directories bmputil keeps its files in")
+            .after_help(const_format::formatcp!(
+                "The config and cache directories can be overridden with the {} and {} environment variables.",
+                paths::CONFIG_DIR_ENV,
+                paths::CACHE_DIR_ENV,
+            ))
+        )
+    );
+
+    parser = parser.subcommand(Command::new("shell")
+        .display_order(5)
+        .about("Start an interactive shell, which remembers which device was selected between commands")
+    );
+
+    if cfg!(feature = "gui") {
+        parser = parser.subcommand(Command::new("gui")
+            .display_order(5)
+            .about("Open a window for updating the firmware of a Black Magic Probe without the command line")
+        );
+    }
+
+    parser = parser.subcommand(Command::new("dfu")
+        .display_order(10)
+        .about("Send single DFU requests to a probe's bootloader (expert use only)")
+        .arg_required_else_help(true)
+        .subcommand_required(true)
+        .arg(Arg::new("expert")
+            .long("expert")
+            .required(true)
+            .takes_value(false)
+            .help("acknowledge that these requests go straight to the bootloader, with none of flash's checks")
+        )
+        .subcommand(Command::new("get-status")
+            .about("Print the bootloader's DFU status and state")
+        )
+        .subcommand(Command::new("clr-status")
+            .about("Clear an error status, returning the bootloader to dfuIDLE")
+        )
+        .subcommand(Command::new("abort")
+            .about("Abort an upload or download in progress, returning the bootloader to dfuIDLE")
+        )
+        .subcommand(Command::new("set-address")
+            .about("Set the DfuSe address pointer")
+            .arg(Arg::new("address")
+                .required(true)
                 .takes_value(true)
+                .validator(dfu_expert::parse_number)
+                .help("address, in decimal or 0x-prefixed hex")
+            )
+        )
+        .subcommand(Command::new("read-block")
+            .about("Read memory with DFU_UPLOAD, printing it as a hex dump")
+            .arg(Arg::new("address")
                 .required(true)
+                .takes_value(true)
+                .validator(dfu_expert::parse_number)
+                .help("address to read from, in decimal or 0x-prefixed hex")
             )
-            .arg(Arg::new("override-firmware-type")
-                .long("override-firmware-type")
-                .required(false)
+            .arg(Arg::new("length")
+                .required(true)
                 .takes_value(true)
-                .possible_values(&["bootloader", "application"])
-                .hide_short_help(true)
-                .help("flash the specified firmware space regardless of autodetected firmware type")
+                .validator(dfu_expert::parse_number)
+                .help("how many bytes to read, in decimal or 0x-prefixed hex")
             )
-            .arg(Arg::new("force-override-flash")
-                .long("force-override-flash")
+            .arg(Arg::new("output")
+                .short('o')
+                .long("output")
                 .required(false)
                 .takes_value(true)
-                .possible_value("really")
-                .hide(true)
-                .help("forcibly override firmware-type autodetection and flash anyway (may result in an unbootable device!)")
+                .value_name("file")
+                .help("write the raw bytes to this file (or - for stdout) instead of a hex dump")
             )
-        );
+        )
+        .subcommand(Command::new("write-block")
+            .about("Write a file as a single DFU_DNLOAD block, without erasing first (needs --allow-dangerous-options=really)")
+            .arg(Arg::new("address")
+                .required(true)
+                .takes_value(true)
+                .validator(dfu_expert::parse_number)
+                .help("address to write to, in decimal or 0x-prefixed hex")
+            )
+            .arg(Arg::new("file")
+                .required(true)
+                .takes_value(true)
+                .help("file holding the block; at most the probe's transfer size")
+            )
+        )
+    );
+
+    // Never actually parsed: main() translates dfu-util's arguments before clap sees them, as they
+    // clash with ours. This is here to list it, and to reserve the name.
+    parser = parser.subcommand(Command::new(dfu_util_compat::SUBCOMMAND)
+        .display_order(10)
+        .about("Run with dfu-util's arguments (-d, -a, -s, -D, ...), for scripts written for dfu-util")
+        .arg(Arg::new("dfu-util-args")
+            .takes_value(true)
+            .multiple_values(true)
+            .allow_hyphen_values(true)
+            .help("dfu-util arguments; see `bmputil dfu-util --help`")
+        )
+    );
 
     let mut debug_subcmd = Command::new("debug")
         .display_order(10)
@@ -371,12 +2433,162 @@ fn main()
 
     parser = parser.subcommand(debug_subcmd);
 
+    parser
+}
+
+
+/// Runs a (non-Windows-specific) subcommand, recording it in the usage statistics.
+fn run_command(subcommand: &str, subcommand_matches: &ArgMatches) -> Result<(), Error>
+{
+    let started = std::time::Instant::now();
+    let transfers_before = telemetry::snapshot();
+    let res = match subcommand {
+        "info" => info_command(subcommand_matches),
+        "flash" => flash(subcommand_matches),
+        "verify" => verify_command(subcommand_matches),
+        "list" => list_command(subcommand_matches),
+        "port" => port_command(subcommand_matches),
+        "which" => which_command(subcommand_matches),
+        "settings" => settings_command(subcommand_matches),
+        "power" => power_command(subcommand_matches),
+        "monitor" => monitor_command(subcommand_matches),
+        "rdp" => rdp_command(subcommand_matches),
+        "terminal" => terminal::terminal_command(subcommand_matches),
+        "watch" => watch::watch_command(subcommand_matches),
+        "tree" => tree::tree_command(subcommand_matches),
+        "recover" => recover::recover_command(subcommand_matches),
+        "switch" => switch_command(subcommand_matches),
+        "reboot" => reboot_command(subcommand_matches),
+        "stats" => stats_command(subcommand_matches),
+        "config" => config_command(subcommand_matches),
+        "use" => use_command(subcommand_matches),
+        "profiles" => profiles_command(subcommand_matches),
+        "dump-descriptors" => dump_descriptors_command(subcommand_matches),
+        "diagnose" => diagnose::diagnose_command(subcommand_matches),
+        "benchmark" => benchmark::benchmark_command(subcommand_matches),
+        "dfu" => dfu_expert::dfu_command(subcommand_matches),
+        "dfu-util" => unreachable!("dfu-util arguments are translated before parsing"),
+        "shell" => shell::run(subcommand_matches),
+        #[cfg(feature = "gui")]
+        "gui" => gui::run(subcommand_matches),
+        #[cfg(target_os = "linux")]
+        "install-udev" => udev::install_udev_command(subcommand_matches),
+        "debug" => match subcommand_matches.subcommand().unwrap() {
+            ("detach", detach_matches) => detach_command(detach_matches),
+            other => unreachable!("Unhandled subcommand {:?}", other),
+        },
+
+
+        &_ => unimplemented!(),
+    };
+
+    if subcommand != "stats" && subcommand != "shell" && subcommand != "gui" && subcommand != "watch" {
+        UsageStats::record(subcommand, started.elapsed(), res.as_ref().map(|_| ()).map_err(|e| &e.kind));
+    }
+
+    if subcommand_matches.occurrences_of("verbosity") > 0 {
+        print_usb_summary(&telemetry::snapshot().since(&transfers_before), started.elapsed());
+    }
+
+    res.map_err(|e| e.in_command(subcommand))
+}
+
+
+/// Prints how much USB traffic a command caused, and how long it spent waiting on it, for `-v`.
+fn print_usb_summary(stats: &TransferStats, elapsed: Duration)
+{
+    eprintln!(
+        "USB: {} control transfer{} ({} failed, {} retried), {:.3}s in transfers of {:.3}s total",
+        stats.control_transfers,
+        if stats.control_transfers == 1 { "" } else { "s" },
+        stats.failed_transfers,
+        stats.retries,
+        stats.transfer_time.as_secs_f64(),
+        elapsed.as_secs_f64(),
+    );
+}
+
+
+/// Prints an error from a subcommand to the user.
+fn print_error(e: &Error)
+{
+    logging::note_in_file(format_args!("Error: {}", e));
+    eprintln!("Error: {}", e);
+    #[cfg(feature = "backtrace")]
+    {
+        if e.backtrace.status() == BacktraceStatus::Disabled {
+            eprintln!("note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace.");
+        }
+    }
+
+    if cfg!(not(feature = "backtrace")) {
+        eprintln!("note: recompile with nightly toolchain and run with `RUST_BACKTRACE=1` environment variable to display a backtrace.");
+    }
+
+    #[cfg(target_os = "linux")]
+    if udev::is_permission_error(e) {
+        udev::print_permission_hint();
+    }
+
+    #[cfg(target_os = "linux")]
+    if wsl::is_not_found_error(e) {
+        wsl::print_usbipd_hint();
+    }
+
+    #[cfg(windows)]
+    if windows::is_missing_driver_error(e) {
+        windows::print_driver_hint();
+    }
+
+    #[cfg(target_os = "macos")]
+    if macos::is_access_error(e) {
+        macos::print_access_hint();
+    }
+
+    if bmda::is_busy_error(e) {
+        bmda::print_busy_hint();
+    }
+}
+
+
+/// Reports the libusb debug log captured with `--usb-diagnostics`, either after the error or in
+/// `file`.
+fn report_usb_diagnostics(file: Option<&str>)
+{
+    let log = diagnostics::captured_log();
+    match file {
+        Some(file) => match std::fs::write(file, log.iter().map(|line| format!("{}\n", line)).collect::<String>()) {
+            Ok(()) => eprintln!("note: libusb debug log written to {}", file),
+            Err(e) => warn!("Failed to write libusb debug log to {}: {}", file, e),
+        },
+        None if log.is_empty() => eprintln!("note: libusb did not log anything"),
+        None => {
+            eprintln!("libusb debug log:");
+            for line in log {
+                eprintln!("  {}", line);
+            }
+        },
+    }
+}
+
 
-    let matches = parser.get_matches();
+fn main()
+{
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let matches = match dfu_util_compat::translate_args(&args) {
+        Some(Ok(translated)) => cli().get_matches_from(translated),
+        Some(Err(e)) => cli().error(clap::ErrorKind::InvalidValue, format!("dfu-util arguments: {}", e)).exit(),
+        None => cli().get_matches_from(&args),
+    };
 
     let (subcommand, subcommand_matches) = matches.subcommand()
         .expect("No subcommand given!"); // Should be impossible, thanks to clap.
 
+    if let Err(e) = logging::init(subcommand_matches, &args) {
+        print_error(&e);
+        e.exit_code().exit();
+    }
+
     // Minor HACK: these Windows specific subcommands and operations need to be checked and handled
     // before the others.
     #[cfg(windows)]
@@ -384,6 +2596,22 @@ fn main()
         // If the install-driver subcommand was explicitly specified, then perform that operation
         // and exit.
         match subcommand {
+            "setup-driver" if subcommand_matches.is_present("check") => {
+                if !windows::report_driver_status() {
+                    ExitCode::Failure.exit();
+                }
+                std::process::exit(0);
+            },
+            "setup-driver" => {
+                windows::ensure_access(
+                    matches
+                        .value_of("windows-wdi-install-mode")
+                        .map(|v| v.parse().unwrap()),
+                    true, // explicitly_requested.
+                    subcommand_matches.is_present("force"),
+                );
+                std::process::exit(0);
+            },
             "debug" => match subcommand_matches.subcommand() {
                 Some(("install-drivers", install_driver_matches)) => {
 
@@ -405,44 +2633,50 @@ fn main()
             _ => (),
         }
 
+        // UsbDk doesn't need a driver bound to each device.
+        if subcommand_matches.value_of("usb-backend") == Some("usbdk") {
+            bmputil::usb::set_libusb_backend(LibusbBackend::UsbDk);
+        }
+
         // Otherwise, potentially install drivers, but still do whatever else the user wanted.
-        windows::ensure_access(
-            matches
-                .value_of("windows-wdi-install-mode")
-                .map(|v| v.parse().unwrap()),
-            false, // explicitly_requested
-            false, // force
-        );
+        if bmputil::usb::libusb_backend() == LibusbBackend::Native {
+            windows::ensure_access(
+                matches
+                    .value_of("windows-wdi-install-mode")
+                    .map(|v| v.parse().unwrap()),
+                false, // explicitly_requested
+                false, // force
+            );
+        }
     }
 
-    let res = match subcommand {
-        "info" => info_command(subcommand_matches),
-        "flash" => flash(subcommand_matches),
-        "debug" => match subcommand_matches.subcommand().unwrap() {
-            ("detach", detach_matches) => detach_command(detach_matches),
-            other => unreachable!("Unhandled subcommand {:?}", other),
-        },
-
-
-        &_ => unimplemented!(),
-    };
+    if subcommand_matches.is_present("usb-diagnostics") {
+        diagnostics::capture();
+    }
+    for code in subcommand_matches.values_of("suppress-warning").into_iter().flatten() {
+        warnings::suppress(WarningCode::from_code(code).expect("Clap ensures only known warning codes are given"));
+    }
 
+    let res = load_config(subcommand_matches)
+        .and_then(|_| run_command(subcommand, subcommand_matches));
 
     // Unfortunately, we have to do the printing ourselves, as we need to print a note
     // in the event that backtraces are supported but not enabled.
     if let Err(e) = res {
-        println!("Error: {}", e);
-        #[cfg(feature = "backtrace")]
-        {
-            if e.backtrace.status() == BacktraceStatus::Disabled {
-                println!("note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace.");
-            }
+        print_error(&e);
+        if subcommand_matches.is_present("usb-diagnostics") {
+            report_usb_diagnostics(subcommand_matches.value_of("usb-diagnostics"));
         }
-
-        if cfg!(not(feature = "backtrace")) {
-            println!("note: recompile with nightly toolchain and run with `RUST_BACKTRACE=1` environment variable to display a backtrace.");
+        #[cfg(windows)]
+        if windows::is_missing_driver_error(&e) && bmputil::usb::libusb_backend() == LibusbBackend::Native {
+            let policy = ConfirmationPolicy::from_cli_args(subcommand_matches);
+            windows::offer_driver_rebind(|question| policy.ask(question));
         }
-
-        std::process::exit(1);
+        #[cfg(target_os = "macos")]
+        if macos::is_access_error(&e) {
+            let policy = ConfirmationPolicy::from_cli_args(subcommand_matches);
+            macos::offer_kext_unload(|question| policy.ask(question));
+        }
+        e.exit_code().exit();
     }
 }
@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil flash --on-connect`, which flashes and verifies every probe that is
+//! plugged in, printing a line with the result for each, until interrupted.
+//!
+//! This is meant for programming batches of probes: probes already connected when it starts are
+//! left alone, and a probe failing doesn't stop the next one from being flashed.
+
+use std::collections::HashSet;
+use std::time::Instant;
+
+use clap::ArgMatches;
+use log::debug;
+
+use bmputil::bmp::{BmpDevice, FirmwareType, ProbeInfo};
+use bmputil::error::{Error, ErrorKind};
+use bmputil::usb::DfuOperatingMode;
+use bmputil::S;
+
+use crate::FirmwareImage;
+use crate::confirm::ConfirmationPolicy;
+use crate::report::{self, FlashRecord, FlashReport};
+use crate::watch::{BusEvent, ProbeWatcher};
+
+fn serial_of(info: &ProbeInfo) -> String
+{
+    info.serial.clone().unwrap_or_else(|| S!("unknown"))
+}
+
+/// Flashes `firmware_data` onto `dev`, returning the firmware version it then runs.
+fn flash_one(matches: &ArgMatches, dev: BmpDevice, firmware_data: &FirmwareImage) -> Result<String, Error>
+{
+    let firmware_type = FirmwareType::detect_from_firmware(dev.profile(), firmware_data)
+        .map_err(|e| e.with_ctx("detecting firmware type"))?;
+    if firmware_type == FirmwareType::Bootloader {
+        return Err(ErrorKind::InvalidFirmware(Some(S!(
+            "firmware appears to be a bootloader; bootloaders can't be flashed with --on-connect"
+        ))).error());
+    }
+    firmware_data.check_address(dev.profile(), firmware_type)?;
+
+    let force = matches.is_present("force");
+    crate::validate_application(force, &dev, firmware_data)?;
+    crate::check_hardware_target(&ConfirmationPolicy::from_cli_args(matches), force, &dev, firmware_data)?;
+    if let Some(version) = crate::already_up_to_date(matches, &dev, firmware_data) {
+        return Ok(format!("{} (already up to date)", version));
+    }
+
+    let options = crate::download_options_from_cli_args(matches).verify(true);
+    let dev = crate::run_flash_pipeline(dev, firmware_data, firmware_type, options, crate::flash_backend_from_cli_args(matches))?;
+
+    if dev.operating_mode() == DfuOperatingMode::FirmwareUpgrade {
+        return Ok(S!("(left in DFU mode)"));
+    }
+
+    crate::firmware_version_after_flash(&dev)
+}
+
+/// Implements `bmputil flash --on-connect`, which only returns on error.
+///
+/// If `report` is given, a record of each probe flashed is added to it.
+pub fn flash_on_connect(
+    matches: &ArgMatches,
+    firmware_data: &FirmwareImage,
+    filename: &str,
+    mut report: Option<&mut FlashReport>,
+) -> Result<(), Error>
+{
+    let mut watcher = ProbeWatcher::new(crate::matcher_from_cli_args(matches))?;
+
+    let already_connected = watcher.poll().len();
+    if already_connected > 0 {
+        println!("Ignoring {} probe(s) that were already connected.", already_connected);
+    }
+    println!("Waiting for probes to flash. Press Ctrl-C to stop.");
+
+    let (mut succeeded, mut failed) = (0, 0);
+    loop {
+        watcher.wait()?;
+
+        // Probes we flash come back (in DFU mode, then in runtime mode) while we're flashing them,
+        // so look past them once we're done with them.
+        let mut flashed = HashSet::new();
+        for event in watcher.poll() {
+            let (dev, info) = match event {
+                BusEvent::Connected(dev, info) => (dev, info),
+                BusEvent::Disconnected(_) => continue,
+            };
+            let serial = serial_of(&info);
+            if flashed.contains(&serial) {
+                debug!("Probe {} re-enumerated after flashing", serial);
+                continue;
+            }
+
+            println!("Flashing probe {} on port {}...", serial, info.port);
+            let started = Instant::now();
+            let record = report.is_some().then(|| FlashRecord::start(&dev, filename, firmware_data));
+            let res = flash_one(matches, *dev, firmware_data);
+            let elapsed = started.elapsed().as_secs_f64();
+            if let (Some(report), Some(record)) = (report.as_deref_mut(), record) {
+                let record = record.finish(res.as_ref().map(|version| Some(version.clone())));
+                report::add_or_warn(report, record, res.is_ok())?;
+            }
+            match res {
+                Ok(version) => {
+                    succeeded += 1;
+                    println!("{}  {}  OK      {}  ({:.1}s)", serial, info.port, version, elapsed);
+                },
+                Err(e) => {
+                    failed += 1;
+                    println!("{}  {}  FAILED  {}  ({:.1}s)", serial, info.port, e, elapsed);
+                },
+            }
+            println!("{} flashed, {} failed so far.", succeeded, failed);
+
+            flashed.insert(serial);
+        }
+
+        // Catch up with the probes we flashed re-enumerating, so they aren't flashed again.
+        for event in watcher.poll() {
+            match event {
+                BusEvent::Connected(_, info) if flashed.contains(&serial_of(&info)) => (),
+                BusEvent::Connected(_, info) => {
+                    debug!("Probe at {} arrived while flashing, will flash it next", info.port);
+                    watcher.forget(&info.port);
+                },
+                BusEvent::Disconnected(_) => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for looking up a USB device's serial number in the OS's own device records.
+//!
+//! Right after a probe re-enumerates (e.g. into DFU mode), reading its serial number string
+//! descriptor sometimes fails or times out, even though the OS has already read it while enumerating
+//! the device. As a fallback, we ask the OS for what it read instead: sysfs on Linux, SetupAPI on
+//! Windows, and the IOKit registry (through `ioreg`) on macOS.
+
+use log::trace;
+
+type UsbDevice = rusb::Device<rusb::Context>;
+
+/// Returns the serial number the OS recorded for `dev` when enumerating it, if it did.
+pub fn serial_number(dev: &UsbDevice) -> Option<String>
+{
+    let serial = serial_number_for_os(dev);
+    trace!(
+        "Serial number of device {}-{} according to the OS: {:?}",
+        dev.bus_number(),
+        dev.address(),
+        serial,
+    );
+
+    serial
+        .filter(|serial| !serial.is_empty())
+        .map(|serial| crate::usb::sanitize_descriptor_string(&serial))
+}
+
+/// Linux: find the sysfs entry with the same bus number and device address.
+#[cfg(target_os = "linux")]
+fn serial_number_for_os(dev: &UsbDevice) -> Option<String>
+{
+    use std::fs;
+    use std::path::Path;
+
+    fn read_attr(dir: &Path, name: &str) -> Option<String>
+    {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|contents| contents.trim().to_string())
+    }
+
+    fs::read_dir("/sys/bus/usb/devices")
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|dir| {
+            read_attr(dir, "busnum").and_then(|bus| bus.parse().ok()) == Some(dev.bus_number())
+                && read_attr(dir, "devnum").and_then(|addr| addr.parse().ok()) == Some(dev.address())
+        })
+        .and_then(|dir| read_attr(&dir, "serial"))
+}
+
+/// macOS: libusb derives the bus number and port path from the device's IOKit location ID, so
+/// rebuild that and look for the device with it in the IOUSB plane of the IOKit registry.
+#[cfg(target_os = "macos")]
+fn serial_number_for_os(dev: &UsbDevice) -> Option<String>
+{
+    use std::process::Command;
+
+    let ports = dev.port_numbers().ok()?;
+    let location_id = ports
+        .iter()
+        .take(6)
+        .enumerate()
+        .fold((dev.bus_number() as u32) << 24, |id, (depth, &port)| {
+            id | ((port as u32 & 0xf) << (20 - 4 * depth))
+        });
+
+    let output = Command::new("ioreg")
+        .args(["-p", "IOUSB", "-l", "-w0"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    // Properties of each device follow the line naming it (`+-o Name@location <class ...>`).
+    let mut current_location = None;
+    let mut current_serial = None;
+    for line in output.lines() {
+        if line.contains("+-o ") {
+            if current_location == Some(location_id) {
+                return current_serial;
+            }
+            current_location = None;
+            current_serial = None;
+        } else if let Some((key, value)) = line.split_once(" = ") {
+            match key.trim().trim_start_matches('|').trim() {
+                "\"locationID\"" => current_location = value.trim().parse().ok(),
+                "\"USB Serial Number\"" => current_serial = Some(value.trim().trim_matches('"').to_string()),
+                _ => (),
+            }
+        }
+    }
+
+    if current_location == Some(location_id) {
+        current_serial
+    } else {
+        None
+    }
+}
+
+/// Windows: the device instance ID of a USB device with a serial number ends with that serial
+/// number, so find the present USB device with the same VID/PID on the same hub port.
+#[cfg(windows)]
+fn serial_number_for_os(dev: &UsbDevice) -> Option<String>
+{
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::{iter, mem, ptr};
+
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::setupapi::{
+        SetupDiDestroyDeviceInfoList, SetupDiEnumDeviceInfo, SetupDiGetClassDevsW, SetupDiGetDeviceInstanceIdW,
+        SetupDiGetDeviceRegistryPropertyW, DIGCF_ALLCLASSES, DIGCF_PRESENT, SPDRP_ADDRESS, SP_DEVINFO_DATA,
+    };
+
+    let desc = dev.device_descriptor().ok()?;
+    let prefix = format!(r"USB\VID_{:04X}&PID_{:04X}\", desc.vendor_id(), desc.product_id());
+    let port_number = dev.port_number() as u32;
+
+    let enumerator: Vec<u16> = OsStr::new("USB").encode_wide().chain(iter::once(0)).collect();
+
+    // SAFETY: every pointer passed is either null where allowed, or points to a live, correctly
+    // sized buffer; the device info set is destroyed before returning.
+    unsafe {
+        let set = SetupDiGetClassDevsW(ptr::null(), enumerator.as_ptr(), ptr::null_mut(), DIGCF_PRESENT | DIGCF_ALLCLASSES);
+        if set == INVALID_HANDLE_VALUE {
+            return None;
+        }
+
+        let mut candidates = Vec::new();
+        let mut index = 0;
+        loop {
+            let mut info: SP_DEVINFO_DATA = mem::zeroed();
+            info.cbSize = mem::size_of::<SP_DEVINFO_DATA>() as u32;
+            if SetupDiEnumDeviceInfo(set, index, &mut info) == 0 {
+                break;
+            }
+            index += 1;
+
+            let mut id_buf = [0u16; 256];
+            if SetupDiGetDeviceInstanceIdW(set, &mut info, id_buf.as_mut_ptr(), id_buf.len() as u32, ptr::null_mut()) == 0 {
+                continue;
+            }
+            let id_len = id_buf.iter().position(|&c| c == 0).unwrap_or(id_buf.len());
+            let instance_id = String::from_utf16_lossy(&id_buf[..id_len]);
+            if !instance_id.to_ascii_uppercase().starts_with(&prefix) {
+                continue;
+            }
+
+            // For USB devices, the address property is the port number on the parent hub.
+            let mut address: u32 = 0;
+            let found = SetupDiGetDeviceRegistryPropertyW(
+                set,
+                &mut info,
+                SPDRP_ADDRESS,
+                ptr::null_mut(),
+                &mut address as *mut u32 as *mut u8,
+                mem::size_of::<u32>() as u32,
+                ptr::null_mut(),
+            );
+            if found != 0 && address == port_number {
+                candidates.push(instance_id[prefix.len()..].to_string());
+            }
+        }
+
+        SetupDiDestroyDeviceInfoList(set);
+
+        // Devices without a serial number get an instance ID made up by Windows, which contains `&`.
+        match candidates.as_slice() {
+            [serial] if !serial.contains('&') => Some(serial.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn serial_number_for_os(_dev: &UsbDevice) -> Option<String>
+{
+    None
+}
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for working out where bmputil keeps its files.
+//!
+//! Each kind of file goes in the directory the platform expects it in: the XDG base directories on
+//! Linux and other Unixes, `~/Library` on macOS, and the (local) application data directories on
+//! Windows. The config and cache directories can also be set outright with [`CONFIG_DIR_ENV`] and
+//! [`CACHE_DIR_ENV`], for packagers (e.g. Nix or Homebrew) and sandboxed environments that need
+//! them somewhere predictable. The cache directory can also be moved with `cache_dir` in the
+//! [configuration file](crate::config), which [`set_cache_dir`] applies.
+//!
+//! Small state files bmputil keeps between invocations (e.g. the serial numbers it last saw) also
+//! go in the cache directory, through [`state_file`] and [`write_state_file`]. Never the system
+//! temporary directory: other users can create files there, with the names we'd use.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable that overrides the config directory.
+pub const CONFIG_DIR_ENV: &str = "BMPUTIL_CONFIG_DIR";
+
+/// Environment variable that overrides the cache directory.
+pub const CACHE_DIR_ENV: &str = "BMPUTIL_CACHE_DIR";
+
+/// Name of bmputil's own subdirectory in each of the platform's directories.
+const APP_DIR_NAME: &str = "bmputil";
+
+/// The cache directory from the configuration file, if it sets one.
+static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Reads an environment variable holding a directory, ignoring it if it's empty or relative, as
+/// the XDG base directory specification requires.
+fn env_dir(var: &str) -> Option<PathBuf>
+{
+    env::var_os(var)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+}
+
+/// Resolves `relative` inside the user's home directory.
+fn home_dir_join(relative: &[&str]) -> Option<PathBuf>
+{
+    let home = env::var_os("HOME").filter(|home| !home.is_empty())?;
+
+    Some(relative.iter().fold(PathBuf::from(home), |dir, component| dir.join(component)))
+}
+
+/// Returns the platform's directory of the given kind, before appending bmputil's subdirectory.
+///
+/// `windows_var` names the environment variable holding the directory on Windows, `macos_dir` the
+/// directory under `~/Library` on macOS, and `xdg_var` and `xdg_default` the XDG environment
+/// variable and its default under `$HOME` everywhere else.
+fn platform_dir(windows_var: &str, macos_dir: &str, xdg_var: &str, xdg_default: &[&str]) -> Option<PathBuf>
+{
+    if cfg!(windows) {
+        env_dir(windows_var)
+    } else if cfg!(target_os = "macos") {
+        home_dir_join(&["Library", macos_dir])
+    } else {
+        env_dir(xdg_var).or_else(|| home_dir_join(xdg_default))
+    }
+}
+
+/// Returns the directory for user configuration files.
+///
+/// This is `$BMPUTIL_CONFIG_DIR` if set, and otherwise `$XDG_CONFIG_HOME/bmputil` (or
+/// `~/.config/bmputil`), `~/Library/Application Support/bmputil` on macOS, or
+/// `%APPDATA%\bmputil` on Windows. `None` if none of those could be determined.
+pub fn config_dir() -> Option<PathBuf>
+{
+    env_dir(CONFIG_DIR_ENV).or_else(|| {
+        platform_dir("APPDATA", "Application Support", "XDG_CONFIG_HOME", &[".config"])
+            .map(|dir| dir.join(APP_DIR_NAME))
+    })
+}
+
+/// Sets the cache directory [`cache_dir`] returns when `$BMPUTIL_CACHE_DIR` isn't set.
+///
+/// Only the first call has any effect.
+pub fn set_cache_dir(dir: PathBuf)
+{
+    let _ = CACHE_DIR.set(dir);
+}
+
+/// Returns the directory for cached files, which can be deleted at any time.
+///
+/// This is `$BMPUTIL_CACHE_DIR` if set, then the directory given to [`set_cache_dir`], and
+/// otherwise `$XDG_CACHE_HOME/bmputil` (or `~/.cache/bmputil`), `~/Library/Caches/bmputil` on
+/// macOS, or `%LOCALAPPDATA%\bmputil\cache` on Windows. `None` if none of those could be determined.
+pub fn cache_dir() -> Option<PathBuf>
+{
+    env_dir(CACHE_DIR_ENV).or_else(|| CACHE_DIR.get().cloned()).or_else(|| {
+        let dir = platform_dir("LOCALAPPDATA", "Caches", "XDG_CACHE_HOME", &[".cache"])?.join(APP_DIR_NAME);
+        // Windows has no separate cache directory, so keep the cache apart from data files.
+        Some(if cfg!(windows) { dir.join("cache") } else { dir })
+    })
+}
+
+/// Returns the directory for persistent, per-user data files.
+///
+/// This is `$XDG_DATA_HOME/bmputil` (or `~/.local/share/bmputil`),
+/// `~/Library/Application Support/bmputil` on macOS, or `%LOCALAPPDATA%\bmputil` on Windows.
+pub fn data_dir() -> Option<PathBuf>
+{
+    platform_dir("LOCALAPPDATA", "Application Support", "XDG_DATA_HOME", &[".local", "share"])
+        .map(|dir| dir.join(APP_DIR_NAME))
+}
+
+/// Returns where the state file `name` is kept: in the [cache directory](cache_dir), which
+/// belongs to the user running bmputil. `None` if that couldn't be determined.
+pub fn state_file(name: &str) -> Option<PathBuf>
+{
+    cache_dir().map(|dir| dir.join(name))
+}
+
+/// Replaces the contents of the state file at `path`, creating its directory if need be.
+///
+/// The contents are written to a new file with a name unique to this write, which is then renamed
+/// over `path`, so concurrent invocations see either the old contents or the new, never part of
+/// either. The new file is only ever created, never opened if it exists, so a link planted in its
+/// place can't redirect the write.
+pub fn write_state_file(path: &Path, contents: &[u8]) -> io::Result<()>
+{
+    static WRITES: AtomicU32 = AtomicU32::new(0);
+
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "state file path has no file name"));
+    };
+    fs::create_dir_all(dir)?;
+
+    // A file left behind by a crashed invocation with the same PID can take a name; try the next.
+    let mut attempts = 0;
+    let (tmp_path, mut file) = loop {
+        let tmp_path = dir.join(format!(
+            ".{}.{}-{}.tmp",
+            name.to_string_lossy(),
+            process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed),
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&tmp_path) {
+            Ok(file) => break (tmp_path, file),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 16 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    };
+
+    let res = file.write_all(contents).and_then(|()| fs::rename(&tmp_path, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    res
+}
+
+/// Seconds since the Unix epoch, for timestamping entries in state files so they can expire.
+pub fn state_timestamp() -> u64
+{
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// A fresh directory for a test to write in.
+    fn test_dir(name: &str) -> PathBuf
+    {
+        let dir = env::temp_dir().join(format!("bmputil-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn state_file_is_replaced_whole()
+    {
+        let dir = test_dir("replace");
+        let path = dir.join("nested").join("state");
+
+        write_state_file(&path, b"first\n").unwrap();
+        write_state_file(&path, b"second\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+        // Nothing is left behind next to it.
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn state_file_write_does_not_follow_links()
+    {
+        let dir = test_dir("links");
+        let victim = dir.join("victim");
+        fs::write(&victim, "precious\n").unwrap();
+        let path = dir.join("state");
+        std::os::unix::fs::symlink(&victim, &path).unwrap();
+
+        write_state_file(&path, b"state\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "precious\n");
+        assert!(!fs::symlink_metadata(&path).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "state\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for `--porcelain` output, which shell scripts can parse without anything but `read`,
+//! `grep`, or `cut`.
+//!
+//! The output is a series of records, one per probe, each a `key=value` line per field and ended by
+//! an empty line:
+//!
+//! ```text
+//! index=0
+//! bus=1
+//! firmware_version=v1.10.0
+//! gdb_port=/dev/ttyACM0
+//! hardware=
+//! mode=runtime
+//! ...
+//!
+//! ```
+//!
+//! Keys don't change between versions (new ones may be added), and every key a record can have is
+//! always printed, empty if there's no value. Backslashes and line breaks in values are escaped as
+//! `\\`, `\n`, and `\r`, lists are comma-separated, and nested fields get dotted keys.
+//!
+//! With `--porcelain`, stdout has only the records: messages that would otherwise go there are
+//! printed to stderr, along with the warnings and errors that always go there.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ArgMatches;
+use serde::Serialize;
+use serde_json::Value;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns on porcelain output if `--porcelain` was given, returning whether it was.
+pub fn enable_from_cli_args(matches: &ArgMatches) -> bool
+{
+    let enabled = matches.is_present("porcelain");
+    ENABLED.store(enabled, Ordering::Relaxed);
+    enabled
+}
+
+/// Where messages for the user go: stdout, unless that's reserved for porcelain output.
+pub fn status_out() -> Box<dyn Write>
+{
+    if ENABLED.load(Ordering::Relaxed) {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+/// Prints a message for the user, as [`println!`] does, except to stderr with `--porcelain`.
+macro_rules! status
+{
+    ($($arg:tt)*) => {
+        {
+            use std::io::Write as _;
+            let _ = writeln!($crate::porcelain::status_out(), $($arg)*);
+        }
+    };
+}
+pub(crate) use status;
+
+
+/// A record of porcelain output.
+#[derive(Debug, Clone, Default)]
+pub struct Record
+{
+    fields: Vec<(String, String)>,
+}
+
+impl Record
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Adds the fields of `value`, in alphabetical order.
+    #[must_use]
+    pub fn fields_of<T: Serialize>(mut self, value: &T) -> Self
+    {
+        let value = serde_json::to_value(value).expect("Serializing a record should not fail");
+        self.flatten("", value);
+        self
+    }
+
+    fn flatten(&mut self, key: &str, value: Value)
+    {
+        match value {
+            Value::Object(fields) => {
+                for (name, value) in fields {
+                    let key = if key.is_empty() { name } else { format!("{}.{}", key, name) };
+                    self.flatten(&key, value);
+                }
+            },
+            Value::Array(items) => {
+                let items: Vec<String> = items.into_iter().map(scalar).collect();
+                self.fields.push((key.to_string(), items.join(",")));
+            },
+            other => self.fields.push((key.to_string(), scalar(other))),
+        }
+    }
+
+    /// Adds a field.
+    #[must_use]
+    pub fn field(mut self, key: &str, value: impl ToString) -> Self
+    {
+        self.fields.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds a field that may be empty.
+    #[must_use]
+    pub fn optional_field(self, key: &str, value: Option<impl ToString>) -> Self
+    {
+        let value = value.map(|value| value.to_string()).unwrap_or_default();
+        self.field(key, value)
+    }
+
+    /// Prints the record to stdout.
+    pub fn print(&self)
+    {
+        let mut stdout = io::stdout().lock();
+        for (key, value) in &self.fields {
+            let _ = writeln!(stdout, "{}={}", key, escape(value));
+        }
+        let _ = writeln!(stdout);
+    }
+}
+
+/// Formats a JSON value that isn't an object or array as a porcelain value.
+fn scalar(value: Value) -> String
+{
+    match value {
+        Value::Null => String::new(),
+        Value::String(string) => string,
+        other => other.to_string(),
+    }
+}
+
+fn escape(value: &str) -> String
+{
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
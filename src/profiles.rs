@@ -0,0 +1,355 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for probe profiles, which describe the USB IDs and flash layout of hardware running
+//! Black Magic Debug firmware.
+//!
+//! The built-in profiles cover the official hardware and the bootloaders we know about. Other
+//! BMP-compatible hardware (e.g. with its own USB IDs, or the application at a different address)
+//! can be described in `profiles.toml` in the [config directory](crate::paths::config_dir):
+//!
+//! ```toml
+//! [[profile]]
+//! name = "My probe"
+//! # One of "black-magic-debug" (the default), "dragonboot", or "stm32".
+//! bootloader = "black-magic-debug"
+//! dfu = "1209:5678"
+//! # Optional; only needed if the firmware doesn't use the official runtime IDs.
+//! runtime = "1209:5679"
+//! # Where the application goes, and how much flash it can use. Both optional.
+//! flash_base = 0x08004000
+//! flash_size = 0x3c000
+//! # Optional; only match devices whose product string contains this, for hardware sharing its
+//! # IDs with other hardware.
+//! product = "(My probe"
+//! ```
+//!
+//! User profiles are consulted before the built-in ones, so they can also override them. Of the
+//! profiles with a device's IDs, the first whose `product` its product string contains is used,
+//! which is how ST-Links converted to Black Magic Debug are told apart from official probes.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::bmp::{BmpPlatform, FirmwareType};
+use crate::error::{Error, ErrorKind, ErrorSource};
+use crate::paths;
+use crate::S;
+use crate::usb::{DfuOperatingMode, Pid, Vid};
+
+/// Name of the user profiles file in the config directory.
+pub const PROFILES_FILE: &str = "profiles.toml";
+
+/// How much flash the application has on an ST-Link converted to Black Magic Debug: the 128 KiB
+/// of its STM32F103 (which clones have too, even where it's sold as 64 KiB), less the 8 KiB the
+/// bootloader takes at the start of it.
+const STLINK_FLASH_SIZE: u32 = 0x1e000;
+
+/// USB IDs and flash layout of a kind of BMP-compatible hardware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeProfile
+{
+    /// Name of the hardware variant, for display.
+    pub name: String,
+    /// The bootloader the hardware uses.
+    pub platform: BmpPlatform,
+    /// The IDs of the hardware in runtime mode, if it has its own.
+    pub runtime_ids: Option<(Vid, Pid)>,
+    /// The IDs of the hardware in DFU mode.
+    pub dfu_ids: (Vid, Pid),
+    /// Where the application firmware is loaded.
+    pub flash_base: u32,
+    /// How much flash the application firmware can use, if known.
+    pub flash_size: Option<u32>,
+    /// Text the device's product string must contain (ignoring case) for this profile to match it,
+    /// for hardware that shares its USB IDs with other hardware.
+    pub product: Option<String>,
+    /// Whether this profile came from the user's profiles file.
+    pub user_defined: bool,
+}
+
+impl ProbeProfile
+{
+    /// The profiles for the hardware and bootloaders bmputil knows about.
+    pub fn built_in() -> Vec<Self>
+    {
+        use BmpPlatform::*;
+
+        // ST-Links converted to Black Magic Debug use its bootloader and IDs, with the firmware at
+        // the same address, but have less flash. Their product strings name them, e.g.
+        // `Black Magic Probe (ST-Link/v2) v1.10.0`, so this has to come before the official probes.
+        let stlink = Self {
+            name: S!("ST-Link (Black Magic Debug)"),
+            platform: BlackMagicDebug,
+            runtime_ids: Some(BmpPlatform::BMD_RUNTIME_VID_PID),
+            dfu_ids: BlackMagicDebug.dfu_ids(),
+            flash_base: BlackMagicDebug.load_address(FirmwareType::Application),
+            flash_size: Some(STLINK_FLASH_SIZE),
+            product: Some(S!("(ST-Link")),
+            user_defined: false,
+        };
+
+        let platforms = [
+            (BlackMagicDebug, Some(BmpPlatform::BMD_RUNTIME_VID_PID)),
+            (DragonBoot, None),
+            (STM32DeviceDFU, None),
+        ]
+        .into_iter()
+        .map(|(platform, runtime_ids)| Self {
+            name: platform.to_string(),
+            platform,
+            runtime_ids,
+            dfu_ids: platform.dfu_ids(),
+            flash_base: platform.load_address(FirmwareType::Application),
+            flash_size: None,
+            product: None,
+            user_defined: false,
+        });
+
+        std::iter::once(stlink).chain(platforms).collect()
+    }
+
+    /// The operating mode a device with these IDs is in, if it matches this profile.
+    pub fn mode_for(&self, ids: (Vid, Pid)) -> Option<DfuOperatingMode>
+    {
+        if self.runtime_ids == Some(ids) {
+            Some(DfuOperatingMode::Runtime)
+        } else if self.dfu_ids == ids {
+            Some(DfuOperatingMode::FirmwareUpgrade)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a device with the product string `product` can be this hardware.
+    pub fn matches_product(&self, product: &str) -> bool
+    {
+        self.product
+            .as_ref()
+            .is_none_or(|needle| product.to_ascii_lowercase().contains(&needle.to_ascii_lowercase()))
+    }
+
+    /// Get the load address for firmware of `firmware_type` on this hardware.
+    pub fn load_address(&self, firmware_type: FirmwareType) -> u32
+    {
+        match firmware_type {
+            FirmwareType::Application => self.flash_base,
+            FirmwareType::Bootloader => self.platform.load_address(FirmwareType::Bootloader),
+        }
+    }
+}
+
+
+/// A profile as written in the profiles file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileEntry
+{
+    name: String,
+    #[serde(default)]
+    bootloader: Option<String>,
+    dfu: String,
+    #[serde(default)]
+    runtime: Option<String>,
+    #[serde(default)]
+    flash_base: Option<u32>,
+    #[serde(default)]
+    flash_size: Option<u32>,
+    #[serde(default)]
+    product: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile
+{
+    #[serde(default)]
+    profile: Vec<ProfileEntry>,
+}
+
+fn invalid_profile(name: &str, why: String) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(io::Error::new(io::ErrorKind::InvalidData, why)))
+        .error()
+        .with_ctx(&format!("reading probe profile {:?}", name))
+}
+
+/// Parses USB IDs written as `vid:pid` in hexadecimal, e.g. `1d50:6018`.
+fn parse_ids(name: &str, ids: &str) -> Result<(Vid, Pid), Error>
+{
+    ids.split_once(':')
+        .and_then(|(vid, pid)| Some((u16::from_str_radix(vid, 16).ok()?, u16::from_str_radix(pid, 16).ok()?)))
+        .map(|(vid, pid)| (Vid(vid), Pid(pid)))
+        .ok_or_else(|| invalid_profile(name, format!("USB IDs {:?} are not in the form vid:pid", ids)))
+}
+
+impl TryFrom<ProfileEntry> for ProbeProfile
+{
+    type Error = Error;
+
+    fn try_from(entry: ProfileEntry) -> Result<Self, Error>
+    {
+        let platform = match entry.bootloader.as_deref() {
+            None | Some("black-magic-debug") => BmpPlatform::BlackMagicDebug,
+            Some("dragonboot") => BmpPlatform::DragonBoot,
+            Some("stm32") => BmpPlatform::STM32DeviceDFU,
+            Some(other) => {
+                return Err(invalid_profile(&entry.name, format!(
+                    "unknown bootloader {:?} (expected black-magic-debug, dragonboot, or stm32)",
+                    other,
+                )));
+            },
+        };
+
+        Ok(Self {
+            platform,
+            runtime_ids: entry.runtime.as_deref().map(|ids| parse_ids(&entry.name, ids)).transpose()?,
+            dfu_ids: parse_ids(&entry.name, &entry.dfu)?,
+            flash_base: entry.flash_base.unwrap_or_else(|| platform.load_address(FirmwareType::Application)),
+            flash_size: entry.flash_size,
+            product: entry.product,
+            user_defined: true,
+            name: entry.name,
+        })
+    }
+}
+
+/// Returns where the user profiles file is, if the config directory could be determined.
+pub fn user_profiles_path() -> Option<PathBuf>
+{
+    paths::config_dir().map(|dir| dir.join(PROFILES_FILE))
+}
+
+/// Reads the profiles in the profiles file at `path`. A missing file has no profiles.
+pub fn load_user_profiles(path: &Path) -> Result<Vec<ProbeProfile>, Error>
+{
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(ErrorKind::External(ErrorSource::StdIo(e)).error()
+                .with_ctx(&format!("reading probe profiles from {}", path.display())));
+        },
+    };
+
+    let file: ProfilesFile = toml::from_str(&contents)
+        .map_err(|e| ErrorKind::External(ErrorSource::Toml(Box::new(e))).error()
+            .with_ctx(&format!("parsing probe profiles in {}", path.display())))?;
+
+    file.profile.into_iter().map(ProbeProfile::try_from).collect()
+}
+
+/// Returns every known profile: the user's, followed by the built-in ones.
+///
+/// The user profiles file is only read once. If it can't be read, a warning is logged, and only
+/// the built-in profiles are used.
+pub fn registry() -> &'static [ProbeProfile]
+{
+    static REGISTRY: OnceLock<Vec<ProbeProfile>> = OnceLock::new();
+
+    REGISTRY.get_or_init(|| {
+        let mut profiles = match user_profiles_path() {
+            Some(path) => load_user_profiles(&path).unwrap_or_else(|e| {
+                warn!("Ignoring user probe profiles: {}", e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        debug!("Loaded {} user probe profile(s)", profiles.len());
+
+        profiles.extend(ProbeProfile::built_in());
+        profiles
+    })
+}
+
+/// Finds the profile for a device with the given USB IDs, and the mode the device is in, without
+/// looking at its product string: profiles that need one are passed over.
+pub fn find(vid: Vid, pid: Pid) -> Option<(&'static ProbeProfile, DfuOperatingMode)>
+{
+    find_in(registry(), vid, pid, None)
+}
+
+/// Like [`find`], but also considers the profiles that need the device's product string.
+pub fn find_for_product(vid: Vid, pid: Pid, product: &str) -> Option<(&'static ProbeProfile, DfuOperatingMode)>
+{
+    find_in(registry(), vid, pid, Some(product))
+}
+
+/// Whether any profile for the given USB IDs needs the device's product string to tell it apart,
+/// so that it's only read when it matters.
+pub fn needs_product(vid: Vid, pid: Pid) -> bool
+{
+    registry()
+        .iter()
+        .any(|profile| profile.product.is_some() && profile.mode_for((vid, pid)).is_some())
+}
+
+fn find_in<'p>(profiles: &'p [ProbeProfile], vid: Vid, pid: Pid, product: Option<&str>) -> Option<(&'p ProbeProfile, DfuOperatingMode)>
+{
+    profiles
+        .iter()
+        .filter(|profile| match product {
+            Some(product) => profile.matches_product(product),
+            None => profile.product.is_none(),
+        })
+        .find_map(|profile| profile.mode_for((vid, pid)).map(|mode| (profile, mode)))
+}
+
+/// Checks that `firmware` fits in the flash `profile` has for firmware of `firmware_type`.
+pub fn check_fits(profile: &ProbeProfile, firmware_type: FirmwareType, firmware: &[u8]) -> Result<(), Error>
+{
+    match (firmware_type, profile.flash_size) {
+        (FirmwareType::Application, Some(size)) if firmware.len() > size as usize => {
+            Err(ErrorKind::InvalidFirmware(Some(format!(
+                "firmware is {} bytes, but {} only has {} bytes of flash for it",
+                firmware.len(),
+                profile.name,
+                size,
+            ))).error())
+        },
+        _ => Ok(()),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn name_for(vid: u16, pid: u16, product: Option<&str>) -> Option<String>
+    {
+        find_in(&ProbeProfile::built_in(), Vid(vid), Pid(pid), product).map(|(profile, _mode)| profile.name.clone())
+    }
+
+    #[test]
+    fn stlink_told_apart_by_product_string()
+    {
+        let official = BmpPlatform::BlackMagicDebug.to_string();
+        let stlink = Some(S!("ST-Link (Black Magic Debug)"));
+
+        assert_eq!(name_for(0x1d50, 0x6018, Some("Black Magic Probe (ST-Link/v2) v1.10.0")), stlink);
+        assert_eq!(name_for(0x1d50, 0x6018, Some("Black Magic Probe v1.10.0")), Some(official.clone()));
+        assert_eq!(name_for(0x1d50, 0x6018, Some("Black Magic Probe (Upgrade), v1.10.0")), Some(official.clone()));
+        // Without a product string, only profiles that don't need one match.
+        assert_eq!(name_for(0x1d50, 0x6018, None), Some(official));
+    }
+
+    #[test]
+    fn stlink_firmware_size_checked()
+    {
+        let profiles = ProbeProfile::built_in();
+        let (stlink, _mode) = find_in(&profiles, Vid(0x1d50), Pid(0x6018), Some("(ST-Link/v2)")).unwrap();
+
+        assert_eq!(stlink.load_address(FirmwareType::Application), 0x0800_2000);
+        assert_eq!(stlink.load_address(FirmwareType::Bootloader), 0x0800_0000);
+        assert!(check_fits(stlink, FirmwareType::Application, &vec![0; STLINK_FLASH_SIZE as usize]).is_ok());
+        assert!(check_fits(stlink, FirmwareType::Application, &vec![0; STLINK_FLASH_SIZE as usize + 1]).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for hub quirks, which adjust how bmputil treats probes attached through hubs (typically
+//! those in docking stations and monitors) known to enumerate unreliably.
+//!
+//! A probe's quirks are those of every hub between it and the host, looked up by the hubs' USB IDs.
+//! A quirk can lengthen the timeouts, stop bmputil relying on hotplug notifications (some hubs
+//! drop or duplicate them), or always reset the probe after flashing rather than trusting it to
+//! detach by itself.
+//!
+//! The built-in quirks come from community reports. Others can be described in `quirks.toml` in
+//! the [config directory](crate::paths::config_dir):
+//!
+//! ```toml
+//! [[hub]]
+//! name = "My dock"
+//! ids = "1234:5678"
+//! # Each of these is optional.
+//! control_timeout_ms = 5000
+//! enumerate_timeout_secs = 20
+//! hotplug = false
+//! force_reset = true
+//! ```
+//!
+//! User quirks are consulted before the built-in ones, so they can also override them; an entry
+//! with only `name` and `ids` turns the quirks for that hub off.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::error::{Error, ErrorKind, ErrorSource};
+use crate::paths;
+use crate::timeouts::Timeouts;
+use crate::usb::{Pid, Vid};
+
+/// Name of the user quirks file in the config directory.
+pub const QUIRKS_FILE: &str = "quirks.toml";
+
+/// How a hub with particular USB IDs should be worked around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HubQuirk
+{
+    /// Name of the hub, for display.
+    pub name: String,
+    /// The USB IDs of the hub.
+    pub ids: (Vid, Pid),
+    /// The least timeout for control transfers to probes behind this hub, if it needs longer.
+    pub control_timeout: Option<Duration>,
+    /// The least time to wait for probes behind this hub to re-enumerate, if it needs longer.
+    pub enumerate_timeout: Option<Duration>,
+    /// Whether hotplug notifications for probes behind this hub can be relied on.
+    pub hotplug: bool,
+    /// Whether to always reset probes behind this hub after flashing.
+    pub force_reset: bool,
+    /// Whether this quirk came from the user's quirks file.
+    pub user_defined: bool,
+}
+
+impl HubQuirk
+{
+    /// The hub quirks bmputil knows about.
+    ///
+    /// Entries are only added here once a hub has been reported to need them, with its USB IDs.
+    pub fn built_in() -> Vec<Self>
+    {
+        Vec::new()
+    }
+}
+
+
+/// The combined quirks of every hub between a probe and the host.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct HubQuirks
+{
+    pub control_timeout: Option<Duration>,
+    pub enumerate_timeout: Option<Duration>,
+    /// Whether to avoid relying on hotplug notifications.
+    pub no_hotplug: bool,
+    pub force_reset: bool,
+}
+
+impl HubQuirks
+{
+    /// Whether there is nothing to work around.
+    pub fn is_empty(&self) -> bool
+    {
+        *self == Self::default()
+    }
+
+    fn merge(&mut self, quirk: &HubQuirk)
+    {
+        self.control_timeout = self.control_timeout.max(quirk.control_timeout);
+        self.enumerate_timeout = self.enumerate_timeout.max(quirk.enumerate_timeout);
+        self.no_hotplug |= !quirk.hotplug;
+        self.force_reset |= quirk.force_reset;
+    }
+
+    /// Lengthens `timeouts` to what these quirks need. Timeouts already longer are left alone.
+    pub fn apply(&self, timeouts: Timeouts) -> Timeouts
+    {
+        let control = timeouts.get_control().max(self.control_timeout.unwrap_or_default());
+        let enumerate = timeouts.get_enumerate().max(self.enumerate_timeout.unwrap_or_default());
+
+        timeouts.control(control).enumerate(enumerate)
+    }
+}
+
+
+/// A quirk as written in the quirks file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuirkEntry
+{
+    name: String,
+    ids: String,
+    #[serde(default)]
+    control_timeout_ms: Option<u64>,
+    #[serde(default)]
+    enumerate_timeout_secs: Option<u64>,
+    #[serde(default)]
+    hotplug: Option<bool>,
+    #[serde(default)]
+    force_reset: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuirksFile
+{
+    #[serde(default)]
+    hub: Vec<QuirkEntry>,
+}
+
+impl TryFrom<QuirkEntry> for HubQuirk
+{
+    type Error = Error;
+
+    fn try_from(entry: QuirkEntry) -> Result<Self, Error>
+    {
+        let ids = entry.ids.split_once(':')
+            .and_then(|(vid, pid)| Some((u16::from_str_radix(vid, 16).ok()?, u16::from_str_radix(pid, 16).ok()?)))
+            .map(|(vid, pid)| (Vid(vid), Pid(pid)))
+            .ok_or_else(|| {
+                let why = format!("USB IDs {:?} are not in the form vid:pid", entry.ids);
+                ErrorKind::External(ErrorSource::StdIo(io::Error::new(io::ErrorKind::InvalidData, why)))
+                    .error()
+                    .with_ctx(&format!("reading hub quirk {:?}", entry.name))
+            })?;
+
+        Ok(Self {
+            ids,
+            control_timeout: entry.control_timeout_ms.map(Duration::from_millis),
+            enumerate_timeout: entry.enumerate_timeout_secs.map(Duration::from_secs),
+            hotplug: entry.hotplug.unwrap_or(true),
+            force_reset: entry.force_reset.unwrap_or(false),
+            user_defined: true,
+            name: entry.name,
+        })
+    }
+}
+
+/// Returns where the user quirks file is, if the config directory could be determined.
+pub fn user_quirks_path() -> Option<PathBuf>
+{
+    paths::config_dir().map(|dir| dir.join(QUIRKS_FILE))
+}
+
+/// Reads the quirks in the quirks file at `path`. A missing file has no quirks.
+pub fn load_user_quirks(path: &Path) -> Result<Vec<HubQuirk>, Error>
+{
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(ErrorKind::External(ErrorSource::StdIo(e)).error()
+                .with_ctx(&format!("reading hub quirks from {}", path.display())));
+        },
+    };
+
+    let file: QuirksFile = toml::from_str(&contents)
+        .map_err(|e| ErrorKind::External(ErrorSource::Toml(Box::new(e))).error()
+            .with_ctx(&format!("parsing hub quirks in {}", path.display())))?;
+
+    file.hub.into_iter().map(HubQuirk::try_from).collect()
+}
+
+/// Returns every known hub quirk: the user's, followed by the built-in ones.
+///
+/// The user quirks file is only read once. If it can't be read, a warning is logged, and only the
+/// built-in quirks are used.
+pub fn registry() -> &'static [HubQuirk]
+{
+    static REGISTRY: OnceLock<Vec<HubQuirk>> = OnceLock::new();
+
+    REGISTRY.get_or_init(|| {
+        let mut quirks = match user_quirks_path() {
+            Some(path) => load_user_quirks(&path).unwrap_or_else(|e| {
+                warn!("Ignoring user hub quirks: {}", e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        debug!("Loaded {} user hub quirk(s)", quirks.len());
+
+        quirks.extend(HubQuirk::built_in());
+        quirks
+    })
+}
+
+/// Finds the quirk for a hub with the given USB IDs.
+pub fn find(vid: Vid, pid: Pid) -> Option<&'static HubQuirk>
+{
+    registry().iter().find(|quirk| quirk.ids == (vid, pid))
+}
+
+/// Returns the combined quirks of every hub between `device` and the host.
+pub fn for_device<T: rusb::UsbContext>(device: &rusb::Device<T>) -> HubQuirks
+{
+    let mut quirks = HubQuirks::default();
+    let mut parent = device.get_parent();
+    while let Some(hub) = parent {
+        if let Ok(desc) = hub.device_descriptor() {
+            if let Some(quirk) = find(Vid(desc.vendor_id()), Pid(desc.product_id())) {
+                debug!("Applying quirks for hub {} on bus {}", quirk.name, hub.bus_number());
+                quirks.merge(quirk);
+            }
+        }
+        parent = hub.get_parent();
+    }
+
+    quirks
+}
@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for the read protection (RDP) of probes flashed through the STM32's built-in DFU
+//! bootloader, which exposes the chip's option bytes as a DfuSe alternate setting.
+//!
+//! A chip with read protection enabled refuses to have its flash read, and its bootloader refuses
+//! most DFU requests, so flashing it fails. Removing read protection makes the chip mass erase its
+//! flash. Level 2 disables the bootloader and debug access for good, so it is never set here.
+
+use std::fmt::{self, Display, Formatter};
+
+use log::debug;
+
+use crate::bmp::{BmpDevice, BmpPlatform};
+use crate::dfu::{DfuError, DfuInterface, DfuProtocol};
+use crate::error::{Error, ErrorKind, InvalidDeviceData};
+use crate::S;
+
+/// Start of the name of the DfuSe alternate setting for the option bytes.
+const OPTION_BYTES: &str = "@Option Bytes";
+
+/// A read protection level.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RdpLevel
+{
+    /// Unprotected.
+    Level0,
+    /// Flash can't be read, and removing the protection mass erases it.
+    Level1,
+    /// Permanently locked.
+    Level2,
+}
+
+impl Display for RdpLevel
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        match self {
+            Self::Level0 => write!(f, "level 0 (not read protected)"),
+            Self::Level1 => write!(f, "level 1 (read protected)"),
+            Self::Level2 => write!(f, "level 2 (permanently read protected)"),
+        }
+    }
+}
+
+/// Where the RDP byte is in a family's option bytes, and the values it takes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct RdpByte
+{
+    offset: usize,
+    level0: u8,
+    level2: u8,
+    /// What to write for level 1. Any value other than those for level 0 and 2 means level 1.
+    level1: u8,
+}
+
+impl RdpByte
+{
+    /// The RDP byte for the family whose option bytes are at `address`.
+    fn for_address(address: u32) -> Option<Self>
+    {
+        match address {
+            // STM32F0, F1, and F3.
+            0x1fff_f800 => Some(Self {
+                offset: 0,
+                level0: 0xa5,
+                level2: 0xcc,
+                level1: 0x00,
+            }),
+            // STM32F2 and F4.
+            0x1fff_c000 => Some(Self {
+                offset: 1,
+                level0: 0xaa,
+                level2: 0xcc,
+                level1: 0x55,
+            }),
+            _ => None,
+        }
+    }
+
+    fn level(&self, option_bytes: &[u8]) -> Option<RdpLevel>
+    {
+        option_bytes.get(self.offset).map(|&value| match value {
+            value if value == self.level0 => RdpLevel::Level0,
+            value if value == self.level2 => RdpLevel::Level2,
+            _ => RdpLevel::Level1,
+        })
+    }
+}
+
+/// Returns where the option bytes are, how long they are, and where the RDP byte is in them.
+fn option_bytes_layout(dfu: &DfuInterface) -> Result<(u32, usize, RdpByte), Error>
+{
+    let DfuProtocol::Dfuse(segments) = dfu.protocol() else {
+        return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingDfuse).error());
+    };
+    let segment = segments
+        .first()
+        .ok_or_else(|| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingOptionBytes).error())?;
+    let rdp = RdpByte::for_address(segment.start).ok_or_else(|| {
+        ErrorKind::OperationNotSupported(format!(
+            "read protection of chips with option bytes at 0x{:08x}",
+            segment.start,
+        )).error()
+    })?;
+    let length = (segment.end() - segment.start as u64) as usize;
+
+    Ok((segment.start, length, rdp))
+}
+
+fn check_platform(dev: &BmpDevice) -> Result<(), Error>
+{
+    if dev.platform() != BmpPlatform::STM32DeviceDFU {
+        return Err(ErrorKind::OperationNotSupported(format!(
+            "read protection on {} devices (only the STM32 built-in DFU bootloader exposes it)",
+            dev.platform(),
+        )).error());
+    }
+
+    Ok(())
+}
+
+/// Reads the read protection level of `dev`, which must be in the STM32 built-in DFU bootloader.
+pub fn read_level(dev: &mut BmpDevice) -> Result<RdpLevel, Error>
+{
+    check_platform(dev)?;
+
+    dev.with_dfu_alt_setting(OPTION_BYTES, |dfu| {
+        let (address, length, rdp) = option_bytes_layout(dfu)?;
+        match dfu.dfuse_upload(address, length) {
+            Ok(option_bytes) => rdp.level(&option_bytes)
+                .ok_or_else(|| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::ShortOptionBytes).error()),
+            // The bootloader refuses to read anything, option bytes included, while protected.
+            Err(e @ (DfuError::ErrorStatus { .. } | DfuError::Usb(rusb::Error::Pipe))) => {
+                debug!("Reading option bytes failed, so assuming read protection: {}", e);
+                Ok(RdpLevel::Level1)
+            },
+            Err(e) => Err(e.into()),
+        }
+    })
+}
+
+/// Removes the read protection of `dev`, which mass erases its flash. The device then resets.
+pub fn remove(dev: &mut BmpDevice) -> Result<(), Error>
+{
+    check_platform(dev)?;
+
+    dev.with_dfu_alt_setting(OPTION_BYTES, |dfu| Ok(dfu.dfuse_read_unprotect()?))
+}
+
+/// Enables level 1 read protection on `dev`. The device then resets.
+pub fn enable(dev: &mut BmpDevice) -> Result<(), Error>
+{
+    check_platform(dev)?;
+
+    dev.with_dfu_alt_setting(OPTION_BYTES, |dfu| {
+        let (address, length, rdp) = option_bytes_layout(dfu)?;
+        let mut option_bytes = dfu.dfuse_upload(address, length)
+            .map_err(|e| Error::from(e).with_ctx("reading option bytes (is read protection already enabled?)"))?;
+        if rdp.level(&option_bytes) != Some(RdpLevel::Level0) {
+            return Err(ErrorKind::OperationNotSupported(S!("enabling read protection when it is already enabled")).error());
+        }
+
+        option_bytes[rdp.offset] = rdp.level1;
+        Ok(dfu.dfuse_write_block(address, &option_bytes)?)
+    })
+}
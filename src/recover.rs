@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil recover`, which looks for the usual reasons a probe is stuck in its
+//! bootloader and gets it running firmware again.
+//!
+//! A probe that failed partway through flashing is typically left in DFU mode with a half-written
+//! application, its bootloader in an error state, or (behind some hubs) not even configured. Each
+//! of those is checked and fixed in turn, and given a known-good image, the probe is then flashed,
+//! verified, and rebooted, falling back to a mass erase where the bootloader supports it.
+
+use clap::ArgMatches;
+use log::debug;
+
+use bmputil::bmp::{wait_for_probe_reboot, Armv7mVectorTable, BmpDevice, DownloadOptions, FirmwareType};
+use bmputil::dfu::{DfuError, DfuState, EraseStrategy};
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::profiles::ProbeProfile;
+use bmputil::usb::DfuOperatingMode;
+
+use crate::confirm::{AuthorizationLevel, ConfirmationPolicy};
+use crate::FlashBackend;
+
+/// What the application on a probe in DFU mode looks like.
+#[derive(Debug)]
+enum Application
+{
+    /// The start of its vector table looks sane, so it's probably intact.
+    Plausible,
+    /// Its flash is erased.
+    Erased,
+    /// Its vector table makes no sense, so it's corrupt or partly written.
+    Corrupt(String),
+    /// The bootloader wouldn't let us read it.
+    Unreadable(DfuError),
+}
+
+/// Checks the vector table at the start of the application, in the same way as an image is checked
+/// before flashing, except that the image's length isn't known.
+fn check_vector_table(profile: &ProbeProfile, vector_table: &[u8]) -> Application
+{
+    if vector_table.iter().all(|&byte| byte == 0xff) {
+        return Application::Erased;
+    }
+
+    let vector_table = Armv7mVectorTable::from_bytes(vector_table);
+    let (Ok(stack_pointer), Ok(reset_vector)) = (vector_table.stack_pointer(), vector_table.reset_vector()) else {
+        return Application::Corrupt(String::from("vector table too short"));
+    };
+    if (stack_pointer & 0xfff0_0000) != 0x2000_0000 {
+        return Application::Corrupt(format!("initial stack pointer 0x{:08x} does not point into SRAM", stack_pointer));
+    }
+
+    let start = profile.load_address(FirmwareType::Application);
+    let end = start as u64 + profile.flash_size.map_or(u32::MAX as u64 - start as u64, u64::from);
+    let reset_address = (reset_vector & !1) as u64;
+    if reset_address < start as u64 || reset_address >= end {
+        return Application::Corrupt(format!("reset vector 0x{:08x} does not point into the application", reset_vector));
+    }
+
+    Application::Plausible
+}
+
+/// Reads the start of the application's vector table, and checks it.
+fn check_application(dev: &mut BmpDevice) -> Result<Application, Error>
+{
+    let profile = dev.profile();
+    let address = profile.load_address(FirmwareType::Application);
+
+    dev.with_dfu_interface(|dfu| {
+        let res = dfu.dfuse_upload(address, 4 * 2);
+        // Leave the bootloader idle whatever happened, ready to be flashed.
+        if let Err(e) = dfu.ensure_idle() {
+            debug!("Failed to return DFU interface to dfuIDLE after reading: {}", e);
+        }
+
+        match res {
+            Ok(vector_table) => Ok(check_vector_table(profile, &vector_table)),
+            Err(e @ (DfuError::ErrorStatus { .. } | DfuError::Usb(rusb::Error::Pipe))) => Ok(Application::Unreadable(e)),
+            Err(e) => Err(e.into()),
+        }
+    })
+}
+
+/// Whether `e` is the bootloader refusing part of a download, which a mass erase may get past.
+fn is_refused_download(e: &Error) -> bool
+{
+    matches!(e.kind, ErrorKind::External(ErrorSource::Dfu(DfuError::ErrorStatus { .. })))
+}
+
+/// Implements `bmputil recover`.
+pub fn recover_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    // Read the image first, so a bad path doesn't show up halfway through recovering.
+    let firmware = matches.value_of("firmware_binary")
+        .map(|filename| {
+            crate::signature_policy(Some(matches))
+                .and_then(|policy| crate::read_signed_firmware_file(filename, &policy))
+                .map_err(|e| e.with_ctx("reading firmware file"))
+        })
+        .transpose()?;
+
+    let matcher = crate::matcher_from_cli_args(matches);
+    let mut results = crate::find_probes(&matcher, matches)?;
+    let mut dev = results.pop_single("recover")?;
+    println!("Found: {}", dev);
+
+    if dev.operating_mode() == DfuOperatingMode::Runtime {
+        let version = dev.firmware_version().map_or_else(|| String::from("unknown version"), |v| v.to_string());
+        println!("The probe is running its firmware ({}), so there is nothing to recover.", version);
+        println!("To reflash it anyway, use `bmputil flash`.");
+        return Ok(());
+    }
+
+    println!("Checking the probe...");
+    if dev.ensure_configured()? {
+        println!("  - It was left unconfigured; selected its configuration.");
+    }
+
+    let status = dev.with_dfu_interface(|dfu| {
+        let status = dfu.get_status()?;
+        if status.state != DfuState::DfuIdle {
+            dfu.ensure_idle()?;
+        }
+        Ok(status)
+    })?;
+    match status.state {
+        DfuState::DfuIdle => println!("  - The bootloader is idle."),
+        DfuState::DfuError => println!("  - The bootloader reported an error ({}); cleared it.", status.status),
+        other => println!("  - The bootloader was left in {}; returned it to idle.", other),
+    }
+
+    let application = check_application(&mut dev)?;
+    match &application {
+        Application::Plausible => println!("  - The firmware looks intact."),
+        Application::Erased => println!("  - There is no firmware; its flash is erased."),
+        Application::Corrupt(why) => println!("  - The firmware is corrupt or partly written: {}.", why),
+        Application::Unreadable(e) => {
+            println!("  - The bootloader refused to read the firmware back ({}).", e);
+            if dev.platform().supports_mass_erase() {
+                println!("    The chip is probably read protected; check with `bmputil rdp status`.");
+            }
+        },
+    }
+
+    let Some(firmware) = firmware else {
+        if matches!(application, Application::Plausible) {
+            println!("Try starting it with `bmputil switch --to runtime`, or give a known-good image to reflash it.");
+        } else {
+            println!("To reflash it, run `bmputil recover <firmware>` with a known-good image, e.g. from the Black Magic Debug releases.");
+        }
+        return Ok(());
+    };
+
+    FirmwareType::validate_application(dev.profile(), &firmware)
+        .map_err(|e| e.with_ctx("validating recovery image"))?;
+    firmware.check_address(dev.profile(), FirmwareType::Application)?;
+    let policy = ConfirmationPolicy::from_cli_args(matches);
+    policy.confirm(
+        AuthorizationLevel::Destructive,
+        "recovering the probe",
+        "Recovering the probe erases whatever firmware is left on it, and flashes the given image instead.",
+    )?;
+
+    let identity = dev.identity();
+    let timeouts = dev.timeouts();
+    let mass_erase_supported = dev.platform().supports_mass_erase();
+    let options = DownloadOptions::new().verify(true);
+    let dev = match crate::run_flash_pipeline(dev, &firmware, FirmwareType::Application, options, FlashBackend::Native) {
+        Err(e) if is_refused_download(&e) && mass_erase_supported => {
+            println!("The bootloader refused the download ({}); retrying with a mass erase.", e);
+            let dev = wait_for_probe_reboot(&identity, timeouts, "recover")?;
+            let options = DownloadOptions::new()
+                .verify(true)
+                .erase_strategy(EraseStrategy::Mass);
+            crate::run_flash_pipeline(dev, &firmware, FirmwareType::Application, options, FlashBackend::Native)
+                .map_err(|e| e.on_attempt(2))?
+        },
+        res => res?,
+    };
+
+    let version = crate::firmware_version_after_flash(&dev)?;
+    println!("Recovered: the probe now runs {}.", version);
+
+    Ok(())
+}
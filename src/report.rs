@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for `bmputil flash --report`, which keeps a machine-readable record of every probe
+//! flashed, as an audit trail of what went onto which probe.
+//!
+//! The report is a JSON array with one [`FlashRecord`] per attempt to flash a probe. Records are
+//! appended to an existing report, and the file is rewritten after each probe, so it stays
+//! complete even when `--on-connect` is stopped with Ctrl-C.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use bmputil::bmp::BmpDevice;
+use bmputil::error::{Error, ErrorKind, ErrorSource, OperationContext};
+use bmputil::warnings;
+
+/// One attempt to flash a probe.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlashRecord
+{
+    pub serial: Option<String>,
+    /// The USB port path the probe was on.
+    pub port: String,
+    /// The firmware file, as given on the command line.
+    pub firmware_file: String,
+    /// SHA-256 of the firmware image, in hexadecimal.
+    pub image_sha256: String,
+    pub version_before: Option<String>,
+    /// `None` if flashing failed, or the probe was left in DFU mode.
+    pub version_after: Option<String>,
+    /// When flashing started, in seconds since the Unix epoch.
+    pub started: u64,
+    pub duration_secs: f64,
+    /// `ok` or `failed`.
+    pub result: String,
+    pub error: Option<String>,
+    /// Where flashing failed, e.g. in which phase.
+    #[serde(default)]
+    pub error_context: Option<OperationContext>,
+    /// The codes of the warnings given while flashing, e.g. `BMPW005`.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    #[serde(skip)]
+    clock: Option<Instant>,
+}
+
+impl FlashRecord
+{
+    /// Starts a record of flashing `image`, from the file `firmware_file`, onto `dev`.
+    pub fn start(dev: &BmpDevice, firmware_file: &str, image: &[u8]) -> Self
+    {
+        let image_sha256 = Sha256::digest(image)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        Self {
+            serial: dev.serial_number().ok().map(|serial| serial.to_string()),
+            port: dev.port(),
+            firmware_file: firmware_file.to_string(),
+            image_sha256,
+            version_before: dev.firmware_version().map(|version| version.to_string()),
+            version_after: None,
+            started: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()),
+            duration_secs: 0.0,
+            result: String::new(),
+            error: None,
+            error_context: None,
+            warnings: Vec::new(),
+            clock: Some(Instant::now()),
+        }
+    }
+
+    /// Completes the record with the outcome of flashing, which gives the firmware version
+    /// the probe then runs, if known, and the warnings given since the last record.
+    pub fn finish(mut self, res: Result<Option<String>, &Error>) -> Self
+    {
+        self.duration_secs = self.clock.map_or(0.0, |clock| clock.elapsed().as_secs_f64());
+        self.warnings = warnings::take_emitted()
+            .into_iter()
+            .map(|warning| warning.code.to_string())
+            .collect();
+        match res {
+            Ok(version) => {
+                self.result = String::from("ok");
+                self.version_after = version;
+            },
+            Err(e) => {
+                self.result = String::from("failed");
+                self.error = Some(e.to_string());
+                self.error_context = e.breadcrumbs();
+            },
+        }
+
+        self
+    }
+}
+
+/// A report file being added to.
+pub struct FlashReport
+{
+    path: PathBuf,
+    records: Vec<FlashRecord>,
+}
+
+fn report_error(e: io::Error, path: &Path) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(e)).error()
+        .with_ctx(&format!("writing flash report {}", path.display()))
+}
+
+impl FlashReport
+{
+    /// Opens the report at `path`, keeping the records already in it.
+    pub fn open(path: &Path) -> Result<Self, Error>
+    {
+        let records = match fs::read_to_string(path) {
+            Ok(contents) if contents.trim().is_empty() => Vec::new(),
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| report_error(e.into(), path))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(report_error(e, path)),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            records,
+        })
+    }
+
+    /// Adds a record, and writes out the report.
+    pub fn add(&mut self, record: FlashRecord) -> Result<(), Error>
+    {
+        self.records.push(record);
+
+        // Write the new report next to the old one first, so the old one survives being interrupted.
+        let json = serde_json::to_string_pretty(&self.records)
+            .expect("Serializing a flash report should not fail");
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_name);
+        fs::write(&temp_path, json + "\n")
+            .and_then(|()| fs::rename(&temp_path, &self.path))
+            .map_err(|e| report_error(e, &self.path))
+    }
+}
+
+/// Adds `record` to `report`. If that fails after a failed flash, the flash error matters more, so
+/// the report error is only logged; otherwise, it's returned.
+pub fn add_or_warn(report: &mut FlashReport, record: FlashRecord, flashed: bool) -> Result<(), Error>
+{
+    match report.add(record) {
+        Err(e) if !flashed => {
+            error!("{}", e);
+            Ok(())
+        },
+        res => res,
+    }
+}
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for retrying USB transfers that failed for transient reasons.
+//!
+//! Cheap hubs and long cables make individual control transfers fail with I/O errors, stalls, or
+//! "busy" often enough that a one-shot transfer isn't good enough for a multi-minute flash.
+//!
+//! Waiting between attempts goes through a [`Clock`], and jitter through a [`RandomSource`], so
+//! retrying can be simulated (see [`crate::clock`]).
+
+use std::time::Duration;
+
+use log::debug;
+
+use crate::clock::{Clock, RandomSource, SystemClock, SystemRandom};
+use crate::usb::telemetry;
+
+/// How often, and how patiently, to retry transfers that failed with a transient error.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RetryPolicy
+{
+    /// How many times to retry after the first attempt.
+    retries: u32,
+    /// How long to wait before the first retry. Doubles with each further retry.
+    backoff: Duration,
+    /// The longest we'll wait between two attempts.
+    max_backoff: Duration,
+    /// Whether to add a random extra wait of up to half the backoff to each retry.
+    jitter: bool,
+}
+
+impl RetryPolicy
+{
+    pub fn new() -> Self
+    {
+        Default::default()
+    }
+
+    /// Set how many times a failed transfer is retried. Defaults to 2; 0 disables retrying.
+    #[must_use]
+    pub fn retries(mut self, retries: u32) -> Self
+    {
+        self.retries = retries;
+        self
+    }
+
+    /// Get the value previously set with `.retries()`.
+    pub fn get_retries(&self) -> u32
+    {
+        self.retries
+    }
+
+    /// Set how long to wait before the first retry, which doubles for each retry after that.
+    /// Defaults to 50 ms.
+    #[must_use]
+    #[allow(dead_code)]
+    pub fn backoff(mut self, backoff: Duration) -> Self
+    {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Get the value previously set with `.backoff()`.
+    #[allow(dead_code)]
+    pub fn get_backoff(&self) -> Duration
+    {
+        self.backoff
+    }
+
+    /// Set whether each retry waits a random extra time, of up to half its backoff, so that
+    /// several probes on one flaky hub don't keep retrying in lockstep. Defaults to false.
+    #[must_use]
+    pub fn jitter(mut self, jitter: bool) -> Self
+    {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Get the value previously set with `.jitter()`.
+    pub fn get_jitter(&self) -> bool
+    {
+        self.jitter
+    }
+
+    /// Whether an error is worth retrying the transfer for.
+    pub fn is_transient(error: &rusb::Error) -> bool
+    {
+        matches!(error, rusb::Error::Io | rusb::Error::Pipe | rusb::Error::Busy)
+    }
+
+    /// How long to wait before retry number `retry` (starting at 1).
+    pub fn delay_before(&self, retry: u32) -> Duration
+    {
+        let factor = 1u32.checked_shl(retry.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    /// How long to wait before retry number `retry` (starting at 1), including any jitter, drawn
+    /// from `random`.
+    pub fn delay_with(&self, retry: u32, random: &dyn RandomSource) -> Duration
+    {
+        let delay = self.delay_before(retry);
+        if !self.jitter {
+            return delay;
+        }
+
+        delay + random.duration_up_to(delay / 2)
+    }
+
+    /// Runs `transfer`, retrying it according to this policy while it fails with a transient error.
+    ///
+    /// `what` describes the transfer, for logging.
+    pub fn run<T, F>(&self, what: &str, transfer: F) -> Result<T, rusb::Error>
+    where
+        F: FnMut() -> Result<T, rusb::Error>,
+    {
+        self.run_with(&SystemClock, &SystemRandom, what, transfer)
+    }
+
+    /// Like [`Self::run`], but waits between attempts on `clock`, with jitter from `random`.
+    pub fn run_with<T, F>(&self, clock: &dyn Clock, random: &dyn RandomSource, what: &str, mut transfer: F) -> Result<T, rusb::Error>
+    where
+        F: FnMut() -> Result<T, rusb::Error>,
+    {
+        let mut retry = 0;
+        loop {
+            match transfer() {
+                Err(e) if Self::is_transient(&e) && retry < self.retries => {
+                    retry += 1;
+                    telemetry::retry();
+                    debug!("Transient error during {} ({}), retrying ({}/{})", what, e, retry, self.retries);
+                    clock.sleep(self.delay_with(retry, random));
+                },
+                other => return other,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy
+{
+    fn default() -> Self
+    {
+        Self {
+            retries: 2,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            jitter: false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::cell::RefCell;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::clock::{ManualClock, SeededRandom};
+
+    /// A [`ManualClock`] that also remembers every sleep.
+    #[derive(Default)]
+    struct RecordingClock
+    {
+        clock: ManualClock,
+        sleeps: RefCell<Vec<Duration>>,
+    }
+
+    impl Clock for RecordingClock
+    {
+        fn now(&self) -> Instant
+        {
+            self.clock.now()
+        }
+
+        fn sleep(&self, duration: Duration)
+        {
+            self.sleeps.borrow_mut().push(duration);
+            self.clock.sleep(duration);
+        }
+    }
+
+    fn retry_until_exhausted(policy: RetryPolicy, seed: u64) -> (Vec<Duration>, u32)
+    {
+        let clock = RecordingClock::default();
+        let mut attempts = 0;
+        let res: Result<(), rusb::Error> = policy.run_with(&clock, &SeededRandom::new(seed), "test", || {
+            attempts += 1;
+            Err(rusb::Error::Io)
+        });
+        assert_eq!(res, Err(rusb::Error::Io));
+
+        (clock.sleeps.into_inner(), attempts)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_limit()
+    {
+        let (sleeps, attempts) = retry_until_exhausted(RetryPolicy::new().retries(6), 0);
+
+        assert_eq!(attempts, 7);
+        assert_eq!(sleeps, [50, 100, 200, 400, 800, 1000].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn jitter_is_reproducible_with_seed()
+    {
+        let policy = RetryPolicy::new().retries(4).jitter(true);
+        let (sleeps, _) = retry_until_exhausted(policy, 42);
+
+        // Each is the backoff (50, 100, 200, then 400 ms) plus up to half of it again.
+        assert_eq!(sleeps, [51_995_990, 130_371_629, 269_926_888, 512_265_622].map(Duration::from_nanos));
+        assert_eq!(retry_until_exhausted(policy, 42).0, sleeps);
+        assert_ne!(retry_until_exhausted(policy, 43).0, sleeps);
+    }
+}
@@ -0,0 +1,359 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for finding the serial ports the OS created for a Black Magic Probe's CDC-ACM interfaces.
+//!
+//! A probe in runtime mode exposes two CDC-ACM functions: the GDB server on interface 0, and the
+//! UART passthrough on interface 2. libusb has no way to tell us which serial port the OS bound to
+//! which interface, so we have to ask each OS in its own way, using the probe's serial number to
+//! tell multiple probes apart.
+
+use std::fmt::{self, Display, Formatter};
+
+use log::{trace, debug};
+
+use crate::bmp::BmpPlatform;
+use crate::error::{Error, ErrorKind, InvalidDeviceData};
+
+/// The serial ports a Black Magic Probe provides.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ProbePort
+{
+    /// The GDB server.
+    Gdb,
+    /// The UART passthrough ("aux" serial port).
+    Uart,
+}
+
+impl ProbePort
+{
+    /// The USB interface number of the CDC-ACM communications interface for this port.
+    pub const fn interface_number(self) -> u8
+    {
+        use ProbePort::*;
+        match self {
+            Gdb => 0,
+            Uart => 2,
+        }
+    }
+}
+
+impl Display for ProbePort
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        use ProbePort::*;
+        match self {
+            Gdb => write!(f, "GDB serial port")?,
+            Uart => write!(f, "UART serial port")?,
+        };
+
+        Ok(())
+    }
+}
+
+
+/// Finds the serial port the OS created for `port` of the probe with the given serial number.
+///
+/// The returned string is suitable for passing directly to GDB's `target extended-remote`.
+pub fn find_serial_port(serial: &str, port: ProbePort) -> Result<String, Error>
+{
+    debug!("Looking up {} for probe with serial number {}", port, serial);
+
+    // The serial number ends up in paths and registry keys, so it had better not be able to point
+    // anywhere else.
+    if !serial.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::BadSerialNumber(serial.to_string()))
+            .error()
+            .with_ctx(&format!("finding the {}", port)));
+    }
+
+    match find_serial_port_for_os(serial, port)? {
+        Some(path) => {
+            trace!("Found {} for probe {} at {}", port, serial, path);
+            Ok(path)
+        },
+        None => Err(
+            ErrorKind::DeviceNotFound.error()
+                .with_ctx(&format!("finding the {} of probe {}", port, serial))
+        ),
+    }
+}
+
+/// Linux: walk `/sys/class/tty`, and match each TTY's parent USB interface and device against the
+/// probe's VID/PID, serial number, and interface number.
+#[cfg(target_os = "linux")]
+fn find_serial_port_for_os(serial: &str, port: ProbePort) -> Result<Option<String>, Error>
+{
+    use std::fs;
+    use std::path::Path;
+
+    use crate::error::ErrorSource;
+
+    fn read_attr(dir: &Path, name: &str) -> Option<String>
+    {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|contents| contents.trim().to_string())
+    }
+
+    let (vid, pid) = BmpPlatform::BlackMagicDebug.runtime_ids();
+
+    let ttys = fs::read_dir("/sys/class/tty")
+        .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error())?;
+
+    for tty in ttys.filter_map(Result::ok) {
+        // For USB serial ports, `device` links to the USB interface, whose parent is the USB device.
+        let interface_dir = match fs::canonicalize(tty.path().join("device")) {
+            Ok(dir) => dir,
+            Err(_) => continue,
+        };
+        let device_dir = match interface_dir.parent() {
+            Some(dir) => dir,
+            None => continue,
+        };
+
+        let matches = read_attr(&interface_dir, "bInterfaceNumber")
+            .and_then(|num| u8::from_str_radix(&num, 16).ok()) == Some(port.interface_number())
+            && read_attr(device_dir, "idVendor").and_then(|v| u16::from_str_radix(&v, 16).ok()) == Some(vid.0)
+            && read_attr(device_dir, "idProduct").and_then(|p| u16::from_str_radix(&p, 16).ok()) == Some(pid.0)
+            && read_attr(device_dir, "serial").as_deref() == Some(serial);
+
+        if matches {
+            return Ok(Some(format!("/dev/{}", tty.file_name().to_string_lossy())));
+        }
+    }
+
+    Ok(None)
+}
+
+/// macOS: the CDC-ACM driver names the callout device after the device's serial number and the
+/// interface number, plus one.
+#[cfg(target_os = "macos")]
+fn find_serial_port_for_os(serial: &str, port: ProbePort) -> Result<Option<String>, Error>
+{
+    use std::path::Path;
+
+    let path = format!("/dev/cu.usbmodem{}{}", serial, port.interface_number() + 1);
+
+    Ok(Path::new(&path).exists().then_some(path))
+}
+
+/// Windows: find the `ParentIdPrefix` Windows assigned the probe's composite device, and use it to
+/// find the device key of the interface, which records the COM port name.
+#[cfg(windows)]
+fn find_serial_port_for_os(serial: &str, port: ProbePort) -> Result<Option<String>, Error>
+{
+    use winreg::enums::*;
+    use winreg::RegKey;
+
+    let (vid, pid) = BmpPlatform::BlackMagicDebug.runtime_ids();
+    let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+
+    let device_key_name = format!(r"SYSTEM\CurrentControlSet\Enum\USB\VID_{:04X}&PID_{:04X}\{}", vid.0, pid.0, serial);
+    trace!(r"Opening HKLM:\{}", device_key_name);
+    let parent_id_prefix: String = match hklm
+        .open_subkey(&device_key_name)
+        .and_then(|key| key.get_value("ParentIdPrefix"))
+    {
+        Ok(prefix) => prefix,
+        Err(e) => {
+            debug!("Could not read ParentIdPrefix for probe {}: {}", serial, e);
+            return Ok(None);
+        },
+    };
+
+    let interface_key_name = format!(
+        r"SYSTEM\CurrentControlSet\Enum\USB\VID_{:04X}&PID_{:04X}&MI_{:02X}\{}&{:04X}\Device Parameters",
+        vid.0,
+        pid.0,
+        port.interface_number(),
+        parent_id_prefix,
+        port.interface_number(),
+    );
+    trace!(r"Opening HKLM:\{}", interface_key_name);
+    match hklm
+        .open_subkey(&interface_key_name)
+        .and_then(|key| key.get_value::<String, _>("PortName"))
+    {
+        Ok(port_name) => Ok(Some(port_name)),
+        Err(e) => {
+            debug!("Could not read PortName for probe {}: {}", serial, e);
+            Ok(None)
+        },
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn find_serial_port_for_os(_serial: &str, _port: ProbePort) -> Result<Option<String>, Error>
+{
+    use crate::S;
+
+    Err(ErrorKind::OperationNotSupported(S!("finding serial ports on this OS")).error())
+}
+
+
+/// Opening serial ports in raw mode, setting their baud rate, and reading from them with a timeout.
+#[cfg(unix)]
+pub mod raw
+{
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    /// Opens a serial port and puts it in raw mode.
+    pub fn open(path: &str) -> io::Result<File>
+    {
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+
+        // SAFETY: the file descriptor is valid for as long as `port` is, and termios is plain data.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(port.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(port.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(port)
+    }
+
+    /// Sets the baud rate of the port, which for the probe's UART is passed on to the target.
+    pub fn set_baud_rate(port: &File, baud: u32) -> io::Result<()>
+    {
+        let speed = match baud {
+            1200 => libc::B1200,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            230400 => libc::B230400,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            460800 => libc::B460800,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            921600 => libc::B921600,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            1000000 => libc::B1000000,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            2000000 => libc::B2000000,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported baud rate {}", baud),
+                ));
+            },
+        };
+
+        // SAFETY: as in `open()`.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(port.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::cfsetspeed(&mut termios, speed) != 0
+                || libc::tcsetattr(port.as_raw_fd(), libc::TCSANOW, &termios) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads from the port, returning 0 if no data arrives within `timeout`.
+    pub fn read_timeout(port: &mut File, buf: &mut [u8], timeout: Duration) -> io::Result<usize>
+    {
+        let mut pollfd = libc::pollfd {
+            fd: port.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+        // SAFETY: we pass exactly one valid pollfd.
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(0),
+            _ => port.read(buf),
+        }
+    }
+}
+
+/// Opening serial ports, setting their baud rate, and reading from them with a timeout.
+#[cfg(windows)]
+pub mod raw
+{
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::windows::io::AsRawHandle;
+    use std::time::Duration;
+
+    use winapi::um::commapi::{GetCommState, SetCommState, SetCommTimeouts};
+    use winapi::um::winbase::{COMMTIMEOUTS, DCB};
+
+    pub fn open(path: &str) -> io::Result<File>
+    {
+        // COM ports above 9 can only be opened with the device namespace prefix.
+        let path = if path.starts_with(r"\\.\") {
+            path.to_string()
+        } else {
+            format!(r"\\.\{}", path)
+        };
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+    }
+
+    /// Sets the baud rate of the port, which for the probe's UART is passed on to the target.
+    pub fn set_baud_rate(port: &File, baud: u32) -> io::Result<()>
+    {
+        // SAFETY: the handle is valid for as long as `port` is, and DCB is plain data.
+        unsafe {
+            let mut dcb: DCB = std::mem::zeroed();
+            dcb.DCBlength = std::mem::size_of::<DCB>() as u32;
+            if GetCommState(port.as_raw_handle() as _, &mut dcb) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            dcb.BaudRate = baud;
+            if SetCommState(port.as_raw_handle() as _, &mut dcb) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads from the port, returning 0 if no data arrives within `timeout`.
+    pub fn read_timeout(port: &mut File, buf: &mut [u8], timeout: Duration) -> io::Result<usize>
+    {
+        // Return as soon as any data is available, or after the timeout if there is none.
+        let mut timeouts = COMMTIMEOUTS {
+            ReadIntervalTimeout: u32::MAX,
+            ReadTotalTimeoutMultiplier: u32::MAX,
+            ReadTotalTimeoutConstant: timeout.as_millis().clamp(1, u32::MAX as u128 - 1) as u32,
+            WriteTotalTimeoutMultiplier: 0,
+            WriteTotalTimeoutConstant: 0,
+        };
+
+        // SAFETY: the handle is valid for as long as `port` is.
+        if unsafe { SetCommTimeouts(port.as_raw_handle() as _, &mut timeouts) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        port.read(buf)
+    }
+}
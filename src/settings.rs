@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for reading and changing probe settings through the firmware's monitor commands.
+//!
+//! Monitor commands have been renamed and added over time, so each setting records which command
+//! controls it in which firmware version. Settings take effect on the running firmware; whether
+//! they survive a power cycle depends on the firmware.
+
+use crate::error::{Error, ErrorKind};
+use crate::gdb_remote::GdbRemote;
+use crate::version::FirmwareVersion;
+
+/// A probe setting that can be read and changed with a monitor command.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProbeSetting
+{
+    pub name: &'static str,
+    pub description: &'static str,
+    /// The monitor command for this setting, by the firmware version it was introduced in, oldest first.
+    commands: &'static [((u32, u32), &'static str)],
+    /// The values this setting accepts, or `None` if it takes a free-form value.
+    pub values: Option<&'static [&'static str]>,
+}
+
+/// The settings we know about.
+pub const KNOWN_SETTINGS: &[ProbeSetting] = &[
+    ProbeSetting {
+        name: "target-power",
+        description: "power the target from the probe",
+        commands: &[((1, 6), "tpwr")],
+        values: Some(&["enable", "disable"]),
+    },
+    ProbeSetting {
+        name: "connect-reset",
+        description: "hold the target in reset while connecting to it",
+        commands: &[((1, 6), "connect_srst"), ((1, 9), "connect_rst")],
+        values: Some(&["enable", "disable"]),
+    },
+    ProbeSetting {
+        name: "frequency",
+        description: "maximum SWD/JTAG clock frequency, in Hz (k and M suffixes accepted)",
+        commands: &[((1, 8), "frequency")],
+        values: None,
+    },
+];
+
+impl ProbeSetting
+{
+    /// Looks up a known setting by name.
+    pub fn find(name: &str) -> Option<&'static ProbeSetting>
+    {
+        KNOWN_SETTINGS.iter().find(|setting| setting.name == name)
+    }
+
+    /// The firmware version this setting first appeared in, as `(major, minor)`.
+    pub fn since(&self) -> (u32, u32)
+    {
+        self.commands[0].0
+    }
+
+    /// Returns the monitor command for this setting on the given firmware version.
+    ///
+    /// If the version is unknown (e.g. a development build), the newest command is assumed.
+    fn command_for(&self, version: Option<&FirmwareVersion>) -> Result<&'static str, Error>
+    {
+        let version = match version {
+            Some(v) => v.major_minor(),
+            None => return Ok(self.commands.last().unwrap().1),
+        };
+
+        self.commands
+            .iter()
+            .rev()
+            .find(|(since, _command)| *since <= version)
+            .map(|(_since, command)| *command)
+            .ok_or_else(|| {
+                let (major, minor) = self.since();
+                ErrorKind::OperationNotSupported(format!(
+                    "setting {} requires firmware v{}.{} or newer",
+                    self.name,
+                    major,
+                    minor,
+                )).error()
+            })
+    }
+
+    /// Reads the current value of this setting, as reported by the firmware.
+    pub fn get(&self, remote: &mut GdbRemote, version: Option<&FirmwareVersion>) -> Result<String, Error>
+    {
+        let command = self.command_for(version)?;
+        let output = remote.monitor(command)?;
+
+        Ok(output.trim().to_string())
+    }
+
+    /// Changes this setting, returning anything the firmware printed in response.
+    pub fn set(&self, remote: &mut GdbRemote, version: Option<&FirmwareVersion>, value: &str) -> Result<String, Error>
+    {
+        if let Some(values) = self.values {
+            if !values.contains(&value) {
+                return Err(ErrorKind::OperationNotSupported(format!(
+                    "value {:?} for setting {} (expected one of: {})",
+                    value,
+                    self.name,
+                    values.join(", "),
+                )).error());
+            }
+        }
+
+        let command = self.command_for(version)?;
+        let output = remote.monitor(&format!("{} {}", command, value))?;
+
+        Ok(output.trim().to_string())
+    }
+}
@@ -0,0 +1,244 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil shell`, an interactive prompt for working through a session with
+//! one probe without retyping the options that select it.
+//!
+//! Commands typed at the prompt are parsed by the same [`clap`] definitions as the normal command
+//! line, with the current selection (`select`) appended, and run by the same functions.
+
+use clap::ArgMatches;
+use log::debug;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+
+use bmputil::error::{Error, ErrorKind};
+use bmputil::settings::KNOWN_SETTINGS;
+use bmputil::S;
+
+/// Commands handled by the shell itself, rather than passed on to the normal command line.
+const SHELL_COMMANDS: &[&str] = &["select", "deselect", "help", "exit", "quit"];
+
+/// Commands from the normal command line that make sense at the prompt.
+const FORWARDED_COMMANDS: &[&str] = &["info", "list", "port", "which", "flash", "verify", "switch", "reboot", "settings", "power", "monitor"];
+
+
+/// Tab completion for the shell's commands, setting names, and firmware file paths.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ShellHelper
+{
+    filenames: FilenameCompleter,
+}
+
+impl Completer for ShellHelper
+{
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)>
+    {
+        let before_cursor = &line[..pos];
+        let words: Vec<&str> = before_cursor.split_whitespace().collect();
+        let completing_new_word = before_cursor.is_empty() || before_cursor.ends_with(char::is_whitespace);
+        let word_index = if completing_new_word { words.len() } else { words.len() - 1 };
+        let partial = if completing_new_word { "" } else { words[word_index] };
+        let start = pos - partial.len();
+
+        let candidates: Vec<&str> = match (word_index, words.first().copied()) {
+            (0, _) => SHELL_COMMANDS.iter().chain(FORWARDED_COMMANDS).copied().collect(),
+            (_, Some("flash" | "verify")) => return self.filenames.complete(line, pos, ctx),
+            (1, Some("settings")) => vec!["list", "get", "set"],
+            (2, Some("settings")) => KNOWN_SETTINGS.iter().map(|setting| setting.name).collect(),
+            (1, Some("power")) => vec!["on", "off"],
+            (_, Some("switch")) => vec!["--to"],
+            _ => Vec::new(),
+        };
+
+        let matches = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(partial))
+            .map(|candidate| Pair {
+                display: candidate.to_string(),
+                replacement: format!("{} ", candidate),
+            })
+            .collect();
+
+        Ok((start, matches))
+    }
+}
+
+
+/// Splits a line into words, honoring double quotes (e.g. around paths with spaces).
+fn split_words(line: &str) -> Result<Vec<String>, Error>
+{
+    let mut words = Vec::new();
+    let mut current: Option<String> = None;
+    let mut in_quotes = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.get_or_insert_with(String::new);
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if let Some(word) = current.take() {
+                    words.push(word);
+                }
+            },
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err(ErrorKind::OperationNotSupported(S!("unterminated quote in shell command")).error());
+    }
+    words.extend(current);
+
+    Ok(words)
+}
+
+fn print_help()
+{
+    println!("Commands:");
+    println!("  select <serial>         select the probe with this serial number for the following commands");
+    println!("  select --index <n> | --serial <serial> | --port <port>");
+    println!("                          select a probe the same way as on the command line");
+    println!("  deselect                forget the selected probe");
+    for command in FORWARDED_COMMANDS {
+        println!("  {:<23} as `bmputil {}` (see `{} --help`)", command, command, command);
+    }
+    println!("  help                    show this help");
+    println!("  exit, quit              leave the shell");
+}
+
+
+/// The state of an interactive session.
+struct Shell
+{
+    /// Command line options selecting a probe, appended to every forwarded command.
+    selection: Vec<String>,
+}
+
+impl Shell
+{
+    /// Parses `words` as a normal bmputil command line, with the current selection appended.
+    fn parse(&self, words: &[String]) -> Result<ArgMatches, clap::Error>
+    {
+        let args = std::iter::once("bmputil")
+            .chain(words.iter().map(String::as_str))
+            .chain(self.selection.iter().map(String::as_str));
+
+        crate::cli().try_get_matches_from(args)
+    }
+
+    fn select(&mut self, args: &[String]) -> Result<(), Error>
+    {
+        let selection = match args {
+            [] => {
+                if self.selection.is_empty() {
+                    println!("No probe selected.");
+                } else {
+                    println!("Selected: {}", self.selection.join(" "));
+                }
+                return Ok(());
+            },
+            [serial] if !serial.starts_with('-') => vec![S!("--serial"), serial.clone()],
+            flags => flags.to_vec(),
+        };
+
+        let words: Vec<String> = std::iter::once(S!("list")).chain(selection.iter().cloned()).collect();
+        let matches = match crate::cli().try_get_matches_from(std::iter::once(S!("bmputil")).chain(words)) {
+            Ok(matches) => matches,
+            Err(e) => {
+                let _ = e.print();
+                return Ok(());
+            },
+        };
+        let (_subcommand, subcommand_matches) = matches.subcommand().unwrap();
+
+        let mut results = crate::matcher_from_cli_args(subcommand_matches).find_matching_probes();
+        let dev = results.pop_single("select")?;
+        println!("Selected: {}", dev);
+
+        self.selection = selection;
+
+        Ok(())
+    }
+
+    /// Runs one line typed at the prompt, returning `false` if the shell should exit.
+    fn run_line(&mut self, line: &str) -> Result<bool, Error>
+    {
+        let words = split_words(line)?;
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (command.as_str(), args),
+            None => return Ok(true),
+        };
+
+        match command {
+            "exit" | "quit" => return Ok(false),
+            "help" => print_help(),
+            "select" => self.select(args)?,
+            "deselect" => self.selection.clear(),
+            command if FORWARDED_COMMANDS.contains(&command) => {
+                let matches = match self.parse(&words) {
+                    Ok(matches) => matches,
+                    // This also covers `--help`, which clap reports as an "error".
+                    Err(e) => {
+                        let _ = e.print();
+                        return Ok(true);
+                    },
+                };
+                let (subcommand, subcommand_matches) = matches.subcommand().unwrap();
+                crate::run_command(subcommand, subcommand_matches)?;
+            },
+            other => println!("Unknown command {:?} (try `help`)", other),
+        };
+
+        Ok(true)
+    }
+}
+
+
+/// Runs the interactive shell until the user exits it.
+pub fn run(_matches: &ArgMatches) -> Result<(), Error>
+{
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()
+        .map_err(|e| ErrorKind::OperationNotSupported(format!("interactive shell on this terminal ({})", e)).error())?;
+    editor.set_helper(Some(ShellHelper {
+        filenames: FilenameCompleter::new(),
+    }));
+
+    let mut shell = Shell {
+        selection: Vec::new(),
+    };
+
+    println!("Black Magic Probe Firmware Manager shell. Type `help` for a list of commands.");
+    loop {
+        let prompt = match shell.selection.as_slice() {
+            [] => S!("bmputil> "),
+            selection => format!("bmputil [{}]> ", selection.join(" ")),
+        };
+
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            // Ctrl-C abandons the current line, like in other shells.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                debug!("Error reading from terminal: {}", e);
+                break;
+            },
+        };
+        let _ = editor.add_history_entry(line.as_str());
+
+        match shell.run_line(&line) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => crate::print_error(&e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for checking [minisign](https://jedisct1.github.io/minisign/) signatures of firmware
+//! images before they are flashed.
+//!
+//! An image's signature is expected next to it, with `.minisig` appended to its name (as
+//! `minisign -S` makes it), and must be from one of the keys in a [`SignaturePolicy`]. The
+//! signature covers the file as it is on disk, so it is checked before an ELF file's binary is
+//! extracted from it.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use minisign_verify::{PublicKey, Signature};
+
+use crate::error::{Error, ErrorKind};
+
+/// Extension added to an image's file name to get its signature's.
+pub const SIGNATURE_EXTENSION: &str = "minisig";
+
+
+/// Returns where the signature for the image at `image` is expected.
+pub fn signature_path(image: &Path) -> PathBuf
+{
+    let mut path = OsString::from(image.as_os_str());
+    path.push(".");
+    path.push(SIGNATURE_EXTENSION);
+
+    PathBuf::from(path)
+}
+
+/// Which keys firmware images may be signed with, and whether they must be signed at all.
+#[derive(Debug, Default)]
+pub struct SignaturePolicy
+{
+    keys: Vec<PublicKey>,
+    require_signed: bool,
+}
+
+impl SignaturePolicy
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Trusts `key`, given either as the base64 public key `minisign -G` prints (`RW...`), or
+    /// as the path to a `minisign.pub` file.
+    pub fn trust(&mut self, key: &str) -> Result<(), Error>
+    {
+        let public_key = PublicKey::from_base64(key.trim())
+            .or_else(|_| PublicKey::from_file(key))
+            .map_err(|e| {
+                ErrorKind::FirmwareSignature(format!("{:?} is neither a minisign public key nor a file holding one: {}", key, e))
+                    .error()
+            })?;
+        self.keys.push(public_key);
+
+        Ok(())
+    }
+
+    /// Makes images without a signature an error, rather than flashing them anyway.
+    #[must_use]
+    pub fn require_signed(mut self, require: bool) -> Self
+    {
+        self.require_signed = require;
+        self
+    }
+
+    pub fn get_require_signed(&self) -> bool
+    {
+        self.require_signed
+    }
+
+    /// Checks the signature of `data`, the contents of the image file at `image`.
+    ///
+    /// An image with a signature must have a valid one from a trusted key. An image without one
+    /// is only an error if signatures are required; then, so is not trusting any keys.
+    pub fn check(&self, image: &Path, data: &[u8]) -> Result<(), Error>
+    {
+        if self.keys.is_empty() {
+            if self.require_signed {
+                return Err(ErrorKind::FirmwareSignature(
+                    String::from("signed images are required, but no trusted keys are configured")
+                ).error());
+            }
+            debug!("No trusted keys configured; not checking the signature of {}", image.display());
+            return Ok(());
+        }
+
+        let sig_path = signature_path(image);
+        let signature = match fs::read_to_string(&sig_path) {
+            Ok(contents) => Signature::decode(&contents).map_err(|e| {
+                ErrorKind::FirmwareSignature(format!("{} is not a minisign signature: {}", sig_path.display(), e))
+                    .error()
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.require_signed => {
+                return Err(ErrorKind::FirmwareSignature(
+                    format!("{} is unsigned (no {} found), and signed images are required", image.display(), sig_path.display())
+                ).error());
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("{} is unsigned (no {} found)", image.display(), sig_path.display());
+                return Ok(());
+            },
+            Err(e) => {
+                return Err(ErrorKind::FirmwareSignature(format!("could not read {}", sig_path.display())).error_from(e));
+            },
+        };
+
+        // Keys whose ID doesn't match the signature's are skipped, so the error reported is about
+        // the key that actually signed the image, if we trust it.
+        let mut res = Err(minisign_verify::Error::UnexpectedKeyId);
+        for key in &self.keys {
+            res = key.verify(data, &signature, false);
+            if !matches!(res, Err(minisign_verify::Error::UnexpectedKeyId)) {
+                break;
+            }
+        }
+
+        match res {
+            Ok(()) => {
+                info!("{} has a valid signature ({})", image.display(), signature.trusted_comment());
+                Ok(())
+            },
+            Err(minisign_verify::Error::UnexpectedKeyId) => Err(ErrorKind::FirmwareSignature(
+                format!("{} is not signed with a trusted key", image.display())
+            ).error()),
+            Err(e) => Err(ErrorKind::FirmwareSignature(
+                format!("the signature of {} does not match it: {}", image.display(), e)
+            ).error()),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for persisting a short-lived snapshot of USB enumeration results between invocations.
+//!
+//! Reading the serial number string descriptor of every connected probe is by far the slowest part
+//! of finding a device, especially on slow hubs, and consecutive commands (e.g. `info` then `flash`)
+//! end up doing it all over again. To avoid that, we remember which serial number we saw at which
+//! bus, device address, and port path, in a small [state file](crate::paths::state_file).
+//!
+//! Entries are only trusted if the bus, address *and* port path all still match, as the OS assigns a
+//! new address every time a device (re-)enumerates, and only for a short while after they were
+//! recorded, as addresses do eventually get reused.
+
+use std::fmt::Write;
+use std::fs;
+use std::time::Duration;
+
+use log::{trace, debug};
+
+use crate::paths;
+
+/// How long an entry in the snapshot is considered valid for.
+const SNAPSHOT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Name of the snapshot's [state file](paths::state_file).
+const SNAPSHOT_FILE: &str = "enumeration";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SnapshotEntry
+{
+    bus: u8,
+    address: u8,
+    port: String,
+    serial: String,
+    /// Seconds since the Unix epoch at which this entry was recorded.
+    recorded: u64,
+}
+
+impl SnapshotEntry
+{
+    fn parse(line: &str) -> Option<Self>
+    {
+        let mut fields = line.splitn(5, '\t');
+
+        Some(Self {
+            bus: fields.next()?.parse().ok()?,
+            address: fields.next()?.parse().ok()?,
+            port: fields.next()?.to_string(),
+            recorded: fields.next()?.parse().ok()?,
+            serial: fields.next()?.to_string(),
+        })
+    }
+}
+
+
+/// A snapshot of which serial numbers were seen at which USB locations.
+#[derive(Debug, Clone, Default)]
+pub struct EnumerationSnapshot
+{
+    entries: Vec<SnapshotEntry>,
+    dirty: bool,
+}
+
+impl EnumerationSnapshot
+{
+    /// Loads the snapshot left by previous invocations, dropping any expired entries.
+    ///
+    /// A missing or unreadable snapshot simply results in an empty one.
+    pub fn load() -> Self
+    {
+        let Some(path) = paths::state_file(SNAPSHOT_FILE) else {
+            return Default::default();
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) => {
+                trace!("No enumeration snapshot loaded from {}: {}", path.display(), e);
+                return Default::default();
+            },
+        };
+
+        let oldest = paths::state_timestamp().saturating_sub(SNAPSHOT_TTL.as_secs());
+        let entries = contents
+            .lines()
+            .filter_map(SnapshotEntry::parse)
+            .filter(|entry| entry.recorded >= oldest)
+            .collect();
+
+        Self {
+            entries,
+            dirty: false,
+        }
+    }
+
+    /// Returns the serial number last seen for the device at this bus, address, and port path,
+    /// if there is a still-valid entry for it.
+    pub fn serial_for(&self, bus: u8, address: u8, port: &str) -> Option<&str>
+    {
+        self.entries
+            .iter()
+            .find(|entry| entry.bus == bus && entry.address == address && entry.port == port)
+            .map(|entry| entry.serial.as_str())
+    }
+
+    /// Records the serial number of the device at this bus, address, and port path, replacing
+    /// anything previously recorded for that location.
+    pub fn record(&mut self, bus: u8, address: u8, port: &str, serial: &str)
+    {
+        // Tabs and newlines would corrupt the file, and no sane serial number contains them anyway.
+        if serial.contains(['\t', '\n', '\r']) || port.contains(['\t', '\n', '\r']) {
+            return;
+        }
+
+        self.entries.retain(|entry| !(entry.bus == bus && entry.port == port));
+        self.entries.push(SnapshotEntry {
+            bus,
+            address,
+            port: port.to_string(),
+            serial: serial.to_string(),
+            recorded: paths::state_timestamp(),
+        });
+        self.dirty = true;
+    }
+
+    /// Writes the snapshot back out, if anything was recorded since it was loaded.
+    ///
+    /// Failing to save the snapshot only costs us speed next time, so errors are merely logged.
+    pub fn save(&self)
+    {
+        if !self.dirty {
+            return;
+        }
+
+        let Some(path) = paths::state_file(SNAPSHOT_FILE) else {
+            return;
+        };
+
+        let mut contents = String::new();
+        for entry in &self.entries {
+            let _ = writeln!(
+                contents,
+                "{}\t{}\t{}\t{}\t{}",
+                entry.bus,
+                entry.address,
+                entry.port,
+                entry.recorded,
+                entry.serial,
+            );
+        }
+
+        if let Err(e) = paths::write_state_file(&path, contents.as_bytes()) {
+            debug!("Failed to save enumeration snapshot to {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for reading firmware from Motorola S-record files (`objcopy -O srec`).
+//!
+//! A probe's flash is written as a single run of bytes, so the data records must cover one
+//! contiguous range of addresses; gaps and overlaps are errors, rather than being filled or
+//! silently resolved.
+
+use crate::error::{Error, ErrorKind};
+use crate::S;
+
+/// The firmware image in an S-record file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrecImage
+{
+    /// The address of the first byte of `data`.
+    pub address: u32,
+    /// The contents of the data records, in address order.
+    pub data: Vec<u8>,
+    /// The start address given by the termination record, if it gives one.
+    pub entry: Option<u32>,
+}
+
+/// A single decoded line of an S-record file.
+struct Record
+{
+    kind: u8,
+    address: u32,
+    data: Vec<u8>,
+}
+
+fn invalid(line: usize, why: impl AsRef<str>) -> Error
+{
+    ErrorKind::InvalidFirmware(Some(format!("S-record line {}: {}", line, why.as_ref()))).error()
+}
+
+/// How many bytes of address records of `kind` have, or `None` if it isn't a known record type.
+fn address_len(kind: u8) -> Option<usize>
+{
+    match kind {
+        0 | 1 | 5 | 9 => Some(2),
+        2 | 6 | 8 => Some(3),
+        3 | 7 => Some(4),
+        _ => None,
+    }
+}
+
+fn parse_record(number: usize, line: &str) -> Result<Record, Error>
+{
+    let Some(rest) = line.strip_prefix('S') else {
+        return Err(invalid(number, "doesn't start with 'S'"));
+    };
+    let mut chars = rest.chars();
+    let kind = chars.next()
+        .and_then(|kind| kind.to_digit(10))
+        .ok_or_else(|| invalid(number, "missing record type"))? as u8;
+    let address_len = address_len(kind)
+        .ok_or_else(|| invalid(number, format!("unknown record type S{}", kind)))?;
+
+    let hex = chars.as_str();
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(invalid(number, "not made of hex byte pairs"));
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|e| invalid(number, format!("invalid hex: {}", e)))?;
+
+    // The count covers the address, data, and checksum.
+    let (&count, counted) = bytes.split_first()
+        .ok_or_else(|| invalid(number, "missing byte count"))?;
+    if counted.len() != count as usize {
+        return Err(invalid(number, format!("byte count is {}, but the record has {} bytes", count, counted.len())));
+    }
+    if counted.len() < address_len + 1 {
+        return Err(invalid(number, "record is too short"));
+    }
+
+    let (body, &[checksum]) = counted.split_at(counted.len() - 1) else {
+        unreachable!("The record was checked to have a checksum");
+    };
+    let sum = bytes[..bytes.len() - 1].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    if !sum != checksum {
+        return Err(invalid(number, format!("checksum is 0x{:02x}, but should be 0x{:02x}", checksum, !sum)));
+    }
+
+    let (address, data) = body.split_at(address_len);
+    let address = address.iter().fold(0u32, |address, &byte| (address << 8) | byte as u32);
+
+    Ok(Record { kind, address, data: data.to_vec() })
+}
+
+/// Parses the contents of an S-record file into the image it describes.
+///
+/// ```
+/// # use bmputil::srec;
+/// let image = srec::parse(b"S00600004844521B\nS1070000DEADBEEFC0\nS10500040102F3\nS5030002FA\nS9030000FC\n").unwrap();
+/// assert_eq!(image.address, 0);
+/// assert_eq!(image.data, [0xde, 0xad, 0xbe, 0xef, 0x01, 0x02]);
+/// assert_eq!(image.entry, Some(0));
+/// ```
+pub fn parse(srec_data: &[u8]) -> Result<SrecImage, Error>
+{
+    let text = std::str::from_utf8(srec_data)
+        .map_err(|e| ErrorKind::InvalidFirmware(Some(format!("S-record file is not text: {}", e))).error())?;
+
+    let mut records = Vec::new();
+    let mut entry = None;
+    let mut terminated = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = parse_record(number, line)?;
+        if terminated {
+            return Err(invalid(number, "record after the termination record"));
+        }
+        match record.kind {
+            // Header; usually the file's name, which we have no use for.
+            0 => (),
+            1..=3 => records.push((number, record)),
+            // Record counts, which catch truncated files.
+            5 | 6 => {
+                let expected = record.address as usize;
+                let mask = if record.kind == 5 { 0xffff } else { 0xff_ffff };
+                if records.len() & mask != expected {
+                    return Err(invalid(number, format!("file says it has {} data records, but has {}", expected, records.len())));
+                }
+            },
+            7..=9 => {
+                entry = Some(record.address);
+                terminated = true;
+            },
+            _ => unreachable!("Unknown record types are rejected when parsing"),
+        }
+    }
+
+    // Records needn't be in order, but together they must make up a single run of bytes.
+    records.sort_by_key(|(_, record)| record.address);
+    let Some(((_, first), rest)) = records.split_first() else {
+        return Err(ErrorKind::InvalidFirmware(Some(S!("S-record file has no data records"))).error());
+    };
+
+    let address = first.address;
+    let mut data = first.data.clone();
+    for (number, record) in rest {
+        let end = address as u64 + data.len() as u64;
+        if (record.address as u64) < end {
+            return Err(invalid(*number, format!("data at 0x{:08x} overlaps data before it, which runs to 0x{:08x}", record.address, end)));
+        }
+        if record.address as u64 > end {
+            return Err(invalid(*number, format!(
+                "gap between 0x{:08x} and 0x{:08x}; the image must be a single contiguous block",
+                end,
+                record.address,
+            )));
+        }
+        data.extend_from_slice(&record.data);
+    }
+
+    if address as u64 + data.len() as u64 > 1 << 32 {
+        return Err(ErrorKind::InvalidFirmware(Some(S!("S-record data runs past the end of the address space"))).error());
+    }
+
+    Ok(SrecImage { address, data, entry })
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// Builds an S-record line of `kind`, with the count and checksum worked out.
+    fn record(kind: u8, address: u32, data: &[u8]) -> String
+    {
+        let address_len = address_len(kind).unwrap();
+        let mut bytes = vec![(address_len + data.len() + 1) as u8];
+        bytes.extend_from_slice(&address.to_be_bytes()[4 - address_len..]);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes.push(!sum);
+
+        let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("S{}{}", kind, hex)
+    }
+
+    fn parse_lines(lines: &[String]) -> Result<SrecImage, Error>
+    {
+        parse(lines.join("\n").as_bytes())
+    }
+
+    fn error_message(res: Result<SrecImage, Error>) -> String
+    {
+        res.expect_err("should be rejected").to_string()
+    }
+
+    #[test]
+    fn parses_each_address_size()
+    {
+        for (data_kind, end_kind, address) in [(1, 9, 0x2000), (2, 8, 0x08_2000), (3, 7, 0x0800_2000)] {
+            let image = parse_lines(&[
+                record(0, 0, b"bmp"),
+                record(data_kind, address, &[1, 2, 3, 4]),
+                record(data_kind, address + 4, &[5, 6]),
+                record(end_kind, address + 1, &[]),
+            ]).unwrap();
+
+            assert_eq!(image.address, address, "S{} records", data_kind);
+            assert_eq!(image.data, [1, 2, 3, 4, 5, 6], "S{} records", data_kind);
+            assert_eq!(image.entry, Some(address + 1), "S{} termination", end_kind);
+        }
+    }
+
+    #[test]
+    fn records_may_be_out_of_order()
+    {
+        let image = parse_lines(&[
+            record(3, 0x0800_2004, &[5, 6]),
+            record(3, 0x0800_2000, &[1, 2, 3, 4]),
+        ]).unwrap();
+
+        assert_eq!(image.address, 0x0800_2000);
+        assert_eq!(image.data, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(image.entry, None);
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch()
+    {
+        let mut line = record(1, 0, &[0xde, 0xad]);
+        line.replace_range(line.len() - 2.., "00");
+
+        let message = error_message(parse_lines(&[line]));
+        assert!(message.contains("line 1: checksum is 0x00"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_non_contiguous_records()
+    {
+        // The image is written as one block, so gaps aren't filled, but are errors.
+        let message = error_message(parse_lines(&[
+            record(3, 0x0800_2000, &[1, 2, 3, 4]),
+            record(3, 0x0800_2008, &[5, 6]),
+        ]));
+        assert!(message.contains("line 2: gap between 0x08002004 and 0x08002008"), "{}", message);
+
+        let message = error_message(parse_lines(&[
+            record(3, 0x0800_2000, &[1, 2, 3, 4]),
+            record(3, 0x0800_2002, &[5, 6]),
+        ]));
+        assert!(message.contains("line 2: data at 0x08002002 overlaps"), "{}", message);
+    }
+
+    #[test]
+    fn checks_record_counts()
+    {
+        let data = [record(1, 0, &[1, 2]), record(1, 2, &[3, 4])];
+
+        let counted = [&data[..], &[record(5, 2, &[]), record(9, 0, &[])]].concat();
+        assert_eq!(parse_lines(&counted).unwrap().data, [1, 2, 3, 4]);
+
+        let miscounted = [&data[..], &[record(5, 3, &[])]].concat();
+        let message = error_message(parse_lines(&miscounted));
+        assert!(message.contains("says it has 3 data records, but has 2"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_records_after_termination()
+    {
+        let message = error_message(parse_lines(&[
+            record(1, 0, &[1, 2]),
+            record(9, 0, &[]),
+            record(1, 2, &[3, 4]),
+        ]));
+        assert!(message.contains("line 3: record after the termination record"), "{}", message);
+    }
+
+    #[test]
+    fn rejects_malformed_records()
+    {
+        let cases = [
+            (S!("X1030000FC"), "doesn't start with 'S'"),
+            (S!("S4030000FC"), "unknown record type S4"),
+            (S!("S1030000F"), "hex byte pairs"),
+            (S!("S1040000FC"), "byte count is 4"),
+            (S!("S10200FD"), "too short"),
+            (record(9, 0, &[]), "no data records"),
+        ];
+
+        for (line, expected) in cases {
+            let message = error_message(parse(line.as_bytes()));
+            assert!(message.contains(expected), "{:?}: {}", line, message);
+        }
+    }
+}
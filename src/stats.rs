@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for opt-in, local-only usage statistics.
+//!
+//! Nothing is recorded unless the user runs `bmputil stats enable`, and nothing ever leaves the
+//! machine on its own: the statistics are kept in a file in the user's data directory, and
+//! `bmputil stats export` prints them as JSON for the user to share (or not) as they see fit.
+//!
+//! Only counts are kept: which operations were run, how long they took, which kinds of errors they
+//! failed with, and which DFU transfer sizes they used. No serial numbers, file names, paths, or error details are recorded.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+
+use log::debug;
+use serde::{Serialize, Deserialize};
+
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::paths::data_dir;
+
+/// Version of the statistics file format, bumped whenever it changes incompatibly.
+const FORMAT_VERSION: u32 = 1;
+
+/// The DFU transfer size used by the operation in progress, or 0 if it hasn't used DFU.
+static TRANSFER_SIZE: AtomicU16 = AtomicU16::new(0);
+
+fn stats_path() -> Result<PathBuf, Error>
+{
+    data_dir()
+        .map(|dir| dir.join("stats.json"))
+        .ok_or_else(|| {
+            ErrorKind::External(ErrorSource::StdIo(io::Error::new(
+                io::ErrorKind::NotFound,
+                "could not determine the user data directory",
+            ))).error()
+        })
+}
+
+fn io_error(e: io::Error, path: &Path) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(e)).error()
+        .with_ctx(&format!("accessing usage statistics at {}", path.display()))
+}
+
+
+/// Aggregated statistics for a single operation (subcommand).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationStats
+{
+    /// How many times the operation was run.
+    pub runs: u64,
+    /// How many runs failed, by [`ErrorKind::name`].
+    pub failures: BTreeMap<String, u64>,
+    /// Total time spent in the operation, in milliseconds.
+    pub total_duration_ms: u64,
+    /// Longest single run of the operation, in milliseconds.
+    pub max_duration_ms: u64,
+    /// How many runs used each DFU transfer size, in bytes.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub transfer_sizes: BTreeMap<u16, u64>,
+}
+
+/// Usage statistics, as stored on disk and exported.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageStats
+{
+    pub format_version: u32,
+    pub bmputil_version: String,
+    pub operations: BTreeMap<String, OperationStats>,
+}
+
+impl Default for UsageStats
+{
+    fn default() -> Self
+    {
+        Self {
+            format_version: FORMAT_VERSION,
+            bmputil_version: env!("CARGO_PKG_VERSION").to_string(),
+            operations: BTreeMap::new(),
+        }
+    }
+}
+
+impl UsageStats
+{
+    /// Whether the user has opted in to collecting statistics.
+    pub fn enabled() -> bool
+    {
+        stats_path().map(|path| path.is_file()).unwrap_or(false)
+    }
+
+    /// Opts in to collecting statistics, keeping anything already collected.
+    pub fn enable() -> Result<(), Error>
+    {
+        if Self::enabled() {
+            return Ok(());
+        }
+
+        Self::default().save()
+    }
+
+    /// Opts out of collecting statistics, deleting anything already collected.
+    pub fn disable() -> Result<(), Error>
+    {
+        let path = stats_path()?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e, &path)),
+        }
+    }
+
+    /// Loads the collected statistics, or returns `None` if the user hasn't opted in.
+    pub fn load() -> Result<Option<Self>, Error>
+    {
+        let path = stats_path()?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(e, &path)),
+        };
+
+        match serde_json::from_str::<Self>(&contents) {
+            Ok(stats) if stats.format_version == FORMAT_VERSION => Ok(Some(stats)),
+            // An unreadable or outdated file still means the user opted in; start over.
+            Ok(_) | Err(_) => {
+                debug!("Discarding unreadable usage statistics in {}", path.display());
+                Ok(Some(Self::default()))
+            },
+        }
+    }
+
+    fn save(&self) -> Result<(), Error>
+    {
+        let path = stats_path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| io_error(e, &path))?;
+        }
+
+        let contents = serde_json::to_string_pretty(self)
+            .expect("serializing usage statistics cannot fail");
+
+        // Rename into place so concurrent invocations never see a partially-written file.
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents)
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .map_err(|e| io_error(e, &path))
+    }
+
+    /// Exports the collected statistics as JSON, suitable for sharing.
+    pub fn export(&self) -> String
+    {
+        serde_json::to_string_pretty(self).expect("serializing usage statistics cannot fail")
+    }
+
+    /// Notes the DFU transfer size negotiated by the operation in progress, to be counted when it
+    /// is [recorded](Self::record).
+    pub fn note_transfer_size(size: u16)
+    {
+        TRANSFER_SIZE.store(size, Ordering::Relaxed);
+    }
+
+    /// Records a run of `operation`, if the user has opted in.
+    ///
+    /// Statistics are never worth failing an operation over, so errors are merely logged.
+    pub fn record(operation: &str, duration: Duration, result: Result<(), &ErrorKind>)
+    {
+        let transfer_size = TRANSFER_SIZE.swap(0, Ordering::Relaxed);
+        let mut stats = match Self::load() {
+            Ok(Some(stats)) => stats,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Failed to load usage statistics: {}", e);
+                return;
+            },
+        };
+
+        let op = stats.operations.entry(operation.to_string()).or_default();
+        let duration_ms = duration.as_millis().min(u64::MAX as u128) as u64;
+        op.runs += 1;
+        op.total_duration_ms = op.total_duration_ms.saturating_add(duration_ms);
+        op.max_duration_ms = op.max_duration_ms.max(duration_ms);
+        if let Err(kind) = result {
+            *op.failures.entry(kind.name().to_string()).or_default() += 1;
+        }
+        if transfer_size != 0 {
+            *op.transfer_sizes.entry(transfer_size).or_default() += 1;
+        }
+
+        stats.bmputil_version = env!("CARGO_PKG_VERSION").to_string();
+        if let Err(e) = stats.save() {
+            debug!("Failed to save usage statistics: {}", e);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil terminal`, a minimal serial terminal on a probe's UART passthrough.
+//!
+//! The terminal is put in raw mode, so every key (including Ctrl-C) goes to the target, except for
+//! [`EXIT_KEY`]. Output from the target is copied to stdout (and the log file, if any) as it
+//! arrives, on a separate thread.
+//!
+//! The baud rate, line ending, and log file are remembered for each probe, by serial number, and
+//! used the next time the same probe is opened, unless given again or `--reset-settings` is passed.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use log::debug;
+
+use bmputil::config::{LineEnding, TerminalSettings};
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::serial_port::{self, raw, ProbePort};
+use bmputil::usb::DfuOperatingMode;
+use bmputil::S;
+
+/// Ctrl-], which leaves the terminal, as in telnet.
+const EXIT_KEY: u8 = 0x1d;
+
+/// How often the thread copying the target's output checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Baud rate used when none is given or remembered.
+const DEFAULT_BAUD: u32 = 115200;
+
+fn io_error(e: io::Error, ctx: &str) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(e)).error().with_ctx(ctx)
+}
+
+
+/// Puts the controlling terminal in raw mode, restoring it when dropped.
+#[cfg(unix)]
+struct RawMode
+{
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode
+{
+    fn enable() -> io::Result<Self>
+    {
+        // SAFETY: termios is plain data, and stdin stays open for the life of the process.
+        unsafe {
+            let mut original: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            // Keep output processing, so a bare `\n` from the target still starts a new line.
+            raw.c_oflag = original.c_oflag;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self { original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode
+{
+    fn drop(&mut self)
+    {
+        // SAFETY: as in `enable()`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+/// Puts the console in raw mode, restoring it when dropped.
+#[cfg(windows)]
+struct RawMode
+{
+    original: u32,
+}
+
+#[cfg(windows)]
+impl RawMode
+{
+    fn enable() -> io::Result<Self>
+    {
+        use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+        use winapi::um::processenv::GetStdHandle;
+        use winapi::um::winbase::STD_INPUT_HANDLE;
+        use winapi::um::wincon::{ENABLE_ECHO_INPUT, ENABLE_LINE_INPUT, ENABLE_PROCESSED_INPUT, ENABLE_VIRTUAL_TERMINAL_INPUT};
+
+        // SAFETY: the standard input handle stays valid for the life of the process.
+        unsafe {
+            let stdin = GetStdHandle(STD_INPUT_HANDLE);
+            let mut original = 0;
+            if GetConsoleMode(stdin, &mut original) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let raw = (original & !(ENABLE_ECHO_INPUT | ENABLE_LINE_INPUT | ENABLE_PROCESSED_INPUT))
+                | ENABLE_VIRTUAL_TERMINAL_INPUT;
+            if SetConsoleMode(stdin, raw) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self { original })
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for RawMode
+{
+    fn drop(&mut self)
+    {
+        use winapi::um::consoleapi::SetConsoleMode;
+        use winapi::um::processenv::GetStdHandle;
+        use winapi::um::winbase::STD_INPUT_HANDLE;
+
+        // SAFETY: as in `enable()`.
+        unsafe {
+            SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), self.original);
+        }
+    }
+}
+
+
+/// Copies everything the target sends to stdout, and `log` if given, until `stop` is set.
+fn copy_output(mut port: File, mut log: Option<File>, stop: Arc<AtomicBool>)
+{
+    let mut buf = [0u8; 1024];
+    let mut stdout = io::stdout();
+    while !stop.load(Ordering::Relaxed) {
+        match raw::read_timeout(&mut port, &mut buf, POLL_INTERVAL) {
+            Ok(0) => (),
+            Ok(read) => {
+                let _ = stdout.write_all(&buf[..read]);
+                let _ = stdout.flush();
+                if let Some(file) = &mut log {
+                    if let Err(e) = file.write_all(&buf[..read]) {
+                        debug!("Error writing to the log file, no longer logging: {}", e);
+                        log = None;
+                    }
+                }
+            },
+            Err(e) => {
+                debug!("Error reading from UART: {}", e);
+                stop.store(true, Ordering::Relaxed);
+            },
+        }
+    }
+}
+
+/// Implements `bmputil terminal`.
+pub fn terminal_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let echo = matches.is_present("echo");
+    // Validated by clap.
+    let given = TerminalSettings {
+        baud: matches.value_of("baud").map(|baud| baud.parse().unwrap()),
+        line_ending: matches.value_of("line-ending").map(|name| LineEnding::from_name(name).unwrap()),
+        // Made absolute, as it's remembered for runs from other directories.
+        log: matches.value_of_os("log")
+            .map(|log| std::path::absolute(log).map_err(|e| io_error(e, "finding the log file")))
+            .transpose()?,
+    };
+
+    let mut results = crate::find_probes(&crate::matcher_from_cli_args(matches), matches)?;
+    let dev = results.pop_single("terminal")?;
+    if dev.operating_mode() != DfuOperatingMode::Runtime {
+        return Err(ErrorKind::OperationNotSupported(S!("opening the UART while in DFU mode")).error());
+    }
+    let serial = dev.serial_number()?.to_string();
+    let path = serial_port::find_serial_port(&serial, ProbePort::Uart)?;
+    drop(dev);
+
+    let saved = if matches.is_present("reset-settings") {
+        if TerminalSettings::clear(&serial)? {
+            eprintln!("Forgot the terminal settings saved for this probe.");
+        }
+        TerminalSettings::default()
+    } else {
+        TerminalSettings::load(&serial)?
+    };
+    let settings = saved.merge(&given);
+    if !saved.is_empty() && settings != given {
+        eprintln!("Using the terminal settings saved for this probe (--reset-settings to forget them).");
+    }
+    if !given.is_empty() && settings != saved {
+        if let Err(e) = settings.save(&serial) {
+            debug!("Failed to save terminal settings: {}", e);
+        }
+    }
+    let baud = settings.baud.unwrap_or(DEFAULT_BAUD);
+    let line_ending = settings.line_ending.unwrap_or_default();
+
+    let log = settings.log
+        .as_ref()
+        .map(|log_path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_path)
+                .map_err(|e| io_error(e, &format!("opening log file {}", log_path.display())))
+        })
+        .transpose()?;
+
+    let mut port = raw::open(&path).map_err(|e| io_error(e, &format!("opening UART serial port {}", path)))?;
+    raw::set_baud_rate(&port, baud).map_err(|e| io_error(e, "setting UART baud rate"))?;
+    let output_port = port.try_clone().map_err(|e| io_error(e, "opening UART serial port"))?;
+
+    eprintln!("Connected to {} at {} baud. Press Ctrl-] to exit.", path, baud);
+    if let Some(log_path) = &settings.log {
+        eprintln!("Logging output to {}.", log_path.display());
+    }
+    let raw_mode = RawMode::enable().map_err(|e| io_error(e, "switching the terminal to raw mode"))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let output_thread = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || copy_output(output_port, log, stop))
+    };
+
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout();
+    let mut buf = [0u8; 256];
+    let res = loop {
+        if stop.load(Ordering::Relaxed) {
+            break Err(ErrorKind::DeviceDisconnectDuringOperation.error().with_ctx("reading from the UART"));
+        }
+
+        let read = match stdin.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(e) => break Err(io_error(e, "reading from the terminal")),
+        };
+        let input = &buf[..read];
+        let (input, exit) = match input.iter().position(|&b| b == EXIT_KEY) {
+            Some(index) => (&input[..index], true),
+            None => (input, false),
+        };
+
+        // Enter sends a carriage return in raw mode; send the probe's line ending instead.
+        let input: Vec<u8> = match line_ending {
+            LineEnding::Cr => input.to_vec(),
+            _ => input
+                .split_inclusive(|&b| b == b'\r')
+                .flat_map(|chunk| match chunk.strip_suffix(b"\r") {
+                    Some(line) => [line, line_ending.bytes()].concat(),
+                    None => chunk.to_vec(),
+                })
+                .collect(),
+        };
+
+        if let Err(e) = port.write_all(&input) {
+            break Err(io_error(e, "writing to the UART"));
+        }
+        if echo {
+            let _ = stdout.write_all(&input);
+            let _ = stdout.flush();
+        }
+        if exit {
+            break Ok(());
+        }
+    };
+
+    stop.store(true, Ordering::Relaxed);
+    let _ = output_thread.join();
+    drop(raw_mode);
+    eprintln!();
+
+    res
+}
@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for the timeouts used when talking to and waiting for probes.
+//!
+//! The defaults suit a probe plugged straight into a reasonably quick machine. Slow hubs and USB
+//! passthrough into VMs can need much longer, both for individual transfers and for a probe to
+//! re-enumerate after it reboots.
+
+use std::time::Duration;
+
+/// How long to wait for USB operations before giving up on them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Timeouts
+{
+    /// Timeout for each individual control transfer, including string descriptor reads.
+    control: Duration,
+    /// How long to wait for a probe to come back after it reboots.
+    enumerate: Duration,
+}
+
+impl Timeouts
+{
+    pub fn new() -> Self
+    {
+        Default::default()
+    }
+
+    /// Set the timeout for individual control transfers. Defaults to 2 seconds.
+    #[must_use]
+    pub fn control(mut self, timeout: Duration) -> Self
+    {
+        self.control = timeout;
+        self
+    }
+
+    /// Get the value previously set with `.control()`.
+    pub fn get_control(&self) -> Duration
+    {
+        self.control
+    }
+
+    /// Set how long to wait for a probe to re-enumerate after it reboots (e.g. into DFU mode or
+    /// after flashing). Defaults to 5 seconds.
+    #[must_use]
+    pub fn enumerate(mut self, timeout: Duration) -> Self
+    {
+        self.enumerate = timeout;
+        self
+    }
+
+    /// Get the value previously set with `.enumerate()`.
+    pub fn get_enumerate(&self) -> Duration
+    {
+        self.enumerate
+    }
+}
+
+impl Default for Timeouts
+{
+    fn default() -> Self
+    {
+        Self {
+            control: Duration::from_secs(2),
+            enumerate: Duration::from_secs(5),
+        }
+    }
+}
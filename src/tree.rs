@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil tree`, which shows the USB topology around Black Magic Probe devices,
+//! in the manner of `lsusb -t`.
+//!
+//! By default, only the hubs leading to a probe and the probes themselves are shown, with the
+//! interfaces of each probe, so it's clear which port path a probe is on and which hubs it goes
+//! through. `--all` shows every device on every bus.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use rusb::UsbContext;
+
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::profiles;
+use bmputil::timeouts::Timeouts;
+use bmputil::usb::{sanitize_descriptor_string, Pid, Vid};
+
+/// bDeviceClass of a hub.
+const CLASS_HUB: u8 = 0x09;
+
+/// A device on the bus, keyed in [`tree_command`] by where it is, so sorting puts it after its hub.
+struct Node
+{
+    device: rusb::Device<rusb::Context>,
+    ids: (Vid, Pid),
+    is_probe: bool,
+}
+
+/// Describes an interface class, for devices that don't name their interfaces.
+fn class_name(class: u8, subclass: u8) -> &'static str
+{
+    match (class, subclass) {
+        (0x02, _) => "CDC communications",
+        (0x0a, _) => "CDC data",
+        (0xfe, 0x01) => "DFU",
+        (0xff, _) => "vendor specific",
+        (CLASS_HUB, _) => "hub",
+        _ => "other",
+    }
+}
+
+/// Reads the product string and interface names of `device`, if it can be opened.
+fn read_strings(device: &rusb::Device<rusb::Context>, timeout: Duration) -> (Option<String>, BTreeMap<u8, String>)
+{
+    let mut names = BTreeMap::new();
+    let Ok(handle) = device.open() else {
+        return (None, names);
+    };
+    let Some(language) = handle.read_languages(timeout).ok().and_then(|languages| languages.first().copied()) else {
+        return (None, names);
+    };
+
+    let product = device.device_descriptor().ok()
+        .and_then(|desc| handle.read_product_string(language, &desc, timeout).ok());
+    if let Ok(config) = device.active_config_descriptor() {
+        for interface in config.interfaces() {
+            if let Some(desc) = interface.descriptors().next() {
+                if let Ok(name) = handle.read_interface_string(language, &desc, timeout) {
+                    names.insert(interface.number(), name);
+                }
+            }
+        }
+    }
+
+    (product.map(|product| sanitize_descriptor_string(&product)), names)
+}
+
+fn print_node(node: &Node, depth: usize, port: Option<u8>, timeout: Duration)
+{
+    let indent = "    ".repeat(depth);
+    let (Vid(vid), Pid(pid)) = node.ids;
+    let is_hub = node.device.device_descriptor().is_ok_and(|desc| desc.class_code() == CLASS_HUB);
+    // Only probes are opened, as opening other devices can disturb them (or need permissions we lack).
+    let (product, interface_names) = if node.is_probe {
+        read_strings(&node.device, timeout)
+    } else {
+        (None, BTreeMap::new())
+    };
+    let product = product
+        .or_else(|| profiles::find(node.ids.0, node.ids.1).map(|(profile, _)| profile.name.clone()))
+        .map(|product| format!(" {}", product))
+        .unwrap_or_default();
+
+    match port {
+        None => println!("{}Bus {:03}: {:04x}:{:04x} root hub", indent, node.device.bus_number(), vid, pid),
+        Some(port) => println!(
+            "{}|__ Port {}: {:04x}:{:04x}{}{}",
+            indent,
+            port,
+            vid,
+            pid,
+            product,
+            if is_hub { " [hub]" } else { "" },
+        ),
+    }
+
+    if !node.is_probe {
+        return;
+    }
+    let Ok(config) = node.device.active_config_descriptor() else {
+        return;
+    };
+    for interface in config.interfaces() {
+        let Some(desc) = interface.descriptors().next() else {
+            continue;
+        };
+        let label = interface_names.get(&interface.number()).map_or_else(
+            || class_name(desc.class_code(), desc.sub_class_code()).to_string(),
+            |name| sanitize_descriptor_string(name),
+        );
+        println!("{}        If {}: {}", indent, interface.number(), label);
+    }
+}
+
+/// Implements `bmputil tree`.
+pub fn tree_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let all = matches.is_present("all");
+    let timeout = Timeouts::default().get_control();
+
+    let context = bmputil::usb::new_context()
+        .map_err(|e| ErrorKind::External(ErrorSource::Libusb(e)).error().with_ctx("initializing libusb"))?;
+    let devices = context.devices()
+        .map_err(|e| ErrorKind::External(ErrorSource::Libusb(e)).error().with_ctx("listing USB devices"))?;
+
+    // Keyed by bus, then the port path, so each hub sorts just before the devices behind it.
+    let mut nodes = BTreeMap::new();
+    for device in devices.iter() {
+        let Ok(desc) = device.device_descriptor() else {
+            continue;
+        };
+        let Ok(ports) = device.port_numbers() else {
+            continue;
+        };
+        let ids = (Vid(desc.vendor_id()), Pid(desc.product_id()));
+        let is_probe = profiles::find(ids.0, ids.1).is_some();
+        nodes.insert((device.bus_number(), ports), Node { device, ids, is_probe });
+    }
+
+    // Unless showing everything, only keep the probes and what leads to them.
+    if !all {
+        let wanted: Vec<(u8, Vec<u8>)> = nodes
+            .iter()
+            .filter(|(_, node)| node.is_probe)
+            .flat_map(|((bus, ports), _)| (0..=ports.len()).map(move |depth| (*bus, ports[..depth].to_vec())))
+            .collect();
+        nodes.retain(|key, _| wanted.contains(key));
+    }
+
+    if nodes.is_empty() {
+        println!("No Black Magic Probe devices found.");
+        return Ok(());
+    }
+
+    for ((_, ports), node) in &nodes {
+        print_node(node, ports.len(), ports.last().copied(), timeout);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for setting up udev rules on Linux, so Black Magic Probes can be used without root.
+//!
+//! Without a rule granting access, libusb can see probes but not open them, failing with
+//! `LIBUSB_ERROR_ACCESS`, which by itself tells a new user very little.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use clap::ArgMatches;
+use log::warn;
+
+use bmputil::bmp::BmpPlatform;
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::usb::{Pid, Vid};
+
+/// Where `install-udev` puts the rules.
+const RULES_PATH: &str = "/etc/udev/rules.d/99-blackmagic-probe.rules";
+
+/// Every VID/PID a Black Magic Probe can show up as, in runtime or DFU mode.
+pub const PROBE_IDS: &[(Vid, Pid)] = &[
+    BmpPlatform::BMD_RUNTIME_VID_PID,
+    BmpPlatform::BMD_DFU_VID_PID,
+    BmpPlatform::DRAGON_BOOT_VID_PID,
+    BmpPlatform::STM32_DFU_VID_PID,
+];
+
+/// Returns the udev rules giving the logged in user access to Black Magic Probes.
+fn rules() -> String
+{
+    let mut rules = String::from(
+        "# Black Magic Probe, in runtime and DFU mode. Installed by bmputil install-udev.\n\
+        # Gives the user logged in at the seat (and the plugdev group) access to the probe.\n",
+    );
+    for (Vid(vid), Pid(pid)) in PROBE_IDS {
+        rules.push_str(&format!(
+            "SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", ATTR{{idProduct}}==\"{:04x}\", \
+            MODE=\"0660\", GROUP=\"plugdev\", TAG+=\"uaccess\"\n",
+            vid,
+            pid,
+        ));
+    }
+
+    rules
+}
+
+/// Whether `e` is libusb failing to open a device for lack of permissions.
+pub fn is_permission_error(e: &Error) -> bool
+{
+    matches!(e.kind, ErrorKind::External(ErrorSource::Libusb(rusb::Error::Access)))
+}
+
+/// Explains a permission error, and how to fix it.
+pub fn print_permission_hint()
+{
+    eprintln!("note: a Black Magic Probe was found, but you do not have permission to access it.");
+    if Path::new(RULES_PATH).exists() {
+        eprintln!(
+            "note: udev rules are installed in {}; unplug and replug the probe, or log out and back in, \
+            for them to take effect.",
+            RULES_PATH,
+        );
+    } else {
+        eprintln!("note: run `sudo bmputil install-udev` to install udev rules granting access, then replug the probe.");
+    }
+}
+
+/// Implements `bmputil install-udev`.
+pub fn install_udev_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let rules = rules();
+    if matches.is_present("print") {
+        print!("{}", rules);
+        return Ok(());
+    }
+
+    fs::write(RULES_PATH, &rules)
+        .map_err(|source| {
+            let hint = if source.kind() == io::ErrorKind::PermissionDenied {
+                "writing udev rules (try again with sudo, or use --print and install them yourself)"
+            } else {
+                "writing udev rules"
+            };
+            ErrorKind::External(ErrorSource::StdIo(source)).error().with_ctx(hint)
+        })?;
+    println!("Wrote udev rules to {}", RULES_PATH);
+
+    // Reload the rules and apply them to probes that are already plugged in.
+    let reloaded = run_udevadm(&["control", "--reload-rules"])
+        && run_udevadm(&["trigger", "--subsystem-match=usb", "--action=change"]);
+    if reloaded {
+        println!("Reloaded udev rules. Replug any connected Black Magic Probes if they are still inaccessible.");
+    } else {
+        println!("Could not reload udev rules; replug any connected Black Magic Probes, or reboot, to apply them.");
+    }
+
+    Ok(())
+}
+
+fn run_udevadm(args: &[&str]) -> bool
+{
+    match Command::new("udevadm").args(args).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            warn!("udevadm {} failed: {}", args.join(" "), status);
+            false
+        },
+        Err(e) => {
+            warn!("Failed to run udevadm: {}", e);
+            false
+        },
+    }
+}
@@ -2,7 +2,77 @@
 // SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
 // SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
 
-use thiserror::Error;
+mod descriptors;
+pub mod diagnostics;
+pub mod dump;
+mod handle;
+pub mod telemetry;
+pub use descriptors::*;
+pub use handle::*;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rusb::UsbContext;
+
+/// Whether [`new_context`] should use the UsbDk backend; see [`set_libusb_backend`].
+static USE_USBDK: AtomicBool = AtomicBool::new(false);
+
+/// The libusb backends available on Windows. Elsewhere, only [`LibusbBackend::Native`] exists.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum LibusbBackend
+{
+    /// libusb's usual backend, which talks to each device through whichever of WinUSB, libusbK, or
+    /// libusb0 Windows has bound to it.
+    #[default]
+    Native,
+    /// The [UsbDk](https://github.com/daynix/UsbDk) filter driver, which needs no driver bound to each
+    /// device, but has to be installed system-wide.
+    UsbDk,
+}
+
+/// Sets the libusb backend the contexts made by [`new_context`] use from now on.
+pub fn set_libusb_backend(backend: LibusbBackend)
+{
+    USE_USBDK.store(backend == LibusbBackend::UsbDk, Ordering::SeqCst);
+}
+
+/// Returns the libusb backend set with [`set_libusb_backend`].
+pub fn libusb_backend() -> LibusbBackend
+{
+    if USE_USBDK.load(Ordering::SeqCst) {
+        LibusbBackend::UsbDk
+    } else {
+        LibusbBackend::Native
+    }
+}
+
+/// Creates a libusb context, logging at debug level if [`diagnostics::capture`] was called, and
+/// using the backend set with [`set_libusb_backend`].
+pub fn new_context() -> rusb::Result<rusb::Context>
+{
+    let mut context = match libusb_backend() {
+        LibusbBackend::Native => rusb::Context::new()?,
+        #[cfg(windows)]
+        LibusbBackend::UsbDk => rusb::Context::with_options(&[rusb::UsbOption::use_usbdk()])?,
+        #[cfg(not(windows))]
+        LibusbBackend::UsbDk => return Err(rusb::Error::NotSupported),
+    };
+    if diagnostics::is_capturing() {
+        context.set_log_level(rusb::LogLevel::Debug);
+    }
+
+    Ok(context)
+}
+
+/// Whether libusb supports hotplug notifications on this platform.
+///
+/// Unlike [`rusb::has_hotplug`], this doesn't set up rusb's global context, which panics if libusb
+/// can't be initialised; having a `context` shows it could be.
+pub fn has_hotplug(_context: &rusb::Context) -> bool
+{
+    // SAFETY: libusb_has_capability() only reads a compile-time table, and needs no context.
+    unsafe { libusb1_sys::libusb_has_capability(rusb::constants::LIBUSB_CAP_HAS_HOTPLUG) != 0 }
+}
 
 /// Simple newtype struct for some clarity in function arguments and whatnot.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -88,146 +158,8 @@ pub enum DfuOperatingMode
     FirmwareUpgrade,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct GenericDescriptorRef<'a>
-{
-    pub raw: &'a [u8],
-}
-
-impl<'a> GenericDescriptorRef<'a>
-{
-    /// Returns the first descriptor found.
-    ///
-    /// Panics if `bytes.len()` < `bytes[0]`.
-    pub fn single_from_bytes(bytes: &'a [u8]) -> Self
-    {
-        let length = bytes[0] as usize;
-
-        Self {
-            raw: &bytes[0..length],
-        }
-    }
-
-    /// Panics if any descriptors have an invalid size.
-    pub fn multiple_from_bytes(bytes: &'a [u8]) -> Vec<Self>
-    {
-        let mut v: Vec<Self> = Vec::new();
-
-        let mut current_bytes = &bytes[0..];
-
-        loop {
-            let descriptor = Self::single_from_bytes(current_bytes);
-            let parsed_count = descriptor.length_usize();
-            let remaining = current_bytes.len() - parsed_count;
-            v.push(descriptor);
-            if remaining == 0 {
-                break;
-            } else if remaining > 2 {
-                current_bytes = &current_bytes[parsed_count..];
-            } else {
-                panic!("Descriptor seems to have an invalid size of {}!", remaining);
-            }
-        }
-
-        v
-    }
-
-    #[allow(dead_code)] // XXX
-    pub fn length(&self) -> u8
-    {
-        self.raw[0]
-    }
-
-    pub fn length_usize(&self) -> usize
-    {
-        self.raw[0] as usize
-    }
-
-    pub fn descriptor_type(&self) -> u8
-    {
-        self.raw[1]
-    }
-}
-
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
-pub enum DescriptorConvertError
-{
-    #[error(
-        "bLength field ({provided_length}) in provided data does not match the correct value\
-        ({correct_length}) for this descriptor type"
-    )]
-    LengthFieldMismatch
-    {
-        provided_length: u8,
-        correct_length: u8,
-    },
-
-    #[error(
-        "bDescriptorType field ({provided_type}) in provided data does not match the correct\
-        value ({correct_type}) for this descriptor type"
-    )]
-    DescriptorTypeMismatch
-    {
-        provided_type: u8,
-        correct_type: u8,
-    },
-}
-
-
-/// Structure of the DFU-class functional descriptor.
-///
-/// Unfortunately, as this structure contains `u16`s at uneven offsets, making this struct
-/// `repr(packed)` would allow you to easily create unaligned references, and thus this
-/// struct does not match the memory layout of the data sent over the USB bus. Sadface indeed.
-#[allow(non_snake_case)]
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-#[repr(C)]
-pub struct DfuFunctionalDescriptor
-{
-    pub bLength: u8, // Should be 0x09.
-    pub bDescriptorType: u8, // Should be 0x21.
-    pub bmAttributes: u8,
-    pub wDetachTimeOut: u16,
-    pub wTransferSize: u16,
-    pub bcdDFUVersion: u16,
-}
-
-impl DfuFunctionalDescriptor
-{
-    pub const LENGTH: u8 = 0x09;
-    pub const TYPE: u8 = 0x21;
-
-    /// Constructs a [DfuFunctionalDescriptor] from a byte slice, via per-field copy.
-    pub fn copy_from_bytes(bytes: &[u8; 0x09]) -> Result<Self, DescriptorConvertError>
-    {
-        if bytes[0] != Self::LENGTH {
-            return Err(DescriptorConvertError::LengthFieldMismatch {
-                provided_length: bytes[0],
-                correct_length: Self::LENGTH,
-            });
-        }
-
-        if bytes[1] != Self::TYPE {
-            return Err(DescriptorConvertError::DescriptorTypeMismatch {
-                provided_type: bytes[0],
-                correct_type: Self::TYPE,
-            });
-        }
-
-        Ok(Self {
-            bLength: bytes[0],
-            bDescriptorType: bytes[1],
-            bmAttributes: bytes[2],
-            wDetachTimeOut: u16::from_le_bytes(bytes[3..=4].try_into().unwrap()),
-            wTransferSize: u16::from_le_bytes(bytes[5..=6].try_into().unwrap()),
-            bcdDFUVersion: u16::from_le_bytes(bytes[7..=8].try_into().unwrap()),
-        })
-    }
-}
-
 /// The libusb version against which error conditions have been checked from its source code.
-pub(crate) const CHECKED_LIBUSB_VERSION: &str = "1.0.26";
+pub const CHECKED_LIBUSB_VERSION: &str = "1.0.26";
 
 /// libusb has an API function whose documentation state non-zero return codes indicate failure
 /// (and thus the [`rusb`](https://docs.rs/rusb) equivalents for them return `Result<T>`),
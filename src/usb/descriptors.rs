@@ -0,0 +1,315 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for parsing the raw USB descriptors libusb hands us as bytes: the "extra" descriptors
+//! following a configuration, interface, or endpoint descriptor, and the capabilities in a BOS
+//! descriptor.
+//!
+//! Rather than walking the bytes by hand, use [`ExtraDescriptors::iter`], which yields each
+//! descriptor as a [`Descriptor`], parsed into a typed variant for the kinds we know.
+
+use thiserror::Error;
+
+/// bDescriptorType of a BOS device capability descriptor.
+const DESCRIPTOR_TYPE_DEVICE_CAPABILITY: u8 = 0x10;
+
+/// bDevCapabilityType of a Container ID capability descriptor.
+const CAPABILITY_TYPE_CONTAINER_ID: u8 = 0x04;
+
+/// The most characters a string descriptor can hold: its 255 bytes, less the 2-byte header, in UTF-16.
+pub const MAX_STRING_DESCRIPTOR_CHARS: usize = 126;
+
+
+/// Makes a string that came from a device (e.g. a product string or serial number) safe to print,
+/// log, or store in a line-based file.
+///
+/// A device can report anything at all, so control characters (which could move the cursor or
+/// change colors in a terminal, or start a new line in a log) and Unicode direction overrides are
+/// escaped, and the string is cut to the length a real string descriptor can have.
+///
+/// ```
+/// # use bmputil::usb::sanitize_descriptor_string;
+/// assert_eq!(sanitize_descriptor_string("Black Magic Probe v1.10.0"), "Black Magic Probe v1.10.0");
+/// assert_eq!(sanitize_descriptor_string("Evil\x1b[2J\n"), "Evil\\u{1b}[2J\\u{a}");
+/// ```
+pub fn sanitize_descriptor_string(string: &str) -> String
+{
+    let is_unsafe = |c: char| c.is_control() || matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}');
+
+    let mut sanitized = String::with_capacity(string.len().min(MAX_STRING_DESCRIPTOR_CHARS));
+    for c in string.chars().take(MAX_STRING_DESCRIPTOR_CHARS) {
+        if is_unsafe(c) {
+            sanitized.extend(c.escape_unicode());
+        } else {
+            sanitized.push(c);
+        }
+    }
+
+    sanitized
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GenericDescriptorRef<'a>
+{
+    pub raw: &'a [u8],
+}
+
+impl<'a> GenericDescriptorRef<'a>
+{
+    pub fn length(&self) -> u8
+    {
+        self.raw[0]
+    }
+
+    pub fn descriptor_type(&self) -> u8
+    {
+        self.raw[1]
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+pub enum DescriptorConvertError
+{
+    #[error(
+        "bLength field ({provided_length}) in provided data does not match the correct value\
+        ({correct_length}) for this descriptor type"
+    )]
+    LengthFieldMismatch
+    {
+        provided_length: u8,
+        correct_length: u8,
+    },
+
+    #[error(
+        "bDescriptorType field ({provided_type}) in provided data does not match the correct\
+        value ({correct_type}) for this descriptor type"
+    )]
+    DescriptorTypeMismatch
+    {
+        provided_type: u8,
+        correct_type: u8,
+    },
+
+    #[error(
+        "descriptor at offset {offset} has an invalid bLength field ({length}) for the {remaining} \
+        bytes remaining"
+    )]
+    InvalidLength
+    {
+        offset: usize,
+        length: u8,
+        remaining: usize,
+    },
+}
+
+
+/// Structure of the DFU-class functional descriptor.
+///
+/// Unfortunately, as this structure contains `u16`s at uneven offsets, making this struct
+/// `repr(packed)` would allow you to easily create unaligned references, and thus this
+/// struct does not match the memory layout of the data sent over the USB bus. Sadface indeed.
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct DfuFunctionalDescriptor
+{
+    pub bLength: u8, // Should be 0x09.
+    pub bDescriptorType: u8, // Should be 0x21.
+    pub bmAttributes: u8,
+    pub wDetachTimeOut: u16,
+    pub wTransferSize: u16,
+    pub bcdDFUVersion: u16,
+}
+
+impl DfuFunctionalDescriptor
+{
+    pub const LENGTH: u8 = 0x09;
+    pub const TYPE: u8 = 0x21;
+
+    /// Constructs a [DfuFunctionalDescriptor] from a byte slice, via per-field copy.
+    pub fn copy_from_bytes(bytes: &[u8; 0x09]) -> Result<Self, DescriptorConvertError>
+    {
+        if bytes[0] != Self::LENGTH {
+            return Err(DescriptorConvertError::LengthFieldMismatch {
+                provided_length: bytes[0],
+                correct_length: Self::LENGTH,
+            });
+        }
+
+        if bytes[1] != Self::TYPE {
+            return Err(DescriptorConvertError::DescriptorTypeMismatch {
+                provided_type: bytes[0],
+                correct_type: Self::TYPE,
+            });
+        }
+
+        Ok(Self {
+            bLength: bytes[0],
+            bDescriptorType: bytes[1],
+            bmAttributes: bytes[2],
+            wDetachTimeOut: u16::from_le_bytes([bytes[3], bytes[4]]),
+            wTransferSize: u16::from_le_bytes([bytes[5], bytes[6]]),
+            bcdDFUVersion: u16::from_le_bytes([bytes[7], bytes[8]]),
+        })
+    }
+
+    /// bitCanDnload: whether the device supports DFU_DNLOAD.
+    #[allow(dead_code)]
+    pub fn can_download(&self) -> bool
+    {
+        self.bmAttributes & (1 << 0) != 0
+    }
+
+    /// bitCanUpload: whether the device supports DFU_UPLOAD.
+    #[allow(dead_code)]
+    pub fn can_upload(&self) -> bool
+    {
+        self.bmAttributes & (1 << 1) != 0
+    }
+
+    /// bitManifestationTolerant: whether the device can still communicate over USB after the
+    /// manifestation phase.
+    pub fn manifestation_tolerant(&self) -> bool
+    {
+        self.bmAttributes & (1 << 2) != 0
+    }
+
+    /// bitWillDetach: whether the device will perform a bus detach-attach sequence by itself when
+    /// it receives DFU_DETACH, rather than waiting for a USB reset.
+    pub fn will_detach(&self) -> bool
+    {
+        self.bmAttributes & (1 << 3) != 0
+    }
+}
+
+
+/// A descriptor parsed from raw bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Descriptor<'a>
+{
+    /// A DFU functional descriptor.
+    ///
+    /// Its descriptor type (0x21) is class-specific, so this is only meaningful for descriptors
+    /// following a DFU interface descriptor.
+    DfuFunctional(DfuFunctionalDescriptor),
+
+    /// A BOS Container ID device capability, uniquely identifying a physical device.
+    ContainerId([u8; 16]),
+
+    /// Any other BOS device capability.
+    DeviceCapability
+    {
+        capability_type: u8,
+        raw: &'a [u8],
+    },
+
+    /// A descriptor of a kind we don't parse.
+    Other(GenericDescriptorRef<'a>),
+}
+
+impl<'a> Descriptor<'a>
+{
+    fn parse(raw: &'a [u8]) -> Self
+    {
+        let generic = GenericDescriptorRef { raw };
+        match generic.descriptor_type() {
+            DfuFunctionalDescriptor::TYPE => {
+                raw.get(..DfuFunctionalDescriptor::LENGTH as usize)
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .and_then(|bytes| DfuFunctionalDescriptor::copy_from_bytes(bytes).ok())
+                    .map_or(Self::Other(generic), Self::DfuFunctional)
+            },
+            DESCRIPTOR_TYPE_DEVICE_CAPABILITY if raw.len() >= 3 => match raw[2] {
+                CAPABILITY_TYPE_CONTAINER_ID => match raw.get(4..20).and_then(|id| id.try_into().ok()) {
+                    Some(id) => Self::ContainerId(id),
+                    None => Self::DeviceCapability {
+                        capability_type: CAPABILITY_TYPE_CONTAINER_ID,
+                        raw,
+                    },
+                },
+                capability_type => Self::DeviceCapability {
+                    capability_type,
+                    raw,
+                },
+            },
+            _ => Self::Other(generic),
+        }
+    }
+}
+
+
+/// A run of descriptors back to back, such as the "extra" bytes libusb gives for an interface.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ExtraDescriptors<'a>
+{
+    bytes: &'a [u8],
+}
+
+impl<'a> ExtraDescriptors<'a>
+{
+    pub fn new(bytes: &'a [u8]) -> Self
+    {
+        Self {
+            bytes,
+        }
+    }
+
+    /// Iterates over the descriptors in order.
+    ///
+    /// If a descriptor has an invalid length, an error is yielded for it and iteration stops, as
+    /// there is no telling where the next descriptor would start.
+    pub fn iter(&self) -> DescriptorIter<'a>
+    {
+        DescriptorIter {
+            bytes: self.bytes,
+            offset: 0,
+        }
+    }
+}
+
+impl<'a> IntoIterator for ExtraDescriptors<'a>
+{
+    type Item = Result<Descriptor<'a>, DescriptorConvertError>;
+    type IntoIter = DescriptorIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter
+    {
+        self.iter()
+    }
+}
+
+/// Iterator over [`ExtraDescriptors`].
+#[derive(Debug, Clone)]
+pub struct DescriptorIter<'a>
+{
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for DescriptorIter<'a>
+{
+    type Item = Result<Descriptor<'a>, DescriptorConvertError>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        let remaining = &self.bytes[self.offset.min(self.bytes.len())..];
+        let &length = remaining.first()?;
+
+        // Every descriptor has at least bLength and bDescriptorType.
+        if length < 2 || length as usize > remaining.len() {
+            let error = DescriptorConvertError::InvalidLength {
+                offset: self.offset,
+                length,
+                remaining: remaining.len(),
+            };
+            self.offset = self.bytes.len();
+            return Some(Err(error));
+        }
+
+        self.offset += length as usize;
+
+        Some(Ok(Descriptor::parse(&remaining[..length as usize])))
+    }
+}
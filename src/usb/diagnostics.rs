@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for capturing libusb's own debug log, so the low-level cause of a failure can be
+//! reported without rebuilding with custom logging or setting `LIBUSB_DEBUG`.
+//!
+//! Capturing is off until [`capture`] is called. After that, libusb contexts created with
+//! [`new_context`](super::new_context) log at debug level, and the most recent messages are kept
+//! in memory for [`captured_log`].
+
+use std::collections::VecDeque;
+use std::ffi::{c_int, c_void, CStr};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libusb1_sys::constants::LIBUSB_LOG_CB_GLOBAL;
+use libusb1_sys::libusb_context;
+use log::trace;
+
+/// How many of the most recent libusb messages to keep. libusb logs a few lines per transfer at
+/// debug level, so this covers the last few hundred transfers.
+const MAX_LINES: usize = 2000;
+
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+
+/// Called by libusb for every message it logs, from any context.
+extern "system" fn log_callback(_context: *mut libusb_context, _level: c_int, message: *mut c_void)
+{
+    if message.is_null() {
+        return;
+    }
+    // SAFETY: libusb passes a null-terminated string, valid for the duration of the call.
+    // (libusb1-sys declares the parameter as a void pointer, but it is a `const char *`.)
+    let message = unsafe { CStr::from_ptr(message as *const _) };
+    let message = message.to_string_lossy();
+    let message = message.trim_end();
+    trace!(target: "libusb", "{}", message);
+
+    // Never panic across the FFI boundary; losing a line of diagnostics is fine.
+    if let Ok(mut log) = LOG.lock() {
+        if log.len() == MAX_LINES {
+            log.pop_front();
+        }
+        log.push_back(message.to_string());
+    }
+}
+
+/// Starts capturing libusb's debug log.
+pub fn capture()
+{
+    if CAPTURING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    // SAFETY: a null context is allowed with LIBUSB_LOG_CB_GLOBAL, and the callback is a plain
+    // function that lives for the whole program.
+    unsafe {
+        libusb1_sys::libusb_set_log_cb(ptr::null_mut(), Some(log_callback), LIBUSB_LOG_CB_GLOBAL);
+    }
+}
+
+/// Whether [`capture`] has been called.
+pub fn is_capturing() -> bool
+{
+    CAPTURING.load(Ordering::SeqCst)
+}
+
+/// Returns the libusb messages captured so far, oldest first.
+pub fn captured_log() -> Vec<String>
+{
+    LOG.lock()
+        .map(|log| log.iter().cloned().collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,446 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for capturing the full set of descriptors a USB device reports into a shareable file, and
+//! running them back through bmputil's parsers.
+//!
+//! Clones and third-party hardware have a habit of reporting descriptors nothing else does. A dump
+//! (from `bmputil dump-descriptors`) can be attached to a bug report, and then kept in
+//! `testdata/descriptors`, where `bmputil dump-descriptors --check` makes sure it stays parseable.
+//!
+//! Dumps are JSON, with each descriptor as hex bytes separated by spaces:
+//!
+//! ```json
+//! {
+//!   "format_version": 1,
+//!   "source": "Black Magic Debug bootloader v1.10 on a BMP v2.3",
+//!   "device": "12 01 00 02 00 00 00 40 50 1d 17 60 00 01 01 02 03 01",
+//!   "configurations": ["09 02 1b 00 01 01 00 80 32 ..."],
+//!   "bos": null,
+//!   "strings": { "1": "Black Magic Debug", "2": "Black Magic Probe DFU" }
+//! }
+//! ```
+//!
+//! Each configuration is the configuration descriptor followed by everything after it (as returned
+//! for its full `wTotalLength`), so interface, endpoint, and class-specific descriptors are all kept
+//! in their original order. The serial number string is left out, as it identifies the user's probe.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use log::debug;
+use rusb::{Direction, Recipient, RequestType};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind, ErrorSource, InvalidDeviceData};
+use crate::profiles::{self, ProbeProfile};
+use crate::usb::{
+    Descriptor, DfuFunctionalDescriptor, DfuOperatingMode, ExtraDescriptors, InterfaceClass, InterfaceSubClass,
+    Pid, UsbDeviceHandle, Vid,
+};
+
+type UsbHandle = rusb::DeviceHandle<rusb::Context>;
+
+/// The version of the dump format this module reads and writes.
+pub const FORMAT_VERSION: u32 = 1;
+
+const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+const DESCRIPTOR_TYPE_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_TYPE_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_TYPE_BOS: u8 = 0x0f;
+
+const DEVICE_DESCRIPTOR_LENGTH: usize = 18;
+const CONFIGURATION_DESCRIPTOR_LENGTH: usize = 9;
+const BOS_DESCRIPTOR_LENGTH: usize = 5;
+
+
+/// Every descriptor a USB device reported, as stored in a dump file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DescriptorDump
+{
+    pub format_version: u32,
+    /// Free-form description of where the dump came from, e.g. the hardware and firmware version.
+    #[serde(default)]
+    pub source: String,
+    /// The device descriptor.
+    pub device: String,
+    /// Each configuration descriptor, with everything following it.
+    pub configurations: Vec<String>,
+    /// The BOS descriptor with its device capabilities, if the device has one.
+    #[serde(default)]
+    pub bos: Option<String>,
+    /// The string descriptors referenced by the other descriptors, except the serial number, by index.
+    #[serde(default)]
+    pub strings: BTreeMap<u8, String>,
+}
+
+/// What bmputil makes of a [`DescriptorDump`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpSummary
+{
+    pub vid: Vid,
+    pub pid: Pid,
+    /// The profile the device matches, and the mode it's in, if bmputil recognises it.
+    pub profile: Option<(&'static ProbeProfile, DfuOperatingMode)>,
+    /// Each DFU interface number, with its DFU functional descriptor.
+    pub dfu_interfaces: Vec<(u8, DfuFunctionalDescriptor)>,
+    pub container_id: Option<[u8; 16]>,
+}
+
+/// The descriptors in a [`DescriptorDump`], decoded back into bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpBytes
+{
+    pub device: Vec<u8>,
+    pub configurations: Vec<Vec<u8>>,
+    pub bos: Option<Vec<u8>>,
+}
+
+
+fn invalid_dump(why: String) -> Error
+{
+    ErrorKind::External(ErrorSource::StdIo(io::Error::new(io::ErrorKind::InvalidData, why))).error()
+}
+
+/// Formats bytes as space-separated hex, as stored in dump files.
+fn encode(bytes: &[u8]) -> String
+{
+    let mut hex = String::with_capacity(bytes.len() * 3);
+    for (index, byte) in bytes.iter().enumerate() {
+        if index > 0 {
+            hex.push(' ');
+        }
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+
+    hex
+}
+
+/// Parses bytes written as hex, ignoring any whitespace between them.
+fn decode(what: &str, hex: &str) -> Result<Vec<u8>, Error>
+{
+    let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(invalid_dump(format!("{} has an odd number of hex digits", what)));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| invalid_dump(format!("{} is not valid hex", what)))
+        })
+        .collect()
+}
+
+/// Reads up to `length` bytes of the descriptor of `descriptor_type` at `index`.
+fn read_descriptor(handle: &UsbHandle, descriptor_type: u8, index: u8, length: usize, timeout: Duration)
+    -> Result<Vec<u8>, Error>
+{
+    let mut buf = vec![0u8; length];
+    let read = UsbDeviceHandle::read_control(
+        handle,
+        rusb::request_type(Direction::In, RequestType::Standard, Recipient::Device),
+        rusb::constants::LIBUSB_REQUEST_GET_DESCRIPTOR,
+        ((descriptor_type as u16) << 8) | index as u16,
+        0,
+        &mut buf,
+        timeout,
+    )?;
+    buf.truncate(read);
+
+    Ok(buf)
+}
+
+/// Reads a descriptor whose total length is in bytes 2 and 3 of its header, as for configuration
+/// and BOS descriptors.
+fn read_descriptor_set(handle: &UsbHandle, descriptor_type: u8, index: u8, header_length: usize, timeout: Duration)
+    -> Result<Vec<u8>, Error>
+{
+    let header = read_descriptor(handle, descriptor_type, index, header_length, timeout)?;
+    if header.len() < header_length {
+        return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::TruncatedDescriptor { descriptor_type }).error());
+    }
+    let total_length = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+    read_descriptor(handle, descriptor_type, index, total_length, timeout)
+}
+
+/// Walks the descriptors following configuration descriptor `index`, calling `f` for each along
+/// with the interface descriptor it follows, if any.
+fn for_each_in_configuration<'a>(
+    index: usize,
+    configuration: &'a [u8],
+    mut f: impl FnMut(Option<&'a [u8]>, &Descriptor<'a>),
+) -> Result<(), Error>
+{
+    let mut interface = None;
+    for descriptor in ExtraDescriptors::new(configuration.get(CONFIGURATION_DESCRIPTOR_LENGTH..).unwrap_or_default()) {
+        let descriptor = descriptor
+            .map_err(|e| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MalformedConfiguration(index)).error_from(e))?;
+        if let Descriptor::Other(generic) = &descriptor {
+            if generic.descriptor_type() == DESCRIPTOR_TYPE_INTERFACE {
+                interface = Some(generic.raw);
+            }
+        }
+        f(interface, &descriptor);
+    }
+
+    Ok(())
+}
+
+impl DescriptorDump
+{
+    /// Reads every descriptor from an open device.
+    ///
+    /// A BOS descriptor the device claims to have but won't return is left out, rather than failing
+    /// the whole dump, as that is exactly the kind of device a dump is wanted for.
+    pub fn capture(handle: &UsbHandle, source: String, timeout: Duration) -> Result<Self, Error>
+    {
+        let device = read_descriptor(handle, DESCRIPTOR_TYPE_DEVICE, 0, DEVICE_DESCRIPTOR_LENGTH, timeout)?;
+        if device.len() < DEVICE_DESCRIPTOR_LENGTH {
+            return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::TruncatedDescriptor {
+                descriptor_type: DESCRIPTOR_TYPE_DEVICE,
+            })
+            .error());
+        }
+
+        let configurations = (0..device[17])
+            .map(|index| {
+                read_descriptor_set(handle, DESCRIPTOR_TYPE_CONFIGURATION, index, CONFIGURATION_DESCRIPTOR_LENGTH, timeout)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let bcd_usb = u16::from_le_bytes([device[2], device[3]]);
+        let bos = if bcd_usb >= 0x0201 {
+            read_descriptor_set(handle, DESCRIPTOR_TYPE_BOS, 0, BOS_DESCRIPTOR_LENGTH, timeout)
+                .inspect_err(|e| debug!("Failed to read BOS descriptor: {}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        // Manufacturer and product, and then the name of every interface.
+        let mut string_indices = vec![device[14], device[15]];
+        for (index, configuration) in configurations.iter().enumerate() {
+            for_each_in_configuration(index, configuration, |_, descriptor| {
+                if let Descriptor::Other(generic) = descriptor {
+                    if generic.descriptor_type() == DESCRIPTOR_TYPE_INTERFACE {
+                        string_indices.extend(generic.raw.get(8).copied());
+                    }
+                }
+            })?;
+        }
+
+        let language = handle.read_languages(timeout)?.into_iter().next();
+        let strings = string_indices
+            .into_iter()
+            .filter(|&index| index != 0 && index != device[16])
+            .filter_map(|index| {
+                let string = handle.read_string_descriptor(language?, index, timeout)
+                    .inspect_err(|e| debug!("Failed to read string descriptor {}: {}", index, e))
+                    .ok()?;
+                Some((index, string))
+            })
+            .collect();
+
+        Ok(Self {
+            format_version: FORMAT_VERSION,
+            source,
+            device: encode(&device),
+            configurations: configurations.iter().map(|configuration| encode(configuration)).collect(),
+            bos: bos.as_deref().map(encode),
+            strings,
+        })
+    }
+
+    /// Reads a dump file.
+    pub fn load(path: &Path) -> Result<Self, Error>
+    {
+        fs::read_to_string(path)
+            .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e)).error())
+            .and_then(|contents| Self::from_json(&contents))
+            .map_err(|e| e.with_ctx(&format!("reading descriptor dump {}", path.display())))
+    }
+
+    /// Parses a dump from the JSON [`to_json()`](Self::to_json) produces.
+    pub fn from_json(json: &str) -> Result<Self, Error>
+    {
+        let dump: Self = serde_json::from_str(json)
+            .map_err(|e| ErrorKind::External(ErrorSource::StdIo(e.into())).error())?;
+
+        if dump.format_version != FORMAT_VERSION {
+            return Err(invalid_dump(format!(
+                "format version {} is not supported (expected {})",
+                dump.format_version,
+                FORMAT_VERSION,
+            )));
+        }
+
+        Ok(dump)
+    }
+
+    /// Serializes the dump, for writing to a file.
+    pub fn to_json(&self) -> String
+    {
+        serde_json::to_string_pretty(self).expect("serializing a descriptor dump cannot fail")
+    }
+
+    /// Decodes the hex of every descriptor in the dump, without checking what it decodes to.
+    pub fn bytes(&self) -> Result<DumpBytes, Error>
+    {
+        let configurations = self.configurations
+            .iter()
+            .enumerate()
+            .map(|(index, configuration)| decode(&format!("configuration {}", index), configuration))
+            .collect::<Result<_, _>>()?;
+
+        Ok(DumpBytes {
+            device: decode("device descriptor", &self.device)?,
+            configurations,
+            bos: self.bos.as_deref().map(|bos| decode("BOS descriptor", bos)).transpose()?,
+        })
+    }
+
+    /// Runs the dump through the same parsing bmputil does for a live device.
+    ///
+    /// As for a live device, every DFU interface must be followed by a valid DFU functional
+    /// descriptor.
+    pub fn check(&self) -> Result<DumpSummary, Error>
+    {
+        let device = decode("device descriptor", &self.device)?;
+        if device.len() != DEVICE_DESCRIPTOR_LENGTH || device[1] != DESCRIPTOR_TYPE_DEVICE {
+            return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MalformedDeviceDescriptor).error());
+        }
+        let vid = Vid(u16::from_le_bytes([device[8], device[9]]));
+        let pid = Pid(u16::from_le_bytes([device[10], device[11]]));
+
+        let mut dfu_interfaces = Vec::new();
+        for (index, configuration) in self.configurations.iter().enumerate() {
+            let configuration = decode(&format!("configuration {}", index), configuration)?;
+            let total_length = configuration.get(2..4).map(|length| u16::from_le_bytes([length[0], length[1]]));
+            if configuration.get(1) != Some(&DESCRIPTOR_TYPE_CONFIGURATION) || total_length != Some(configuration.len() as u16) {
+                return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MalformedConfiguration(index)).error());
+            }
+
+            let mut current_dfu_interface = None;
+            let mut missing_functional = Vec::new();
+            for_each_in_configuration(index, &configuration, |interface, descriptor| {
+                let is_dfu = interface.is_some_and(|interface| {
+                    interface.get(5) == Some(&InterfaceClass::APPLICATION_SPECIFIC.0)
+                        && interface.get(6) == Some(&InterfaceSubClass::DFU.0)
+                });
+                let number = interface.and_then(|interface| interface.get(2).copied());
+
+                match descriptor {
+                    Descriptor::Other(generic) if generic.descriptor_type() == DESCRIPTOR_TYPE_INTERFACE => {
+                        missing_functional.extend(current_dfu_interface.take());
+                        if is_dfu {
+                            current_dfu_interface = number;
+                        }
+                    },
+                    Descriptor::DfuFunctional(functional) if is_dfu => {
+                        if let Some(number) = current_dfu_interface.take() {
+                            dfu_interfaces.push((number, functional.clone()));
+                        }
+                    },
+                    _ => (),
+                }
+            })?;
+            missing_functional.extend(current_dfu_interface);
+
+            if let Some(&interface) = missing_functional.first() {
+                return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MissingFunctionalDescriptor { interface })
+                    .error());
+            }
+        }
+
+        let container_id = match &self.bos {
+            Some(bos) => {
+                let bos = decode("BOS descriptor", bos)?;
+                if bos.len() < BOS_DESCRIPTOR_LENGTH || bos[1] != DESCRIPTOR_TYPE_BOS {
+                    return Err(ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MalformedBos).error());
+                }
+                let mut container_id = None;
+                for descriptor in ExtraDescriptors::new(bos.get(bos[0] as usize..).unwrap_or_default()) {
+                    let descriptor = descriptor
+                        .map_err(|e| ErrorKind::DeviceSeemsInvalid(InvalidDeviceData::MalformedBos).error_from(e))?;
+                    if let Descriptor::ContainerId(id) = descriptor {
+                        container_id = Some(id);
+                    }
+                }
+                container_id
+            },
+            None => None,
+        };
+
+        Ok(DumpSummary {
+            vid,
+            pid,
+            profile: profiles::find(vid, pid),
+            dfu_interfaces,
+            container_id,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::dfu::{DfuProtocol, DFUSE_VERSION};
+
+    /// Runs every dump in `testdata/descriptors` through the parsers, as `dump-descriptors --check`
+    /// does, and checks what they parse to makes sense.
+    #[test]
+    fn corpus_parses()
+    {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join("descriptors");
+        let mut paths: Vec<_> = fs::read_dir(&corpus)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty(), "no dumps in {}", corpus.display());
+
+        for path in paths {
+            let name = path.display();
+            let dump = DescriptorDump::load(&path).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let summary = dump.check().unwrap_or_else(|e| panic!("{}: {}", name, e));
+
+            assert!(summary.profile.is_some(), "{}: {:04x}:{:04x} is not a known probe", name, summary.vid.0, summary.pid.0);
+            assert!(!summary.dfu_interfaces.is_empty(), "{}: no DFU interface", name);
+
+            for (interface, functional) in &summary.dfu_interfaces {
+                assert!(
+                    matches!(functional.bcdDFUVersion, 0x0100 | 0x0110 | DFUSE_VERSION),
+                    "{}: interface {} has unknown bcdDFUVersion {:#06x}",
+                    name,
+                    interface,
+                    functional.bcdDFUVersion,
+                );
+                assert_ne!(functional.wTransferSize, 0, "{}: interface {} has no transfer size", name, interface);
+
+                // DfuSe interfaces describe their memory in their name, which has to parse too.
+                if functional.bcdDFUVersion == DFUSE_VERSION {
+                    let layouts: Vec<_> = dump.strings.values().filter(|string| string.starts_with('@')).collect();
+                    assert!(!layouts.is_empty(), "{}: DfuSe interface {} without a memory layout", name, interface);
+                    for layout in layouts {
+                        match DfuProtocol::parse_dfuse_layout(layout) {
+                            Ok(DfuProtocol::Dfuse(segments)) => assert!(!segments.is_empty(), "{}: {:?} has no segments", name, layout),
+                            other => panic!("{}: {:?} parsed as {:?}", name, layout, other),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,442 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module abstracting over the operations we perform on an open USB device, so protocol logic
+//! like DFU can run against a [`MockHandle`] instead of real hardware.
+//!
+//! [`UsbDeviceHandle`] is implemented for [`rusb::DeviceHandle`], which is what everything uses
+//! outside of tests. Control transfers made through it are counted by [`telemetry`], and logged,
+//! with their data, at trace level under [`TRANSFER_LOG_TARGET`].
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use log::{log_enabled, trace, Level};
+use rusb::{Direction, UsbContext};
+
+use super::telemetry;
+
+
+/// The log target every control transfer made through a [`rusb::DeviceHandle`] is logged under,
+/// so it can be filtered separately from the rest of the trace output.
+pub const TRANSFER_LOG_TARGET: &str = "bmputil::usb::transfers";
+
+/// Logs a control transfer, with the data sent, or received if it succeeded.
+fn log_transfer(request_type: u8, request: u8, value: u16, index: u16, data: &[u8], res: &rusb::Result<usize>)
+{
+    if !log_enabled!(target: TRANSFER_LOG_TARGET, Level::Trace) {
+        return;
+    }
+
+    let direction = if request_type & 0x80 != 0 { "IN" } else { "OUT" };
+    let hex: Vec<String> = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let result = match res {
+        Ok(length) => format!("{} bytes", length),
+        Err(e) => format!("failed: {}", e),
+    };
+    trace!(
+        target: TRANSFER_LOG_TARGET,
+        "{} bmRequestType=0x{:02x} bRequest=0x{:02x} wValue=0x{:04x} wIndex=0x{:04x}: {} [{}]",
+        direction,
+        request_type,
+        request,
+        value,
+        index,
+        result,
+        hex.join(" "),
+    );
+}
+
+
+/// The operations performed on an open USB device.
+///
+/// These mirror the [`rusb::DeviceHandle`] methods of the same names.
+pub trait UsbDeviceHandle
+{
+    fn claim_interface(&mut self, interface: u8) -> rusb::Result<()>;
+
+    fn release_interface(&mut self, interface: u8) -> rusb::Result<()>;
+
+    fn reset(&mut self) -> rusb::Result<()>;
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    /// Reads the string descriptor naming `interface` (its first alternate setting), in the first
+    /// language the device supports.
+    fn read_interface_name(&self, interface: u8, timeout: Duration) -> rusb::Result<String>;
+
+    fn set_alternate_setting(&mut self, interface: u8, setting: u8) -> rusb::Result<()>;
+
+    /// Reads the string descriptor naming alternate setting `setting` of `interface`, in the first
+    /// language the device supports.
+    fn read_alt_setting_name(&self, interface: u8, setting: u8, timeout: Duration) -> rusb::Result<String>;
+}
+
+impl<T: UsbContext> UsbDeviceHandle for rusb::DeviceHandle<T>
+{
+    fn claim_interface(&mut self, interface: u8) -> rusb::Result<()>
+    {
+        rusb::DeviceHandle::claim_interface(self, interface)
+    }
+
+    fn release_interface(&mut self, interface: u8) -> rusb::Result<()>
+    {
+        rusb::DeviceHandle::release_interface(self, interface)
+    }
+
+    fn reset(&mut self) -> rusb::Result<()>
+    {
+        rusb::DeviceHandle::reset(self)
+    }
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>
+    {
+        let res = telemetry::control_transfer(|| {
+            rusb::DeviceHandle::read_control(self, request_type, request, value, index, buf, timeout)
+        });
+        let received = res.as_ref().map_or(&[][..], |&length| &buf[..length.min(buf.len())]);
+        log_transfer(request_type, request, value, index, received, &res);
+
+        res
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>
+    {
+        let res = telemetry::control_transfer(|| {
+            rusb::DeviceHandle::write_control(self, request_type, request, value, index, buf, timeout)
+        });
+        log_transfer(request_type, request, value, index, buf, &res);
+
+        res
+    }
+
+    fn read_interface_name(&self, interface: u8, timeout: Duration) -> rusb::Result<String>
+    {
+        let config = self.device().active_config_descriptor()?;
+        let interface_descriptor = config
+            .interfaces()
+            .find(|iface| iface.number() == interface)
+            .and_then(|iface| iface.descriptors().next())
+            .ok_or(rusb::Error::NotFound)?;
+
+        let languages = self.read_languages(timeout)?;
+        let language = languages.first().ok_or(rusb::Error::NotFound)?;
+
+        self.read_interface_string(*language, &interface_descriptor, timeout)
+    }
+
+    fn set_alternate_setting(&mut self, interface: u8, setting: u8) -> rusb::Result<()>
+    {
+        rusb::DeviceHandle::set_alternate_setting(self, interface, setting)
+    }
+
+    fn read_alt_setting_name(&self, interface: u8, setting: u8, timeout: Duration) -> rusb::Result<String>
+    {
+        let config = self.device().active_config_descriptor()?;
+        let interface_descriptor = config
+            .interfaces()
+            .find(|iface| iface.number() == interface)
+            .and_then(|iface| iface.descriptors().find(|desc| desc.setting_number() == setting))
+            .ok_or(rusb::Error::NotFound)?;
+
+        let languages = self.read_languages(timeout)?;
+        let language = languages.first().ok_or(rusb::Error::NotFound)?;
+
+        self.read_interface_string(*language, &interface_descriptor, timeout)
+    }
+}
+
+
+/// A control transfer a [`MockHandle`] expects, and how it answers it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockTransfer
+{
+    direction: Direction,
+    request: u8,
+    value: u16,
+    index: u16,
+    /// The data expected for OUT transfers, or returned for IN transfers.
+    data: Vec<u8>,
+    error: Option<rusb::Error>,
+}
+
+impl MockTransfer
+{
+    /// Expects an IN control transfer, answered with `response`.
+    pub fn control_in(request: u8, value: u16, index: u16, response: &[u8]) -> Self
+    {
+        Self {
+            direction: Direction::In,
+            request,
+            value,
+            index,
+            data: response.to_vec(),
+            error: None,
+        }
+    }
+
+    /// Expects an OUT control transfer carrying `data`, which the device accepts in full.
+    pub fn control_out(request: u8, value: u16, index: u16, data: &[u8]) -> Self
+    {
+        Self {
+            direction: Direction::Out,
+            request,
+            value,
+            index,
+            data: data.to_vec(),
+            error: None,
+        }
+    }
+
+    /// Fail the transfer with `error` instead of completing it.
+    #[must_use]
+    pub fn fail(mut self, error: rusb::Error) -> Self
+    {
+        self.error = Some(error);
+        self
+    }
+}
+
+
+/// A scripted stand-in for a USB device, for exercising protocol logic without hardware.
+///
+/// Control transfers must happen in the order they were given with [`MockHandle::expect`]; an
+/// unexpected transfer panics, failing the test using it.
+///
+/// ```
+/// use std::time::Duration;
+/// use bmputil::dfu::{DfuInterface, DfuState};
+/// use bmputil::usb::{DfuFunctionalDescriptor, DfuRequest, MockHandle, MockTransfer};
+///
+/// let functional_descriptor = DfuFunctionalDescriptor::copy_from_bytes(
+///     &[0x09, 0x21, 0x0b, 0xff, 0x00, 0x00, 0x04, 0x10, 0x01],
+/// ).unwrap();
+/// let mut handle = MockHandle::new()
+///     .expect(MockTransfer::control_in(DfuRequest::GetStatus as u8, 0, 0, &[0, 0, 0, 0, 2, 0]));
+///
+/// let dfu = DfuInterface::open(&mut handle, 0, functional_descriptor, Duration::from_secs(1)).unwrap();
+/// assert_eq!(dfu.get_status().unwrap().state, DfuState::DfuIdle);
+/// dfu.release().unwrap();
+/// assert!(handle.is_done());
+/// ```
+#[derive(Debug, Default)]
+pub struct MockHandle
+{
+    script: RefCell<VecDeque<MockTransfer>>,
+    interface_names: BTreeMap<u8, String>,
+    alt_setting_names: BTreeMap<(u8, u8), String>,
+    alt_settings: BTreeMap<u8, u8>,
+    claimed: Vec<u8>,
+    resets: usize,
+}
+
+impl MockHandle
+{
+    pub fn new() -> Self
+    {
+        Default::default()
+    }
+
+    /// Adds a transfer to the end of the script.
+    #[must_use]
+    pub fn expect(self, transfer: MockTransfer) -> Self
+    {
+        self.script.borrow_mut().push_back(transfer);
+        self
+    }
+
+    /// Set the string descriptor naming `interface`.
+    #[must_use]
+    pub fn interface_name(mut self, interface: u8, name: &str) -> Self
+    {
+        self.interface_names.insert(interface, name.to_string());
+        self
+    }
+
+    /// Set the string descriptor naming alternate setting `setting` of `interface`.
+    #[must_use]
+    pub fn alt_setting_name(mut self, interface: u8, setting: u8, name: &str) -> Self
+    {
+        self.alt_setting_names.insert((interface, setting), name.to_string());
+        self
+    }
+
+    /// The alternate setting last selected for `interface`.
+    pub fn alt_setting(&self, interface: u8) -> u8
+    {
+        self.alt_settings.get(&interface).copied().unwrap_or(0)
+    }
+
+    /// Whether every scripted transfer has happened.
+    pub fn is_done(&self) -> bool
+    {
+        self.script.borrow().is_empty()
+    }
+
+    /// The interfaces currently claimed.
+    pub fn claimed_interfaces(&self) -> &[u8]
+    {
+        &self.claimed
+    }
+
+    /// How many times the device was reset.
+    pub fn resets(&self) -> usize
+    {
+        self.resets
+    }
+
+    /// Takes the next scripted transfer, checking it matches the one being made.
+    fn next_transfer(&self, direction: Direction, request: u8, value: u16, index: u16) -> MockTransfer
+    {
+        let transfer = self.script
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or_else(|| {
+                panic!("unexpected {:?} transfer: request {}, value {}, index {}", direction, request, value, index)
+            });
+
+        assert!(
+            transfer.direction == direction
+                && transfer.request == request
+                && transfer.value == value
+                && transfer.index == index,
+            "expected {:?}, got {:?} transfer: request {}, value {}, index {}",
+            transfer,
+            direction,
+            request,
+            value,
+            index,
+        );
+
+        transfer
+    }
+}
+
+impl UsbDeviceHandle for MockHandle
+{
+    fn claim_interface(&mut self, interface: u8) -> rusb::Result<()>
+    {
+        if self.claimed.contains(&interface) {
+            return Err(rusb::Error::Busy);
+        }
+        self.claimed.push(interface);
+        Ok(())
+    }
+
+    fn release_interface(&mut self, interface: u8) -> rusb::Result<()>
+    {
+        let position = self.claimed
+            .iter()
+            .position(|&claimed| claimed == interface)
+            .ok_or(rusb::Error::NotFound)?;
+        self.claimed.remove(position);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> rusb::Result<()>
+    {
+        self.resets += 1;
+        Ok(())
+    }
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize>
+    {
+        assert_eq!(request_type & 0x80, 0x80, "IN transfer with an OUT request type");
+        let transfer = self.next_transfer(Direction::In, request, value, index);
+        if let Some(error) = transfer.error {
+            return Err(error);
+        }
+
+        let length = transfer.data.len().min(buf.len());
+        buf[..length].copy_from_slice(&transfer.data[..length]);
+        Ok(length)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize>
+    {
+        assert_eq!(request_type & 0x80, 0, "OUT transfer with an IN request type");
+        let transfer = self.next_transfer(Direction::Out, request, value, index);
+        if let Some(error) = transfer.error {
+            return Err(error);
+        }
+
+        assert_eq!(transfer.data, buf, "unexpected data in request {}", request);
+        Ok(buf.len())
+    }
+
+    fn read_interface_name(&self, interface: u8, _timeout: Duration) -> rusb::Result<String>
+    {
+        self.interface_names
+            .get(&interface)
+            .cloned()
+            .ok_or(rusb::Error::NotFound)
+    }
+
+    fn set_alternate_setting(&mut self, interface: u8, setting: u8) -> rusb::Result<()>
+    {
+        if !self.claimed.contains(&interface) {
+            return Err(rusb::Error::NotFound);
+        }
+        self.alt_settings.insert(interface, setting);
+        Ok(())
+    }
+
+    fn read_alt_setting_name(&self, interface: u8, setting: u8, _timeout: Duration) -> rusb::Result<String>
+    {
+        self.alt_setting_names
+            .get(&(interface, setting))
+            .cloned()
+            .ok_or(rusb::Error::NotFound)
+    }
+}
@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for counting the USB transfers bmputil makes, and the time spent in them, so slowness can
+//! be pinned on the bus (a slow hub, a VM) or on bmputil itself.
+//!
+//! Control transfers made through [`UsbDeviceHandle`](super::UsbDeviceHandle), which covers all of
+//! DFU, are counted, as are the retries [`RetryPolicy`](crate::retry::RetryPolicy) makes. The
+//! counts are process-wide, and read with [`snapshot`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static CONTROL_TRANSFERS: AtomicU64 = AtomicU64::new(0);
+static FAILED_TRANSFERS: AtomicU64 = AtomicU64::new(0);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static TRANSFER_NANOS: AtomicU64 = AtomicU64::new(0);
+
+
+/// The USB transfers made so far.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TransferStats
+{
+    /// How many control transfers were made, including failed ones.
+    pub control_transfers: u64,
+    /// How many of the control transfers failed.
+    pub failed_transfers: u64,
+    /// How many transfers were retried after a transient error.
+    pub retries: u64,
+    /// Total time spent waiting for transfers to complete.
+    pub transfer_time: Duration,
+}
+
+impl TransferStats
+{
+    /// Returns the transfers made since `earlier` was taken.
+    pub fn since(&self, earlier: &Self) -> Self
+    {
+        Self {
+            control_transfers: self.control_transfers.saturating_sub(earlier.control_transfers),
+            failed_transfers: self.failed_transfers.saturating_sub(earlier.failed_transfers),
+            retries: self.retries.saturating_sub(earlier.retries),
+            transfer_time: self.transfer_time.saturating_sub(earlier.transfer_time),
+        }
+    }
+}
+
+/// Runs `transfer`, counting it as a control transfer, and timing it.
+pub fn control_transfer<T>(transfer: impl FnOnce() -> rusb::Result<T>) -> rusb::Result<T>
+{
+    let start = Instant::now();
+    let res = transfer();
+    let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+
+    CONTROL_TRANSFERS.fetch_add(1, Ordering::Relaxed);
+    TRANSFER_NANOS.fetch_add(nanos, Ordering::Relaxed);
+    if res.is_err() {
+        FAILED_TRANSFERS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    res
+}
+
+/// Counts a transfer being retried.
+pub fn retry()
+{
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the transfers made so far.
+pub fn snapshot() -> TransferStats
+{
+    TransferStats {
+        control_transfers: CONTROL_TRANSFERS.load(Ordering::Relaxed),
+        failed_transfers: FAILED_TRANSFERS.load(Ordering::Relaxed),
+        retries: RETRIES.load(Ordering::Relaxed),
+        transfer_time: Duration::from_nanos(TRANSFER_NANOS.load(Ordering::Relaxed)),
+    }
+}
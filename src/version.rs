@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for parsing Black Magic Probe firmware versions.
+//!
+//! The firmware reports its version as part of its USB product string, in `git describe` format,
+//! e.g. `Black Magic Probe (ST-Link/v2) v1.10.0-rc1-23-gdeadbeef-dirty`.
+
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::usb::MAX_STRING_DESCRIPTOR_CHARS;
+
+/// What every Black Magic Debug product string starts with.
+pub(crate) const PRODUCT_STRING_MARKER: &str = "Black Magic Probe";
+
+/// A parsed firmware version.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FirmwareVersion
+{
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// Pre-release tag, e.g. `rc1`.
+    pub pre_release: Option<String>,
+    /// Number of commits since the tagged version, for development builds.
+    pub commits_since: Option<u32>,
+    /// Abbreviated hash of the commit the firmware was built from, for development builds.
+    pub commit: Option<String>,
+    /// Whether the firmware was built from a tree with uncommitted changes.
+    pub dirty: bool,
+}
+
+impl FirmwareVersion
+{
+    /// Finds and parses the version in a product string, e.g. `Black Magic Probe v1.9.2`.
+    pub fn from_product_string(product: &str) -> Option<Self>
+    {
+        product
+            .split_whitespace()
+            .rev()
+            .find_map(|word| word.parse().ok())
+    }
+
+    /// Finds the version a firmware image reports itself as, from the product string built into it.
+    ///
+    /// ```
+    /// # use bmputil::version::FirmwareVersion;
+    /// let image = b"\x00\x50\x00\x20Black Magic Probe (native) v1.10.0\x00...";
+    /// assert_eq!(FirmwareVersion::from_image(image), "v1.10.0".parse().ok());
+    /// ```
+    pub fn from_image(image: &[u8]) -> Option<Self>
+    {
+        image_product_strings(image).find_map(Self::from_product_string)
+    }
+
+    pub fn major_minor(&self) -> (u32, u32)
+    {
+        (self.major, self.minor)
+    }
+
+    /// Whether this is a tagged release (including pre-releases), rather than a development build.
+    #[allow(dead_code)]
+    pub fn is_release(&self) -> bool
+    {
+        self.commits_since.is_none() && !self.dirty
+    }
+}
+
+impl FromStr for FirmwareVersion
+{
+    type Err = ();
+
+    /// Parses a version of the form `v1.10.0-rc1-23-gdeadbeef-dirty`, where everything after
+    /// the minor version is optional.
+    fn from_str(s: &str) -> Result<Self, ()>
+    {
+        let s = s.strip_prefix('v').ok_or(())?;
+        let mut parts = s.split('-');
+
+        let mut numbers = parts.next().ok_or(())?.split('.');
+        let major = numbers.next().ok_or(())?.parse().map_err(|_| ())?;
+        let minor = numbers.next().ok_or(())?.parse().map_err(|_| ())?;
+        let patch = match numbers.next() {
+            Some(patch) => patch.parse().map_err(|_| ())?,
+            None => 0,
+        };
+        if numbers.next().is_some() {
+            return Err(());
+        }
+
+        let mut version = Self {
+            major,
+            minor,
+            patch,
+            pre_release: None,
+            commits_since: None,
+            commit: None,
+            dirty: false,
+        };
+
+        let rest: Vec<&str> = parts.collect();
+        let mut rest = rest.as_slice();
+
+        if let [dirty @ .., "dirty"] = rest {
+            version.dirty = true;
+            rest = dirty;
+        }
+        if let [before @ .., count, hash] = rest {
+            if let (Ok(count), Some(hash)) = (count.parse(), hash.strip_prefix('g')) {
+                version.commits_since = Some(count);
+                version.commit = Some(hash.to_string());
+                rest = before;
+            }
+        }
+        match rest {
+            [] => (),
+            [pre_release] if !pre_release.is_empty() => version.pre_release = Some(pre_release.to_string()),
+            _ => return Err(()),
+        }
+
+        Ok(version)
+    }
+}
+
+impl Display for FirmwareVersion
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        write!(f, "v{}.{}.{}", self.major, self.minor, self.patch)?;
+        if let Some(pre_release) = &self.pre_release {
+            write!(f, "-{}", pre_release)?;
+        }
+        if let (Some(count), Some(commit)) = (self.commits_since, &self.commit) {
+            write!(f, "-{}-g{}", count, commit)?;
+        }
+        if self.dirty {
+            write!(f, "-dirty")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PartialOrd for FirmwareVersion
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering>
+    {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FirmwareVersion
+{
+    /// Orders versions by release, with pre-releases before the release they precede, and
+    /// development builds after the version they're based on.
+    fn cmp(&self, other: &Self) -> Ordering
+    {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre_release, &other.pre_release) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => pre_release_key(a).cmp(&pre_release_key(b)),
+            })
+            .then_with(|| self.commits_since.unwrap_or(0).cmp(&other.commits_since.unwrap_or(0)))
+            .then_with(|| self.commit.cmp(&other.commit))
+            .then_with(|| self.dirty.cmp(&other.dirty))
+    }
+}
+
+/// Iterates over what look like product strings built into a firmware image, in order.
+pub(crate) fn image_product_strings(image: &[u8]) -> impl Iterator<Item = &str>
+{
+    let marker = PRODUCT_STRING_MARKER.as_bytes();
+
+    (0..image.len().saturating_sub(marker.len()))
+        .filter(move |&start| image[start..].starts_with(marker))
+        .filter_map(move |start| {
+            // The product string is NUL-terminated, and no longer than a string descriptor.
+            let string = image[start..]
+                .split(|&byte| byte == 0)
+                .next()?;
+            let string = string.get(..MAX_STRING_DESCRIPTOR_CHARS).unwrap_or(string);
+            std::str::from_utf8(string).ok()
+        })
+}
+
+/// Splits a pre-release tag like `rc10` into `("rc", 10)`, so that `rc10` sorts after `rc2`.
+fn pre_release_key(tag: &str) -> (&str, u32)
+{
+    let digits_start = tag.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let (name, number) = tag.split_at(digits_start);
+
+    (name, number.parse().unwrap_or(0))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn version(s: &str) -> FirmwareVersion
+    {
+        s.parse().unwrap_or_else(|()| panic!("{} should parse", s))
+    }
+
+    #[test]
+    fn parses_release()
+    {
+        let parsed = version("v1.10.2");
+
+        assert_eq!((parsed.major, parsed.minor, parsed.patch), (1, 10, 2));
+        assert_eq!(parsed.pre_release, None);
+        assert!(parsed.is_release());
+    }
+
+    #[test]
+    fn missing_patch_is_zero()
+    {
+        assert_eq!(version("v1.9"), version("v1.9.0"));
+        assert_eq!(version("v1.9").to_string(), "v1.9.0");
+    }
+
+    #[test]
+    fn parses_pre_release_and_dirty_suffixes()
+    {
+        let parsed = version("v1.10.0-rc1");
+        assert_eq!(parsed.pre_release.as_deref(), Some("rc1"));
+        assert!(parsed.is_release());
+
+        let parsed = version("v1.10.0-dirty");
+        assert_eq!(parsed.pre_release, None);
+        assert!(parsed.dirty);
+        assert!(!parsed.is_release());
+
+        let parsed = version("v1.10.0-rc1-23-gdeadbeef-dirty");
+        assert_eq!(parsed.pre_release.as_deref(), Some("rc1"));
+        assert_eq!(parsed.commits_since, Some(23));
+        assert_eq!(parsed.commit.as_deref(), Some("deadbeef"));
+        assert!(parsed.dirty);
+        assert_eq!(parsed.to_string(), "v1.10.0-rc1-23-gdeadbeef-dirty");
+
+        let parsed = version("v1.9.2-5-g0123abc");
+        assert_eq!(parsed.pre_release, None);
+        assert_eq!(parsed.commits_since, Some(5));
+        assert!(!parsed.dirty);
+    }
+
+    #[test]
+    fn rejects_malformed_versions()
+    {
+        for bad in ["", "v", "1.10.0", "v1", "v1.", "vx.10.0", "v1.10.0.1", "v1.10.0-", "v1.10.0-rc1-extra", "v1.10.0-rc1-23"] {
+            assert_eq!(bad.parse::<FirmwareVersion>(), Err(()), "{:?} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn pre_releases_sort_before_their_release()
+    {
+        let ordered = [
+            "v1.9.2",
+            "v1.9.2-3-gabc1234",
+            "v1.10.0-rc1",
+            "v1.10.0-rc1-4-gabc1234",
+            "v1.10.0-rc2",
+            "v1.10.0-rc10",
+            "v1.10.0",
+            "v1.10.0-dirty",
+            "v1.10.0-1-gabc1234",
+            "v1.10.1-rc1",
+        ];
+
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{} should sort before {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn finds_version_in_product_string()
+    {
+        assert_eq!(
+            FirmwareVersion::from_product_string("Black Magic Probe (ST-Link/v2) v1.10.0-rc1"),
+            Some(version("v1.10.0-rc1")),
+        );
+        assert_eq!(FirmwareVersion::from_product_string("Black Magic Probe"), None);
+    }
+}
@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for the warnings given to the user, each of which has a stable code (e.g. `BMPW001`)
+//! that scripts can match on.
+//!
+//! Warnings go through [`emit`] rather than straight to the logger, so they can be suppressed by
+//! code (`--suppress-warning`), printed as JSON alongside JSON output, and collected for reports.
+//! Codes are never reused or renumbered; new warnings get the next free number.
+
+use std::fmt::{self, Display, Formatter};
+use std::io::Write;
+use std::sync::Mutex;
+
+use log::warn;
+use serde::Serialize;
+
+/// The kinds of warnings bmputil gives.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum WarningCode
+{
+    /// No probe matched, but some were filtered out.
+    FilteredOut,
+    /// No probe matched, and errors occurred while looking for probes.
+    SearchErrors,
+    /// A probe matched, but errors occurred while looking for probes, so it may be the wrong one.
+    AmbiguousMatch,
+    /// Firmware type detection was overridden.
+    FirmwareTypeOverridden,
+    /// The bootloader is being updated.
+    BootloaderUpdate,
+    /// An image that failed validation is being flashed anyway.
+    ValidationOverridden,
+    /// An image built for different hardware than the probe's is being flashed.
+    HardwareMismatch,
+    /// The Black Magic Debug App may be using a probe that's about to be flashed.
+    BmdaRunning,
+}
+
+impl WarningCode
+{
+    /// Every warning code, in order.
+    pub const ALL: &'static [Self] = &[
+        Self::FilteredOut,
+        Self::SearchErrors,
+        Self::AmbiguousMatch,
+        Self::FirmwareTypeOverridden,
+        Self::BootloaderUpdate,
+        Self::ValidationOverridden,
+        Self::HardwareMismatch,
+        Self::BmdaRunning,
+    ];
+
+    /// The stable code for this warning, e.g. `BMPW001`.
+    pub fn code(self) -> &'static str
+    {
+        use WarningCode::*;
+        match self {
+            FilteredOut => "BMPW001",
+            SearchErrors => "BMPW002",
+            AmbiguousMatch => "BMPW003",
+            FirmwareTypeOverridden => "BMPW004",
+            BootloaderUpdate => "BMPW005",
+            ValidationOverridden => "BMPW006",
+            HardwareMismatch => "BMPW007",
+            BmdaRunning => "BMPW008",
+        }
+    }
+
+    /// A short description of this warning, for listing the codes.
+    pub fn summary(self) -> &'static str
+    {
+        use WarningCode::*;
+        match self {
+            FilteredOut => "no matching probe, but probes were filtered out",
+            SearchErrors => "no matching probe, and errors occurred while searching",
+            AmbiguousMatch => "matching probe found, but errors occurred while searching",
+            FirmwareTypeOverridden => "firmware type detection overridden",
+            BootloaderUpdate => "updating the bootloader",
+            ValidationOverridden => "flashing an image that failed validation",
+            HardwareMismatch => "flashing an image built for different hardware",
+            BmdaRunning => "flashing a probe the Black Magic Debug App may be using",
+        }
+    }
+
+    /// Finds the warning with the given code, ignoring case.
+    pub fn from_code(code: &str) -> Option<Self>
+    {
+        Self::ALL.iter().copied().find(|warning| warning.code().eq_ignore_ascii_case(code))
+    }
+}
+
+impl Display for WarningCode
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// A warning that was given.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning
+{
+    pub code: &'static str,
+    pub message: String,
+}
+
+struct State
+{
+    suppressed: Vec<WarningCode>,
+    json: bool,
+    emitted: Vec<Warning>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    suppressed: Vec::new(),
+    json: false,
+    emitted: Vec::new(),
+});
+
+fn state() -> std::sync::MutexGuard<'static, State>
+{
+    // Nothing holding the lock can panic, but don't lose warnings over it if something does.
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Stops warnings with `code` from being printed. They are still collected by [`take_emitted`].
+pub fn suppress(code: WarningCode)
+{
+    state().suppressed.push(code);
+}
+
+/// Sets whether warnings are printed to stderr as JSON, one object per line, rather than logged.
+pub fn set_json(json: bool)
+{
+    state().json = json;
+}
+
+/// Gives a warning.
+pub fn emit(code: WarningCode, message: impl Into<String>)
+{
+    let warning = Warning {
+        code: code.code(),
+        message: message.into(),
+    };
+
+    let mut state = state();
+    if !state.suppressed.contains(&code) {
+        if state.json {
+            let line = serde_json::to_string(&warning).expect("Serializing a warning should not fail");
+            let _ = writeln!(std::io::stderr(), "{}", line);
+        } else {
+            warn!("[{}] {}", warning.code, warning.message);
+        }
+    }
+    state.emitted.push(warning);
+}
+
+/// Returns the warnings given since the last call.
+pub fn take_emitted() -> Vec<Warning>
+{
+    std::mem::take(&mut state().emitted)
+}
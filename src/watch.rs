@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module implementing `bmputil watch`, which prints a line each time a probe is connected or
+//! disconnected, until interrupted.
+//!
+//! Each time something may have changed on the bus, the probes matching the command line are looked
+//! for again and compared with the last time, keyed by USB port and mode, so a probe switching
+//! between runtime and DFU mode shows up as a disconnect followed by a connect. Where libusb
+//! supports hotplug notifications, that happens as soon as any Black Magic Probe device arrives or
+//! leaves; otherwise, the bus is polled.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+use log::debug;
+use rusb::{Hotplug, HotplugBuilder, Registration, UsbContext};
+use serde::Serialize;
+
+use bmputil::bmp::{BmpDevice, BmpMatcher, ProbeInfo};
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+use bmputil::config::OutputFormat;
+use bmputil::profiles;
+use bmputil::usb::{DfuOperatingMode, Pid, Vid};
+
+/// How often to look for changes without hotplug notifications, and how often to look anyway with
+/// them, in case one was missed.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Hotplug callback that notes when any Black Magic Probe device arrives or leaves.
+struct BusWatcher
+{
+    changed: Arc<AtomicBool>,
+}
+
+impl BusWatcher
+{
+    fn note(&self, device: &rusb::Device<rusb::Context>)
+    {
+        if let Ok(desc) = device.device_descriptor() {
+            if profiles::find(Vid(desc.vendor_id()), Pid(desc.product_id())).is_some() {
+                self.changed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl Hotplug<rusb::Context> for BusWatcher
+{
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>)
+    {
+        self.note(&device);
+    }
+
+    fn device_left(&mut self, device: rusb::Device<rusb::Context>)
+    {
+        self.note(&device);
+    }
+}
+
+/// A line of `bmputil watch --format json`.
+#[derive(Debug, Serialize)]
+struct WatchEvent<'a>
+{
+    /// `connected` or `disconnected`.
+    event: &'static str,
+    /// Seconds since the Unix epoch.
+    time: f64,
+    #[serde(flatten)]
+    probe: &'a ProbeInfo,
+}
+
+fn print_event(event: &'static str, probe: &ProbeInfo, json: bool, start: Instant)
+{
+    let mut stdout = io::stdout().lock();
+    if json {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64());
+        let line = serde_json::to_string(&WatchEvent { event, time, probe })
+            .expect("Serializing a watch event to JSON should not fail");
+        let _ = writeln!(stdout, "{}", line);
+    } else {
+        let _ = writeln!(
+            stdout,
+            "[{:>9.3}] {:<12} {:<8} {:<7} port {}{}",
+            start.elapsed().as_secs_f64(),
+            event,
+            probe.serial.as_deref().unwrap_or("unknown"),
+            probe.mode,
+            probe.port,
+            probe.gdb_port.as_deref().map(|port| format!(" ({})", port)).unwrap_or_default(),
+        );
+    }
+    // Flush each line, so events show up straight away when piped into something else.
+    let _ = stdout.flush();
+}
+
+/// What changed on the bus between two looks with [`ProbeWatcher::poll`].
+pub enum BusEvent
+{
+    /// A probe was connected, or switched mode.
+    Connected(Box<BmpDevice>, ProbeInfo),
+    /// A probe was disconnected, or switched mode. Its information is from when it was connected.
+    Disconnected(ProbeInfo),
+}
+
+/// Watches for the probes matching a [`BmpMatcher`] being connected and disconnected.
+pub struct ProbeWatcher
+{
+    matcher: BmpMatcher,
+    context: rusb::Context,
+    hotplug: Option<Registration<rusb::Context>>,
+    changed: Arc<AtomicBool>,
+    present: HashMap<(String, DfuOperatingMode), ProbeInfo>,
+}
+
+impl ProbeWatcher
+{
+    pub fn new(matcher: BmpMatcher) -> Result<Self, Error>
+    {
+        // Check libusb works at all first, as asking about hotplug support would otherwise panic.
+        let context = bmputil::usb::new_context()
+            .map_err(|e| ErrorKind::External(ErrorSource::Libusb(e)).error().with_ctx("initializing libusb"))?;
+
+        let changed = Arc::new(AtomicBool::new(false));
+        let hotplug = if bmputil::usb::has_hotplug(&context) {
+            let watcher = BusWatcher {
+                changed: Arc::clone(&changed),
+            };
+            HotplugBuilder::new()
+                .enumerate(false)
+                .register(&context, Box::new(watcher))
+                .inspect_err(|e| debug!("Failed to register for hotplug notifications, falling back to polling: {}", e))
+                .ok()
+        } else {
+            debug!("libusb does not support hotplug notifications on this platform, falling back to polling");
+            None
+        };
+
+        Ok(Self {
+            matcher,
+            context,
+            hotplug,
+            changed,
+            present: HashMap::new(),
+        })
+    }
+
+    /// Looks for the probes again, returning what changed since the last time. The first time,
+    /// every probe already connected is reported as connected.
+    pub fn poll(&mut self) -> Vec<BusEvent>
+    {
+        let results = self.matcher.find_matching_probes();
+        for error in &results.errors {
+            debug!("Error while looking for probes: {}", error);
+        }
+
+        let mut events = Vec::new();
+        let mut now_present = HashMap::new();
+        for dev in results.found {
+            let key = (dev.port(), dev.operating_mode());
+            let info = match self.present.remove(&key) {
+                Some(info) => info,
+                None => {
+                    let info = dev.info();
+                    events.push(BusEvent::Connected(Box::new(dev), info.clone()));
+                    info
+                },
+            };
+            now_present.insert(key, info);
+        }
+        events.extend(self.present.drain().map(|(_, info)| BusEvent::Disconnected(info)));
+        self.present = now_present;
+
+        events
+    }
+
+    /// Forgets about the probe on USB port `port`, so the next [`poll()`](Self::poll) reports it as
+    /// connected again if it's still there.
+    pub fn forget(&mut self, port: &str)
+    {
+        self.present.retain(|(present_port, _), _| present_port != port);
+    }
+
+    /// Blocks until something may have changed on the bus, or it's time to look again anyway.
+    pub fn wait(&mut self) -> Result<(), Error>
+    {
+        if self.hotplug.is_none() {
+            thread::sleep(POLL_INTERVAL);
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + POLL_INTERVAL;
+        while !self.changed.swap(false, Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            self.context.handle_events(Some(deadline - now))
+                .map_err(|e| ErrorKind::External(ErrorSource::Libusb(e)).error().with_ctx("waiting for USB events"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Implements `bmputil watch`, which only returns on error.
+pub fn watch_command(matches: &ArgMatches) -> Result<(), Error>
+{
+    let json = crate::output_format(matches) == OutputFormat::Json;
+    let mut watcher = ProbeWatcher::new(crate::matcher_from_cli_args(matches))?;
+
+    if !json {
+        eprintln!("Watching for Black Magic Probe devices. Press Ctrl-C to stop.");
+    }
+
+    let start = Instant::now();
+    loop {
+        for event in watcher.poll() {
+            match event {
+                BusEvent::Connected(_, info) => print_event("connected", &info, json, start),
+                BusEvent::Disconnected(info) => print_event("disconnected", &info, json, start),
+            }
+        }
+        watcher.wait()?;
+    }
+}
@@ -50,6 +50,14 @@ use winapi::um::winbase::{STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, STD_ERROR_HANDLE}
 use winapi::um::consoleapi::AllocConsole;
 use deelevate::{Token, PrivilegeLevel};
 
+use bmputil::error::{Error, ErrorKind, ErrorSource};
+
+/// Hardware ID (without the `USB\` enumerator) of the DFU interface of a BMP in app mode.
+const APP_MODE_DFU_HWID: &str = "VID_1D50&PID_6018&MI_04";
+
+/// Hardware ID (without the `USB\` enumerator) of a BMP in DFU mode.
+const DFU_MODE_HWID: &str = "VID_1D50&PID_6017";
+
 /// From fnctl.h
 /// ```c
 /// #define _O_TEXT        0x4000  // file mode is text (translated)
@@ -382,6 +390,133 @@ pub fn hwid_bound_to_driver(hardware_id: &str, enumerator: &str) -> IoResult<Vec
 }
 
 
+/// Checks which services (i.e. drivers, such as `WinUSB` or `libusbK`) Windows is using for the
+/// device nodes with [HardwareId] `hardware_id`, one for each instance of the device seen so far.
+/// Devices that have never been plugged in have none.
+///
+/// `hardware_id` should *not* include the enumerator name. e.g. no leading `USB\`.
+///
+/// This function checks the `Service` value of each subkey of
+/// `HKLM:\SYSTEM\CurrentControlSet\Enum\USB\{hardware_id}`.
+///
+/// [HardwareId]: (https://learn.microsoft.com/en-us/windows-hardware/drivers/install/hardware-ids)
+pub fn hwid_services(hardware_id: &str) -> IoResult<Vec<String>>
+{
+    let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+    let enum_subkey_name = format!(r"SYSTEM\CurrentControlSet\Enum\USB\{}", hardware_id);
+
+    trace!(r"Opening HKLM:\{}", &enum_subkey_name);
+    let device_key = match hklm.open_subkey(&enum_subkey_name) {
+        Ok(key) => key,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut services: Vec<String> = Vec::new();
+    for instance_name in device_key.enum_keys() {
+        let instance_key = device_key.open_subkey(instance_name?)?;
+        if let Ok(service) = instance_key.get_value::<String, _>("Service") {
+            if !services.iter().any(|known| known.eq_ignore_ascii_case(&service)) {
+                services.push(service);
+            }
+        }
+    }
+
+    Ok(services)
+}
+
+/// Describes the services [hwid_services] finds for `hardware_id`, for printing.
+fn describe_services(hardware_id: &str) -> String
+{
+    match hwid_services(hardware_id) {
+        Ok(services) if services.is_empty() => String::from("service unknown until the device is plugged in"),
+        Ok(services) => format!("service {}", services.join(", ")),
+        Err(e) => format!("service could not be checked ({})", e),
+    }
+}
+
+/// Whether a device node with `hardware_id` has been seen bound to a service other than WinUSB.
+fn bound_to_other_service(hardware_id: &str) -> bool
+{
+    hwid_services(hardware_id)
+        .map(|services| services.iter().any(|service| !service.eq_ignore_ascii_case("WinUSB")))
+        .unwrap_or(false)
+}
+
+/// Prints which drivers are bound to the BMP device nodes bmputil needs WinUSB for, and which
+/// service each uses, returning whether all of them have a driver.
+pub fn report_driver_status() -> bool
+{
+    let mut all_bound = true;
+    for (description, hwid) in [("DFU interface in app mode", APP_MODE_DFU_HWID), ("DFU mode", DFU_MODE_HWID)] {
+        match hwid_bound_to_driver(hwid, "USB") {
+            Ok(driver_names) if driver_names.is_empty() => {
+                println!("{} (USB\\{}): no driver installed", description, hwid);
+                all_bound = false;
+            },
+            Ok(driver_names) => println!(
+                "{} (USB\\{}): {} ({})",
+                description,
+                hwid,
+                driver_names.join(", "),
+                describe_services(hwid),
+            ),
+            Err(e) => {
+                println!("{} (USB\\{}): could not check ({})", description, hwid, e);
+                all_bound = false;
+            },
+        }
+    }
+
+    all_bound
+}
+
+/// Whether `e` is what libusb reports for a device without a driver it can use (i.e. WinUSB).
+pub fn is_missing_driver_error(e: &Error) -> bool
+{
+    matches!(e.kind, ErrorKind::External(ErrorSource::Libusb(rusb::Error::NotSupported)))
+}
+
+/// Explains a missing driver error, and how to fix it.
+pub fn print_driver_hint()
+{
+    eprintln!("note: libusb cannot use the driver Windows has bound to the Black Magic Probe. Driver status:");
+    let all_bound = report_driver_status();
+    if [APP_MODE_DFU_HWID, DFU_MODE_HWID].into_iter().any(bound_to_other_service) {
+        eprintln!(
+            "note: the probe is bound to a driver other than WinUSB. \
+            Run `bmputil setup-driver --force` to rebind it to WinUSB, then replug the probe, \
+            or pass `--usb-backend usbdk` if UsbDk is installed."
+        );
+    } else if all_bound {
+        eprintln!(
+            "note: a driver is installed for each interface, but it may not be WinUSB. \
+            Run `bmputil setup-driver --force` to replace it with WinUSB, then replug the probe."
+        );
+    } else {
+        eprintln!("note: run `bmputil setup-driver` to install WinUSB (needs administrator access), then replug the probe.");
+    }
+}
+
+
+/// Offers to rebind the Black Magic Probe interfaces to WinUSB after a missing driver error, by
+/// running `bmputil setup-driver --force`, which asks for administrator access itself.
+pub fn offer_driver_rebind(ask: impl FnOnce(&str) -> bool)
+{
+    if !ask("Rebind the Black Magic Probe to WinUSB now (needs administrator access)?") {
+        return;
+    }
+
+    let status = env::current_exe()
+        .and_then(|exe| std::process::Command::new(exe).args(["setup-driver", "--force"]).status());
+    match status {
+        Ok(status) if status.success() => println!("Done. Replug the probe, then try again."),
+        Ok(status) => error!("Rebinding the driver failed ({})", status),
+        Err(e) => error!("Failed to run `bmputil setup-driver --force`: {}", e),
+    }
+}
+
+
 /// This function ensures that all connected Black Magic Probe devices have the necessary drivers installed, via libwdi.
 /// If `explicitly_requested` is true, then this will print if there is nothing to do.
 /// If `force` is true, then this will install even if there is an existing driver.
@@ -400,7 +535,7 @@ pub fn ensure_access(parent_pid: Option<u32>, explicitly_requested: bool, force:
         devices_needing_driver.push(DFU_MODE_WDI_INFO.clone());
     } else {
 
-        match hwid_bound_to_driver("VID_1D50&PID_6018&MI_04", "USB") {
+        match hwid_bound_to_driver(APP_MODE_DFU_HWID, "USB") {
             Ok(driver_names) if driver_names.len() == 0 => {
                 devices_needing_driver.push(APP_MODE_WDI_INFO.clone());
                 info!("Scheduling WinUSB driver installation for app mode BMP device...");
@@ -417,7 +552,7 @@ pub fn ensure_access(parent_pid: Option<u32>, explicitly_requested: bool, force:
             },
         }
 
-        match hwid_bound_to_driver("VID_1D50&PID_6017", "USB") {
+        match hwid_bound_to_driver(DFU_MODE_HWID, "USB") {
             Ok(driver_names) if driver_names.len() == 0 => {
                 devices_needing_driver.push(DFU_MODE_WDI_INFO.clone());
                 info!("Scheduling WinUSB driver installation for DFU mode BMP device...");
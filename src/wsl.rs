@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2022-2023 1BitSquared <info@1bitsquared.com>
+// SPDX-FileContributor: Written by Mikaela Szekely <mikaela.szekely@qyriad.me>
+//! Module for explaining missing probes when running under WSL.
+//!
+//! WSL 2 has no USB devices of its own: they have to be attached from Windows with usbipd-win
+//! (`usbipd bind`, then `usbipd attach --wsl`), and a probe that reboots into DFU mode comes back
+//! as a new device that has to be attached again. We can ask usbipd-win, through WSL's Windows
+//! interop, which probes Windows sees and what state they're in, and tell the user exactly what
+//! to run.
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use log::debug;
+
+use bmputil::error::{Error, ErrorKind};
+
+use crate::udev::PROBE_IDS;
+
+/// Where usbipd-win installs itself, for when the Windows `PATH` isn't passed through to WSL.
+const USBIPD_INSTALL_PATH: &str = "/mnt/c/Program Files/usbipd-win/usbipd.exe";
+
+/// Whether bmputil is running under the Windows Subsystem for Linux.
+pub fn is_wsl() -> bool
+{
+    env::var_os("WSL_DISTRO_NAME").is_some()
+        || fs::read_to_string("/proc/sys/kernel/osrelease")
+            .map(|release| release.to_ascii_lowercase().contains("microsoft"))
+            .unwrap_or(false)
+}
+
+/// How far a probe has got to being usable from WSL, according to usbipd-win.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum AttachState
+{
+    /// Not shared with usbipd yet, which needs `usbipd bind` as administrator.
+    NotShared,
+    /// Shared, but not attached to WSL.
+    Shared,
+    /// Already attached to WSL (possibly another distribution).
+    Attached,
+}
+
+/// A Black Magic Probe connected to Windows, as listed by `usbipd list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UsbipdDevice
+{
+    busid: String,
+    state: AttachState,
+}
+
+/// Runs `usbipd list` on the Windows side, returning its output, or `None` if usbipd-win isn't
+/// installed or couldn't be run.
+fn usbipd_list() -> Option<String>
+{
+    ["usbipd.exe", USBIPD_INSTALL_PATH]
+        .iter()
+        .find_map(|usbipd| {
+            Command::new(usbipd)
+                .arg("list")
+                .output()
+                .inspect_err(|e| debug!("Failed to run {}: {}", usbipd, e))
+                .ok()
+                .filter(|output| output.status.success())
+        })
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Picks the Black Magic Probes out of `usbipd list` output.
+///
+/// Connected devices are listed as `BUSID  VID:PID  DEVICE  STATE`, e.g.
+/// `1-4    1d50:6018  Black Magic GDB Server, Black Magic UART Port   Not shared`.
+fn parse_usbipd_list(output: &str) -> Vec<UsbipdDevice>
+{
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let busid = columns.next()?;
+            let (vid, pid) = columns.next()?.split_once(':')?;
+            let vid = u16::from_str_radix(vid, 16).ok()?;
+            let pid = u16::from_str_radix(pid, 16).ok()?;
+            if !PROBE_IDS.iter().any(|(probe_vid, probe_pid)| probe_vid.0 == vid && probe_pid.0 == pid) {
+                return None;
+            }
+
+            // Older usbipd-win versions say "Not attached" and "Attached - <distribution>".
+            let line = line.trim_end();
+            let state = if line.ends_with("Not shared") {
+                AttachState::NotShared
+            } else if line.contains("Attached") && !line.contains("Not attached") {
+                AttachState::Attached
+            } else {
+                AttachState::Shared
+            };
+
+            Some(UsbipdDevice {
+                busid: busid.to_string(),
+                state,
+            })
+        })
+        .collect()
+}
+
+/// Whether `e` is a probe not being found, which under WSL is most likely a usbipd problem.
+pub fn is_not_found_error(e: &Error) -> bool
+{
+    matches!(e.kind, ErrorKind::DeviceNotFound) && is_wsl()
+}
+
+/// Explains how to make probes connected to Windows visible in WSL.
+pub fn print_usbipd_hint()
+{
+    eprintln!("note: running under WSL, where USB devices have to be attached from Windows with usbipd-win.");
+
+    let output = match usbipd_list() {
+        Some(output) => output,
+        None => {
+            eprintln!(
+                "note: usbipd-win does not seem to be installed. Install it from \
+                https://github.com/dorssel/usbipd-win, then see \
+                https://learn.microsoft.com/windows/wsl/connect-usb for how to attach the probe.",
+            );
+            return;
+        },
+    };
+
+    let devices = parse_usbipd_list(&output);
+    if devices.is_empty() {
+        eprintln!("note: usbipd does not see a Black Magic Probe connected to Windows either; check that it's plugged in.");
+        return;
+    }
+
+    for device in &devices {
+        let busid = &device.busid;
+        match device.state {
+            AttachState::NotShared => {
+                eprintln!("note: the Black Magic Probe at bus ID {} is not shared with WSL yet. In an administrator", busid);
+                eprintln!("      Windows terminal, run:");
+                eprintln!("        usbipd bind --busid {}", busid);
+                eprintln!("      and then, in any Windows terminal:");
+                eprintln!("        usbipd attach --wsl --auto-attach --busid {}", busid);
+            },
+            AttachState::Shared => {
+                eprintln!("note: the Black Magic Probe at bus ID {} is shared but not attached to WSL. In a Windows", busid);
+                eprintln!("      terminal, run:");
+                eprintln!("        usbipd attach --wsl --auto-attach --busid {}", busid);
+            },
+            AttachState::Attached => {
+                eprintln!(
+                    "note: the Black Magic Probe at bus ID {} is attached to WSL, but may be attached to another \
+                    distribution. Detach it with `usbipd detach --busid {}` and attach it again from here.",
+                    busid,
+                    busid,
+                );
+            },
+        }
+    }
+    if devices.iter().any(|device| device.state != AttachState::Attached) {
+        eprintln!(
+            "note: --auto-attach keeps the probe attached when it reboots (e.g. into DFU mode for flashing); \
+            leave that command running while using bmputil.",
+        );
+    }
+}